@@ -0,0 +1,49 @@
+//! Benchmarks for the network-free stages of the download pipeline.
+//!
+//! Run with `cargo bench`. The fixtures live in `tests/fixtures` and stand in
+//! for the fiction and chapter pages normally fetched from Royal Road.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rustyroad::{parse_chapter_content, parse_story, BookWriter, CleanOpts, EpubWriter};
+
+const FICTION: &str = include_str!("../tests/fixtures/fiction.html");
+const CHAPTER: &str = include_str!("../tests/fixtures/chapter.html");
+
+fn parsing(c: &mut Criterion) {
+    c.bench_function("parse_story", |b| b.iter(|| parse_story(FICTION).unwrap()));
+}
+
+fn cleaning(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clean");
+    group.throughput(Throughput::Bytes(CHAPTER.len() as u64));
+    group.bench_function("parse_chapter_content", |b| {
+        b.iter(|| parse_chapter_content(CHAPTER, &CleanOpts::default()).unwrap())
+    });
+    group.finish();
+}
+
+fn generation(c: &mut Criterion) {
+    let story = parse_story(FICTION).unwrap();
+    let content = parse_chapter_content(CHAPTER, &CleanOpts::default()).unwrap();
+
+    let mut group = c.benchmark_group("generate");
+    group.throughput(Throughput::Elements(story.chapters.len() as u64));
+    group.bench_function("epub", |b| {
+        b.iter(|| {
+            let mut writer = EpubWriter::new(&story).unwrap();
+            writer.add_toc();
+
+            for (i, chapter) in story.chapters.iter().enumerate() {
+                writer.add_chapter(i, chapter, &content).unwrap();
+            }
+
+            let mut out = Vec::new();
+            writer.write(&mut out).unwrap();
+            out
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parsing, cleaning, generation);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+//! Anthologies: several stories bound into one book, like the entries of a
+//! writing contest or an author's short fiction.
+
+use crate::{detect_language, Story};
+
+/// One story made of `stories`, in order, titled `title` or after the
+/// stories. Each story's chapters are filed under its title as their volume,
+/// in place of any volumes of its own, so the table of contents nests them
+/// under it. The anthology has no URL of its own; its description lists its
+/// stories with their authors and sources.
+pub fn anthology(stories: Vec<Story>, title: Option<&str>) -> Story {
+    let titles: Vec<&str> = stories.iter().map(|story| story.title.as_str()).collect();
+    let mut authors: Vec<&str> = Vec::new();
+    let mut tags: Vec<String> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    for story in &stories {
+        if !authors.contains(&story.author.as_str()) {
+            authors.push(&story.author);
+        }
+        for tag in &story.tags {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        for warning in &story.warnings {
+            if !warnings.contains(warning) {
+                warnings.push(warning.clone());
+            }
+        }
+    }
+
+    // the book can only be marked as one language
+    let language = stories.first().and_then(|first| {
+        let language = detect_language(first);
+        stories
+            .iter()
+            .all(|story| detect_language(story) == language)
+            .then_some(language)
+    });
+
+    let mut description = format!("An anthology of {} stories:\n", stories.len());
+    for story in &stories {
+        description.push_str(&format!(
+            "{} by {}, from {}\n",
+            story.title, story.author, story.url
+        ));
+    }
+
+    let mut anthology = Story {
+        url: String::new(),
+        title: title.map_or_else(|| list(&titles), str::to_string),
+        author: list(&authors),
+        description,
+        cover: String::new(),
+        tags,
+        chapters: Vec::new(),
+        words: stories.iter().map(|story| story.words).sum(),
+        status: None,
+        pages: Vec::new(),
+        language,
+        warnings,
+    };
+    for story in stories {
+        anthology
+            .chapters
+            .extend(story.chapters.into_iter().map(|mut chapter| {
+                chapter.volume = Some(story.title.clone());
+                chapter
+            }));
+    }
+    anthology
+}
+
+/// `items` as e.g. `A, B and C`.
+fn list(items: &[&str]) -> String {
+    match items {
+        [] => String::new(),
+        [item] => item.to_string(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
@@ -0,0 +1,489 @@
+//! Audiobooks, read out by a text-to-speech engine from the text
+//! `--format txt` writes. Experimental: reading out a long story takes its
+//! time, and the voices are what the engines make of it.
+//!
+//! The engine is a local program, espeak-ng or piper, or an HTTP API that
+//! answers a posted text with audio. A book is written either as a
+//! directory with an audio file per chapter and an `.m3u` playlist, or as
+//! one `.m4b` with chapter marks, put together by ffmpeg.
+
+use eyre::{eyre, Result, WrapErr};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::calibre::on_path;
+use crate::text::html_to_text;
+use crate::{write_atomically, BookWriter, Chapter, Client, Cover, Image, Reception, Story};
+
+/// The text-to-speech engine an audiobook is read by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TtsEngine {
+    /// espeak-ng (or espeak), with `--tts-voice` as its voice, e.g. `en-us`
+    #[default]
+    Espeak,
+    /// piper, with the voice model file given by `--tts-voice`
+    Piper,
+    /// An HTTP API at `--tts-url`, posted `{"text": ..., "voice": ...}` and
+    /// answering with the audio
+    Http,
+}
+
+/// How an audiobook is read out.
+#[derive(Clone, Default)]
+pub struct TtsOpts {
+    pub engine: TtsEngine,
+    /// The engine's voice: a name for espeak and the API, a model file for
+    /// piper.
+    pub voice: Option<String>,
+    /// Where the HTTP API is. A book read by the API has to be written
+    /// within tokio's multi-threaded runtime.
+    pub url: Option<String>,
+    /// The client the HTTP API is reached with, keeping to its proxy,
+    /// timeouts, retries and limits. Default: one with the default
+    /// [`ClientOpts`](crate::ClientOpts)
+    pub client: Option<Client>,
+}
+
+/// The engine, found and ready to read.
+#[derive(Clone)]
+enum Engine {
+    Espeak(PathBuf),
+    Piper(PathBuf),
+    Http(reqwest::Url, Box<Client>),
+}
+
+impl Engine {
+    fn find(opts: &TtsOpts) -> Result<Engine> {
+        match opts.engine {
+            TtsEngine::Espeak => on_path("espeak-ng")
+                .or_else(|| on_path("espeak"))
+                .map(Engine::Espeak)
+                .ok_or_else(|| {
+                    eyre!("--format audiobook needs espeak-ng on the PATH, or another --tts")
+                }),
+            TtsEngine::Piper => {
+                if opts.voice.is_none() {
+                    return Err(eyre!(
+                        "--tts piper needs the voice model to read with as --tts-voice"
+                    ));
+                }
+                on_path("piper")
+                    .map(Engine::Piper)
+                    .ok_or_else(|| eyre!("--tts piper needs piper on the PATH"))
+            }
+            TtsEngine::Http => {
+                let url = opts
+                    .url
+                    .as_deref()
+                    .ok_or_else(|| eyre!("--tts http needs the API's URL as --tts-url"))?;
+                let url = reqwest::Url::parse(url)
+                    .wrap_err_with(|| format!("invalid --tts-url {:?}", url))?;
+                // fail before any chapter is read rather than at the first
+                runtime()?;
+                let client = match &opts.client {
+                    Some(client) => client.clone(),
+                    None => Client::new(Default::default())?,
+                };
+                Ok(Engine::Http(url, Box::new(client)))
+            }
+        }
+    }
+
+    /// Reads `text` out into a file in `dir` named `name` and the
+    /// extension of the audio, and returns its path.
+    fn speak(&self, text: &str, voice: Option<&str>, dir: &Path, name: &str) -> Result<PathBuf> {
+        let out = dir.join(format!("{}.wav", name));
+        let mut command = match self {
+            Engine::Espeak(program) => {
+                let mut command = Command::new(program);
+                command.arg("--stdin").arg("-w").arg(&out);
+                if let Some(voice) = voice {
+                    command.args(["-v", voice]);
+                }
+                command
+            }
+            Engine::Piper(program) => {
+                let mut command = Command::new(program);
+                command
+                    .args(["--model", voice.unwrap_or_default(), "--output_file"])
+                    .arg(&out);
+                command
+            }
+            Engine::Http(url, client) => return speak_http(client, url, text, voice, dir, name),
+        };
+        run_with_input(&mut command, text)?;
+        if !out.is_file() {
+            return Err(eyre!("{:?} wrote no audio", command.get_program()));
+        }
+        Ok(out)
+    }
+}
+
+/// Runs `command`, writing `input` to it.
+fn run_with_input(command: &mut Command, input: &str) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("could not run {}", program))?;
+    // the input is written from a thread of its own while stderr is read,
+    // so an engine that logs a lot before reading all of it can't stall
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    let written = writer
+        .join()
+        .map_err(|_| eyre!("could not write to {}", program))?;
+    // an engine that exits early breaks the pipe; what it said matters more
+    if !output.status.success() {
+        return Err(eyre!(
+            "{} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    written.wrap_err_with(|| format!("could not write to {}", program))?;
+    Ok(())
+}
+
+/// The runtime the HTTP API is asked from. The book is written outside of
+/// the async code that downloads it, so the requests block on it, which
+/// tokio only allows on its multi-threaded runtime.
+fn runtime() -> Result<tokio::runtime::Handle> {
+    let handle = tokio::runtime::Handle::try_current()
+        .map_err(|_| eyre!("--tts http has to run within a tokio runtime"))?;
+    if handle.runtime_flavor() != tokio::runtime::RuntimeFlavor::MultiThread {
+        return Err(eyre!("--tts http needs tokio's multi-threaded runtime"));
+    }
+    Ok(handle)
+}
+
+fn speak_http(
+    client: &Client,
+    url: &reqwest::Url,
+    text: &str,
+    voice: Option<&str>,
+    dir: &Path,
+    name: &str,
+) -> Result<PathBuf> {
+    let runtime = runtime()?;
+    let (mime, data) = tokio::task::block_in_place(|| {
+        runtime.block_on(async {
+            let json = serde_json::json!({ "text": text, "voice": voice });
+            let resp = client
+                .post_json(url.clone(), &json)
+                .await
+                .wrap_err_with(|| format!("the TTS API at {} failed", url))?;
+            let mime = resp
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|mime| mime.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            Ok::<_, eyre::Error>((mime, client.bytes(resp).await?))
+        })
+    })?;
+    let ext = match mime.split(';').next().unwrap_or_default().trim() {
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/ogg" | "audio/opus" => "ogg",
+        "audio/mp4" | "audio/aac" | "audio/x-m4a" => "m4a",
+        "audio/flac" => "flac",
+        "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+        _ if data.starts_with(b"RIFF") => "wav",
+        other => return Err(eyre!("the TTS API at {} sent {:?}, not audio", url, other)),
+    };
+    let out = dir.join(format!("{}.{}", name, ext));
+    std::fs::write(&out, data).wrap_err_with(|| format!("could not write {}", out.display()))?;
+    Ok(out)
+}
+
+/// The length of the audio at `path` in seconds: read from the header of a
+/// WAV file, asked of ffprobe for anything else.
+fn duration(path: &Path) -> Option<f64> {
+    if path.extension().is_some_and(|ext| ext == "wav") {
+        let data = std::fs::read(path).ok()?;
+        let byte_rate = u32::from_le_bytes(data.get(28..32)?.try_into().ok()?);
+        return (byte_rate > 0).then(|| (data.len().saturating_sub(44)) as f64 / byte_rate as f64);
+    }
+    let output = Command::new(on_path("ffprobe")?)
+        .args(["-v", "error", "-show_entries", "format=duration", "-of"])
+        .arg("default=noprint_wrappers=1:nokey=1")
+        .arg(path)
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// A part of the book that is read out.
+struct Track {
+    title: String,
+    text: String,
+}
+
+/// Writes an audiobook, reading every chapter out once the book is
+/// complete.
+pub struct AudiobookWriter {
+    engine: Engine,
+    voice: Option<String>,
+    title: String,
+    author: String,
+    cover: Option<Cover>,
+    tracks: Vec<Track>,
+}
+
+impl AudiobookWriter {
+    /// Fails right away if the engine isn't there, before the chapters are
+    /// downloaded.
+    pub fn new(story: &Story, opts: &TtsOpts) -> Result<AudiobookWriter> {
+        log::warn!("audiobooks are experimental, and take a while to read out");
+        Ok(AudiobookWriter {
+            engine: Engine::find(opts)?,
+            voice: opts.voice.clone(),
+            title: story.title.clone(),
+            author: story.author.clone(),
+            cover: None,
+            tracks: Vec::new(),
+        })
+    }
+
+    /// Reads out every track into `dir`, returning the files with their
+    /// titles.
+    fn speak_all(&self, dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+        let mut files = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            log::info!(
+                "reading out {} ({}/{})...",
+                track.title,
+                i + 1,
+                self.tracks.len()
+            );
+            let name = format!("{:03} - {}", i + 1, file_name(&track.title));
+            let file = self
+                .engine
+                .speak(&track.text, self.voice.as_deref(), dir, &name)
+                .wrap_err_with(|| format!("could not read out {}", track.title))?;
+            files.push((file, track.title.clone()));
+        }
+        Ok(files)
+    }
+
+    /// Writes the tracks into `dir` with a playlist of them.
+    fn write_dir(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        let mut playlist = String::from("#EXTM3U\n");
+        playlist.push_str(&format!("#PLAYLIST:{} by {}\n", self.title, self.author));
+        for (file, title) in self.speak_all(dir)? {
+            let seconds = duration(&file).map_or(-1, |seconds| seconds.round() as i64);
+            playlist.push_str(&format!(
+                "#EXTINF:{},{}\n{}\n",
+                seconds,
+                title,
+                file.file_name().unwrap().to_string_lossy()
+            ));
+        }
+        let path = dir.join("playlist.m3u");
+        std::fs::write(&path, playlist)
+            .wrap_err_with(|| format!("could not write {}", path.display()))
+    }
+
+    /// Reads out the tracks and has ffmpeg put them together as an M4B
+    /// with a chapter for each.
+    fn write_m4b(&self, out: &mut dyn Write) -> Result<()> {
+        let ffmpeg = on_path("ffmpeg").ok_or_else(|| {
+            eyre!("an .m4b audiobook needs ffmpeg on the PATH; give --out a directory instead")
+        })?;
+        let dir = tempfile::tempdir()?;
+        let files = self.speak_all(dir.path())?;
+
+        let mut list = String::new();
+        let mut chapters = format!(
+            ";FFMETADATA1\ntitle={}\nartist={}\n",
+            metadata_value(&self.title),
+            metadata_value(&self.author)
+        );
+        let mut start = 0.0;
+        for (file, title) in &files {
+            list.push_str(&format!(
+                "file '{}'\n",
+                file.to_string_lossy().replace('\'', r"'\''")
+            ));
+            let end = start + duration(file).unwrap_or_default();
+            chapters.push_str(&format!(
+                "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+                (start * 1000.0) as u64,
+                (end * 1000.0) as u64,
+                metadata_value(title)
+            ));
+            start = end;
+        }
+        let list_path = dir.path().join("tracks.txt");
+        std::fs::write(&list_path, list)?;
+        let chapters_path = dir.path().join("chapters.txt");
+        std::fs::write(&chapters_path, chapters)?;
+
+        let book = dir.path().join("book.m4b");
+        let mut command = Command::new(&ffmpeg);
+        command
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "concat",
+                "-safe",
+                "0",
+                "-i",
+            ])
+            .arg(&list_path)
+            .arg("-i")
+            .arg(&chapters_path);
+        let cover = match &self.cover {
+            Some(cover) => {
+                let path = dir.path().join(format!("cover.{}", cover.ext));
+                std::fs::write(&path, &cover.data)?;
+                command.arg("-i").arg(path);
+                true
+            }
+            None => false,
+        };
+        command.args(["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1"]);
+        if cover {
+            command.args([
+                "-map",
+                "2:v",
+                "-c:v",
+                "copy",
+                "-disposition:v",
+                "attached_pic",
+            ]);
+        }
+        command.args(["-c:a", "aac", "-b:a", "64k"]).arg(&book);
+        log::info!("putting the audiobook together with ffmpeg...");
+        let output = command.output().wrap_err("could not run ffmpeg")?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "ffmpeg failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        std::io::copy(&mut std::fs::File::open(&book)?, out)?;
+        Ok(())
+    }
+}
+
+/// `title` as a file name.
+fn file_name(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(80)
+        .collect();
+    name.trim().to_string()
+}
+
+/// `value` escaped for an ffmpeg metadata file.
+fn metadata_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// The text of `html` that is read out: its paragraphs, without the scene
+/// breaks and anything else with no words in it.
+fn speech(html: &str) -> String {
+    html_to_text(html)
+        .split("\n\n")
+        .filter(|paragraph| paragraph.chars().any(char::is_alphanumeric))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+impl BookWriter for AudiobookWriter {
+    fn add_cover(&mut self, cover: &Cover, _in_toc: bool) -> Result<()> {
+        self.cover = Some(cover.clone());
+        Ok(())
+    }
+
+    fn add_toc(&mut self) {}
+
+    // the title is read out first
+    fn add_chapter(&mut self, _nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        self.tracks.push(Track {
+            title: chapter.name.clone(),
+            text: format!("{}.\n\n{}", chapter.name, speech(content)),
+        });
+        Ok(())
+    }
+
+    fn add_image(&mut self, _image: &Image) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_source_page(&mut self, _story: &Story, _in_toc: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_colophon(&mut self, _paragraphs: &[String], _in_toc: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_reviews(&mut self, _reception: &Reception, _in_toc: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// The front matter is read out before the chapters: the title, the
+    /// author and the description.
+    fn add_front_matter(&mut self, story: &Story, _in_toc: bool) -> Result<()> {
+        let mut text = format!("{}. By {}.", story.title, story.author);
+        for line in story.description.lines().map(str::trim) {
+            if !line.is_empty() {
+                text.push_str("\n\n");
+                text.push_str(line);
+            }
+        }
+        self.tracks.insert(
+            0,
+            Track {
+                title: story.title.clone(),
+                text,
+            },
+        );
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, _added: usize, _total: usize) {
+        self.title = format!("{} [INCOMPLETE]", story.title);
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        self.write_m4b(out)
+    }
+
+    /// A path ending in `.m4b` is written as one file, anything else as a
+    /// directory.
+    fn write_to(&mut self, path: &Path) -> Result<()> {
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("m4b"))
+        {
+            return write_atomically(path, |out| self.write_m4b(out));
+        }
+        self.write_dir(path)
+    }
+}
@@ -0,0 +1,210 @@
+//! AZW3 output for Kindles, by way of an external converter.
+//!
+//! The book is written as an EPUB first and converted with Calibre's
+//! `ebook-convert`, or with Amazon's `kindlegen` where Calibre isn't
+//! installed. `kindlegen` writes a combined MOBI/KF8 file, which Kindles open
+//! like an AZW3 and older models can still read.
+
+use eyre::{eyre, Result, WrapErr};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::calibre::{calibre_tool, on_path};
+use crate::{
+    BookWriter, Chapter, Compression, Cover, EpubVersion, EpubWriter, Image, Manifest, Reception,
+    Series, Story, Style,
+};
+
+/// A program that turns an EPUB into a Kindle book.
+#[derive(Debug, Clone)]
+enum Converter {
+    EbookConvert(PathBuf),
+    Kindlegen(PathBuf),
+}
+
+impl Converter {
+    /// The first converter installed, preferring Calibre's.
+    fn find() -> Result<Converter> {
+        if let Some(path) = calibre_tool("ebook-convert") {
+            return Ok(Converter::EbookConvert(path));
+        }
+        if let Some(path) = on_path("kindlegen") {
+            return Ok(Converter::Kindlegen(path));
+        }
+        Err(eyre!(
+            "--format azw3 needs ebook-convert (part of Calibre, https://calibre-ebook.com) \
+             or kindlegen on the PATH, and neither was found; install one, or write an epub \
+             and send it to the Kindle with --send-to-kindle"
+        ))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Converter::EbookConvert(_) => "ebook-convert",
+            Converter::Kindlegen(_) => "kindlegen",
+        }
+    }
+
+    /// Converts the EPUB at `epub` into a Kindle book next to it, and
+    /// returns the path of the Kindle book.
+    fn convert(&self, epub: &Path) -> Result<PathBuf> {
+        let out = epub.with_extension(match self {
+            Converter::EbookConvert(_) => "azw3",
+            Converter::Kindlegen(_) => "mobi",
+        });
+        let output = match self {
+            Converter::EbookConvert(program) => Command::new(program).arg(epub).arg(&out).output(),
+            // kindlegen takes the output's name without a directory, and puts
+            // it next to the input
+            Converter::Kindlegen(program) => Command::new(program)
+                .arg(epub)
+                .arg("-o")
+                .arg(out.file_name().unwrap())
+                .output(),
+        }
+        .wrap_err_with(|| format!("could not run {}", self.name()))?;
+
+        // kindlegen exits with 1 when it only has warnings, and still writes
+        // the book
+        if out.is_file()
+            && (output.status.success()
+                || matches!(self, Converter::Kindlegen(_)) && output.status.code() == Some(1))
+        {
+            return Ok(out);
+        }
+        let log = String::from_utf8_lossy(if output.stderr.is_empty() {
+            &output.stdout
+        } else {
+            &output.stderr
+        })
+        .into_owned();
+        let tail: Vec<&str> = log.lines().rev().take(5).collect();
+        let tail: Vec<&str> = tail.into_iter().rev().collect();
+        Err(eyre!(
+            "{} failed ({}): {}",
+            self.name(),
+            output.status,
+            tail.join("\n")
+        ))
+    }
+}
+
+/// Writes an AZW3: an EPUB handed to a converter once it is complete.
+pub struct Azw3Writer {
+    inner: EpubWriter,
+    converter: Converter,
+}
+
+impl Azw3Writer {
+    /// Fails right away if no converter is installed, before the chapters
+    /// are downloaded.
+    pub fn new(story: &Story) -> Result<Azw3Writer> {
+        Ok(Azw3Writer {
+            converter: Converter::find()?,
+            inner: EpubWriter::new(story)?,
+        })
+    }
+
+    /// See [`EpubWriter::set_version`].
+    pub fn set_version(&mut self, version: EpubVersion) {
+        self.inner.set_version(version);
+    }
+
+    /// See [`EpubWriter::set_toc_word_counts`].
+    pub fn set_toc_word_counts(&mut self) {
+        self.inner.set_toc_word_counts();
+    }
+
+    /// See [`EpubWriter::set_nested_toc`].
+    pub fn set_nested_toc(&mut self) {
+        self.inner.set_nested_toc();
+    }
+
+    /// See [`EpubWriter::set_compression`].
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.inner.set_compression(compression);
+    }
+
+    /// See [`EpubWriter::set_reproducible`].
+    pub fn set_reproducible(&mut self, story: &Story) {
+        self.inner.set_reproducible(story);
+    }
+}
+
+impl BookWriter for Azw3Writer {
+    fn add_cover(&mut self, cover: &Cover, in_toc: bool) -> Result<()> {
+        self.inner.add_cover(cover, in_toc)
+    }
+
+    fn add_toc(&mut self) {
+        self.inner.add_toc();
+    }
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        self.inner.add_chapter(nr, chapter, content)
+    }
+
+    fn chapter_link(&self, nr: usize) -> Option<String> {
+        self.inner.chapter_link(nr)
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        self.inner.add_image(image)
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_source_page(story, in_toc)
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        self.inner.add_colophon(paragraphs, in_toc)
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        self.inner.add_reviews(reception, in_toc)
+    }
+
+    fn add_title_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_title_page(story, in_toc)
+    }
+
+    // the converter drops what it doesn't know, so there is no use storing
+    // a manifest
+    fn add_manifest(&mut self, _manifest: &Manifest) -> Result<()> {
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize) {
+        self.inner.mark_incomplete(story, added, total);
+    }
+
+    fn add_front_matter(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_front_matter(story, in_toc)
+    }
+
+    fn set_series(&mut self, series: &Series) {
+        self.inner.set_series(series);
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.inner.set_style(style);
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let epub = dir.path().join("book.epub");
+        {
+            let mut file = std::io::BufWriter::new(
+                std::fs::File::create(&epub)
+                    .wrap_err_with(|| format!("could not create {}", epub.display()))?,
+            );
+            self.inner.write(&mut file)?;
+            file.flush()?;
+        }
+        log::info!("converting to azw3 with {}...", self.converter.name());
+        let book = self.converter.convert(&epub)?;
+        std::io::copy(&mut std::fs::File::open(&book)?, out)?;
+        Ok(())
+    }
+}
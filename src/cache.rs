@@ -0,0 +1,487 @@
+//! An on-disk cache of fetched chapter pages.
+//!
+//! Every page is stored as `<key>.html` next to a `<key>.json` holding its
+//! URL and the `ETag`/`Last-Modified` validators the server sent. Pages
+//! with validators are revalidated on the next fetch; pages without any are
+//! reused as they are, since chapters rarely change once published.
+//!
+//! The details and cover of every story downloaded through the cache are
+//! kept in `stories/`, so a book can be written again without the network.
+//!
+//! An entry's modification time is when its page was last used, for trimming
+//! the cache down to the pages used most recently.
+
+use eyre::{eyre, Result, WrapErr};
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::http::{challenge_error, is_challenge_page};
+use crate::sites::same_story;
+use crate::{Client, Cover, Story};
+
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    /// Pages served from the cache without downloading them again.
+    hits: Arc<AtomicUsize>,
+}
+
+/// What the cache holds, from [`Cache::stats`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    /// The chapter pages.
+    pub pages: usize,
+    /// The stories whose details are kept.
+    pub stories: usize,
+    /// The size of everything in the cache.
+    pub bytes: u64,
+    /// When the page used longest ago was last used, as a unix timestamp.
+    pub oldest: Option<u64>,
+    /// When the page used most recently was.
+    pub newest: Option<u64>,
+}
+
+/// What [`Cache::purge`] and [`Cache::evict`] removed.
+#[derive(Debug, Clone, Default)]
+pub struct Purged {
+    pub pages: usize,
+    /// The stories whose kept details went with them.
+    pub stories: usize,
+    /// The room that was freed.
+    pub bytes: u64,
+}
+
+/// A cached page, as found by [`Cache::pages`].
+struct CachedPage {
+    key: String,
+    bytes: u64,
+    used: SystemTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Parses a size such as `500M` or `2G`, in powers of 1024. A bare number
+/// is in bytes.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => text.split_at(i),
+        None => (text, ""),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", text))?;
+    let unit = unit
+        .trim()
+        .trim_end_matches(['B', 'b'])
+        .trim_end_matches('i');
+    let power = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return Err(format!("unknown unit in size {:?}, use K, M, G or T", text)),
+    };
+    Ok((number * 1024f64.powi(power)) as u64)
+}
+
+fn unix(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The name `url` is cached under.
+pub(crate) fn key(url: &Url) -> String {
+    hash(url.as_str().as_bytes())
+}
+
+/// `data`'s FNV-1a hash in hex. Unlike `DefaultHasher`, FNV-1a is
+/// guaranteed to stay the same between builds.
+pub(crate) fn hash(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn header(headers: &HeaderMap, name: reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Cache> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("could not create the cache {}", dir.display()))?;
+        Ok(Cache {
+            dir,
+            hits: Default::default(),
+        })
+    }
+
+    /// `$XDG_CACHE_HOME/rustyroad`, falling back to `~/.cache/rustyroad`
+    /// (or the local app data directory on Windows).
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+        Some(base.join("rustyroad"))
+    }
+
+    fn body_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.html", key))
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn entry(&self, key: &str, url: &Url) -> Option<Entry> {
+        if !self.body_path(key).is_file() {
+            return None;
+        }
+        let data = std::fs::read_to_string(self.entry_path(key)).ok()?;
+        let entry: Entry = serde_json::from_str(&data).ok()?;
+        // a hash collision, treat it as a miss
+        (entry.url == url.as_str()).then_some(entry)
+    }
+
+    /// The cached page of `url`, without checking that it is current.
+    pub fn page(&self, url: &Url) -> Option<PathBuf> {
+        let key = key(url);
+        self.entry(&key, url).map(|_| self.body_path(&key))
+    }
+
+    /// The number of pages served from the cache without downloading them
+    /// again so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Counts a page served from the cache, and marks its entry as used
+    /// now.
+    pub(crate) fn count_hit(&self, url: &Url) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        if let Ok(file) = File::options()
+            .append(true)
+            .open(self.entry_path(&key(url)))
+        {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    /// Drops the cached page of `url`, so the next fetch downloads it
+    /// again.
+    pub(crate) fn forget(&self, url: &Url) {
+        let key = key(url);
+        let _ = std::fs::remove_file(self.entry_path(&key));
+        let _ = std::fs::remove_file(self.body_path(&key));
+    }
+
+    fn stories_dir(&self) -> PathBuf {
+        self.dir.join("stories")
+    }
+
+    fn story_path(&self, url: &str, ext: &str) -> PathBuf {
+        let key = match Url::parse(url) {
+            Ok(url) => key(&url),
+            Err(_) => url.to_string(),
+        };
+        self.stories_dir().join(format!("{}.{}", key, ext))
+    }
+
+    /// Keeps the details and chapter list of `story`, replacing any kept
+    /// before.
+    pub fn save_story(&self, story: &Story) -> Result<()> {
+        let dir = self.stories_dir();
+        std::fs::create_dir_all(&dir)
+            .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        let path = self.story_path(&story.url, "json");
+        std::fs::write(&path, serde_json::to_string(story)?)
+            .wrap_err_with(|| format!("could not write {}", path.display()))
+    }
+
+    /// Keeps the cover of the story at `url`.
+    pub fn save_cover(&self, url: &str, cover: &Cover) -> Result<()> {
+        let path = self.story_path(url, "cover");
+        std::fs::write(&path, &cover.data)
+            .wrap_err_with(|| format!("could not write {}", path.display()))
+    }
+
+    /// The story at `url`, which may be written any way `--url` takes it,
+    /// as it was last saved, with its cover if that was saved too.
+    pub fn story(&self, url: &str) -> Result<Option<(Story, Option<Cover>)>> {
+        let Some((path, story)) = self.story_file(url)? else {
+            return Ok(None);
+        };
+        let cover = path.with_extension("cover");
+        let cover = if cover.is_file() {
+            Some(Cover::load(&cover)?)
+        } else {
+            None
+        };
+        Ok(Some((story, cover)))
+    }
+
+    /// The file the story at `url` is kept in, and the story.
+    fn story_file(&self, url: &str) -> Result<Option<(PathBuf, Story)>> {
+        let dir = self.stories_dir();
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return Ok(None);
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let data = std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("could not read {}", path.display()))?;
+            let Ok(story) = serde_json::from_str::<Story>(&data) else {
+                log::warn!("ignoring the unreadable {}", path.display());
+                continue;
+            };
+            if same_story(url, &story.url) {
+                return Ok(Some((path, story)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every page in the cache. Its other files, like the stories and the
+    /// requests counted for `--rate-limit`, aren't pages.
+    fn pages(&self) -> Result<Vec<CachedPage>> {
+        let entries = std::fs::read_dir(&self.dir)
+            .wrap_err_with(|| format!("could not read the cache {}", self.dir.display()))?;
+        let mut pages = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".json"))
+                .filter(|key| key.len() == 16 && key.chars().all(|c| c.is_ascii_hexdigit()))
+            else {
+                continue;
+            };
+            let Ok(meta) = path.metadata() else {
+                continue;
+            };
+            let body = self.body_path(key).metadata().map_or(0, |body| body.len());
+            pages.push(CachedPage {
+                key: key.to_string(),
+                bytes: meta.len() + body,
+                used: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+        Ok(pages)
+    }
+
+    /// The size of every file in the stories directory, and how many
+    /// stories there are.
+    fn stories_size(&self) -> (usize, u64) {
+        let Ok(entries) = std::fs::read_dir(self.stories_dir()) else {
+            return (0, 0);
+        };
+        let (mut stories, mut bytes) = (0, 0);
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_some_and(|ext| ext == "json") {
+                stories += 1;
+            }
+            bytes += path.metadata().map_or(0, |meta| meta.len());
+        }
+        (stories, bytes)
+    }
+
+    /// How many pages and stories the cache holds, and how much room they
+    /// take.
+    pub fn stats(&self) -> Result<CacheStats> {
+        let pages = self.pages()?;
+        let (stories, story_bytes) = self.stories_size();
+        let others: u64 = [crate::rate_limit::RATE_LIMIT_FILE]
+            .iter()
+            .filter_map(|name| self.dir.join(name).metadata().ok())
+            .map(|meta| meta.len())
+            .sum();
+        Ok(CacheStats {
+            pages: pages.len(),
+            stories,
+            bytes: pages.iter().map(|page| page.bytes).sum::<u64>() + story_bytes + others,
+            oldest: pages.iter().map(|page| page.used).min().map(unix),
+            newest: pages.iter().map(|page| page.used).max().map(unix),
+        })
+    }
+
+    fn remove_page(&self, page: &CachedPage, purged: &mut Purged) {
+        // the entry goes first, so a page left behind is a miss
+        let _ = std::fs::remove_file(self.entry_path(&page.key));
+        let _ = std::fs::remove_file(self.body_path(&page.key));
+        purged.pages += 1;
+        purged.bytes += page.bytes;
+    }
+
+    /// Removes the pages not used within `older_than`, of the story at
+    /// `story` only if given, or else all of them. The kept details of the
+    /// story at `story`, or of those not downloaded within `older_than`,
+    /// go with them.
+    pub fn purge(&self, older_than: Option<Duration>, story: Option<&str>) -> Result<Purged> {
+        let before = older_than.and_then(|age| SystemTime::now().checked_sub(age));
+        let old = |time: SystemTime| before.is_none_or(|before| time < before);
+
+        let story = match story {
+            Some(url) => match self.story_file(url)? {
+                Some(found) => Some(found),
+                None => return Err(eyre!("{} isn't in the cache {}", url, self.dir.display())),
+            },
+            None => None,
+        };
+        let keys: Option<Vec<String>> = story.as_ref().map(|(_, story)| {
+            story
+                .chapters
+                .iter()
+                .filter_map(|chapter| crate::chapter_url(&chapter.link).ok())
+                .map(|url| key(&url))
+                .collect()
+        });
+
+        let mut purged = Purged::default();
+        for page in self.pages()? {
+            let of_story = keys.as_ref().is_none_or(|keys| keys.contains(&page.key));
+            if of_story && old(page.used) {
+                self.remove_page(&page, &mut purged);
+            }
+        }
+
+        let story_files = match &story {
+            Some((path, _)) => vec![path.clone()],
+            None => std::fs::read_dir(self.stories_dir())
+                .map(|entries| {
+                    entries
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                        .filter(|path| {
+                            path.metadata()
+                                .and_then(|meta| meta.modified())
+                                .is_ok_and(old)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        for path in story_files {
+            for file in [path.clone(), path.with_extension("cover")] {
+                if let Ok(meta) = file.metadata() {
+                    purged.bytes += meta.len();
+                    let _ = std::fs::remove_file(&file);
+                }
+            }
+            purged.stories += 1;
+        }
+        Ok(purged)
+    }
+
+    /// Removes the pages used longest ago until those left take no more
+    /// than `max_bytes`.
+    pub fn evict(&self, max_bytes: u64) -> Result<Purged> {
+        let mut pages = self.pages()?;
+        let mut total: u64 = pages.iter().map(|page| page.bytes).sum();
+        let mut purged = Purged::default();
+        if total <= max_bytes {
+            return Ok(purged);
+        }
+        pages.sort_by_key(|page| page.used);
+        for page in &pages {
+            if total <= max_bytes {
+                break;
+            }
+            total -= page.bytes;
+            self.remove_page(page, &mut purged);
+        }
+        Ok(purged)
+    }
+
+    /// Fetches `url` through the cache and returns the path of the cached
+    /// page, which stays valid until the next fetch of the same URL.
+    pub async fn fetch(&self, client: &Client, url: Url) -> Result<PathBuf> {
+        self.fetch_page(client, url, false).await
+    }
+
+    /// Like [`Cache::fetch`], but downloads a page cached without an `ETag`
+    /// or `Last-Modified` again instead of using it as it is, so a changed
+    /// page is always seen.
+    pub async fn refetch(&self, client: &Client, url: Url) -> Result<PathBuf> {
+        self.fetch_page(client, url, true).await
+    }
+
+    async fn fetch_page(&self, client: &Client, url: Url, refetch: bool) -> Result<PathBuf> {
+        let key = key(&url);
+        let body = self.body_path(&key);
+
+        let resp = match self.entry(&key, &url) {
+            Some(entry) if entry.etag.is_none() && entry.last_modified.is_none() && refetch => {
+                Some(client.get(url.clone()).await?)
+            }
+            Some(entry) if entry.etag.is_none() && entry.last_modified.is_none() => {
+                log::debug!("using cached {}", url);
+                self.count_hit(&url);
+                return Ok(body);
+            }
+            Some(entry) => {
+                client
+                    .get_if_modified(
+                        url.clone(),
+                        entry.etag.as_deref(),
+                        entry.last_modified.as_deref(),
+                    )
+                    .await?
+            }
+            None => Some(client.get(url.clone()).await?),
+        };
+
+        let Some(resp) = resp else {
+            log::debug!("cached {} is still current", url);
+            self.count_hit(&url);
+            return Ok(body);
+        };
+
+        let entry = Entry {
+            url: url.to_string(),
+            etag: header(resp.headers(), ETAG),
+            last_modified: header(resp.headers(), LAST_MODIFIED),
+        };
+        let data = client.bytes(resp).await?;
+        if is_challenge_page(&String::from_utf8_lossy(&data)) {
+            return Err(challenge_error(&url).into());
+        }
+
+        // the page takes the old one's place at once, and before its entry,
+        // so an interrupted write leaves the old page or a miss
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)
+            .wrap_err_with(|| format!("could not write to {}", self.dir.display()))?;
+        file.write_all(&data)?;
+        file.persist(&body)
+            .wrap_err_with(|| format!("could not write {}", body.display()))?;
+        std::fs::write(self.entry_path(&key), serde_json::to_string(&entry)?)?;
+
+        Ok(body)
+    }
+}
@@ -0,0 +1,193 @@
+//! Adding books to a Calibre library with `calibredb`.
+//!
+//! Every book is tagged with the story's URL as a `rustyroad` identifier,
+//! followed by `#2` and so on for the volumes of a split book, so writing it
+//! again (e.g. with `update`) replaces the book's file and metadata in the
+//! library rather than adding a second copy.
+
+use eyre::{eyre, Result, WrapErr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{Cover, Series, Story};
+
+/// The identifier type books are tagged with.
+const IDENTIFIER: &str = "rustyroad";
+
+/// Where Calibre puts its tools on macOS, which isn't on the `PATH`.
+const CALIBRE_MACOS: &str = "/Applications/calibre.app/Contents/MacOS";
+
+/// The program `name` in one of the `PATH`'s directories.
+pub(crate) fn on_path(name: &str) -> Option<PathBuf> {
+    let names = if cfg!(windows) {
+        vec![format!("{}.exe", name), format!("{}.bat", name)]
+    } else {
+        vec![name.to_string()]
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// One of Calibre's command line tools, such as `calibredb`, if Calibre is
+/// installed.
+pub(crate) fn calibre_tool(name: &str) -> Option<PathBuf> {
+    on_path(name).or_else(|| {
+        let path = Path::new(CALIBRE_MACOS).join(name);
+        (cfg!(target_os = "macos") && path.is_file()).then_some(path)
+    })
+}
+
+/// The book at `file` for the calibre library at `library`, or the one
+/// `calibredb` uses by default.
+pub struct CalibreBook<'a> {
+    pub library: Option<&'a Path>,
+    pub file: &'a Path,
+    pub story: &'a Story,
+    /// The book's number, if it is one volume of the story.
+    pub volume: Option<usize>,
+    pub series: Option<&'a Series>,
+    pub cover: Option<&'a Cover>,
+}
+
+impl CalibreBook<'_> {
+    /// Adds the book to the library, or replaces it there if the library
+    /// already has the story. Returns the book's id in the library.
+    pub fn add(&self) -> Result<u64> {
+        let calibredb = CalibreBook::calibredb()?;
+        // calibre only takes the cover as a file
+        let cover = match self.cover {
+            Some(cover) => {
+                let file = tempfile::Builder::new()
+                    .suffix(&format!(".{}", cover.ext))
+                    .tempfile()?;
+                std::fs::write(file.path(), &cover.data)?;
+                Some(file)
+            }
+            None => None,
+        };
+        let cover = cover.as_ref().map(|file| file.path());
+        let title = match self.volume {
+            Some(nr) => format!("{} Vol. {}", self.story.title, nr),
+            None => self.story.title.clone(),
+        };
+
+        // an anthology has no URL to know it by, so it is always added anew
+        let existing = match self.story.url.is_empty() {
+            true => None,
+            false => self.find(&calibredb)?,
+        };
+        let Some(id) = existing else {
+            let mut command = self.command(&calibredb, "add");
+            command
+                .arg("--title")
+                .arg(&title)
+                .arg("--authors")
+                .arg(&self.story.author);
+            if !self.story.url.is_empty() {
+                command
+                    .arg("--identifier")
+                    .arg(format!("{}:{}", IDENTIFIER, self.key()));
+            }
+            if !self.story.tags.is_empty() {
+                command.arg("--tags").arg(self.story.tags.join(","));
+            }
+            if let Some(series) = self.series {
+                command
+                    .arg("--series")
+                    .arg(&series.name)
+                    .arg("--series-index")
+                    .arg(series.index.to_string());
+            }
+            if let Some(cover) = cover {
+                command.arg("--cover").arg(cover);
+            }
+            let out = run(command.arg(self.file))?;
+            // e.g. "Added book ids: 12"
+            return out
+                .lines()
+                .find_map(|line| line.strip_prefix("Added book ids:"))
+                .and_then(|ids| ids.trim().split(',').next()?.trim().parse().ok())
+                .ok_or_else(|| eyre!("calibredb didn't add {}: {}", self.file.display(), out));
+        };
+
+        run(self
+            .command(&calibredb, "add_format")
+            .arg(id.to_string())
+            .arg(self.file))?;
+        let mut command = self.command(&calibredb, "set_metadata");
+        command.arg(id.to_string());
+        let mut fields = vec![
+            format!("title:{}", title),
+            format!("authors:{}", self.story.author),
+            format!("tags:{}", self.story.tags.join(",")),
+        ];
+        if let Some(series) = self.series {
+            fields.push(format!("series:{}", series.name));
+            fields.push(format!("series_index:{}", series.index));
+        }
+        if let Some(cover) = cover {
+            fields.push(format!("cover:{}", cover.display()));
+        }
+        for field in fields {
+            command.arg("--field").arg(field);
+        }
+        run(&mut command)?;
+        Ok(id)
+    }
+
+    /// Where `calibredb` is installed.
+    pub fn calibredb() -> Result<PathBuf> {
+        calibre_tool("calibredb").ok_or_else(|| {
+            eyre!("--add-to-calibre needs calibredb, which comes with Calibre (https://calibre-ebook.com), on the PATH")
+        })
+    }
+
+    /// What the book's identifier holds.
+    fn key(&self) -> String {
+        match self.volume {
+            Some(nr) => format!("{}#{}", self.story.url, nr),
+            None => self.story.url.clone(),
+        }
+    }
+
+    /// The id of the library's book of the story, if it has one.
+    fn find(&self, calibredb: &Path) -> Result<Option<u64>> {
+        let query = format!("identifiers:\"={}:={}\"", IDENTIFIER, self.key());
+        let mut command = self.command(calibredb, "search");
+        command.arg("--limit").arg("1").arg(query);
+        let output = command.output().wrap_err("could not run calibredb")?;
+        // calibredb fails when nothing matches
+        if !output.status.success() {
+            return Ok(None);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split(',')
+            .next()
+            .and_then(|id| id.trim().parse().ok()))
+    }
+
+    fn command(&self, calibredb: &Path, subcommand: &str) -> Command {
+        let mut command = Command::new(calibredb);
+        command.arg(subcommand);
+        if let Some(library) = self.library {
+            command.arg("--with-library").arg(library);
+        }
+        command
+    }
+}
+
+/// Runs `command` and returns what it printed, failing with what it printed
+/// to stderr if it fails.
+fn run(command: &mut Command) -> Result<String> {
+    let output = command.output().wrap_err("could not run calibredb")?;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "calibredb failed ({}): {}; calibredb can't change a library while Calibre has it open",
+            output.status,
+            err.trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,279 @@
+//! Cleaning passes applied to the chapter content before it is serialized.
+
+use ego_tree::NodeId;
+use html5ever::tendril::StrTendril;
+use html5ever::{Attribute, LocalName, QualName};
+use markup5ever::{namespace_url, ns};
+use regex::Regex;
+use scraper::node::Element;
+use scraper::{ElementRef, Html, Node};
+
+use crate::footnotes::{self, Footnotes};
+use crate::minify;
+use crate::normalize;
+use crate::notes::{self, NotePolicy};
+use crate::plugs;
+use crate::sanitize;
+use crate::spoilers::{self, Spoilers};
+use crate::tables::{self, TableMode};
+use crate::typography::{self, Typography};
+use crate::watermark;
+
+#[derive(Clone, Debug)]
+pub struct CleanOpts {
+    /// Decode entities escaped twice, repair mojibake, drop zero-width
+    /// spaces, collapse runs of whitespace and compose the text to NFC.
+    /// Default: true
+    pub normalize_text: bool,
+
+    /// Replace scene break markers (`* * *`, `~~~`, repeated `<hr>`s, ...)
+    /// with a single `<hr class="scene-break"/>`. Default: false
+    pub normalize_breaks: bool,
+
+    /// Quote and dash style for the prose. Default: Off
+    pub typography: Typography,
+
+    /// What to do with translator notes. Default: Keep
+    pub translator_notes: NotePolicy,
+
+    /// Keep the author's notes before and after the chapter, at the start
+    /// and end of the chapter text. Default: false, they are dropped
+    pub authors_notes: bool,
+
+    /// Remove the promotional blocks at the start and end of the chapter:
+    /// Patreon and Discord links, "read ahead on..." and the like.
+    /// Default: false
+    pub strip_plugs: bool,
+
+    /// Further patterns marking a block as a plug for `strip_plugs`,
+    /// matched against its text. Default: none
+    pub plug_patterns: Vec<Regex>,
+
+    /// Strip the content down to the elements and attributes e-readers
+    /// handle, dropping scripts, tracking pixels and event handlers.
+    /// Default: true
+    pub sanitize: bool,
+
+    /// How spoiler blocks are written. Default: Inline
+    pub spoilers: Spoilers,
+
+    /// How the footnotes authors link up by hand are written. Default:
+    /// Section
+    pub footnotes: Footnotes,
+
+    /// How tables are written. Default: Fit
+    pub tables: TableMode,
+
+    /// Guess where the chapter text is on pages that none of the sites'
+    /// selectors match, as for stories crawled with
+    /// [`crawl_story`](crate::crawl_story). Default: false, they fail
+    pub generic: bool,
+
+    /// Strip the site's classes and inline styles, then unwrap the spans
+    /// and divs that only held them. Default: false
+    pub strip_styles: bool,
+
+    /// Classes `strip_styles` keeps besides the ones the cleaning passes
+    /// add. Default: none
+    pub keep_classes: Vec<String>,
+
+    /// CSS properties `strip_styles` keeps in inline styles besides
+    /// `text-align`. Default: none
+    pub keep_styles: Vec<String>,
+}
+
+impl Default for CleanOpts {
+    fn default() -> CleanOpts {
+        CleanOpts {
+            normalize_text: true,
+            normalize_breaks: false,
+            typography: Typography::default(),
+            translator_notes: NotePolicy::default(),
+            authors_notes: false,
+            strip_plugs: false,
+            plug_patterns: Vec::new(),
+            sanitize: true,
+            spoilers: Spoilers::default(),
+            footnotes: Footnotes::default(),
+            tables: TableMode::default(),
+            generic: false,
+            strip_styles: false,
+            keep_classes: Vec::new(),
+            keep_styles: Vec::new(),
+        }
+    }
+}
+
+/// Runs the enabled cleaning passes over the subtree rooted at `content`.
+/// Elements the page hides from its readers are always removed, except for
+/// the text of spoilers.
+pub fn clean(doc: &mut Html, content: NodeId, opts: &CleanOpts) {
+    if opts.normalize_text {
+        normalize::normalize(doc, content);
+    }
+    spoilers::convert(doc, content, opts.spoilers);
+    watermark::strip_hidden(doc, content);
+    if opts.strip_plugs {
+        plugs::strip_plugs(doc, content, &opts.plug_patterns);
+    }
+    footnotes::convert(doc, content, opts.footnotes);
+    notes::translator_notes(doc, content, opts.translator_notes);
+    if opts.normalize_breaks {
+        normalize_breaks(doc, content);
+    }
+    typography::apply(doc, content, opts.typography);
+    tables::convert(doc, content, opts.tables);
+    if opts.sanitize {
+        sanitize::sanitize(doc, content);
+    }
+    if opts.strip_styles {
+        minify::minify(doc, content, &opts.keep_classes, &opts.keep_styles);
+    }
+}
+
+pub(crate) fn html_name(local: &str) -> QualName {
+    QualName::new(None, ns!(html), LocalName::from(local))
+}
+
+pub(crate) fn new_element(name: &str, attrs: &[(&str, &str)]) -> Node {
+    let attrs = attrs
+        .iter()
+        .map(|(name, value)| Attribute {
+            name: QualName::new(None, ns!(), LocalName::from(*name)),
+            value: StrTendril::from(*value),
+        })
+        .collect();
+
+    Node::Element(Element::new(html_name(name), attrs))
+}
+
+/// Collects the ids of all descendants of `root` (excluding `root` itself)
+/// that satisfy `pred`.
+pub(crate) fn find(doc: &Html, root: NodeId, pred: impl Fn(ElementRef) -> bool) -> Vec<NodeId> {
+    let root = doc.tree.get(root).unwrap();
+    root.descendants()
+        .skip(1)
+        .filter_map(ElementRef::wrap)
+        .filter(|el| pred(*el))
+        .map(|el| el.id())
+        .collect()
+}
+
+pub(crate) fn is_blank_text(node: &Node) -> bool {
+    matches!(node, Node::Text(text) if text.trim().is_empty())
+}
+
+fn is_break_glyph(c: char) -> bool {
+    matches!(
+        c,
+        '*' | '~'
+            | '#'
+            | '-'
+            | '='
+            | '_'
+            | '+'
+            | '•'
+            | '·'
+            | '°'
+            | '◇'
+            | '◆'
+            | '❖'
+            | '♦'
+            | '⁂'
+            | '§'
+            | '※'
+            | '†'
+            | '✦'
+            | '✧'
+            | '★'
+            | '☆'
+            | '—'
+            | '–'
+    )
+}
+
+/// Returns true for text such as `* * *`, `~~~`, `◇◇◇` or a lone `⁂`.
+fn is_break_text(text: &str) -> bool {
+    let glyphs: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if glyphs.is_empty() || !glyphs.iter().all(|c| is_break_glyph(*c)) {
+        return false;
+    }
+
+    // a lone "-" or "*" is more likely a typo than a break
+    glyphs.len() >= 3 || matches!(glyphs[0], '⁂' | '❖' | '§' | '※' | '◇' | '◆')
+}
+
+fn is_break_marker(el: ElementRef) -> bool {
+    match el.value().name() {
+        "hr" => true,
+        "p" | "div" | "center" => {
+            // only consider leaf blocks, e.g. <p><strong>* * *</strong></p>
+            let only_inline = el.descendants().skip(1).all(|node| match node.value() {
+                Node::Element(el) => matches!(
+                    el.name(),
+                    "span" | "strong" | "b" | "em" | "i" | "u" | "br" | "center"
+                ),
+                _ => true,
+            });
+
+            only_inline && is_break_text(&el.text().collect::<String>())
+        }
+        _ => false,
+    }
+}
+
+fn is_scene_break(el: &Element) -> bool {
+    el.name() == "hr" && el.attr("class") == Some("scene-break")
+}
+
+fn normalize_breaks(doc: &mut Html, content: NodeId) {
+    // markers nested inside other markers are replaced along with their parent
+    let markers = find(doc, content, |el| {
+        is_break_marker(el)
+            && !el
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(is_break_marker)
+    });
+
+    for id in markers {
+        let mut node = doc.tree.get_mut(id).unwrap();
+        node.insert_before(new_element("hr", &[("class", "scene-break")]));
+        node.detach();
+    }
+
+    // collapse runs of breaks separated only by whitespace or empty paragraphs
+    let breaks = find(doc, content, |el| is_scene_break(el.value()));
+    for id in breaks {
+        let Some(node) = doc.tree.get(id) else {
+            continue;
+        };
+        if node.parent().is_none() {
+            continue;
+        }
+
+        let mut redundant = Vec::new();
+        let mut skipped = Vec::new();
+        let mut next = node.next_sibling();
+        while let Some(sibling) = next {
+            let empty_block = ElementRef::wrap(sibling)
+                .map(|el| el.value().name() == "p" && el.text().all(|t| t.trim().is_empty()))
+                .unwrap_or(false);
+
+            if empty_block {
+                skipped.push(sibling.id());
+            } else if sibling.value().as_element().is_some_and(is_scene_break) {
+                redundant.append(&mut skipped);
+                redundant.push(sibling.id());
+            } else if !is_blank_text(sibling.value()) {
+                break;
+            }
+            next = sibling.next_sibling();
+        }
+
+        for id in redundant {
+            doc.tree.get_mut(id).unwrap().detach();
+        }
+    }
+}
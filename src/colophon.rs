@@ -0,0 +1,108 @@
+//! The colophon: a page at the end of the book saying where the story comes
+//! from, who wrote it and when it was downloaded, so a copy that gets passed
+//! around still credits the author.
+//!
+//! Its text is a template of paragraphs separated by blank lines, in which
+//! `{title}`, `{author}`, `{url}`, `{date}` (the day the book was made) and
+//! `{chapters}` (how many chapters it holds) are filled in.
+
+use eyre::{eyre, Result, WrapErr};
+use std::path::Path;
+
+use crate::{book_date, Story};
+
+pub const DEFAULT_TEMPLATE: &str = "\
+{title} was written by {author} and published at {url}.
+
+This copy of its {chapters} chapters was downloaded on {date} for personal reading. It is not for redistribution; all rights remain with the author.
+
+If you enjoyed the story, please support the author where it was published.";
+
+const PLACEHOLDERS: [&str; 5] = ["title", "author", "url", "date", "chapters"];
+
+#[derive(Debug, Clone)]
+pub struct Colophon {
+    template: String,
+}
+
+impl Default for Colophon {
+    fn default() -> Colophon {
+        Colophon {
+            template: DEFAULT_TEMPLATE.to_string(),
+        }
+    }
+}
+
+impl Colophon {
+    /// The colophon with the template at `path`, or the default one.
+    pub fn load(path: Option<&Path>) -> Result<Colophon> {
+        let Some(path) = path else {
+            return Ok(Colophon::default());
+        };
+        let template = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read the colophon template {}", path.display()))?;
+        let colophon = Colophon { template };
+        // an unknown placeholder is an error as soon as the template is read
+        colophon
+            .fill(&[
+                ("title", ""),
+                ("author", ""),
+                ("url", ""),
+                ("date", ""),
+                ("chapters", ""),
+            ])
+            .wrap_err_with(|| format!("in the colophon template {}", path.display()))?;
+        Ok(colophon)
+    }
+
+    /// The colophon's paragraphs for `story` with `chapters` chapters, as
+    /// plain text, dated like a `reproducible` book if it is one.
+    pub fn render(
+        &self,
+        story: &Story,
+        chapters: usize,
+        reproducible: bool,
+    ) -> Result<Vec<String>> {
+        let chapters = chapters.to_string();
+        let date = book_date(story, reproducible);
+        let text = self.fill(&[
+            ("title", &story.title),
+            ("author", &story.author),
+            ("url", &story.url),
+            ("date", &date),
+            ("chapters", &chapters),
+        ])?;
+        Ok(text
+            .split("\n\n")
+            .map(|paragraph| paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|paragraph| !paragraph.is_empty())
+            .collect())
+    }
+
+    fn fill(&self, values: &[(&str, &str); PLACEHOLDERS.len()]) -> Result<String> {
+        let template = self.template.replace("\r\n", "\n");
+        let mut out = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| eyre!("unclosed {{ in the colophon"))?;
+            let name = &rest[start + 1..start + end];
+            let (_, value) = values
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)
+                .ok_or_else(|| {
+                    eyre!(
+                        "unknown placeholder {{{}}} in the colophon, expected one of {}",
+                        name,
+                        PLACEHOLDERS.map(|p| format!("{{{}}}", p)).join(", ")
+                    )
+                })?;
+            out.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
@@ -0,0 +1,243 @@
+//! The `rustyroad.toml` config file: defaults for the command line options,
+//! and overrides for particular stories.
+//!
+//! Keys are named after the options they set, e.g.
+//!
+//! ```toml
+//! concurrent = 8
+//! output-dir = "/home/me/Books"
+//!
+//! [stories."https://www.royalroad.com/fiction/12345"]
+//! out = "Example.epub"
+//! typography = "smart"
+//! ```
+//!
+//! Options given on the command line win over the file.
+
+use eyre::{Result, WrapErr};
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::cache::parse_size;
+use crate::sites::same_story;
+use crate::{
+    EpubVersion, Format, Replacements, Schedule, SideChapters, TableMode, TtsEngine, Typography,
+};
+
+/// The directory rustyroad's config files are in:
+/// `$XDG_CONFIG_HOME/rustyroad`, falling back to `~/.config/rustyroad` (or
+/// the app data directory on Windows).
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("rustyroad"))
+}
+
+/// Reads a size like `500M` or `2G` into bytes.
+fn size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) => parse_size(&text).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Reads an enum option by the name it has on the command line.
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: clap::ValueEnum,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(name) => T::from_str(&name, true).map(Some).map_err(|_| {
+            let names: Vec<String> = T::value_variants()
+                .iter()
+                .filter_map(|value| value.to_possible_value())
+                .map(|value| value.get_name().to_string())
+                .collect();
+            D::Error::custom(format!(
+                "unknown value {:?}, expected one of {}",
+                name,
+                names.join(", ")
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Reads a cron schedule like `0 6 * * *`.
+fn schedule<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Schedule>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(text) => text.parse().map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Reads a regex.
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .map_err(|err| D::Error::custom(format!("invalid regex {:?}: {}", pattern, err))),
+        None => Ok(None),
+    }
+}
+
+/// Reads a list of regexes.
+fn regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Regex>>, D::Error> {
+    match Option::<Vec<String>>::deserialize(deserializer)? {
+        Some(patterns) => patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|err| {
+                    D::Error::custom(format!("invalid regex {:?}: {}", pattern, err))
+                })
+            })
+            .collect::<Result<Vec<Regex>, D::Error>>()
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub concurrent: Option<usize>,
+    pub story_concurrent: Option<u64>,
+    pub output_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "value_enum")]
+    pub format: Option<Format>,
+    #[serde(deserialize_with = "value_enum")]
+    pub epub_version: Option<EpubVersion>,
+    #[serde(deserialize_with = "value_enum")]
+    pub tts: Option<TtsEngine>,
+    pub tts_voice: Option<String>,
+    pub tts_url: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub typography: Option<Typography>,
+    #[serde(deserialize_with = "value_enum")]
+    pub table_mode: Option<TableMode>,
+    pub css: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub colophon_template: Option<PathBuf>,
+    pub exec: Option<String>,
+    pub reproducible: Option<bool>,
+    pub chapter_title_template: Option<String>,
+    pub chapter_headings: Option<bool>,
+    #[serde(deserialize_with = "value_enum")]
+    pub side_chapters: Option<SideChapters>,
+    #[serde(deserialize_with = "regex")]
+    pub side_chapter_regex: Option<Regex>,
+    pub strip_plugs: Option<bool>,
+    #[serde(deserialize_with = "regexes")]
+    pub plug_patterns: Option<Vec<Regex>>,
+    pub embed_font: Option<Vec<PathBuf>>,
+    pub strip_styles: Option<bool>,
+    pub keep_classes: Option<Vec<String>>,
+    pub keep_styles: Option<Vec<String>>,
+    pub max_image_width: Option<u32>,
+    pub image_quality: Option<u8>,
+    pub compression: Option<u32>,
+    pub store_images: Option<bool>,
+    pub cache_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "size")]
+    pub cache_max_size: Option<u64>,
+    pub library: Option<PathBuf>,
+    pub user_agent: Option<String>,
+    pub cookies_file: Option<PathBuf>,
+    pub browser_cookies: Option<PathBuf>,
+    pub cf_clearance: Option<String>,
+    pub proxy: Option<String>,
+    pub max_retries: Option<u32>,
+    pub timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub delay: Option<u64>,
+    pub burst: Option<u32>,
+    pub rate_limit: Option<u32>,
+    /// Requests-per-minute limits for these hosts, in place of
+    /// `rate-limit`.
+    pub rate_limits: BTreeMap<String, u32>,
+    pub polite: Option<bool>,
+    #[serde(deserialize_with = "size")]
+    pub max_download_size: Option<u64>,
+    /// Find/replace rules for every chapter.
+    pub replacements: Replacements,
+    /// Selectors to scrape with in place of the built-in ones, by name.
+    pub selectors: BTreeMap<String, Vec<String>>,
+    /// Overrides for the stories at these URLs.
+    pub stories: BTreeMap<String, StoryConfig>,
+}
+
+/// The options that can be set for a single story.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct StoryConfig {
+    pub out: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub format: Option<Format>,
+    pub chapters: Option<String>,
+    /// A cover image file or URL.
+    pub cover: Option<String>,
+    pub title_map: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub typography: Option<Typography>,
+    pub include_authors_notes: Option<bool>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub language: Option<String>,
+    /// `false` leaves the story out of `sync-follows`.
+    pub sync: Option<bool>,
+    /// Rules added to, or taking the place of, the global replacements.
+    pub replacements: Replacements,
+    /// Crawl the story with `--generic`, and the `--next-selector` to
+    /// crawl it with.
+    pub generic: Option<bool>,
+    pub next_selector: Option<String>,
+    #[serde(deserialize_with = "value_enum")]
+    pub side_chapters: Option<SideChapters>,
+    #[serde(deserialize_with = "regex")]
+    pub side_chapter_regex: Option<Regex>,
+    /// When `watch` checks the story, as a cron schedule.
+    #[serde(deserialize_with = "schedule")]
+    pub schedule: Option<Schedule>,
+}
+
+impl Config {
+    /// `rustyroad.toml` in the user's config directory, e.g.
+    /// `~/.config/rustyroad/rustyroad.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("rustyroad.toml"))
+    }
+
+    /// Reads the config from `path`, or the default file if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let file = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Config::default_path().filter(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let data = std::fs::read_to_string(&file)
+            .wrap_err_with(|| format!("could not read {}", file.display()))?;
+        toml::from_str(&data).wrap_err_with(|| format!("invalid config {}", file.display()))
+    }
+
+    /// The overrides for the story at `url`. A key matches the URL itself
+    /// and any page below it, so `https://www.royalroad.com/fiction/12345`
+    /// also matches the story's URL with its title after the id. Keys and
+    /// URLs may be written any way `--url` takes them, e.g. as `12345`.
+    pub fn story(&self, url: &str) -> Option<&StoryConfig> {
+        self.stories
+            .iter()
+            .find_map(|(key, story)| same_story(url, key).then_some(story))
+    }
+}
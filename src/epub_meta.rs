@@ -0,0 +1,146 @@
+//! Metadata epub-builder can't write, added to the package document of an
+//! epub it generated, and the rewrite of the generated archive that adds it.
+
+use eyre::{eyre, Result};
+use std::io::{Read, Seek, Write};
+use tempfile::SpooledTempFile;
+use zip::read::ZipFile;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::spool::{spool, Compression, ContentSpool};
+use crate::{escape, Series};
+
+/// The `<meta>` elements for a book's place in a series: Calibre's own,
+/// which most readers understand, and for EPUB 3 the standard
+/// `belongs-to-collection`.
+pub(crate) fn series(series: &Series) -> Vec<Meta> {
+    let name = escape(&series.name);
+    let index = series.index.to_string();
+    vec![
+        Meta::Any(format!(
+            r#"<meta name="calibre:series" content="{}"/>"#,
+            name
+        )),
+        Meta::Any(format!(
+            r#"<meta name="calibre:series_index" content="{}"/>"#,
+            index
+        )),
+        Meta::Epub3(format!(
+            r##"<meta property="belongs-to-collection" id="series">{}</meta><meta refines="#series" property="collection-type">series</meta><meta refines="#series" property="group-position">{}</meta>"##,
+            name, index
+        )),
+    ]
+}
+
+/// An element to add to the package's `<metadata>`.
+pub(crate) enum Meta {
+    Any(String),
+    /// Only valid in EPUB 3 packages.
+    Epub3(String),
+}
+
+/// Returns the package document `opf` with `meta` added to its
+/// `<metadata>`.
+pub(crate) fn add_to_package(opf: &str, meta: &[Meta]) -> Result<String> {
+    let end = opf
+        .find("</metadata>")
+        .ok_or_else(|| eyre!("the package document has no metadata"))?;
+    let epub3 = opf.contains(r#"version="3"#);
+
+    let mut added = String::new();
+    for meta in meta {
+        match meta {
+            Meta::Any(xml) => added.push_str(xml),
+            Meta::Epub3(xml) if epub3 => added.push_str(xml),
+            Meta::Epub3(_) => continue,
+        }
+        added.push('\n');
+    }
+
+    Ok(format!("{}{}{}", &opf[..end], added, &opf[end..]))
+}
+
+/// Returns `epub` with the text entries `select` picks replaced by what
+/// `edit` makes of them, and the stand-ins for the entries in `content`
+/// replaced by the spooled ones. Every other entry is copied as it is,
+/// keeping the uncompressed `mimetype` first, unless the content's
+/// [`Compression`] isn't the default, which they are compressed again
+/// with. If `reproducible`, every entry is dated at the earliest date zip
+/// files have, in place of when it was made.
+pub(crate) fn rewrite(
+    epub: impl Read + Seek,
+    content: &mut ContentSpool,
+    reproducible: bool,
+    select: impl Fn(&str) -> bool,
+    mut edit: impl FnMut(&str, String) -> Result<String>,
+) -> Result<SpooledTempFile> {
+    let mut archive = ZipArchive::new(epub)?;
+    let mut spooled = content.finish()?;
+    let compression = content.compression();
+    let mut out = ZipWriter::new(spool());
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        if content.contains(&name) {
+            // the spool compressed it already
+            let file = spooled.by_name(&name)?;
+            let options = reproducible.then(|| dated(file_options(&file), true));
+            copy(&mut out, file, options)?;
+            continue;
+        }
+        if !select(&name) {
+            let options = match file.compression() {
+                CompressionMethod::Stored => reproducible.then(|| dated(file_options(&file), true)),
+                _ if reproducible || compression != Compression::default() => {
+                    Some(dated(compression.options(&name), reproducible))
+                }
+                _ => None,
+            };
+            copy(&mut out, file, options)?;
+            continue;
+        }
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        let text = edit(&name, text)?;
+        out.start_file(
+            name.as_str(),
+            dated(compression.options(&name), reproducible),
+        )?;
+        out.write_all(text.as_bytes())?;
+    }
+
+    Ok(out.finish()?)
+}
+
+/// Copies `file` into `out`: compressed as it is, or with `options`, which
+/// copying it as it is can't change.
+fn copy<W: Write + Seek>(
+    out: &mut ZipWriter<W>,
+    mut file: ZipFile,
+    options: Option<FileOptions>,
+) -> Result<()> {
+    let Some(options) = options else {
+        out.raw_copy_file(file)?;
+        return Ok(());
+    };
+    let name = file.name().to_string();
+    out.start_file(name, options)?;
+    std::io::copy(&mut file, out)?;
+    Ok(())
+}
+
+/// The options `file` was compressed with, as far as the archive says.
+fn file_options(file: &ZipFile) -> FileOptions {
+    FileOptions::default().compression_method(file.compression())
+}
+
+/// `options`, dated at the earliest date zip files have if `fixed`.
+fn dated(options: FileOptions, fixed: bool) -> FileOptions {
+    match fixed {
+        true => options.last_modified_time(DateTime::default()),
+        false => options,
+    }
+}
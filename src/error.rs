@@ -0,0 +1,219 @@
+//! The failures worth telling apart. Each kind has an exit code of its own,
+//! so a script running rustyroad can tell a changed site layout from a
+//! missing chapter or a block without parsing the message.
+
+use std::fmt;
+
+use crate::selectors;
+
+/// Exit code for errors of no particular kind.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code when the book was written with chapters replaced by
+/// placeholders.
+pub const EXIT_PARTIAL: i32 = 2;
+/// Exit code for a [`ScrapeError`].
+pub const EXIT_SCRAPE: i32 = 3;
+/// Exit code for a [`ChapterError`].
+pub const EXIT_CHAPTER: i32 = 4;
+/// Exit code for [`HttpError::Blocked`] and [`HttpError::Challenge`].
+pub const EXIT_BLOCKED: i32 = 5;
+/// Exit code for any other [`HttpError`], and for requests that got no
+/// answer at all.
+pub const EXIT_HTTP: i32 = 6;
+
+/// A page that can't be read as what it should be.
+#[derive(Debug)]
+pub enum ScrapeError {
+    /// The page has nothing matching `selector`, which is how the scraper
+    /// finds `what`. Usually the site changed its layout. `name` is what
+    /// the selector can be replaced by in the config file, if it can be.
+    SelectorChanged {
+        what: &'static str,
+        selector: String,
+        name: Option<&'static str>,
+    },
+    /// The URL is not on any supported site.
+    UnsupportedSite { url: String, sites: String },
+    /// The URL is on a supported site, but not a story or a chapter.
+    NotAStory { url: String },
+    /// The story is marked as mature, and the site kept asking the reader
+    /// to confirm they are an adult in place of showing it.
+    AgeCheck { url: String },
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrapeError::SelectorChanged {
+                what,
+                selector,
+                name,
+            } => {
+                write!(
+                    f,
+                    "could not find the {} (nothing matches `{}`); the site's layout may have changed, please report it",
+                    what, selector
+                )?;
+                if let Some(name) = name {
+                    write!(
+                        f,
+                        ", or give other selectors for {:?} under [selectors] in the config file",
+                        name
+                    )?;
+                }
+                Ok(())
+            }
+            ScrapeError::UnsupportedSite { url, sites } => {
+                write!(
+                    f,
+                    "{} is not on a supported site ({}), --generic may still download it",
+                    url, sites
+                )
+            }
+            ScrapeError::NotAStory { url } => {
+                write!(f, "{} is not a story or a chapter of one", url)
+            }
+            ScrapeError::AgeCheck { url } => write!(
+                f,
+                "{} is marked as mature, and confirming the age check didn't open it; it may only be open to accounts that allow mature content, so try again with --username or --cookies-file",
+                url
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+impl ScrapeError {
+    pub(crate) fn selector_changed(what: &'static str, selector: &str) -> ScrapeError {
+        ScrapeError::SelectorChanged {
+            what,
+            selector: selector.to_string(),
+            name: None,
+        }
+    }
+
+    /// The selectors named `name` in the [`selectors`](crate::selectors)
+    /// registry match nothing.
+    pub(crate) fn named_selector_changed(what: &'static str, name: &'static str) -> ScrapeError {
+        ScrapeError::SelectorChanged {
+            what,
+            selector: selectors::joined(name),
+            name: Some(name),
+        }
+    }
+}
+
+/// A chapter that can't be put in the book.
+#[derive(Debug)]
+pub enum ChapterError {
+    /// The chapter page is gone, e.g. because the author deleted or
+    /// unpublished the chapter.
+    NotFound { url: String },
+    /// The chapter is there, but only for some readers, e.g. the story's
+    /// followers or those logged in.
+    Locked { url: String },
+    /// A page holding several chapters has no anchor for one of them.
+    MissingAnchor { anchor: String },
+}
+
+impl fmt::Display for ChapterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChapterError::NotFound { url } => write!(
+                f,
+                "the chapter at {} doesn't exist (any more); run again to pick up the current chapter list",
+                url
+            ),
+            ChapterError::Locked { url } => write!(
+                f,
+                "the chapter at {} is locked; it may only be open to readers who are logged in, follow the story or support the author, so try again with --username or --cookies-file",
+                url
+            ),
+            ChapterError::MissingAnchor { anchor } => {
+                write!(f, "couldn't find the anchor #{} in the chapter content", anchor)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChapterError {}
+
+impl ChapterError {
+    /// Whether `err` is a chapter that was deleted or is locked, as opposed
+    /// to one that failed to download or to parse.
+    pub fn is_unavailable(err: &eyre::Report) -> bool {
+        err.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<ChapterError>(),
+                Some(ChapterError::NotFound { .. } | ChapterError::Locked { .. })
+            )
+        })
+    }
+}
+
+/// A request the site didn't answer as it should.
+#[derive(Debug)]
+pub enum HttpError {
+    /// An error status other than a block.
+    Status { status: u16, url: String },
+    /// The site kept refusing requests through the longest cooldown.
+    Blocked { status: u16, cooldown: u64 },
+    /// The site answered with a Cloudflare challenge.
+    Challenge { host: String },
+    /// The site's robots.txt asks crawlers not to fetch the page.
+    Disallowed { url: String },
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Status { status, url } => write!(f, "HTTP {} for {}", status, url),
+            HttpError::Blocked { status, cooldown } => write!(
+                f,
+                "still blocked (HTTP {}) after a {}s cooldown, giving up; try again later with a lower --concurrent or a --delay",
+                status, cooldown
+            ),
+            HttpError::Challenge { host } => write!(
+                f,
+                "{} answered with a Cloudflare challenge, which rustyroad can't pass. \
+                 Open the page in a browser, then use the browser's cookies with \
+                 --browser-cookies <cookie database> or --cookies-file <cookies.txt>, \
+                 or just its cf_clearance cookie with --cf-clearance <value>; \
+                 pass the browser's User-Agent with --user-agent too, since the \
+                 clearance only holds for it",
+                host
+            ),
+            HttpError::Disallowed { url } => write!(
+                f,
+                "the site's robots.txt disallows {}, which --polite leaves alone",
+                url
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// The exit code for `err`: that of the first error in its chain with one
+/// of its own, or [`EXIT_FAILURE`].
+pub fn exit_code(err: &eyre::Report) -> i32 {
+    err.chain()
+        .find_map(|cause| {
+            if cause.is::<ScrapeError>() {
+                Some(EXIT_SCRAPE)
+            } else if cause.is::<ChapterError>() {
+                Some(EXIT_CHAPTER)
+            } else if let Some(err) = cause.downcast_ref::<HttpError>() {
+                Some(match err {
+                    HttpError::Blocked { .. } | HttpError::Challenge { .. } => EXIT_BLOCKED,
+                    HttpError::Status { .. } | HttpError::Disallowed { .. } => EXIT_HTTP,
+                })
+            } else if cause.is::<reqwest::Error>() {
+                Some(EXIT_HTTP)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(EXIT_FAILURE)
+}
@@ -0,0 +1,156 @@
+//! A rough estimate of the EPUB size, for `--dry-run`.
+
+use eyre::{Result, WrapErr};
+use reqwest::Url;
+use std::fmt;
+use std::io::{Cursor, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::{chapter_url, fetch_chapter_content, xhtml_page, CleanOpts, Client, FetchOpts, Story};
+
+/// How many chapters are downloaded to estimate the size of the rest.
+pub const ESTIMATE_SAMPLES: usize = 3;
+
+/// The container, package document, navigation and stylesheet, roughly.
+const BASE_OVERHEAD: u64 = 4 * 1024;
+
+/// The manifest, spine and table of contents entries for one chapter.
+const CHAPTER_OVERHEAD: u64 = 300;
+
+#[derive(Clone, Debug)]
+pub struct SizeEstimate {
+    /// Compressed size of all chapters, extrapolated from the samples.
+    pub chapters: u64,
+    pub cover: u64,
+    pub overhead: u64,
+    pub sampled: usize,
+    pub total_chapters: usize,
+}
+
+impl SizeEstimate {
+    pub fn total(&self) -> u64 {
+        self.chapters + self.cover + self.overhead
+    }
+
+    /// The relative error to expect. Chapter lengths vary, so an estimate
+    /// from a few samples is a lot less certain than one from all of them.
+    pub fn margin(&self) -> f64 {
+        if self.sampled >= self.total_chapters {
+            0.1
+        } else {
+            0.3
+        }
+    }
+}
+
+impl fmt::Display for SizeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "~{} (estimate, ±{:.0}%, from {} of {} chapters; cover {})",
+            human_size(self.total()),
+            self.margin() * 100.0,
+            self.sampled,
+            self.total_chapters,
+            human_size(self.cover)
+        )
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Picks up to `n` chapters spread over the story: the first, the last and
+/// evenly spaced ones in between.
+fn sample_indices(total: usize, n: usize) -> Vec<usize> {
+    if total <= n {
+        return (0..total).collect();
+    }
+
+    let mut indices: Vec<usize> = (0..n).map(|i| i * (total - 1) / (n - 1).max(1)).collect();
+    indices.dedup();
+    indices
+}
+
+/// Deflates `data` the way the EPUB writer would and returns the size of
+/// the compressed entry.
+fn compressed_size(data: &str) -> Result<u64> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file(
+        "chapter.xhtml",
+        FileOptions::default().compression_method(CompressionMethod::Deflated),
+    )?;
+    zip.write_all(data.as_bytes())?;
+    Ok(zip.finish()?.into_inner().len() as u64)
+}
+
+/// Estimates the size of the finished EPUB by downloading a few sample
+/// chapters and asking the server for the size of the cover.
+///
+/// Chapters are not sized with HEAD requests: the page length is dominated
+/// by the site around the chapter, not the text that ends up in the book.
+pub async fn estimate_size(
+    client: &Client,
+    story: &Story,
+    clean: &CleanOpts,
+) -> Result<SizeEstimate> {
+    let total = story.chapters.len();
+    let samples = sample_indices(total, ESTIMATE_SAMPLES);
+
+    let mut sampled_size = 0;
+    for &i in &samples {
+        let chapter = &story.chapters[i];
+        log::info!("sampling chapter {}...", i + 1);
+        let content =
+            fetch_chapter_content(client, &chapter.link, clean, &FetchOpts::default(), None)
+                .await
+                .wrap_err_with(|| {
+                    let url = chapter_url(&chapter.link)
+                        .map(|url| url.to_string())
+                        .unwrap_or_else(|_| chapter.link.clone());
+                    format!("chapter {} ({:?}) at {}", i + 1, chapter.name, url)
+                })?;
+        sampled_size += compressed_size(&xhtml_page(&chapter.name, &content))?;
+    }
+
+    let chapters = if samples.is_empty() {
+        0
+    } else {
+        sampled_size * total as u64 / samples.len() as u64
+    };
+
+    // fall back to downloading the cover if the server doesn't say; a
+    // generated cover is too small to count
+    let cover = if story.has_cover() {
+        let cover_url = Url::parse(&story.cover)?;
+        match client.content_length(cover_url.clone()).await {
+            Ok(Some(len)) => len,
+            _ => client.bytes(client.get(cover_url).await?).await?.len() as u64,
+        }
+    } else {
+        0
+    };
+
+    Ok(SizeEstimate {
+        chapters,
+        cover,
+        overhead: BASE_OVERHEAD + CHAPTER_OVERHEAD * total as u64,
+        sampled: samples.len(),
+        total_chapters: total,
+    })
+}
@@ -0,0 +1,136 @@
+//! Reading chapters back out of a book generated by a previous run.
+
+use eyre::{eyre, Result, WrapErr};
+use scraper::Selector;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::images::mime_for_ext;
+use crate::verify::parse_xml;
+use crate::{local_images, Cover, Image, Manifest, ManifestChapter, Story};
+
+/// Where the manifest is stored inside books written by
+/// [`EpubWriter`](crate::EpubWriter).
+pub const EMBEDDED_MANIFEST: &str = "rustyroad.json";
+
+pub struct ExistingBook {
+    archive: ZipArchive<File>,
+}
+
+impl ExistingBook {
+    pub fn open(path: impl AsRef<Path>) -> Result<ExistingBook> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?;
+        let archive = ZipArchive::new(file)
+            .wrap_err_with(|| format!("{} is not a valid epub", path.display()))?;
+
+        Ok(ExistingBook { archive })
+    }
+
+    fn read_bytes(&mut self, file: &str) -> Result<Option<Vec<u8>>> {
+        let mut entry = match self.archive.by_name(&format!("OEBPS/{}", file)) {
+            Ok(entry) => entry,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn read(&mut self, file: &str) -> Result<Option<String>> {
+        self.read_bytes(file)?
+            .map(|data| String::from_utf8(data).wrap_err_with(|| format!("{} is not utf-8", file)))
+            .transpose()
+    }
+
+    /// The manifest embedded in the book, if it was written with one.
+    pub fn manifest(&mut self) -> Result<Option<Manifest>> {
+        self.read(EMBEDDED_MANIFEST)?
+            .map(|data| Manifest::parse(&data, EMBEDDED_MANIFEST))
+            .transpose()
+    }
+
+    /// The book's cover, if it was written with one.
+    pub fn cover(&mut self) -> Result<Option<Cover>> {
+        for ext in ["jpg", "png", "gif"] {
+            if let Some(data) = self.read_bytes(&format!("cover.{}", ext))? {
+                return Ok(Some(Cover {
+                    ext: ext.to_string(),
+                    data,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reconstructs a manifest for a book without an embedded one by
+    /// matching the chapter titles in its table of contents against
+    /// `story`. Renamed chapters won't match and are downloaded again.
+    pub fn manifest_from_toc(&mut self, story: &Story) -> Result<Manifest> {
+        let ncx = self
+            .read("toc.ncx")?
+            .ok_or_else(|| eyre!("the existing epub has no toc.ncx"))?;
+        let ncx = parse_xml(&ncx);
+
+        let nav_point = Selector::parse("navPoint").unwrap();
+        let label = Selector::parse("navLabel > text").unwrap();
+        let content = Selector::parse("content").unwrap();
+
+        let mut manifest = Manifest::new(story, false);
+        manifest.generated = 0;
+        manifest.chapters = ncx
+            .select(&nav_point)
+            .filter_map(|point| {
+                let title = point.select(&label).next()?.text().collect::<String>();
+                let file = point.select(&content).next()?.value().attr("src")?;
+                let chapter = story.chapters.iter().find(|c| c.name == title.trim())?;
+                Some(ManifestChapter {
+                    name: chapter.name.clone(),
+                    link: chapter.link.clone(),
+                    file: file.to_string(),
+                })
+            })
+            .filter(|chapter| chapter.file.starts_with("chapter_"))
+            .collect();
+
+        Ok(manifest)
+    }
+
+    /// Returns the serialized chapter content of `file` (e.g.
+    /// `chapter_3.xhtml`), as it was originally passed to
+    /// [`BookWriter::add_chapter`](crate::BookWriter::add_chapter).
+    pub fn chapter_content(&mut self, file: &str) -> Result<String> {
+        let xhtml = self
+            .read(file)?
+            .ok_or_else(|| eyre!("{} is missing from the existing epub", file))?;
+
+        // we wrote these files ourselves, so the content is exactly what sits
+        // between the body tags
+        let start = xhtml.find("<body>").map(|i| i + "<body>".len());
+        let end = xhtml.rfind("</body>");
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => Ok(xhtml[start..end].trim().to_string()),
+            _ => Err(eyre!("{} has no body", file)),
+        }
+    }
+
+    /// The embedded images `content` (from [`chapter_content`]) refers to.
+    /// Images missing from the book are skipped with a warning.
+    ///
+    /// [`chapter_content`]: ExistingBook::chapter_content
+    pub fn chapter_images(&mut self, content: &str) -> Result<Vec<Image>> {
+        let mut images = Vec::new();
+        for file in local_images(content) {
+            let mime = file.rsplit_once('.').and_then(|(_, ext)| mime_for_ext(ext));
+            match (self.read_bytes(&file)?, mime) {
+                (Some(data), Some(mime)) => images.push(Image { file, mime, data }),
+                _ => log::warn!("{} is missing from the existing epub", file),
+            }
+        }
+        Ok(images)
+    }
+}
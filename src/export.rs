@@ -0,0 +1,599 @@
+//! Plain HTML and Markdown output, for reading in a browser or converting
+//! further with tools like pandoc, and plain text for text-to-speech.
+//!
+//! A book is written either as a single file, with the images embedded as
+//! `data:` URLs, or as a directory holding an index page, one file per
+//! chapter, the cover and the images. Text books have no images.
+
+use base64::Engine;
+use eyre::{Result, WrapErr};
+use std::io::Write;
+use std::path::Path;
+
+use crate::markdown::{escape_markdown, html_to_markdown};
+use crate::reviews::{reviews_page, REVIEWS_TITLE};
+use crate::text::html_to_text;
+use crate::volumes::word_count;
+use crate::{
+    book_date, escape, thousands, write_atomically, BookWriter, Chapter, Cover, Image, Reception,
+    Story, StoryStatus, Style,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Html,
+    Markdown,
+    Text,
+}
+
+/// What separates the pages of a text book written as one file.
+const TEXT_SEPARATOR: &str = "\n\n\n* * *\n\n\n";
+
+struct Page {
+    /// The page's file name without extension, which is also its anchor in
+    /// a single file.
+    id: String,
+    title: String,
+    in_toc: bool,
+    /// Html, Markdown or text, depending on the kind of book.
+    body: String,
+}
+
+/// Collects the book's pages, which are written out by
+/// [`BookWriter::write_to`] as a file or a directory.
+pub struct ExportWriter {
+    kind: Kind,
+    title: String,
+    author: String,
+    description: String,
+    /// Shown in the front matter if the book has a title page.
+    tags: Vec<String>,
+    /// The story's content warnings, shown with its tags.
+    warnings: Vec<String>,
+    /// The story's URL, if the front matter lists the book's details.
+    source: Option<String>,
+    /// The story's status, shown with its details.
+    status: Option<StoryStatus>,
+    words: usize,
+    /// The date the book is written, shown with the details.
+    date: String,
+    language: String,
+    cover: Option<Cover>,
+    images: Vec<Image>,
+    pages: Vec<Page>,
+    /// The stylesheet of html books.
+    style: Style,
+}
+
+fn data_url(mime: &str, data: &[u8]) -> String {
+    format!(
+        "data:{};base64,{}",
+        mime,
+        base64::engine::general_purpose::STANDARD.encode(data)
+    )
+}
+
+impl ExportWriter {
+    fn new(kind: Kind, story: &Story) -> ExportWriter {
+        ExportWriter {
+            kind,
+            title: story.title.clone(),
+            author: story.author.clone(),
+            description: story.description.clone(),
+            tags: Vec::new(),
+            warnings: Vec::new(),
+            source: None,
+            status: None,
+            words: 0,
+            date: book_date(story, false),
+            language: story.lang().to_string(),
+            cover: None,
+            images: Vec::new(),
+            pages: Vec::new(),
+            style: Style::default(),
+        }
+    }
+
+    /// Writes an html page, or a directory of them.
+    pub fn html(story: &Story) -> ExportWriter {
+        ExportWriter::new(Kind::Html, story)
+    }
+
+    /// Writes a Markdown file, or a directory of them.
+    pub fn markdown(story: &Story) -> ExportWriter {
+        ExportWriter::new(Kind::Markdown, story)
+    }
+
+    /// Writes a text file, or a directory of them.
+    pub fn text(story: &Story) -> ExportWriter {
+        ExportWriter::new(Kind::Text, story)
+    }
+
+    /// See [`Fb2Builder::set_reproducible`](crate::Fb2Builder::set_reproducible).
+    pub fn set_reproducible(&mut self, story: &Story) {
+        self.date = book_date(story, true);
+    }
+
+    fn ext(&self) -> &'static str {
+        match self.kind {
+            Kind::Html => "html",
+            Kind::Markdown => "md",
+            Kind::Text => "txt",
+        }
+    }
+
+    /// Whether `path` names a single file rather than a directory.
+    fn is_file(&self, path: &Path) -> bool {
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match self.kind {
+            Kind::Html => matches!(ext.as_deref(), Some("html" | "htm")),
+            Kind::Markdown => matches!(ext.as_deref(), Some("md" | "markdown")),
+            Kind::Text => matches!(ext.as_deref(), Some("txt")),
+        }
+    }
+
+    /// Points the `src` of embedded images at `data:` URLs, for books
+    /// written as one file.
+    fn inline_images(&self, body: &str) -> String {
+        let mut body = body.to_string();
+        for image in &self.images {
+            if !body.contains(&image.file) {
+                continue;
+            }
+            let url = data_url(image.mime, &image.data);
+            body = match self.kind {
+                Kind::Html => body.replace(
+                    &format!("src=\"{}\"", image.file),
+                    &format!("src=\"{}\"", url),
+                ),
+                Kind::Markdown => {
+                    body.replace(&format!("]({})", image.file), &format!("]({})", url))
+                }
+                Kind::Text => body,
+            };
+        }
+        body
+    }
+
+    fn cover_src(&self, single: bool) -> Result<Option<String>> {
+        let Some(cover) = &self.cover else {
+            return Ok(None);
+        };
+        Ok(Some(if single {
+            data_url(cover.mime()?, &cover.data)
+        } else {
+            format!("cover.{}", cover.ext)
+        }))
+    }
+
+    /// The book's title, author, cover, description and table of
+    /// contents. `link` gives the link to a page.
+    fn front_matter(&self, cover: Option<&str>, link: impl Fn(&Page) -> String) -> String {
+        let toc = self.pages.iter().filter(|page| page.in_toc);
+        let description = self
+            .description
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let status = self
+            .status
+            .map(|status| format!("{} · ", status.name()))
+            .unwrap_or_default();
+
+        match self.kind {
+            Kind::Html => {
+                let mut out = format!(
+                    "<header>\n<h1>{}</h1>\n<p class=\"author\">by {}</p>\n",
+                    escape(&self.title),
+                    escape(&self.author)
+                );
+                if !self.tags.is_empty() {
+                    out.push_str(&format!(
+                        "<p class=\"tags\">{}</p>\n",
+                        escape(&self.tags.join(" · "))
+                    ));
+                }
+                if !self.warnings.is_empty() {
+                    out.push_str(&format!(
+                        "<p class=\"warnings\">Content warnings: {}</p>\n",
+                        escape(&self.warnings.join(" · "))
+                    ));
+                }
+                if let Some(cover) = cover {
+                    out.push_str(&format!(
+                        "<p class=\"cover\"><img src=\"{}\" alt=\"Cover\"/></p>\n",
+                        cover
+                    ));
+                }
+                for line in description {
+                    out.push_str(&format!("<p>{}</p>\n", escape(line)));
+                }
+                if let Some(source) = &self.source {
+                    out.push_str(&format!(
+                        "<p class=\"details\">{} words · {}downloaded {}<br/><a href=\"{url}\">{url}</a></p>\n",
+                        thousands(self.words),
+                        status,
+                        self.date,
+                        url = escape(source)
+                    ));
+                }
+                out.push_str("</header>\n<nav>\n<h2>Contents</h2>\n<ol>\n");
+                for page in toc {
+                    out.push_str(&format!(
+                        "<li><a href=\"{}\">{}</a></li>\n",
+                        link(page),
+                        escape(&page.title)
+                    ));
+                }
+                out.push_str("</ol>\n</nav>\n");
+                out
+            }
+            Kind::Markdown => {
+                let mut out = format!(
+                    "# {}\n\nby {}\n\n",
+                    escape_markdown(&self.title),
+                    escape_markdown(&self.author)
+                );
+                if !self.tags.is_empty() {
+                    out.push_str(&format!(
+                        "*{}*\n\n",
+                        escape_markdown(&self.tags.join(" · "))
+                    ));
+                }
+                if !self.warnings.is_empty() {
+                    out.push_str(&format!(
+                        "*Content warnings: {}*\n\n",
+                        escape_markdown(&self.warnings.join(" · "))
+                    ));
+                }
+                if let Some(cover) = cover {
+                    out.push_str(&format!("![Cover]({})\n\n", cover));
+                }
+                for line in description {
+                    out.push_str(&format!("{}\n\n", escape_markdown(line)));
+                }
+                if let Some(source) = &self.source {
+                    out.push_str(&format!(
+                        "*{} words · {}downloaded {}*\\\n<{}>\n\n",
+                        thousands(self.words),
+                        status,
+                        self.date,
+                        source
+                    ));
+                }
+                out.push_str("## Contents\n\n");
+                for (i, page) in toc.enumerate() {
+                    out.push_str(&format!(
+                        "{}. [{}]({})\n",
+                        i + 1,
+                        escape_markdown(&page.title),
+                        link(page)
+                    ));
+                }
+                out
+            }
+            // text has no links to list the pages with
+            Kind::Text => {
+                let mut out = format!("{}\nby {}\n\n", self.title, self.author);
+                if !self.tags.is_empty() {
+                    out.push_str(&format!("{}\n\n", self.tags.join(" · ")));
+                }
+                if !self.warnings.is_empty() {
+                    out.push_str(&format!(
+                        "Content warnings: {}\n\n",
+                        self.warnings.join(" · ")
+                    ));
+                }
+                for line in description {
+                    out.push_str(&format!("{}\n\n", line));
+                }
+                if let Some(source) = &self.source {
+                    out.push_str(&format!(
+                        "{} words · {}downloaded {}\n{}\n\n",
+                        thousands(self.words),
+                        status,
+                        self.date,
+                        source
+                    ));
+                }
+                out
+            }
+        }
+    }
+
+    fn html_document(&self, title: &str, style: &str, body: &str) -> String {
+        format!(
+            "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\"/>\n<title>{}</title>\n{}\n</head>\n<body>\n{}</body>\n</html>\n",
+            escape(&self.language),
+            escape(title),
+            style,
+            body
+        )
+    }
+
+    fn write_file(&self, out: &mut dyn Write) -> Result<()> {
+        let cover = self.cover_src(true)?;
+        let front = self.front_matter(cover.as_deref(), |page| format!("#{}", page.id));
+
+        match self.kind {
+            Kind::Html => {
+                let mut body = front;
+                for page in &self.pages {
+                    body.push_str(&format!(
+                        "<section id=\"{}\">\n<h2>{}</h2>\n{}\n</section>\n",
+                        page.id,
+                        escape(&page.title),
+                        self.inline_images(&page.body)
+                    ));
+                }
+                let css = self
+                    .style
+                    .stylesheet(|font| data_url(font.mime, &font.data));
+                let style = format!("<style>{}</style>", css);
+                out.write_all(self.html_document(&self.title, &style, &body).as_bytes())?;
+            }
+            Kind::Markdown => {
+                let mut text = front;
+                for page in &self.pages {
+                    text.push_str(&format!(
+                        "\n<a id=\"{}\"></a>\n\n## {}\n\n{}\n",
+                        page.id,
+                        escape_markdown(&page.title),
+                        self.inline_images(&page.body)
+                    ));
+                }
+                out.write_all(text.as_bytes())?;
+            }
+            Kind::Text => {
+                let mut text = front.trim_end().to_string();
+                for page in &self.pages {
+                    text.push_str(TEXT_SEPARATOR);
+                    text.push_str(&format!("{}\n\n{}", page.title, page.body));
+                }
+                text.push('\n');
+                out.write_all(text.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_dir(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        let ext = self.ext();
+        let write = |name: &str, data: &[u8]| -> Result<()> {
+            let path = dir.join(name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, data)
+                .wrap_err_with(|| format!("could not write {}", path.display()))
+        };
+
+        if self.kind != Kind::Text {
+            if let Some(cover) = &self.cover {
+                write(&format!("cover.{}", cover.ext), &cover.data)?;
+            }
+            for image in &self.images {
+                write(&image.file, &image.data)?;
+            }
+        }
+
+        let cover = self.cover_src(false)?;
+        let front = self.front_matter(cover.as_deref(), |page| format!("{}.{}", page.id, ext));
+        let style = "<link rel=\"stylesheet\" type=\"text/css\" href=\"stylesheet.css\"/>";
+        match self.kind {
+            Kind::Html => {
+                for font in &self.style.fonts {
+                    write(&font.file, &font.data)?;
+                }
+                let css = self.style.stylesheet(|font| font.file.clone());
+                write("stylesheet.css", css.as_bytes())?;
+                write(
+                    "index.html",
+                    self.html_document(&self.title, style, &front).as_bytes(),
+                )?;
+            }
+            Kind::Markdown => write("index.md", front.as_bytes())?,
+            Kind::Text => {
+                let mut index = front;
+                index.push_str("Contents\n\n");
+                for page in self.pages.iter().filter(|page| page.in_toc) {
+                    index.push_str(&format!("{}.txt: {}\n", page.id, page.title));
+                }
+                write("index.txt", index.as_bytes())?;
+            }
+        }
+
+        for (i, page) in self.pages.iter().enumerate() {
+            let link = |page: Option<&Page>, label: &str| {
+                page.map(|page| (format!("{}.{}", page.id, ext), label.to_string()))
+            };
+            let nav: Vec<(String, String)> = [
+                link(i.checked_sub(1).and_then(|i| self.pages.get(i)), "Previous"),
+                Some((format!("index.{}", ext), "Contents".to_string())),
+                link(self.pages.get(i + 1), "Next"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+
+            let text = match self.kind {
+                Kind::Html => {
+                    let nav: Vec<String> = nav
+                        .iter()
+                        .map(|(href, label)| format!("<a href=\"{}\">{}</a>", href, label))
+                        .collect();
+                    let body = format!(
+                        "<h1>{}</h1>\n{}\n<nav>{}</nav>\n",
+                        escape(&page.title),
+                        page.body,
+                        nav.join(" · ")
+                    );
+                    self.html_document(&page.title, style, &body)
+                }
+                Kind::Markdown => {
+                    let nav: Vec<String> = nav
+                        .iter()
+                        .map(|(href, label)| format!("[{}]({})", label, href))
+                        .collect();
+                    format!(
+                        "# {}\n\n{}\n\n{}\n",
+                        escape_markdown(&page.title),
+                        page.body,
+                        nav.join(" · ")
+                    )
+                }
+                Kind::Text => format!("{}\n\n{}\n", page.title, page.body),
+            };
+            write(&format!("{}.{}", page.id, ext), text.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl BookWriter for ExportWriter {
+    fn add_cover(&mut self, cover: &Cover, _in_toc: bool) -> Result<()> {
+        cover.mime()?;
+        self.cover = Some(cover.clone());
+        Ok(())
+    }
+
+    // the title and table of contents are always part of the front matter,
+    // a title page only adds the tags and warnings
+    fn add_title_page(&mut self, story: &Story, _in_toc: bool) -> Result<()> {
+        self.tags = story.tags.clone();
+        self.warnings = story.warnings.clone();
+        Ok(())
+    }
+
+    // the front matter only adds the details
+    fn add_front_matter(&mut self, story: &Story, _in_toc: bool) -> Result<()> {
+        self.tags = story.tags.clone();
+        self.warnings = story.warnings.clone();
+        self.source = Some(story.url.clone());
+        self.status = story.status;
+        Ok(())
+    }
+
+    fn add_toc(&mut self) {}
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        if self.source.is_some() {
+            self.words += word_count(content);
+        }
+        let body = match self.kind {
+            Kind::Html => content.to_string(),
+            Kind::Markdown => html_to_markdown(content),
+            Kind::Text => html_to_text(content),
+        };
+        self.pages.push(Page {
+            id: format!("chapter_{}", nr),
+            title: chapter.name.clone(),
+            in_toc: true,
+            body,
+        });
+        Ok(())
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        if !self.images.iter().any(|other| other.file == image.file) {
+            self.images.push(image.clone());
+        }
+        Ok(())
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        let body = match self.kind {
+            Kind::Html => format!(
+                "<p>{} by {}</p>\n<p>Read the original at <a href=\"{url}\">{url}</a></p>",
+                escape(&story.title),
+                escape(&story.author),
+                url = escape(&story.url),
+            ),
+            Kind::Markdown => format!(
+                "{} by {}\n\nRead the original at <{}>",
+                escape_markdown(&story.title),
+                escape_markdown(&story.author),
+                story.url,
+            ),
+            Kind::Text => format!(
+                "{} by {}\n\nRead the original at {}",
+                story.title, story.author, story.url,
+            ),
+        };
+        self.pages.push(Page {
+            id: "source".to_string(),
+            title: "Source".to_string(),
+            in_toc,
+            body,
+        });
+        Ok(())
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        let html = reviews_page(reception);
+        let body = match self.kind {
+            Kind::Html => html,
+            Kind::Markdown => html_to_markdown(&html),
+            Kind::Text => html_to_text(&html),
+        };
+        self.pages.push(Page {
+            id: "reviews".to_string(),
+            title: REVIEWS_TITLE.to_string(),
+            in_toc,
+            body,
+        });
+        Ok(())
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        let body = match self.kind {
+            Kind::Html => paragraphs
+                .iter()
+                .map(|paragraph| format!("<p>{}</p>", escape(paragraph)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Kind::Markdown => paragraphs
+                .iter()
+                .map(|paragraph| escape_markdown(paragraph))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            Kind::Text => paragraphs.join("\n\n"),
+        };
+        self.pages.push(Page {
+            id: "colophon".to_string(),
+            title: "Colophon".to_string(),
+            in_toc,
+            body,
+        });
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize) {
+        self.title = format!("{} [INCOMPLETE]", story.title);
+        self.description = format!(
+            "[INCOMPLETE] This book only contains {} of {} chapters.\n{}",
+            added, total, story.description
+        );
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.style = style.clone();
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        self.write_file(out)
+    }
+
+    /// A path ending in the format's extension, e.g. `story.html`, is
+    /// written as a single file, anything else as a directory.
+    fn write_to(&mut self, path: &Path) -> Result<()> {
+        if !self.is_file(path) {
+            return self.write_dir(path);
+        }
+        write_atomically(path, |out| self.write_file(out))
+    }
+}
@@ -0,0 +1,433 @@
+//! FictionBook 2 output.
+//!
+//! FB2 is a single XML document: the metadata, one `<section>` per chapter,
+//! and every image embedded as a base64 `<binary>`. Chapter content is
+//! converted from the cleaned xhtml into FB2's much smaller set of elements.
+
+use base64::Engine;
+use ego_tree::NodeRef;
+use eyre::Result;
+use scraper::{Html, Node};
+use std::io::Write;
+
+use crate::images::image_id;
+use crate::reviews::{reviews_page, REVIEWS_TITLE};
+use crate::volumes::word_count;
+use crate::{
+    book_date, escape, front_matter, thousands, BookWriter, Chapter, Cover, Image, Reception,
+    Series, Story, IMAGE_DIR, WORDS_PLACEHOLDER,
+};
+
+/// Builds an FB2 document as chapters are added. Nothing is written until
+/// [`BookWriter::write`].
+pub struct Fb2Builder {
+    title: String,
+    author: String,
+    annotation: String,
+    tags: Vec<String>,
+    url: String,
+    cover: Option<Cover>,
+    images: Vec<Image>,
+    series: Option<Series>,
+    /// The front matter page and whether it has a title, written once the
+    /// word count is known.
+    front_matter: Option<(String, bool)>,
+    words: usize,
+    sections: String,
+    /// The book's date.
+    date: String,
+    language: String,
+}
+
+impl Fb2Builder {
+    pub fn new(story: &Story) -> Fb2Builder {
+        Fb2Builder {
+            title: story.title.clone(),
+            author: story.author.clone(),
+            annotation: story.description.clone(),
+            tags: story.tags.clone(),
+            url: story.url.clone(),
+            cover: None,
+            images: Vec::new(),
+            series: None,
+            front_matter: None,
+            words: 0,
+            sections: String::new(),
+            date: book_date(story, false),
+            language: story.lang().to_string(),
+        }
+    }
+
+    /// Dates the book by `SOURCE_DATE_EPOCH`, or else by the story's latest
+    /// chapter, instead of the day it is written, so the same story with the
+    /// same chapters makes the same book.
+    pub fn set_reproducible(&mut self, story: &Story) {
+        self.date = book_date(story, true);
+    }
+
+    fn cover_id(&self) -> Option<String> {
+        self.cover
+            .as_ref()
+            .map(|cover| format!("cover.{}", cover.ext))
+    }
+}
+
+/// The FB2 genre for the story's tags. FB2 requires at least one genre, so
+/// stories without a recognized tag fall back to general prose.
+fn genres(tags: &[String]) -> Vec<&'static str> {
+    let mut genres: Vec<&'static str> = tags
+        .iter()
+        .filter_map(|tag| match tag.to_lowercase().as_str() {
+            "fantasy" | "high fantasy" | "low fantasy" | "urban fantasy" => Some("sf_fantasy"),
+            "sci-fi" | "science fiction" | "space opera" => Some("sf"),
+            "litrpg" | "gamelit" => Some("sf_litrpg"),
+            "horror" => Some("sf_horror"),
+            "romance" => Some("love"),
+            "mystery" => Some("det"),
+            "action" => Some("sf_action"),
+            "adventure" => Some("adventure"),
+            "comedy" => Some("humor"),
+            "post apocalyptic" => Some("sf_postapocalyptic"),
+            _ => None,
+        })
+        .collect();
+    genres.dedup();
+
+    if genres.is_empty() {
+        genres.push("prose");
+    }
+    genres
+}
+
+fn paragraphs(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("<p>{}</p>", escape(line)))
+        .collect()
+}
+
+impl BookWriter for Fb2Builder {
+    fn add_cover(&mut self, cover: &Cover, _in_toc: bool) -> Result<()> {
+        cover.mime()?;
+        self.cover = Some(cover.clone());
+        Ok(())
+    }
+
+    // readers build the table of contents from the section titles
+    fn add_toc(&mut self) {}
+
+    fn chapter_link(&self, nr: usize) -> Option<String> {
+        Some(format!("#chapter-{}", nr))
+    }
+
+    fn add_front_matter(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.front_matter = Some((front_matter(story, WORDS_PLACEHOLDER, &self.date), in_toc));
+        Ok(())
+    }
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        self.words += word_count(content);
+        let body = html_to_fb2(content);
+        self.sections.push_str(&format!(
+            "<section id=\"chapter-{}\"><title><p>{}</p></title>{}</section>\n",
+            nr,
+            escape(&chapter.name),
+            if body.is_empty() {
+                "<empty-line/>"
+            } else {
+                &body
+            }
+        ));
+        Ok(())
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        if !self.images.iter().any(|other| other.file == image.file) {
+            self.images.push(image.clone());
+        }
+        Ok(())
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        // readers build the table of contents from section titles, so an
+        // untitled section stays out of it
+        let title = if in_toc {
+            "<title><p>Source</p></title>"
+        } else {
+            ""
+        };
+        self.sections.push_str(&format!(
+            "<section id=\"source\">{}<p>{} by {}</p><p>Read the original at <a l:href=\"{url}\">{url}</a></p></section>\n",
+            title,
+            escape(&story.title),
+            escape(&story.author),
+            url = escape(&story.url),
+        ));
+        Ok(())
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        let title = if in_toc {
+            format!("<title><p>{}</p></title>", REVIEWS_TITLE)
+        } else {
+            String::new()
+        };
+        self.sections.push_str(&format!(
+            "<section id=\"reviews\">{}{}</section>\n",
+            title,
+            html_to_fb2(&reviews_page(reception))
+        ));
+        Ok(())
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        let title = if in_toc {
+            "<title><p>Colophon</p></title>"
+        } else {
+            ""
+        };
+        self.sections
+            .push_str(&format!("<section id=\"colophon\">{}", title));
+        for paragraph in paragraphs {
+            self.sections
+                .push_str(&format!("<p>{}</p>", escape(paragraph)));
+        }
+        self.sections.push_str("</section>\n");
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize) {
+        self.title = format!("{} [INCOMPLETE]", story.title);
+        self.annotation = format!(
+            "[INCOMPLETE] This book only contains {} of {} chapters.\n{}",
+            added, total, story.description
+        );
+    }
+
+    fn set_series(&mut self, series: &Series) {
+        self.series = Some(series.clone());
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        let date = &self.date;
+        let genres: String = genres(&self.tags)
+            .iter()
+            .map(|genre| format!("<genre>{}</genre>", genre))
+            .collect();
+        let keywords = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!("<keywords>{}</keywords>", escape(&self.tags.join(", ")))
+        };
+        let coverpage = match self.cover_id() {
+            Some(id) => format!("<coverpage><image l:href=\"#{}\"/></coverpage>", id),
+            None => String::new(),
+        };
+        // FB2 only numbers books in a series with whole numbers
+        let front_matter = match &self.front_matter {
+            Some((page, in_toc)) => format!(
+                "<section id=\"front-matter\">{}{}</section>\n",
+                if *in_toc {
+                    "<title><p>About this Book</p></title>"
+                } else {
+                    ""
+                },
+                html_to_fb2(&page.replace(WORDS_PLACEHOLDER, &thousands(self.words)))
+            ),
+            None => String::new(),
+        };
+        let sequence = match &self.series {
+            Some(series) => format!(
+                "<sequence name=\"{}\" number=\"{}\"/>",
+                escape(&series.name),
+                series.index.round() as i64
+            ),
+            None => String::new(),
+        };
+
+        write!(
+            out,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<FictionBook xmlns="http://www.gribuser.ru/xml/fictionbook/2.0" xmlns:l="http://www.w3.org/1999/xlink">
+<description>
+<title-info>{genres}<author><nickname>{author}</nickname></author><book-title>{title}</book-title><annotation>{annotation}</annotation>{keywords}<lang>{lang}</lang>{coverpage}{sequence}</title-info>
+<document-info><author><nickname>{author}</nickname></author><program-used>rustyroad</program-used><date value="{date}">{date}</date><src-url>{url}</src-url><id>{url}</id><version>1.0</version></document-info>
+</description>
+<body>
+<title><p>{title}</p></title>
+{front_matter}{sections}</body>
+"#,
+            genres = genres,
+            author = escape(&self.author),
+            title = escape(&self.title),
+            annotation = paragraphs(&self.annotation),
+            keywords = keywords,
+            coverpage = coverpage,
+            sequence = sequence,
+            date = date,
+            lang = escape(&self.language),
+            url = escape(&self.url),
+            front_matter = front_matter,
+            sections = self.sections,
+        )?;
+
+        if let (Some(cover), Some(id)) = (&self.cover, self.cover_id()) {
+            writeln!(
+                out,
+                "<binary id=\"{}\" content-type=\"{}\">{}</binary>",
+                id,
+                cover.mime()?,
+                base64::engine::general_purpose::STANDARD.encode(&cover.data)
+            )?;
+        }
+
+        for image in &self.images {
+            writeln!(
+                out,
+                "<binary id=\"{}\" content-type=\"{}\">{}</binary>",
+                image.id(),
+                image.mime,
+                base64::engine::general_purpose::STANDARD.encode(&image.data)
+            )?;
+        }
+
+        writeln!(out, "</FictionBook>")?;
+        Ok(())
+    }
+}
+
+/// Accumulates FB2 markup. Inline formatting is tracked on a stack so that
+/// it can be closed and reopened when a paragraph has to be split, e.g. at
+/// a `<br>`.
+#[derive(Default)]
+struct Converter {
+    out: String,
+    /// The block element currently open (`p` or `subtitle`).
+    block: Option<&'static str>,
+    inline: Vec<(String, &'static str)>,
+}
+
+impl Converter {
+    fn open_block(&mut self, name: &'static str) {
+        self.close_block();
+        self.out.push_str(&format!("<{}>", name));
+        for (open, _) in &self.inline {
+            self.out.push_str(open);
+        }
+        self.block = Some(name);
+    }
+
+    fn close_block(&mut self) {
+        if let Some(name) = self.block.take() {
+            for (_, close) in self.inline.iter().rev() {
+                self.out.push_str(close);
+            }
+            self.out.push_str(&format!("</{}>", name));
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.block.is_none() {
+            if text.trim().is_empty() {
+                return;
+            }
+            self.open_block("p");
+        }
+        self.out.push_str(&escape(text));
+    }
+
+    fn inline(&mut self, node: NodeRef<Node>, open: String, close: &'static str) {
+        if self.block.is_some() {
+            self.out.push_str(&open);
+        }
+        self.inline.push((open, close));
+        self.children(node);
+        self.inline.pop();
+        if self.block.is_some() {
+            self.out.push_str(close);
+        }
+    }
+
+    fn children(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    fn node(&mut self, node: NodeRef<Node>) {
+        let el = match node.value() {
+            Node::Text(text) => return self.text(text),
+            Node::Element(el) => el,
+            _ => return,
+        };
+
+        match el.name() {
+            "em" | "i" | "cite" | "dfn" => self.inline(node, "<emphasis>".into(), "</emphasis>"),
+            "strong" | "b" => self.inline(node, "<strong>".into(), "</strong>"),
+            "s" | "strike" | "del" => {
+                self.inline(node, "<strikethrough>".into(), "</strikethrough>")
+            }
+            "sub" => self.inline(node, "<sub>".into(), "</sub>"),
+            "sup" => self.inline(node, "<sup>".into(), "</sup>"),
+            "code" | "kbd" | "samp" | "tt" => self.inline(node, "<code>".into(), "</code>"),
+            // links inside the chapter would point at ids FB2 can't hold on
+            // inline elements, so only links to other chapters and leaving
+            // the book are kept
+            "a" => match el.attr("href") {
+                Some(href) if !href.starts_with('#') || href.starts_with("#chapter-") => {
+                    self.inline(node, format!("<a l:href=\"{}\">", escape(href)), "</a>")
+                }
+                _ => self.children(node),
+            },
+            "br" => {
+                if self.block == Some("p") {
+                    self.close_block();
+                }
+            }
+            "hr" => {
+                self.close_block();
+                self.out.push_str("<empty-line/>");
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.open_block("subtitle");
+                self.children(node);
+                self.close_block();
+            }
+            "blockquote" => {
+                self.close_block();
+                self.out.push_str("<cite>");
+                self.children(node);
+                self.close_block();
+                self.out.push_str("</cite>");
+            }
+            // only embedded images can be shown, FB2 has no remote images
+            "img" => {
+                if let Some(src) = el.attr("src") {
+                    if src.starts_with(&format!("{}/", IMAGE_DIR)) {
+                        self.close_block();
+                        self.out
+                            .push_str(&format!("<image l:href=\"#{}\"/>", escape(&image_id(src))));
+                    }
+                }
+            }
+            "script" | "style" => (),
+            "span" | "u" | "small" | "big" | "font" | "abbr" | "q" | "mark" => self.children(node),
+            // everything else is treated as a block
+            _ => {
+                self.close_block();
+                self.children(node);
+                self.close_block();
+            }
+        }
+    }
+}
+
+/// Converts cleaned chapter xhtml into the body of an FB2 section.
+pub fn html_to_fb2(content: &str) -> String {
+    let fragment = Html::parse_fragment(content);
+    let mut converter = Converter::default();
+    converter.children(fragment.tree.root());
+    converter.close_block();
+    converter.out
+}
@@ -0,0 +1,48 @@
+//! Story feeds, which list the latest chapters of a story in a fraction of
+//! the size of its page, for checking books for new chapters cheaply.
+
+use eyre::{Result, WrapErr};
+use scraper::Selector;
+use std::collections::HashSet;
+
+use crate::sites::{chapter_key, site_for, story_url};
+use crate::verify::parse_xml;
+use crate::{chapter_url, Client, Manifest};
+
+/// The chapter links in a feed.
+fn feed_links(xml: &str) -> Vec<String> {
+    let doc = parse_xml(xml);
+    doc.select(&Selector::parse("item > link").unwrap())
+        .map(|link| link.text().collect::<String>().trim().to_string())
+        .filter(|link| !link.is_empty())
+        .collect()
+}
+
+/// Whether the feed of the story of the book `manifest` describes lists
+/// chapters the book doesn't have. `None` if the site has no feed, or it
+/// lists no chapters at all.
+pub async fn feed_has_new_chapters(client: &Client, manifest: &Manifest) -> Result<Option<bool>> {
+    let url = story_url(&manifest.url)?;
+    let Some(feed) = site_for(&url)?.feed_url(&url) else {
+        return Ok(None);
+    };
+    let xml = client
+        .text(client.get(feed.clone()).await?)
+        .await
+        .wrap_err_with(|| format!("could not fetch the feed {}", feed))?;
+    let links = feed_links(&xml);
+    if links.is_empty() {
+        return Ok(None);
+    }
+
+    let key = |link: &str| chapter_key(&chapter_url(link).ok()?);
+    let known: HashSet<String> = manifest
+        .chapters
+        .iter()
+        .filter_map(|chapter| key(&chapter.link))
+        .collect();
+    // a link that can't be told apart from the others may well be new
+    Ok(Some(links.iter().any(|link| {
+        key(link).is_none_or(|key| !known.contains(&key))
+    })))
+}
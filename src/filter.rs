@@ -0,0 +1,55 @@
+use crate::Story;
+use eyre::{Result, WrapErr};
+use regex::Regex;
+
+/// Selects which stories to download when working through a list of them.
+///
+/// Every pattern that is set has to match: the author and title patterns
+/// against the story's author and title, the tag pattern against at least
+/// one of its tags. Matching is case-insensitive.
+#[derive(Debug, Default)]
+pub struct StoryFilter {
+    author: Option<Regex>,
+    tag: Option<Regex>,
+    title: Option<Regex>,
+}
+
+fn compile(pattern: Option<&str>, what: &str) -> Result<Option<Regex>> {
+    pattern
+        .map(|p| {
+            Regex::new(&format!("(?i){}", p)).wrap_err_with(|| format!("invalid {} filter", what))
+        })
+        .transpose()
+}
+
+impl StoryFilter {
+    pub fn new(
+        author: Option<&str>,
+        tag: Option<&str>,
+        title: Option<&str>,
+    ) -> Result<StoryFilter> {
+        Ok(StoryFilter {
+            author: compile(author, "author")?,
+            tag: compile(tag, "tag")?,
+            title: compile(title, "title")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none() && self.tag.is_none() && self.title.is_none()
+    }
+
+    pub fn matches(&self, story: &Story) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|re| re.is_match(&story.author))
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|re| re.is_match(&story.title))
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|re| story.tags.iter().any(|tag| re.is_match(tag)))
+    }
+}
@@ -0,0 +1,216 @@
+//! Footnotes authors link up by hand: a short marker such as `1` or `[1]`
+//! linking to `#fn1`, and a paragraph further down with that id (or an
+//! `<a name>`, as exported from Word) and usually a link back. On the site
+//! they work as plain anchors; in a book they are made into notes readers
+//! recognize.
+
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Node};
+use std::collections::HashMap;
+
+use crate::clean::new_element;
+
+/// How footnotes are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Footnotes {
+    /// The notes are gathered in a section at the end of the chapter,
+    /// linked both ways. This works in any format.
+    #[default]
+    Section,
+    /// The same, with `epub:type="noteref"` on the markers and every note
+    /// in an `<aside epub:type="footnote">`, which EPUB 3 readers such as
+    /// Kobo and Apple Books show as pop-ups.
+    Epub3,
+}
+
+/// Markers longer than this are taken for ordinary links within the page.
+const MAX_MARKER: usize = 6;
+
+/// Elements a note can be, when the target is inside one.
+const BLOCKS: &[&str] = &["p", "li", "div", "dd", "aside", "section", "blockquote"];
+
+/// The anchor `el` is known by, its id or else its name.
+fn anchor(el: ElementRef<'_>) -> Option<&str> {
+    el.value().id().or_else(|| match el.value().name() {
+        "a" => el.value().attr("name"),
+        _ => None,
+    })
+}
+
+fn fragment(el: ElementRef<'_>) -> Option<&str> {
+    el.value()
+        .attr("href")?
+        .strip_prefix('#')
+        .filter(|frag| !frag.is_empty())
+}
+
+/// The block below `content` that holds the note at `target`, if it is in
+/// one.
+fn note_block(doc: &Html, content: NodeId, target: NodeId) -> Option<NodeId> {
+    let target = doc.tree.get(target).unwrap();
+    std::iter::once(target)
+        .chain(target.ancestors())
+        .take_while(|node| node.id() != content)
+        .filter_map(ElementRef::wrap)
+        .find(|el| BLOCKS.contains(&el.value().name()))
+        .map(|el| el.id())
+}
+
+/// The attributes of the element `id`, but for its anchor.
+fn attrs_without_anchor(doc: &Html, id: NodeId) -> Vec<(String, String)> {
+    let Node::Element(el) = doc.tree.get(id).unwrap().value() else {
+        return Vec::new();
+    };
+    el.attrs()
+        .filter(|(attr, _)| !matches!(*attr, "id" | "name"))
+        .map(|(attr, value)| (attr.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Replaces the element `id` with one named `name` with the attributes
+/// `attrs`, keeping its content.
+fn set_element(doc: &mut Html, id: NodeId, name: &str, attrs: &[(&str, &str)]) {
+    *doc.tree.get_mut(id).unwrap().value() = new_element(name, attrs);
+}
+
+/// Gives the element `id` the attributes `attrs` instead of its own.
+fn set_attrs(doc: &mut Html, id: NodeId, attrs: &[(String, String)]) {
+    let name = match doc.tree.get(id).unwrap().value() {
+        Node::Element(el) => el.name().to_string(),
+        _ => return,
+    };
+    let attrs: Vec<(&str, &str)> = attrs
+        .iter()
+        .map(|(attr, value)| (attr.as_str(), value.as_str()))
+        .collect();
+    set_element(doc, id, &name, &attrs);
+}
+
+/// Links the footnotes below `content` up as `style`, numbering them from 1.
+pub(crate) fn convert(doc: &mut Html, content: NodeId, style: Footnotes) {
+    let root = doc.tree.get(content).unwrap();
+    let order: Vec<NodeId> = root.descendants().map(|node| node.id()).collect();
+    let position = |id: NodeId| order.iter().position(|other| *other == id);
+    let anchors: HashMap<&str, NodeId> = root
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter_map(|el| Some((anchor(el)?, el.id())))
+        .collect();
+
+    // (marker, note) for every link to a note further down, through a
+    // marker that reads like a footnote's
+    let refs: Vec<(NodeId, NodeId)> = root
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter(|el| el.value().name() == "a")
+        .filter_map(|el| {
+            let target = *anchors.get(fragment(el)?)?;
+            let marker = el.text().collect::<String>();
+            let marker = marker.trim();
+            if marker.is_empty()
+                || marker.chars().count() > MAX_MARKER
+                || position(target) <= position(el.id())
+            {
+                return None;
+            }
+            let block = note_block(doc, content, target)?;
+            // not a link within the note itself
+            (!el.ancestors().any(|node| node.id() == block)).then_some((el.id(), block))
+        })
+        .collect();
+    if refs.is_empty() {
+        return;
+    }
+    log::debug!("linking up {} footnote(s)", refs.len());
+
+    // the notes in the order they are first referred to, and the new ids
+    // of the old anchors of the markers
+    let mut notes: Vec<NodeId> = Vec::new();
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    let mut markers = Vec::with_capacity(refs.len());
+    for (marker, block) in refs {
+        let nr = match notes.iter().position(|note| *note == block) {
+            Some(i) => i + 1,
+            None => {
+                notes.push(block);
+                notes.len()
+            }
+        };
+        if let Some(old) = anchor(ElementRef::wrap(doc.tree.get(marker).unwrap()).unwrap()) {
+            renamed
+                .entry(old.to_string())
+                .or_insert(format!("fnref-{}", nr));
+        }
+        markers.push((marker, nr));
+    }
+
+    // markers referring to the same note all get a link, the first an id
+    let mut seen = vec![false; notes.len()];
+    for (marker, nr) in markers {
+        let href = format!("#fn-{}", nr);
+        let ref_id = format!("fnref-{}", nr);
+        let mut attrs = vec![("class", "footnote-ref"), ("href", href.as_str())];
+        if !std::mem::replace(&mut seen[nr - 1], true) {
+            attrs.push(("id", ref_id.as_str()));
+        }
+        if style == Footnotes::Epub3 {
+            attrs.push(("epub:type", "noteref"));
+        }
+        set_element(doc, marker, "a", &attrs);
+    }
+
+    // links back from the notes go to the new ids, and the old anchors in
+    // the notes are dropped
+    let backlinks: Vec<(NodeId, Option<String>)> = notes
+        .iter()
+        .flat_map(|note| doc.tree.get(*note).unwrap().descendants().skip(1))
+        .filter_map(ElementRef::wrap)
+        .filter(|el| el.value().name() == "a")
+        .map(|el| {
+            (
+                el.id(),
+                fragment(el).and_then(|frag| renamed.get(frag)).cloned(),
+            )
+        })
+        .collect();
+    for (id, ref_id) in backlinks {
+        let mut attrs = attrs_without_anchor(doc, id);
+        if let Some(ref_id) = ref_id {
+            attrs.retain(|(attr, _)| attr != "href");
+            attrs.push(("href".to_string(), format!("#{}", ref_id)));
+        }
+        set_attrs(doc, id, &attrs);
+    }
+
+    let section = doc
+        .tree
+        .get_mut(content)
+        .unwrap()
+        .append(new_element("div", &[("class", "footnotes")]))
+        .id();
+    for (i, note) in notes.into_iter().enumerate() {
+        let note_id = format!("fn-{}", i + 1);
+        let mut attrs = attrs_without_anchor(doc, note);
+        let note = match style {
+            Footnotes::Section => {
+                attrs.push(("id".to_string(), note_id));
+                set_attrs(doc, note, &attrs);
+                note
+            }
+            Footnotes::Epub3 => {
+                set_attrs(doc, note, &attrs);
+                let mut aside = doc.tree.orphan(new_element(
+                    "aside",
+                    &[
+                        ("id", &note_id),
+                        ("class", "footnote"),
+                        ("epub:type", "footnote"),
+                    ],
+                ));
+                aside.append_id(note);
+                aside.id()
+            }
+        };
+        doc.tree.get_mut(section).unwrap().append_id(note);
+    }
+}
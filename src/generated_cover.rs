@@ -0,0 +1,145 @@
+//! A plain typographic cover for stories without one: the title and author
+//! in a pixel font on a colored background, so the book still stands out on
+//! a reader's shelf.
+
+use eyre::{Result, WrapErr};
+use font8x8::{UnicodeFonts, BASIC_FONTS, LATIN_FONTS};
+use image::{ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+use crate::{Cover, Story};
+
+const WIDTH: u32 = 600;
+const HEIGHT: u32 = 900;
+const MARGIN: u32 = 48;
+const TEXT: Rgb<u8> = Rgb([0xf5, 0xf1, 0xe8]);
+
+/// Muted backgrounds light text reads well on.
+const BACKGROUNDS: [[u8; 3]; 8] = [
+    [0x2e, 0x4a, 0x62],
+    [0x5b, 0x2a, 0x3c],
+    [0x2f, 0x55, 0x3f],
+    [0x6b, 0x3e, 0x26],
+    [0x3d, 0x35, 0x63],
+    [0x24, 0x4f, 0x55],
+    [0x63, 0x2c, 0x2c],
+    [0x41, 0x41, 0x41],
+];
+
+fn glyph(c: char) -> [u8; 8] {
+    BASIC_FONTS
+        .get(c)
+        .or_else(|| LATIN_FONTS.get(c))
+        .or_else(|| BASIC_FONTS.get('?'))
+        .unwrap_or_default()
+}
+
+/// Splits `text` into lines of at most `width` characters, breaking words
+/// only when a single word is too long.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        while word.len() > width {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            lines.push(word.drain(..width).collect());
+        }
+        let word: String = word.into_iter().collect();
+        if word.is_empty() {
+            continue;
+        }
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draws `line` centered at height `y`, every font pixel `scale` pixels
+/// wide.
+fn draw_line(img: &mut RgbImage, line: &str, y: u32, scale: u32) {
+    let width = line.chars().count() as u32 * 8 * scale;
+    let mut x = WIDTH.saturating_sub(width) / 2;
+    for c in line.chars() {
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..8 {
+                if bits & (1 << col) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let (px, py) = (x + col * scale + dx, y + row as u32 * scale + dy);
+                        if px < WIDTH && py < HEIGHT {
+                            img.put_pixel(px, py, TEXT);
+                        }
+                    }
+                }
+            }
+        }
+        x += 8 * scale;
+    }
+}
+
+/// The lines of `text` and their scale: as large as fits in `height`,
+/// but no larger than `max_scale`.
+fn fit(text: &str, height: u32, max_scale: u32) -> (Vec<String>, u32) {
+    for scale in (2..=max_scale).rev() {
+        let lines = wrap(text, ((WIDTH - 2 * MARGIN) / (8 * scale)) as usize);
+        if lines.len() as u32 * 10 * scale <= height {
+            return (lines, scale);
+        }
+    }
+    (wrap(text, ((WIDTH - 2 * MARGIN) / 16) as usize), 2)
+}
+
+/// Renders a PNG cover with the story's title and author. The background
+/// color is picked from the title, so a story always gets the same cover.
+pub fn generate_cover(story: &Story) -> Result<Cover> {
+    let hash = story
+        .title
+        .bytes()
+        .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u32));
+    let background = BACKGROUNDS[hash as usize % BACKGROUNDS.len()];
+    let mut img = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb(background));
+
+    // the title in the upper two thirds, the author below a rule
+    let (lines, scale) = fit(&story.title, 520, 7);
+    let block = lines.len() as u32 * 10 * scale;
+    let mut y = 120 + (520 - block.min(520)) / 2;
+    for line in &lines {
+        draw_line(&mut img, line, y, scale);
+        y += 10 * scale;
+    }
+
+    let rule = HEIGHT - 220;
+    for x in WIDTH / 2 - 60..WIDTH / 2 + 60 {
+        for y in rule..rule + 4 {
+            img.put_pixel(x, y, TEXT);
+        }
+    }
+
+    let (lines, scale) = fit(&story.author, 100, 3);
+    let mut y = rule + 48;
+    for line in &lines {
+        draw_line(&mut img, line, y, scale);
+        y += 10 * scale;
+    }
+
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+        .wrap_err("could not encode the generated cover")?;
+    Ok(Cover {
+        ext: "png".to_string(),
+        data,
+    })
+}
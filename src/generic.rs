@@ -0,0 +1,437 @@
+//! Best-effort downloads from sites none of the [`SITES`](crate::SITES)
+//! know: the story is crawled from its first chapter by following each
+//! page's link to the next, and the chapter text is picked out of each page
+//! the way reader modes do, by where the longest run of paragraphs is.
+//!
+//! Nothing here knows the site, so chapters can be missed, cut short or
+//! carry the page's clutter along; the book says as much.
+
+use ego_tree::NodeId;
+use eyre::{Result, WrapErr};
+use regex::Regex;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Node};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use crate::{fetch_document, selector, sites, Chapter, Client, FetchOpts, Story};
+
+/// Crawling stops after this many chapters, in case the next links go
+/// round in a way the visited pages don't catch.
+const MAX_CHAPTERS: usize = 5000;
+
+/// Paragraphs shorter than this don't count towards the text's score.
+const MIN_PARAGRAPH: usize = 25;
+
+/// Class and id words of the elements that hold an article's text.
+fn positive() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"(?i)article|body|chapter|content|entry|main|page|post|story|text").unwrap()
+    })
+}
+
+/// Class and id words of the elements around it.
+fn negative() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r"(?i)ad-|advert|banner|comment|footer|menu|meta|nav|popup|related|share|sharing|sidebar|social|sponsor|widget",
+        )
+        .unwrap()
+    })
+}
+
+/// `el`'s text on one line.
+fn text(el: ElementRef) -> String {
+    el.text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The share of `el`'s text that is in links.
+fn link_density(el: ElementRef) -> f64 {
+    let total = text(el).len();
+    if total == 0 {
+        return 1.0;
+    }
+    let Ok(links) = selector("a") else {
+        return 0.0;
+    };
+    let linked: usize = el.select(&links).map(|a| text(a).len()).sum();
+    linked as f64 / total as f64
+}
+
+/// How much `el`'s class and id look like those of an article, or of what
+/// sits around one.
+fn class_weight(el: ElementRef) -> f64 {
+    let mut weight = 0.0;
+    for name in [el.value().attr("class"), el.value().id()]
+        .into_iter()
+        .flatten()
+    {
+        if negative().is_match(name) {
+            weight -= 25.0;
+        }
+        if positive().is_match(name) {
+            weight += 25.0;
+        }
+    }
+    weight
+}
+
+/// What an element starts with as a candidate for holding the text, by
+/// its kind.
+fn tag_weight(el: ElementRef) -> f64 {
+    match el.value().name() {
+        "article" | "main" => 10.0,
+        "div" | "section" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "form" | "ol" | "ul" | "dl" | "address" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    }
+}
+
+/// The text `el` holds itself, outside its child elements, which is where
+/// pages that break their lines with `<br>`s keep it.
+fn own_text(el: ElementRef) -> String {
+    el.children()
+        .filter_map(|child| match child.value() {
+            Node::Text(text) => Some(&**text),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The element of `doc` that most likely holds the chapter text: each
+/// paragraph scores for its parent, and half as much for the parent's
+/// parent, by its length and commas, and the scores are cut down by how
+/// much of each element is links.
+pub(crate) fn find_content(doc: &Html) -> Option<NodeId> {
+    let paragraphs = selector("p, pre, div, td").ok()?;
+    let mut scores: HashMap<NodeId, f64> = HashMap::new();
+    let mut score_for = |el: ElementRef, score: f64| {
+        *scores
+            .entry(el.id())
+            .or_insert_with(|| tag_weight(el) + class_weight(el)) += score;
+    };
+
+    for paragraph in doc.select(&paragraphs) {
+        let is_div = matches!(paragraph.value().name(), "div" | "td");
+        let line = match is_div {
+            true => own_text(paragraph),
+            false => text(paragraph),
+        };
+        let line = line.trim();
+        if line.len() < MIN_PARAGRAPH {
+            continue;
+        }
+        let score = 1.0 + line.matches(',').count() as f64 + (line.len() / 100).min(3) as f64;
+        let mut parents = paragraph.ancestors().filter_map(ElementRef::wrap);
+        // a block of text lines scores for itself
+        let first = match is_div {
+            true => Some(paragraph),
+            false => parents.next(),
+        };
+        if let Some(parent) = first {
+            score_for(parent, score);
+            if let Some(grandparent) = parents.next() {
+                score_for(grandparent, score / 2.0);
+            }
+        }
+    }
+
+    scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let el = ElementRef::wrap(doc.tree.get(id)?)?;
+            (!matches!(el.value().name(), "html" | "body"))
+                .then(|| (id, score * (1.0 - link_density(el))))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(id, _)| id)
+        .or_else(|| {
+            let fallback = selector("article, main, body").ok()?;
+            doc.select(&fallback).next().map(|el| el.id())
+        })
+}
+
+/// Removes what sits in the text found by [`find_content`] without being
+/// part of it: navigation, forms, sharing buttons, comments, and the short
+/// blocks that are mostly links, like "Previous chapter | Next chapter".
+pub(crate) fn strip_clutter(doc: &mut Html, content: NodeId) {
+    let clutter = crate::clean::find(doc, content, |el| {
+        let name = el.value().name();
+        if matches!(
+            name,
+            "nav" | "aside" | "footer" | "form" | "button" | "iframe" | "noscript" | "script"
+        ) {
+            return true;
+        }
+        if class_weight(el) < 0.0 {
+            return true;
+        }
+        matches!(name, "p" | "div" | "ul" | "table")
+            && text(el).len() < 200
+            && el.select(&selector("img").unwrap()).next().is_none()
+            && link_density(el) > 0.5
+    });
+    for id in clutter {
+        if let Some(mut node) = doc.tree.get_mut(id) {
+            node.detach();
+        }
+    }
+}
+
+/// The link on the chapter page `doc` at `url` to the next chapter: the
+/// first that `next` selects, or else one marked `rel="next"` or reading
+/// "Next" or "Next chapter".
+fn next_link(doc: &Html, url: &Url, next: Option<&str>) -> Result<Option<Url>> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE
+        .get_or_init(|| Regex::new(r"(?i)^next(?: (?:chapter|part|page|episode|post))?$").unwrap());
+    let href = |el: ElementRef| -> Option<String> {
+        let link = match el.value().attr("href") {
+            Some(_) => el,
+            // a selector for the block the link is in will do
+            None => el.select(&selector("a[href]").ok()?).next()?,
+        };
+        link.value().attr("href").map(str::to_string)
+    };
+
+    let found = match next {
+        Some(next) => {
+            let next =
+                selector(next).wrap_err_with(|| format!("invalid --next-selector {:?}", next))?;
+            doc.select(&next).find_map(href)
+        }
+        None => {
+            let rel = selector(r#"link[rel~="next"], a[rel~="next"]"#)?;
+            let links = selector("a[href]")?;
+            doc.select(&rel).find_map(href).or_else(|| {
+                doc.select(&links)
+                    .find(|a| {
+                        let label: String = text(*a)
+                            .chars()
+                            .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+                            .collect();
+                        re.is_match(&label.split_whitespace().collect::<Vec<_>>().join(" "))
+                    })
+                    .and_then(href)
+            })
+        }
+    };
+    let Some(found) = found else {
+        return Ok(None);
+    };
+    let mut link = match url.join(found.trim()) {
+        Ok(link) => link,
+        Err(_) => return Ok(None),
+    };
+    link.set_fragment(None);
+    Ok(matches!(link.scheme(), "http" | "https").then_some(link))
+}
+
+/// The value of the `<meta>` of `doc` that `css` selects.
+fn meta(doc: &Html, css: &str) -> Option<String> {
+    doc.select(&selector(css).ok()?)
+        .find_map(|el| el.value().attr("content"))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+/// Reads an ISO 8601 time like `2021-04-05T18:30:00+02:00` into a unix
+/// timestamp.
+fn iso_time(time: &str) -> Option<u64> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r"^(\d{4})-(\d{2})-(\d{2})(?:[T ](\d{2}):(\d{2})(?::\d{2}(?:\.\d+)?)?(Z|[+-]\d{2}:?\d{2})?)?").unwrap()
+    });
+    let caps = re.captures(time.trim())?;
+    let number = |i: usize| {
+        caps.get(i)
+            .map_or(Some(0), |m| m.as_str().parse::<i64>().ok())
+    };
+    let utc = sites::unix_time(number(1)?, number(2)?, number(3)?, number(4)?, number(5)?)?;
+    let offset = match caps.get(6).map(|m| m.as_str().replace(':', "")) {
+        Some(offset) if offset != "Z" => {
+            let minutes =
+                offset[1..3].parse::<i64>().ok()? * 60 + offset[3..].parse::<i64>().ok()?;
+            if offset.starts_with('-') {
+                -minutes
+            } else {
+                minutes
+            }
+        }
+        _ => 0,
+    };
+    u64::try_from(utc as i64 - offset * 60).ok()
+}
+
+/// The name of the chapter on the page `doc`: the heading its text starts
+/// with, or else the page's title without the site's name.
+fn chapter_name(doc: &Html, content: Option<NodeId>, site: Option<&str>) -> Option<String> {
+    let leading = content
+        .and_then(|id| ElementRef::wrap(doc.tree.get(id)?))
+        .and_then(|content| {
+            content
+                .descendants()
+                .skip(1)
+                .filter_map(ElementRef::wrap)
+                .find(|el| !text(*el).is_empty())
+        })
+        .filter(|el| matches!(el.value().name(), "h1" | "h2" | "h3"))
+        .map(text);
+    let title = || {
+        let title = meta(doc, r#"meta[property="og:title"]"#)
+            .or_else(|| doc.select(&selector("title").ok()?).next().map(text))?;
+        let title = match site {
+            Some(site) => title.replace(site, ""),
+            None => title,
+        };
+        let title = title.trim_matches(|c: char| c.is_whitespace() || "-–—|:·»".contains(c));
+        Some(title.to_string())
+    };
+    leading.or_else(title).filter(|name| !name.is_empty())
+}
+
+/// What is read from each page of the crawl.
+struct Page {
+    chapter: Chapter,
+    next: Option<Url>,
+    title: Option<String>,
+    author: Option<String>,
+    language: Option<String>,
+}
+
+fn read_page(doc: &Html, url: &Url, next: Option<&str>, nr: usize) -> Result<Page> {
+    let content = find_content(doc);
+    let site = meta(doc, r#"meta[property="og:site_name"]"#);
+    Ok(Page {
+        chapter: Chapter {
+            name: chapter_name(doc, content, site.as_deref())
+                .unwrap_or_else(|| format!("Chapter {}", nr)),
+            link: url.to_string(),
+            volume: None,
+            published: meta(doc, r#"meta[property="article:published_time"]"#)
+                .as_deref()
+                .and_then(iso_time),
+            words: None,
+            order: None,
+        },
+        next: next_link(doc, url, next)?,
+        title: site.or_else(|| {
+            doc.select(&selector("title").ok()?)
+                .next()
+                .map(text)
+                .filter(|title| !title.is_empty())
+        }),
+        author: meta(doc, r#"meta[name="author"]"#)
+            .or_else(|| meta(doc, r#"meta[property="article:author"]"#))
+            .filter(|author| !author.starts_with("http")),
+        language: sites::page_language(doc),
+    })
+}
+
+/// Crawls the story whose first chapter is at `url`, on a site rustyroad
+/// has no backend for, by following each chapter's link to the next: the
+/// first link `next` selects, or else the page's `rel="next"` or "Next"
+/// link. Stops at a page without one, or with one back to a page already
+/// read or off the site.
+pub async fn crawl_story(
+    client: &Client,
+    url: &str,
+    next: Option<&str>,
+    fetch: &FetchOpts,
+) -> Result<Story> {
+    let input = url.trim();
+    let url = match input.contains("://") {
+        true => input.to_string(),
+        false => format!("https://{}", input),
+    };
+    let start = Url::parse(&url).wrap_err_with(|| format!("invalid url {:?}", input))?;
+    let host = start.host_str().unwrap_or_default().to_string();
+    log::warn!(
+        "downloading {} with --generic: the chapter text is guessed, check the book",
+        start
+    );
+
+    let mut story = Story {
+        url: start.to_string(),
+        title: String::new(),
+        author: String::new(),
+        description: String::new(),
+        cover: String::new(),
+        tags: Vec::new(),
+        chapters: Vec::new(),
+        words: None,
+        status: None,
+        pages: Vec::new(),
+        language: None,
+        warnings: Vec::new(),
+    };
+    let mut seen = HashSet::new();
+    let mut page_url = Some(start.clone());
+    while let Some(url) = page_url.take() {
+        seen.insert(url.clone());
+        if story.chapters.len() == MAX_CHAPTERS {
+            log::warn!("stopping after {} chapters, at {}", MAX_CHAPTERS, url);
+            break;
+        }
+        // the page isn't `Send`, so it is gone before the next await
+        let page = {
+            let doc = fetch_document(client, url.as_str(), fetch, None)
+                .await
+                .wrap_err_with(|| format!("could not fetch {}", url))?;
+            read_page(&doc, &url, next, story.chapters.len() + 1)?
+        };
+        if story.chapters.is_empty() {
+            story.title = page.title.unwrap_or_else(|| host.clone());
+            story.author = page.author.unwrap_or_else(|| host.clone());
+            story.language = page.language;
+        }
+        log::debug!(
+            "chapter {}: {:?} at {}",
+            story.chapters.len() + 1,
+            page.chapter.name,
+            url
+        );
+        story.chapters.push(page.chapter);
+
+        page_url = match page.next {
+            Some(next) if seen.contains(&next) => {
+                log::debug!("{} links back to {}, stopping", url, next);
+                None
+            }
+            Some(next) if next.host_str() != Some(&host) => {
+                log::info!(
+                    "the next link on {} leaves the site for {}, stopping",
+                    url,
+                    next
+                );
+                None
+            }
+            next => next,
+        };
+    }
+    if story.chapters.len() == 1 && next.is_none() {
+        log::warn!(
+            "found no link to a next chapter on {}, give --next-selector to say which it is",
+            start
+        );
+    }
+    log::info!("found {} chapters from {}", story.chapters.len(), start);
+
+    story.description = format!(
+        "Downloaded from {} on a best-effort basis: the chapters were found by \
+         following the links from one to the next and their text was guessed \
+         from the pages, so some may be missing or carry parts of the site.",
+        start
+    );
+    Ok(story)
+}
@@ -0,0 +1,896 @@
+//! The HTTP client shared by every fetch.
+//!
+//! Besides issuing requests, the client notices when the site starts
+//! refusing us (403/429 responses or Cloudflare challenges) and pauses all
+//! requests for a growing cooldown instead of hammering it into a real ban.
+//! With a delay configured, requests also draw from a token bucket shared by
+//! every concurrent fetch, so they stay under the site's rate limits in the
+//! first place.
+//! A cap on the requests out at once is shared the same way, e.g. between
+//! stories downloaded side by side, and a requests-per-minute limit for
+//! each host even with the runs before and after.
+//! In polite mode the client also reads each host's robots.txt before its
+//! first request there, and keeps to what it asks.
+//! Cookies are kept for the client's lifetime, so a login carries over to
+//! every later request. A Cloudflare challenge can't be waited out, so it
+//! fails right away, pointing at the cookies that get past it.
+//! Requests go out through a [`Transport`], the network unless another one
+//! is given, so the rest can run against recorded pages.
+
+use eyre::{eyre, Result, WrapErr};
+use futures::future::BoxFuture;
+use reqwest::cookie::Jar;
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, IF_MODIFIED_SINCE, IF_NONE_MATCH, RETRY_AFTER,
+};
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode, Url};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+
+use crate::rate_limit::{RateLimits, RATE_LIMIT_FILE};
+use crate::robots::Robots;
+use crate::sites::SITES;
+use crate::{Cache, HttpError};
+
+/// Cooldown after the first block. Doubles with every consecutive block.
+const BASE_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// How often the remaining cooldown is logged.
+const COUNTDOWN_STEP: Duration = Duration::from_secs(10);
+
+/// In polite mode, the time between two requests to a host whose
+/// robots.txt asks for no `Crawl-delay`.
+pub const POLITE_DELAY: Duration = Duration::from_secs(1);
+
+/// In polite mode, how many requests may be out at once.
+pub const POLITE_IN_FLIGHT: usize = 2;
+
+#[derive(Clone, Debug)]
+pub struct ClientOpts {
+    /// The longest single pause after being blocked. If the site is still
+    /// blocking us after a pause this long, requests fail. Default: 10 minutes
+    pub max_cooldown: Duration,
+
+    /// How often a request that failed with a network error or a server
+    /// error (5xx) is retried before giving up. Default: 3
+    pub max_retries: u32,
+
+    /// The pause before the first retry. Doubles with every further retry,
+    /// plus up to as much again of random jitter. Default: 1 second
+    pub retry_delay: Duration,
+
+    /// The longest a whole request may take, body included. Default: 60
+    /// seconds
+    pub timeout: Duration,
+
+    /// The longest connecting may take. Default: 10 seconds
+    pub connect_timeout: Duration,
+
+    /// Sent with every request. Default: `rustyroad/<version>`
+    pub user_agent: String,
+
+    /// Cookies to start with, in the Netscape `cookies.txt` format that
+    /// browser extensions export, e.g. a logged in session. Default: None
+    pub cookies_file: Option<PathBuf>,
+
+    /// Cookies of the supported sites to take from a browser's cookie
+    /// database: Firefox's `cookies.sqlite` or Chrome's `Cookies`.
+    /// Default: None
+    pub browser_cookies: Option<PathBuf>,
+
+    /// A `cf_clearance` cookie to send to the supported sites, copied from
+    /// a browser that passed the Cloudflare challenge. Default: None
+    pub cf_clearance: Option<String>,
+
+    /// Send every request through this proxy: an `http://`, `https://`,
+    /// `socks5://` or `socks5h://` URL, with `user:password@` if it needs
+    /// a login. Default: None, but reqwest honors `HTTPS_PROXY` and friends
+    pub proxy: Option<String>,
+
+    /// The average time between two requests, however many are made at
+    /// once. Zero doesn't limit requests. Default: zero
+    pub delay: Duration,
+
+    /// How many requests may be made back to back after a quiet spell
+    /// before `delay` applies. Default: 1
+    pub burst: u32,
+
+    /// How many requests may wait for a response at once, across every
+    /// fetch sharing the client. Zero doesn't limit them. Default: zero
+    pub max_in_flight: usize,
+
+    /// How many requests a minute may go to any one host, counting those
+    /// of every run of rustyroad. Zero doesn't limit them. Default: zero
+    pub rate_limit: u32,
+
+    /// Limits in place of `rate_limit` for these hosts and their
+    /// subdomains. Default: none
+    pub host_rate_limits: BTreeMap<String, u32>,
+
+    /// The file the requests are kept track of in for the limits. Default:
+    /// `rate-limit.json` in [`Cache::default_dir`](crate::Cache::default_dir)
+    pub rate_limit_file: Option<PathBuf>,
+
+    /// Keep to each host's robots.txt: fail on the paths it disallows, and
+    /// wait its `Crawl-delay`, or [`POLITE_DELAY`], between requests to the
+    /// host. At most [`POLITE_IN_FLIGHT`] requests are out at once, and the
+    /// default `user_agent` becomes [`POLITE_USER_AGENT`]. Default: false
+    pub polite: bool,
+
+    /// How many body bytes the client may read before
+    /// [`Client::over_limit`] says so. Requests aren't refused past it; the
+    /// caller decides when to stop. Default: None
+    pub download_limit: Option<u64>,
+}
+
+/// The user agent when none is configured.
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "rustyroad/",
+    env!("CARGO_PKG_VERSION"),
+    " (+https://github.com/wtodd1/rustyroad)"
+);
+
+/// The user agent in polite mode when none is configured, saying what the
+/// requests are for and where to read about them.
+pub const POLITE_USER_AGENT: &str = concat!(
+    "rustyroad/",
+    env!("CARGO_PKG_VERSION"),
+    " (archiving stories for offline reading; honors robots.txt; +https://github.com/wtodd1/rustyroad)"
+);
+
+impl Default for ClientOpts {
+    fn default() -> ClientOpts {
+        ClientOpts {
+            max_cooldown: Duration::from_secs(600),
+            max_retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cookies_file: None,
+            browser_cookies: None,
+            cf_clearance: None,
+            proxy: None,
+            delay: Duration::ZERO,
+            burst: 1,
+            max_in_flight: 0,
+            rate_limit: 0,
+            host_rate_limits: BTreeMap::new(),
+            rate_limit_file: None,
+            polite: false,
+            download_limit: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BlockState {
+    /// Number of blocked responses since the last successful one.
+    consecutive: u32,
+    /// The length of the last pause.
+    last_cooldown: Duration,
+    paused_until: Option<Instant>,
+}
+
+/// The token bucket requests are paced by: a token is added every `delay`,
+/// up to `burst` of them, and every request takes one.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What polite mode keeps for a host.
+struct PoliteHost {
+    robots: Robots,
+    /// When the next request to the host may go out.
+    next: Instant,
+}
+
+/// Counters for progress reporting.
+#[derive(Default)]
+struct Stats {
+    /// Requests waiting to be retried.
+    retrying: AtomicUsize,
+    /// Retries of failed requests so far.
+    retries: AtomicUsize,
+    /// Response body bytes read through [`Client::bytes`] and
+    /// [`Client::text`].
+    received: AtomicU64,
+    /// `download_limit`, as raised with [`Client::raise_limit`].
+    limit: AtomicU64,
+    /// Chapters that still looked like a wall when fetched again.
+    suspects: AtomicUsize,
+}
+
+/// Sends requests for a [`Client`], which keeps its retries, cooldowns and
+/// limits on top. `reqwest::Client` sends them over the network; tests give
+/// [`Client::with_transport`] one answering from fixtures instead. Responses
+/// made with `reqwest::Response::from` carry no URL, which only shows in
+/// error messages.
+pub trait Transport: Send + Sync {
+    fn execute(&self, request: Request) -> BoxFuture<'static, reqwest::Result<Response>>;
+}
+
+impl Transport for reqwest::Client {
+    fn execute(&self, request: Request) -> BoxFuture<'static, reqwest::Result<Response>> {
+        Box::pin(reqwest::Client::execute(self, request))
+    }
+}
+
+#[derive(Clone)]
+pub struct Client {
+    /// Builds the requests, with the cookies, user agent and timeouts.
+    inner: reqwest::Client,
+    transport: Arc<dyn Transport>,
+    opts: ClientOpts,
+    state: Arc<Mutex<BlockState>>,
+    bucket: Arc<Mutex<Bucket>>,
+    /// The requests allowed out at once, with `max_in_flight`.
+    in_flight: Option<Arc<Semaphore>>,
+    rate_limits: Option<RateLimits>,
+    /// In polite mode, the hosts asked so far, by origin.
+    hosts: Arc<Mutex<HashMap<String, PoliteHost>>>,
+    stats: Arc<Stats>,
+}
+
+/// The domains of the supported sites, the only ones browser cookies and
+/// `cf_clearance` are taken for.
+fn site_domains() -> impl Iterator<Item = &'static str> {
+    SITES.iter().flat_map(|site| site.domains()).copied()
+}
+
+/// Returns true if a response is Cloudflare's challenge, which only a
+/// browser can pass.
+fn is_challenge(headers: &HeaderMap) -> bool {
+    headers
+        .get("cf-mitigated")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"challenge"))
+}
+
+/// Returns true if `html` is Cloudflare's "Just a moment..." interstitial
+/// instead of the page asked for.
+pub fn is_challenge_page(html: &str) -> bool {
+    let head = &html[..html.len().min(4096)];
+    (head.contains("<title>Just a moment...</title>") || head.contains("cf_chl_opt"))
+        && html.contains("challenge-platform")
+}
+
+pub(crate) fn challenge_error(url: &Url) -> HttpError {
+    HttpError::Challenge {
+        host: url.host_str().unwrap_or(url.as_str()).to_string(),
+    }
+}
+
+/// Returns true if a response looks like the site refusing to serve us,
+/// rather than an ordinary error.
+fn is_blocked(status: StatusCode, headers: &HeaderMap) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::FORBIDDEN => true,
+        StatusCode::SERVICE_UNAVAILABLE => headers.contains_key("cf-mitigated"),
+        _ => false,
+    }
+}
+
+/// Errors worth retrying: the request never got a response.
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request()
+}
+
+/// A random duration up to `max`, so clients retrying at the same time
+/// spread out.
+fn jitter(max: Duration) -> Duration {
+    max.mul_f64(fastrand::f64())
+}
+
+/// Adds the cookies in a Netscape `cookies.txt` file to `jar`.
+fn load_cookies(jar: &Jar, path: &Path) -> Result<()> {
+    let file = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("could not read {}", path.display()))?;
+
+    let mut loaded = 0;
+    for line in file.lines() {
+        // curl marks HttpOnly cookies with a prefix on an otherwise
+        // commented out line
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, _, path, secure, _, name, value] = fields[..] else {
+            return Err(eyre!("{} is not a cookies.txt file", path.display()));
+        };
+        let scheme = if secure.eq_ignore_ascii_case("true") {
+            "https"
+        } else {
+            "http"
+        };
+        let url = Url::parse(&format!(
+            "{}://{}{}",
+            scheme,
+            domain.trim_start_matches('.'),
+            path
+        ))?;
+        jar.add_cookie_str(
+            &format!("{}={}; Domain={}; Path={}", name, value, domain, path),
+            &url,
+        );
+        loaded += 1;
+    }
+
+    log::debug!("loaded {} cookie(s) from {}", loaded, path.display());
+    Ok(())
+}
+
+/// The proxy at `url`. SOCKS5 proxies resolve host names themselves with
+/// `socks5h://`.
+fn parse_proxy(url: &str) -> Result<reqwest::Proxy> {
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+    if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+        return Err(eyre!(
+            "invalid proxy {}, use an http://, https://, socks5:// or socks5h:// URL",
+            url
+        ));
+    }
+    reqwest::Proxy::all(url).wrap_err_with(|| format!("invalid proxy {}", url))
+}
+
+/// Adds the cookies of the supported sites from a Firefox or Chrome cookie
+/// database to `jar`. The database is copied first, since a running
+/// browser keeps it locked. Chrome encrypts most cookie values, which are
+/// skipped with a warning.
+fn load_browser_cookies(jar: &Jar, path: &Path) -> Result<()> {
+    let copy = tempfile::NamedTempFile::new()?;
+    std::fs::copy(path, copy.path())
+        .wrap_err_with(|| format!("could not read {}", path.display()))?;
+    let db = rusqlite::Connection::open_with_flags(
+        copy.path(),
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+
+    let tables: Vec<String> = db
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let query = if tables.iter().any(|table| table == "moz_cookies") {
+        "SELECT host, path, isSecure, name, value, 0 FROM moz_cookies"
+    } else if tables.iter().any(|table| table == "cookies") {
+        "SELECT host_key, path, is_secure, name, value, length(encrypted_value) FROM cookies"
+    } else {
+        return Err(eyre!(
+            "{} is not a Firefox or Chrome cookie database",
+            path.display()
+        ));
+    };
+
+    let (mut loaded, mut encrypted) = (0, 0);
+    let mut stmt = db.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let host: String = row.get(0)?;
+        let domain = host.trim_start_matches('.');
+        if !site_domains().any(|site| domain == site || domain.ends_with(&format!(".{}", site))) {
+            continue;
+        }
+        let (path, secure, name, value): (String, bool, String, String) =
+            (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+        if value.is_empty() && row.get::<_, i64>(5)? > 0 {
+            encrypted += 1;
+            continue;
+        }
+
+        let scheme = if secure { "https" } else { "http" };
+        let url = Url::parse(&format!("{}://{}{}", scheme, domain, path))?;
+        jar.add_cookie_str(
+            &format!("{}={}; Domain={}; Path={}", name, value, host, path),
+            &url,
+        );
+        loaded += 1;
+    }
+
+    if encrypted > 0 {
+        log::warn!(
+            "skipped {} encrypted cookie(s) in {}; export them with a cookies.txt \
+             extension and use --cookies-file instead",
+            encrypted,
+            path.display()
+        );
+    }
+    log::debug!("loaded {} cookie(s) from {}", loaded, path.display());
+    Ok(())
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+impl Client {
+    /// Creates the client. Connections are kept alive and reused for every
+    /// request, and HTTP/2 is used where the server supports it, so a long
+    /// story doesn't pay for a new TLS handshake per chapter.
+    pub fn new(opts: ClientOpts) -> Result<Client> {
+        let jar = Arc::new(Jar::default());
+        if let Some(path) = &opts.cookies_file {
+            load_cookies(&jar, path)?;
+        }
+        if let Some(path) = &opts.browser_cookies {
+            load_browser_cookies(&jar, path)?;
+        }
+        if let Some(clearance) = &opts.cf_clearance {
+            for domain in site_domains() {
+                let url = Url::parse(&format!("https://{}/", domain))?;
+                jar.add_cookie_str(
+                    &format!("cf_clearance={}; Domain=.{}; Path=/", clearance, domain),
+                    &url,
+                );
+            }
+        }
+
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &opts.proxy {
+            builder = builder.proxy(parse_proxy(proxy)?);
+        }
+        let inner = builder
+            .cookie_provider(jar)
+            .user_agent(match opts.polite && opts.user_agent == DEFAULT_USER_AGENT {
+                true => POLITE_USER_AGENT,
+                false => opts.user_agent.as_str(),
+            })
+            .timeout(opts.timeout)
+            .connect_timeout(opts.connect_timeout)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()?;
+
+        let bucket = Bucket {
+            tokens: opts.burst.max(1) as f64,
+            updated: Instant::now(),
+        };
+        let max_in_flight = match (opts.polite, opts.max_in_flight) {
+            (true, 0) => POLITE_IN_FLIGHT,
+            (true, max) => max.min(POLITE_IN_FLIGHT),
+            (false, max) => max,
+        };
+        let in_flight = (max_in_flight > 0).then(|| Arc::new(Semaphore::new(max_in_flight)));
+        let rate_limit_file = opts
+            .rate_limit_file
+            .clone()
+            .or_else(|| Cache::default_dir().map(|dir| dir.join(RATE_LIMIT_FILE)))
+            .unwrap_or_else(|| PathBuf::from(RATE_LIMIT_FILE));
+        let rate_limits = RateLimits::new(
+            rate_limit_file,
+            opts.rate_limit,
+            opts.host_rate_limits.clone(),
+        )?;
+        let download_limit = opts.download_limit.unwrap_or(u64::MAX);
+        Ok(Client {
+            transport: Arc::new(inner.clone()),
+            inner,
+            opts,
+            state: Default::default(),
+            bucket: Arc::new(Mutex::new(bucket)),
+            in_flight,
+            rate_limits,
+            hosts: Default::default(),
+            stats: Arc::new(Stats {
+                limit: AtomicU64::new(download_limit),
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Creates the client with requests sent through `transport` rather
+    /// than the network.
+    pub fn with_transport(opts: ClientOpts, transport: impl Transport + 'static) -> Result<Client> {
+        Ok(Client {
+            transport: Arc::new(transport),
+            ..Client::new(opts)?
+        })
+    }
+
+    async fn execute(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        self.transport.execute(request.build()?).await
+    }
+
+    /// Reads the body of `resp`, counting it towards [`Client::received`].
+    pub async fn bytes(&self, resp: Response) -> Result<Vec<u8>> {
+        let data = resp.bytes().await?.to_vec();
+        self.count_received(data.len());
+        Ok(data)
+    }
+
+    /// Reads the body of `resp` as text, counting it towards
+    /// [`Client::received`].
+    pub async fn text(&self, resp: Response) -> Result<String> {
+        let url = resp.url().clone();
+        let text = resp.text().await?;
+        self.count_received(text.len());
+        if is_challenge_page(&text) {
+            return Err(challenge_error(&url).into());
+        }
+        Ok(text)
+    }
+
+    pub(crate) fn count_received(&self, bytes: usize) {
+        self.stats
+            .received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The number of body bytes read so far.
+    pub fn received(&self) -> u64 {
+        self.stats.received.load(Ordering::Relaxed)
+    }
+
+    /// Whether more than `download_limit` bytes were read so far.
+    pub fn over_limit(&self) -> bool {
+        self.received() > self.stats.limit.load(Ordering::Relaxed)
+    }
+
+    /// Lets the client read `bytes` more before it is over its limit again.
+    pub fn raise_limit(&self, bytes: u64) {
+        let limit = self.received().saturating_add(bytes);
+        self.stats.limit.fetch_max(limit, Ordering::Relaxed);
+    }
+
+    /// The number of requests currently waiting to be retried.
+    pub fn retrying(&self) -> usize {
+        self.stats.retrying.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a failed request was retried so far.
+    pub fn retries(&self) -> usize {
+        self.stats.retries.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_suspect(&self) {
+        self.stats.suspects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of chapters so far that looked like a login or JavaScript
+    /// wall rather than the chapter, even fetched again.
+    pub fn suspects(&self) -> usize {
+        self.stats.suspects.load(Ordering::Relaxed)
+    }
+
+    /// Sends a GET request, waiting out any cooldown first. Responses with
+    /// an error status are turned into errors naming the status and URL.
+    pub async fn get(&self, url: Url) -> Result<Response> {
+        self.send(Method::GET, url, HeaderMap::new(), None).await
+    }
+
+    /// Sends a GET request with the validators of a cached copy. Returns
+    /// `None` if the server answers that the cached copy is still current.
+    pub async fn get_if_modified(
+        &self,
+        url: Url,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<Option<Response>> {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag.and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = last_modified.and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(IF_MODIFIED_SINCE, date);
+        }
+
+        match self.send(Method::GET, url, headers, None).await {
+            Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED => Ok(None),
+            Ok(resp) => Ok(Some(resp)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Submits a form. Unlike GET requests this is never retried, since the
+    /// server may have acted on it already.
+    pub async fn post_form(&self, url: Url, form: &[(&str, &str)]) -> Result<Response> {
+        self.wait_for_cooldown().await;
+        self.wait_for_token().await;
+
+        let resp = {
+            let _permit = self.take_turn().await;
+            self.execute(self.inner.post(url.clone()).form(form))
+                .await?
+        };
+        if is_challenge(resp.headers()) {
+            return Err(challenge_error(&url).into());
+        }
+        if !resp.status().is_success() {
+            return Err(HttpError::Status {
+                status: resp.status().as_u16(),
+                url: url.to_string(),
+            }
+            .into());
+        }
+        Ok(resp)
+    }
+
+    /// Posts `json` to an API that answers the same every time, so unlike
+    /// [`post_form`](Self::post_form) it is retried like a GET request.
+    pub async fn post_json(&self, url: Url, json: &serde_json::Value) -> Result<Response> {
+        self.send(Method::POST, url, HeaderMap::new(), Some(json))
+            .await
+    }
+
+    /// Sends a HEAD request and returns the `Content-Length`, if the server
+    /// reports one.
+    pub async fn content_length(&self, url: Url) -> Result<Option<u64>> {
+        let resp = self.send(Method::HEAD, url, HeaderMap::new(), None).await?;
+        Ok(resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()))
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        json: Option<&serde_json::Value>,
+    ) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            if self.opts.polite {
+                self.wait_for_robots(&url).await?;
+            }
+            self.wait_for_cooldown().await;
+            self.wait_for_token().await;
+            if let (Some(limits), Some(host)) = (&self.rate_limits, url.host_str()) {
+                limits.take(host).await?;
+            }
+
+            let sent = {
+                let _permit = self.take_turn().await;
+                let mut request = self
+                    .inner
+                    .request(method.clone(), url.clone())
+                    .headers(headers.clone());
+                if let Some(json) = json {
+                    request = request.json(json);
+                }
+                self.execute(request).await
+            };
+            let resp = match sent {
+                Ok(resp) => resp,
+                Err(err) if is_transient(&err) && retries < self.opts.max_retries => {
+                    retries += 1;
+                    self.retry_pause(&url, &err.to_string(), retries, None)
+                        .await;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if is_challenge(resp.headers()) {
+                return Err(challenge_error(&url).into());
+            }
+            if !is_blocked(resp.status(), resp.headers()) {
+                self.state.lock().await.consecutive = 0;
+
+                let status = resp.status();
+                if status.is_server_error() && retries < self.opts.max_retries {
+                    retries += 1;
+                    let reason = format!("HTTP {}", status.as_u16());
+                    self.retry_pause(&url, &reason, retries, retry_after(resp.headers()))
+                        .await;
+                    continue;
+                }
+                if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+                    return Err(HttpError::Status {
+                        status: status.as_u16(),
+                        url: url.to_string(),
+                    }
+                    .into());
+                }
+                return Ok(resp);
+            }
+
+            self.register_block(&url, resp.status(), retry_after(resp.headers()))
+                .await?;
+        }
+    }
+
+    /// Waits before retry number `retry` of a failed request: the
+    /// server's `Retry-After` if it sent one, exponential backoff otherwise.
+    async fn retry_pause(
+        &self,
+        url: &Url,
+        reason: &str,
+        retry: u32,
+        retry_after: Option<Duration>,
+    ) {
+        let backoff = self.opts.retry_delay * 2u32.saturating_pow(retry - 1);
+        let pause = retry_after.unwrap_or_else(|| backoff + jitter(backoff));
+
+        log::warn!(
+            "request for {} failed ({}), retrying in {:.1}s ({}/{})",
+            url,
+            reason,
+            pause.as_secs_f64(),
+            retry,
+            self.opts.max_retries
+        );
+        self.stats.retries.fetch_add(1, Ordering::Relaxed);
+        self.stats.retrying.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(pause).await;
+        self.stats.retrying.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Fails if the robots.txt of `url`'s host disallows it, and waits out
+    /// the host's crawl delay otherwise. The robots.txt is fetched with the
+    /// first request to the host.
+    async fn wait_for_robots(&self, url: &Url) -> Result<()> {
+        let origin = url.origin().ascii_serialization();
+        let wait = {
+            // held while fetching robots.txt, so it is only fetched once
+            let mut hosts = self.hosts.lock().await;
+            if !hosts.contains_key(&origin) {
+                let robots = self.fetch_robots(url).await;
+                let next = Instant::now();
+                hosts.insert(origin.clone(), PoliteHost { robots, next });
+            }
+            let host = hosts.get_mut(&origin).unwrap();
+
+            let path = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            if !host.robots.allows(&path) {
+                return Err(HttpError::Disallowed {
+                    url: url.to_string(),
+                }
+                .into());
+            }
+            let now = Instant::now();
+            let start = host.next.max(now);
+            host.next = start + host.robots.crawl_delay.unwrap_or(POLITE_DELAY);
+            start - now
+        };
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+
+    /// The robots.txt of `url`'s host. A host without one, or one that
+    /// can't be read, asks nothing of us besides [`POLITE_DELAY`].
+    async fn fetch_robots(&self, url: &Url) -> Robots {
+        let mut robots_url = url.clone();
+        robots_url.set_path("/robots.txt");
+        robots_url.set_query(None);
+        robots_url.set_fragment(None);
+
+        let text = match self.execute(self.inner.get(robots_url.clone())).await {
+            Ok(resp) if resp.status().is_success() => resp.text().await.map_err(|e| e.to_string()),
+            Ok(resp) => Err(format!("HTTP {}", resp.status().as_u16())),
+            Err(err) => Err(err.to_string()),
+        };
+        let robots = match text {
+            Ok(text) => {
+                self.count_received(text.len());
+                Robots::parse(&text)
+            }
+            Err(err) => {
+                log::debug!("no robots.txt at {} ({})", robots_url, err);
+                Robots::default()
+            }
+        };
+        if let Some(delay) = robots.crawl_delay {
+            log::info!(
+                "{} asks for {:.1}s between requests",
+                url.host_str().unwrap_or_default(),
+                delay.as_secs_f64()
+            );
+        }
+        robots
+    }
+
+    async fn wait_for_cooldown(&self) {
+        let until = self.state.lock().await.paused_until;
+        if let Some(until) = until {
+            tokio::time::sleep_until(until.into()).await;
+        }
+    }
+
+    /// Waits until fewer than `max_in_flight` requests are out. The request
+    /// may go out while the permit is held.
+    async fn take_turn(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.in_flight {
+            Some(in_flight) => in_flight.acquire().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Takes a token from the bucket, waiting until there is one.
+    async fn wait_for_token(&self) {
+        if self.opts.delay.is_zero() {
+            return;
+        }
+        let burst = self.opts.burst.max(1) as f64;
+        let delay = self.opts.delay.as_secs_f64();
+
+        // the lock is held while waiting, so requests get their turn in the
+        // order they asked for it
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() / delay;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) * delay);
+            tokio::time::sleep(wait).await;
+            bucket.tokens = 1.0;
+            bucket.updated = Instant::now();
+        }
+        bucket.tokens -= 1.0;
+    }
+
+    async fn register_block(
+        &self,
+        url: &Url,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().await;
+
+        // another request already started a pause that covers this one
+        if state
+            .paused_until
+            .is_some_and(|until| until > Instant::now())
+        {
+            return Ok(());
+        }
+
+        if state.last_cooldown >= self.opts.max_cooldown && state.consecutive > 0 {
+            return Err(HttpError::Blocked {
+                status: status.as_u16(),
+                cooldown: state.last_cooldown.as_secs(),
+            }
+            .into());
+        }
+
+        state.consecutive += 1;
+        let backoff = BASE_COOLDOWN * 2u32.saturating_pow(state.consecutive - 1);
+        let cooldown = retry_after
+            .unwrap_or_default()
+            .max(backoff)
+            .min(self.opts.max_cooldown);
+
+        state.last_cooldown = cooldown;
+        state.paused_until = Some(Instant::now() + cooldown);
+        drop(state);
+
+        log::warn!(
+            "the server is refusing requests (HTTP {} for {}), pausing all requests for {}s",
+            status.as_u16(),
+            url,
+            cooldown.as_secs()
+        );
+        countdown(cooldown);
+
+        Ok(())
+    }
+}
+
+/// Logs the remaining cooldown every few seconds until it is over.
+fn countdown(cooldown: Duration) {
+    tokio::spawn(async move {
+        let end = Instant::now() + cooldown;
+        loop {
+            let remaining = end.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                log::info!("cooldown over, resuming requests");
+                return;
+            }
+            log::info!("resuming in {}s...", remaining.as_secs());
+            tokio::time::sleep(remaining.min(COUNTDOWN_STEP)).await;
+        }
+    });
+}
@@ -0,0 +1,338 @@
+//! Embedding the images a chapter links to, so the book reads offline.
+
+use eyre::{eyre, Result, WrapErr};
+use futures::{stream, StreamExt};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+use scraper::{Html, Node};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::clean::{find, new_element};
+use crate::{cache, serialize_element, Client, Cover};
+
+/// Where embedded images are stored inside the book.
+pub const IMAGE_DIR: &str = "images";
+
+/// How many of a chapter's images are downloaded at once.
+const IMAGES_AT_ONCE: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct Image {
+    /// The path inside the book, e.g. `images/0123456789abcdef.png`, which
+    /// is also the `src` the chapter refers to it by.
+    pub file: String,
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// An id for the image that is a valid XML name, for formats that
+    /// refer to images by id rather than path.
+    pub fn id(&self) -> String {
+        image_id(&self.file)
+    }
+}
+
+pub(crate) fn image_id(file: &str) -> String {
+    file.replace('/', "-")
+}
+
+fn ext_for_mime(mime: &str) -> Option<&'static str> {
+    match mime.split(';').next()?.trim() {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/svg+xml" => Some("svg"),
+        _ => None,
+    }
+}
+
+pub(crate) fn mime_for_ext(ext: &str) -> Option<&'static str> {
+    match ext.to_lowercase().as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "svg" => Some("image/svg+xml"),
+        _ => None,
+    }
+}
+
+/// The quality WebP and AVIF images are re-encoded as JPEG at.
+const JPEG_QUALITY: u8 = 90;
+
+pub(crate) fn is_webp(data: &[u8]) -> bool {
+    data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP")
+}
+
+pub(crate) fn is_avif(data: &[u8]) -> bool {
+    data.get(4..12) == Some(b"ftypavif") || data.get(4..12) == Some(b"ftypavis")
+}
+
+/// Re-encodes a WebP image, which many e-readers can't show, as a PNG if it
+/// has transparency or else a JPEG. Returns the new image and its extension.
+pub(crate) fn convert_webp(data: &[u8]) -> Result<(Vec<u8>, &'static str)> {
+    let image = image::load_from_memory_with_format(data, ImageFormat::WebP)
+        .wrap_err("could not decode the WebP image")?;
+    reencode(image)
+}
+
+/// Re-encodes an AVIF image like [`convert_webp`]. Decoding AVIF takes the
+/// `avif` feature.
+pub(crate) fn convert_avif(data: &[u8]) -> Result<(Vec<u8>, &'static str)> {
+    if !cfg!(feature = "avif") {
+        return Err(eyre!(
+            "AVIF images need rustyroad built with the `avif` feature"
+        ));
+    }
+    let image = image::load_from_memory_with_format(data, ImageFormat::Avif)
+        .wrap_err("could not decode the AVIF image")?;
+    reencode(image)
+}
+
+fn reencode(image: image::DynamicImage) -> Result<(Vec<u8>, &'static str)> {
+    let mut out = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut out, ImageFormat::Png)?;
+        return Ok((out.into_inner(), "png"));
+    }
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))?;
+    Ok((out.into_inner(), "jpg"))
+}
+
+/// How images are scaled down and recompressed before they go into the
+/// book.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageOpts {
+    /// Scale images wider than this down to this width.
+    pub max_width: Option<u32>,
+    /// Recompress JPEG images at this quality, from 1 to 100.
+    pub quality: Option<u8>,
+}
+
+impl ImageOpts {
+    /// `data`, an image of type `mime`, scaled down and recompressed, or
+    /// `None` if that doesn't make it any smaller. Only JPEG and PNG images
+    /// are touched, and PNG images only when they are scaled down.
+    pub fn shrink(&self, data: &[u8], mime: &str) -> Result<Option<Vec<u8>>> {
+        let format = match mime {
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/png" => ImageFormat::Png,
+            _ => return Ok(None),
+        };
+        if self.max_width.is_none() && (self.quality.is_none() || format == ImageFormat::Png) {
+            return Ok(None);
+        }
+
+        let mut image = image::load_from_memory_with_format(data, format)?;
+        let scale = self.max_width.filter(|width| image.width() > *width);
+        if let Some(width) = scale {
+            image = image.resize(width, u32::MAX, FilterType::Lanczos3);
+        } else if format == ImageFormat::Png {
+            return Ok(None);
+        }
+
+        let mut out = Cursor::new(Vec::new());
+        if format == ImageFormat::Png {
+            image.write_to(&mut out, ImageFormat::Png)?;
+        } else {
+            let quality = self.quality.unwrap_or(JPEG_QUALITY);
+            image
+                .to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality))?;
+        }
+        let out = out.into_inner();
+        // recompressing an image that was already small can grow it
+        Ok((scale.is_some() || out.len() < data.len()).then_some(out))
+    }
+
+    /// `image` shrunk with [`ImageOpts::shrink`], or as it is if it can't be.
+    pub fn shrink_image(&self, mut image: Image) -> Image {
+        match self.shrink(&image.data, image.mime) {
+            Ok(Some(data)) => image.data = data,
+            Ok(None) => {}
+            Err(err) => log::warn!("could not shrink the image {}: {:#}", image.file, err),
+        }
+        image
+    }
+
+    /// `cover` shrunk with [`ImageOpts::shrink`], or as it is if it can't be.
+    pub fn shrink_cover(&self, mut cover: Cover) -> Cover {
+        let Ok(mime) = cover.mime() else {
+            return cover;
+        };
+        match self.shrink(&cover.data, mime) {
+            Ok(Some(data)) => cover.data = data,
+            Ok(None) => {}
+            Err(err) => log::warn!("could not shrink the cover: {:#}", err),
+        }
+        cover
+    }
+}
+
+/// The paths of the embedded images `content` refers to.
+pub fn local_images(content: &str) -> Vec<String> {
+    let fragment = Html::parse_fragment(content);
+    let mut files: Vec<String> = fragment
+        .tree
+        .root()
+        .descendants()
+        .filter_map(|node| node.value().as_element())
+        .filter(|el| el.name() == "img")
+        .filter_map(|el| el.attr("src"))
+        .filter(|src| src.starts_with(&format!("{}/", IMAGE_DIR)))
+        .map(str::to_string)
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+async fn fetch_image(client: &Client, url: Url) -> Result<Image> {
+    let resp = client.get(url.clone()).await?;
+
+    // prefer what the server says over the extension, which image hosts
+    // often leave out or get wrong
+    let ext = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ext_for_mime)
+        .or_else(|| {
+            let (_, ext) = url.path().rsplit_once('.')?;
+            ext_for_mime(mime_for_ext(ext)?)
+        })
+        .ok_or_else(|| eyre!("unsupported image format"))?;
+    let mut data = client.bytes(resp).await?;
+
+    // go by the image itself for WebP and AVIF, which are often served
+    // under another type
+    let ext = if is_webp(&data) {
+        let (converted, ext) = convert_webp(&data)?;
+        data = converted;
+        ext
+    } else if is_avif(&data) {
+        let (converted, ext) = convert_avif(&data)?;
+        data = converted;
+        ext
+    } else {
+        ext
+    };
+
+    // named after what it shows rather than where it came from, so an
+    // image every chapter repeats, like a divider or a stat block, is
+    // stored in the book once however many URLs it is served at
+    Ok(Image {
+        file: format!("{}/{}.{}", IMAGE_DIR, cache::hash(&data), ext),
+        mime: mime_for_ext(ext).unwrap(),
+        data,
+    })
+}
+
+/// The remote image urls in `content`, resolved against `page`.
+fn remote_images(page: &Url, content: &str) -> Vec<Url> {
+    let fragment = Html::parse_fragment(content);
+    let mut urls: Vec<Url> = fragment
+        .tree
+        .root()
+        .descendants()
+        .filter_map(|node| node.value().as_element())
+        .filter(|el| el.name() == "img")
+        .filter_map(|el| page.join(el.attr("src")?).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .collect();
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+/// Points the `src` of every image in `images` at the embedded copy.
+fn rewrite_images(page: &Url, content: &str, images: &HashMap<Url, Image>) -> Option<String> {
+    let mut fragment = Html::parse_fragment(content);
+    let root = fragment.root_element().id();
+
+    for id in find(&fragment, root, |el| el.value().name() == "img") {
+        let Node::Element(el) = fragment.tree.get(id).unwrap().value() else {
+            continue;
+        };
+        let Some(image) = el
+            .attr("src")
+            .and_then(|src| page.join(src).ok())
+            .and_then(|url| images.get(&url))
+        else {
+            continue;
+        };
+
+        let attrs: Vec<(String, String)> = el
+            .attrs()
+            .map(|(name, value)| {
+                let value = if name == "src" { &image.file } else { value };
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        let attrs: Vec<(&str, &str)> = attrs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        *fragment.tree.get_mut(id).unwrap().value() = new_element("img", &attrs);
+    }
+
+    // the content is a single element, which parsing wrapped in <html>
+    let content = fragment
+        .tree
+        .get(root)
+        .unwrap()
+        .children()
+        .find(|node| node.value().is_element())?
+        .id();
+    serialize_element(&fragment, content).ok()
+}
+
+/// Downloads the images in `content`, a chapter from `page`, and points
+/// their `src` at the copies returned alongside the new content. Images
+/// that can't be downloaded are left pointing at the original.
+pub async fn embed_images(client: &Client, page: &Url, content: &str) -> (String, Vec<Image>) {
+    if !content.contains("<img") {
+        return (content.to_string(), Vec::new());
+    }
+
+    // the client's rate limit and retries apply to every image as to any
+    // other request
+    let fetched: Vec<(Url, Result<Image>)> = stream::iter(remote_images(page, content))
+        .map(|url| async move {
+            let image = fetch_image(client, url.clone()).await;
+            (url, image)
+        })
+        .buffer_unordered(IMAGES_AT_ONCE)
+        .collect()
+        .await;
+    let mut images = HashMap::new();
+    for (url, image) in fetched {
+        match image {
+            Ok(image) => {
+                images.insert(url, image);
+            }
+            Err(err) => log::warn!("could not embed the image {}: {:#}", url, err),
+        }
+    }
+    if images.is_empty() {
+        return (content.to_string(), Vec::new());
+    }
+
+    match rewrite_images(page, content, &images) {
+        Some(content) => {
+            let mut images: Vec<Image> = images.into_values().collect();
+            images.sort_by(|a, b| a.file.cmp(&b.file));
+            images.dedup_by(|a, b| a.file == b.file);
+            (content, images)
+        }
+        None => (content.to_string(), Vec::new()),
+    }
+}
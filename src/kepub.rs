@@ -0,0 +1,222 @@
+//! Kobo's KEPUB flavor of EPUB.
+//!
+//! A kepub is an epub whose text is split into `koboSpan` spans, one per
+//! sentence, with ids of the form `kobo.<paragraph>.<sentence>`, and whose
+//! body is wrapped in `book-columns` and `book-inner` divs. Kobo readers use
+//! the spans to track reading progress and page-turn statistics, and fall
+//! back to a slower renderer for books without them.
+
+use ego_tree::NodeId;
+use eyre::{eyre, Result};
+use regex::Regex;
+use scraper::node::Text;
+use scraper::{Html, Node};
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::clean::new_element;
+use crate::typography::{is_block, is_verbatim};
+use crate::{
+    serialize_element, BookWriter, Chapter, Compression, Cover, EpubVersion, EpubWriter, Image,
+    Manifest, Reception, Series, Story, Style,
+};
+
+fn sentence() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r#"(?s).+?(?:[.!?…]+['"”’)\]]*(?:\s+|$)|$)"#).unwrap())
+}
+
+/// Splits `text` into sentences, each keeping the whitespace after it.
+fn sentences(text: &str) -> Vec<&str> {
+    sentence().find_iter(text).map(|m| m.as_str()).collect()
+}
+
+/// Wraps every sentence below `content` in a `koboSpan`.
+fn add_spans(doc: &mut Html, content: NodeId) {
+    // (text node, paragraph, sentences)
+    let mut nodes = Vec::new();
+    let mut last_block = None;
+    let mut paragraph = 0;
+    for node in doc.tree.get(content).unwrap().descendants() {
+        let Node::Text(text) = node.value() else {
+            continue;
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let mut verbatim = false;
+        let mut block = None;
+        for ancestor in node.ancestors() {
+            let Some(el) = ancestor.value().as_element() else {
+                continue;
+            };
+            verbatim |= is_verbatim(el.name());
+            if block.is_none() && is_block(el.name()) {
+                block = Some(ancestor.id());
+            }
+        }
+        if verbatim {
+            continue;
+        }
+
+        if block != last_block || nodes.is_empty() {
+            paragraph += 1;
+        }
+        last_block = block;
+
+        let sentences: Vec<String> = sentences(&text.text)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        nodes.push((node.id(), paragraph, sentences));
+    }
+
+    let mut last_paragraph = 0;
+    let mut nr = 0;
+    for (id, paragraph, sentences) in nodes {
+        if paragraph != last_paragraph {
+            last_paragraph = paragraph;
+            nr = 0;
+        }
+
+        for sentence in sentences {
+            nr += 1;
+            let span_id = format!("kobo.{}.{}", paragraph, nr);
+            doc.tree
+                .get_mut(id)
+                .unwrap()
+                .insert_before(new_element(
+                    "span",
+                    &[("class", "koboSpan"), ("id", &span_id)],
+                ))
+                .append(Node::Text(Text {
+                    text: sentence.into(),
+                }));
+        }
+        doc.tree.get_mut(id).unwrap().detach();
+    }
+}
+
+/// Converts a chapter's content (a single serialized element) to kepub.
+pub(crate) fn kepubify(content: &str) -> Result<String> {
+    let mut fragment = Html::parse_fragment(content);
+    let root = fragment.root_element().id();
+    // the content is a single element, which parsing wrapped in <html>
+    let content = fragment
+        .tree
+        .get(root)
+        .unwrap()
+        .children()
+        .find(|node| node.value().is_element())
+        .ok_or_else(|| eyre!("empty chapter content"))?
+        .id();
+
+    add_spans(&mut fragment, content);
+
+    Ok(format!(
+        r#"<div id="book-columns"><div id="book-inner">{}</div></div>"#,
+        serialize_element(&fragment, content)?
+    ))
+}
+
+/// Writes a kepub: an epub whose chapters are converted with the Kobo
+/// markup.
+pub struct KepubWriter {
+    inner: EpubWriter,
+}
+
+impl KepubWriter {
+    pub fn new(story: &Story) -> Result<KepubWriter> {
+        Ok(KepubWriter {
+            inner: EpubWriter::new(story)?,
+        })
+    }
+
+    /// See [`EpubWriter::set_version`].
+    pub fn set_version(&mut self, version: EpubVersion) {
+        self.inner.set_version(version);
+    }
+
+    /// See [`EpubWriter::set_toc_word_counts`].
+    pub fn set_toc_word_counts(&mut self) {
+        self.inner.set_toc_word_counts();
+    }
+
+    /// See [`EpubWriter::set_nested_toc`].
+    pub fn set_nested_toc(&mut self) {
+        self.inner.set_nested_toc();
+    }
+
+    /// See [`EpubWriter::set_compression`].
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.inner.set_compression(compression);
+    }
+
+    /// See [`EpubWriter::set_reproducible`].
+    pub fn set_reproducible(&mut self, story: &Story) {
+        self.inner.set_reproducible(story);
+    }
+}
+
+impl BookWriter for KepubWriter {
+    fn add_cover(&mut self, cover: &Cover, in_toc: bool) -> Result<()> {
+        self.inner.add_cover(cover, in_toc)
+    }
+
+    fn add_toc(&mut self) {
+        self.inner.add_toc();
+    }
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        self.inner.add_chapter(nr, chapter, &kepubify(content)?)
+    }
+
+    fn chapter_link(&self, nr: usize) -> Option<String> {
+        self.inner.chapter_link(nr)
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        self.inner.add_image(image)
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_source_page(story, in_toc)
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        self.inner.add_colophon(paragraphs, in_toc)
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        self.inner.add_reviews(reception, in_toc)
+    }
+
+    fn add_title_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_title_page(story, in_toc)
+    }
+
+    fn add_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        self.inner.add_manifest(manifest)
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize) {
+        self.inner.mark_incomplete(story, added, total);
+    }
+
+    fn add_front_matter(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        self.inner.add_front_matter(story, in_toc)
+    }
+
+    fn set_series(&mut self, series: &Series) {
+        self.inner.set_series(series);
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.inner.set_style(style);
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        self.inner.write(out)
+    }
+}
@@ -0,0 +1,156 @@
+//! Mailing books to a Kindle's Send-to-Kindle address.
+//!
+//! The SMTP server is configured in `smtp.json` in the user's config
+//! directory, or another file given explicitly, and every setting can be
+//! overridden with a `RUSTYROAD_SMTP_*` environment variable.
+
+use eyre::{eyre, Result, WrapErr};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::config::config_dir;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with STARTTLS, usually on port 587.
+    #[default]
+    StartTls,
+    /// Connect with TLS, usually on port 465.
+    Tls,
+    /// No encryption, for local relays only.
+    None,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to the usual port for `security`.
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The sender, which has to be on the Kindle account's approved list.
+    pub from: String,
+    pub security: SmtpSecurity,
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("RUSTYROAD_SMTP_{}", name))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+impl SmtpConfig {
+    /// `$XDG_CONFIG_HOME/rustyroad/smtp.json`, falling back to
+    /// `~/.config/rustyroad` (or the app data directory on Windows).
+    pub fn default_path() -> Option<PathBuf> {
+        Some(config_dir()?.join("smtp.json"))
+    }
+
+    /// Reads the config from `path`, or the default file if there is one,
+    /// and applies the environment on top.
+    pub fn load(path: Option<&Path>) -> Result<SmtpConfig> {
+        let file = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => SmtpConfig::default_path().filter(|path| path.is_file()),
+        };
+
+        let mut config = match &file {
+            Some(file) => {
+                let data = std::fs::read_to_string(file)
+                    .wrap_err_with(|| format!("could not read {}", file.display()))?;
+                serde_json::from_str(&data)
+                    .wrap_err_with(|| format!("invalid SMTP config {}", file.display()))?
+            }
+            None => SmtpConfig::default(),
+        };
+
+        if let Some(host) = env("HOST") {
+            config.host = host;
+        }
+        if let Some(port) = env("PORT") {
+            config.port = Some(
+                port.parse()
+                    .map_err(|_| eyre!("invalid RUSTYROAD_SMTP_PORT {:?}", port))?,
+            );
+        }
+        if let Some(username) = env("USERNAME") {
+            config.username = Some(username);
+        }
+        if let Some(password) = env("PASSWORD") {
+            config.password = Some(password);
+        }
+        if let Some(from) = env("FROM") {
+            config.from = from;
+        }
+        if let Some(security) = env("SECURITY") {
+            config.security = serde_json::from_value(serde_json::Value::String(security.clone()))
+                .map_err(|_| {
+                eyre!(
+                    "invalid RUSTYROAD_SMTP_SECURITY {:?}, use starttls, tls or none",
+                    security
+                )
+            })?;
+        }
+
+        if config.host.is_empty() || config.from.is_empty() {
+            return Err(eyre!(
+                "sending books needs an SMTP host and sender, set them in {} or with \
+                 RUSTYROAD_SMTP_HOST and RUSTYROAD_SMTP_FROM",
+                file.or_else(SmtpConfig::default_path)
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "smtp.json".to_string())
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Mails the epub at `path` to the Send-to-Kindle address `to`.
+pub async fn send_to_kindle(config: &SmtpConfig, to: &str, path: &Path, title: &str) -> Result<()> {
+    let data =
+        std::fs::read(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "book.epub".to_string());
+
+    let message = Message::builder()
+        .from(config.from.parse().wrap_err("invalid SMTP sender")?)
+        .to(to.parse().wrap_err("invalid Kindle address")?)
+        .subject(title)
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(format!("{}, sent by rustyroad.", title)))
+                .singlepart(
+                    Attachment::new(name).body(data, ContentType::parse("application/epub+zip")?),
+                ),
+        )?;
+
+    let mut transport = match config.security {
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        }
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    if let Some(port) = config.port {
+        transport = transport.port(port);
+    }
+    if let Some(username) = &config.username {
+        let password = config.password.clone().unwrap_or_default();
+        transport = transport.credentials(Credentials::new(username.clone(), password));
+    }
+
+    transport
+        .build()
+        .send(message)
+        .await
+        .wrap_err_with(|| format!("could not send {} to {}", path.display(), to))?;
+    Ok(())
+}
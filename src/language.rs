@@ -0,0 +1,158 @@
+//! Telling which language a story is written in, for the book's metadata;
+//! readers pick their dictionary and hyphenation rules by it.
+//!
+//! Sites give the language of their pages, which is usually the language of
+//! the site rather than of the story, so the description is read as well.
+//! Which script it is written in settles Chinese, Japanese, Korean and
+//! Russian; other languages are told apart by their most common words.
+
+use crate::Story;
+
+/// The language books are marked as when nothing says otherwise.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// The words that make up much of any text in each language, and that are
+/// rare in the others.
+const COMMON_WORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "is", "in", "that", "it", "with", "his", "her", "was", "he",
+            "she", "for", "you", "this", "but", "they", "be",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "la", "de", "que", "y", "en", "los", "las", "del", "se", "por", "un", "una",
+            "con", "su", "para", "es", "al", "lo", "como",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "a", "de", "que", "e", "do", "da", "em", "um", "uma", "os", "no", "na", "se",
+            "com", "não", "por", "mais", "as", "dos",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "la", "de", "et", "les", "des", "un", "une", "est", "du", "que", "en", "il",
+            "elle", "dans", "qui", "pas", "pour", "sur", "au",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "ein", "eine", "zu", "den", "mit", "sich",
+            "des", "auf", "für", "dem", "er", "sie", "es", "auch",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "e", "la", "un", "una", "per", "non", "sono", "del", "della", "gli",
+            "le", "con", "si", "lo", "nel", "è", "ma",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "van", "is", "dat", "niet", "zijn", "op", "te", "met",
+            "voor", "hij", "zij", "maar", "ook", "er", "aan", "wat",
+        ],
+    ),
+    (
+        "id",
+        &[
+            "yang", "dan", "di", "itu", "dengan", "untuk", "tidak", "ini", "dari", "dalam", "akan",
+            "pada", "juga", "saya", "ke", "ada", "karena", "bisa", "mereka", "aku",
+        ],
+    ),
+];
+
+/// How many words a text needs before its common words say anything.
+const MIN_WORDS: usize = 20;
+
+/// The language `text` is most likely written in, or `None` if it is too
+/// short or too mixed to tell.
+pub fn guess_language(text: &str) -> Option<&'static str> {
+    let mut letters = 0;
+    let (mut kana, mut hangul, mut han, mut cyrillic) = (0, 0, 0, 0);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match c {
+            '\u{3040}'..='\u{30ff}' => kana += 1,
+            '\u{ac00}'..='\u{d7af}' | '\u{1100}'..='\u{11ff}' => hangul += 1,
+            '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' => han += 1,
+            '\u{0400}'..='\u{04ff}' => cyrillic += 1,
+            _ => (),
+        }
+    }
+    if letters == 0 {
+        return None;
+    }
+    // Japanese mixes kana into its kanji, Chinese has none
+    if kana * 10 > letters {
+        return Some("ja");
+    }
+    if hangul * 2 > letters {
+        return Some("ko");
+    }
+    if han * 2 > letters {
+        return Some("zh");
+    }
+    if cyrillic * 2 > letters {
+        return Some("ru");
+    }
+
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+    let mut scores: Vec<(&str, usize)> = COMMON_WORDS
+        .iter()
+        .map(|(language, common)| {
+            let hits = words
+                .iter()
+                .filter(|word| common.contains(&word.as_str()))
+                .count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    let (language, best) = scores[0];
+    let runner_up = scores[1].1;
+    // a clear winner among words that make up a good part of the text
+    (best * 10 >= words.len() && best * 2 > runner_up * 3).then_some(language)
+}
+
+/// The language of `story`: what its description reads as, or else what
+/// the site says, or [`DEFAULT_LANGUAGE`].
+pub fn detect_language(story: &Story) -> String {
+    let site = story.language.as_deref();
+    match guess_language(&story.description) {
+        Some(guess) if site.map(primary_subtag) != Some(guess) => {
+            if let Some(site) = site {
+                log::info!(
+                    "the site marks {} as {:?}, but it reads as {:?}",
+                    story.title,
+                    site,
+                    guess
+                );
+            }
+            guess.to_string()
+        }
+        _ => site.unwrap_or(DEFAULT_LANGUAGE).to_string(),
+    }
+}
+
+/// The language of a tag like `en-US`, without its region.
+fn primary_subtag(tag: &str) -> &str {
+    tag.split(['-', '_']).next().unwrap_or(tag)
+}
@@ -0,0 +1,2058 @@
+//! Scrape stories from Royal Road and turn them into e-books.
+//!
+//! [`fetch_story`] reads a story's metadata and chapter list, and
+//! [`fetch_chapter_content`] fetches and cleans one chapter. The result can
+//! be written with an [`EpubWriter`] (or any other [`BookWriter`]):
+//!
+//! ```no_run
+//! # async fn run() -> eyre::Result<()> {
+//! use rustyroad::{BookWriter, CleanOpts, Client, EpubWriter, FetchOpts};
+//!
+//! let client = Client::new(Default::default())?;
+//! let story = rustyroad::fetch_story(&client, "https://www.royalroad.com/fiction/12345".into()).await?;
+//!
+//! let mut book = EpubWriter::new(&story)?;
+//! book.add_toc();
+//! for (i, chapter) in story.chapters.iter().enumerate() {
+//!     let content = rustyroad::fetch_chapter_content(
+//!         &client,
+//!         &chapter.link,
+//!         &CleanOpts::default(),
+//!         &FetchOpts::default(),
+//!         None,
+//!     )
+//!     .await?;
+//!     book.add_chapter(i, chapter, &content)?;
+//! }
+//! book.write(&mut std::fs::File::create("story.epub")?)?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod anthology;
+mod audiobook;
+mod azw3;
+mod cache;
+mod calibre;
+mod clean;
+mod colophon;
+mod config;
+mod epub_meta;
+mod error;
+mod estimate;
+mod existing;
+mod export;
+mod fb2;
+mod feed;
+mod filter;
+mod footnotes;
+mod generated_cover;
+mod generic;
+mod http;
+mod images;
+mod kepub;
+mod kindle;
+mod language;
+mod library;
+mod links;
+mod manifest;
+mod markdown;
+mod minify;
+mod normalize;
+mod notes;
+mod notify;
+mod opds;
+mod plugs;
+mod prefetch;
+mod rate_limit;
+mod replacements;
+mod reviews;
+mod robots;
+mod sanitize;
+mod schedule;
+mod script;
+mod selection;
+mod selectors;
+mod serialize;
+mod side_chapters;
+mod sites;
+mod spoilers;
+mod spool;
+mod style;
+mod suspect;
+mod tables;
+mod text;
+mod title_map;
+mod tui;
+mod typography;
+mod verify;
+mod volumes;
+mod watermark;
+
+use ego_tree::NodeId;
+use epub_builder::EpubBuilder;
+use epub_builder::EpubContent;
+use epub_builder::ReferenceType;
+use epub_builder::TocElement;
+use epub_builder::ZipLibrary;
+use eyre::{eyre, Result, WrapErr};
+use futures::StreamExt;
+use html5ever::tendril::TendrilSink;
+use reqwest::Url;
+use reviews::{reviews_page, REVIEWS_TITLE};
+use scraper::ElementRef;
+use scraper::Html;
+use scraper::Node;
+use scraper::Selector;
+use serde::{Deserialize, Serialize};
+use serialize::SerializeOpts;
+use spool::{copy_spool, spool, ContentSpool};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use xml5ever::serialize::TraversalScope;
+
+pub use anthology::anthology;
+pub use audiobook::{AudiobookWriter, TtsEngine, TtsOpts};
+pub use azw3::Azw3Writer;
+pub use cache::{parse_size, Cache, CacheStats, Purged};
+pub use calibre::CalibreBook;
+pub use clean::CleanOpts;
+pub use colophon::Colophon;
+pub use config::{Config, StoryConfig};
+pub use error::{
+    exit_code, ChapterError, HttpError, ScrapeError, EXIT_BLOCKED, EXIT_CHAPTER, EXIT_FAILURE,
+    EXIT_HTTP, EXIT_PARTIAL, EXIT_SCRAPE,
+};
+pub use estimate::{estimate_size, human_size, SizeEstimate, ESTIMATE_SAMPLES};
+pub use existing::{ExistingBook, EMBEDDED_MANIFEST};
+pub use export::ExportWriter;
+pub use fb2::{html_to_fb2, Fb2Builder};
+pub use feed::feed_has_new_chapters;
+pub use filter::StoryFilter;
+pub use footnotes::Footnotes;
+pub use generated_cover::generate_cover;
+pub use generic::crawl_story;
+pub use http::{is_challenge_page, Client, ClientOpts, Transport, DEFAULT_USER_AGENT};
+pub use images::{embed_images, local_images, Image, ImageOpts, IMAGE_DIR};
+pub use kepub::KepubWriter;
+pub use kindle::{send_to_kindle, SmtpConfig, SmtpSecurity};
+pub use language::{detect_language, guess_language, DEFAULT_LANGUAGE};
+pub use library::{Library, LibraryEntry};
+pub use links::ChapterLinks;
+pub use manifest::{Manifest, ManifestChapter, MANIFEST_VERSION};
+pub use markdown::html_to_markdown;
+pub use notes::NotePolicy;
+pub use notify::{NewChapters, WebhookFormat};
+pub use opds::serve_opds;
+pub use prefetch::Prefetch;
+pub use replacements::Replacements;
+pub use reviews::{Reception, Review};
+pub use schedule::{BookStatus, Schedule, WatchStatus};
+pub use script::ChapterScript;
+pub use selection::ChapterSelection;
+pub use selectors::{set_selectors, SELECTORS};
+pub use side_chapters::{arrange_side_chapters, side_chapter_regex, SideChapters, SIDE_VOLUME};
+pub use sites::{
+    same_story, site_for, story_url, Ao3, ReadingList, RoyalRoad, ScribbleHub, SearchResult,
+    SearchSort, SiteScraper, Unavailable, SITES,
+};
+pub use spoilers::Spoilers;
+pub use spool::{Compression, SPOOL_LIMIT};
+pub use style::{Font, Style};
+pub use tables::TableMode;
+pub use title_map::{TitleMap, TitleTemplate};
+pub use tui::{choose_chapters, TuiOptions};
+pub use typography::Typography;
+pub use verify::{validate_epub, verify_epub, verify_fb2, VerifyReport};
+pub use volumes::{detect_volumes, volume_path, word_count, Split, SplitWriter};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub name: String,
+    pub link: String,
+    /// The volume the author filed the chapter under, on sites that have
+    /// volumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    /// When the chapter was published, as a unix timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<u64>,
+    /// The number of words in the chapter, once it has been downloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub words: Option<u64>,
+    /// The chapter's place in the site's own numbering, on sites that give
+    /// one, which the chapter list is sorted by once it is read.
+    #[serde(skip)]
+    pub order: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Story {
+    /// Canonical URL of the fiction page.
+    pub url: String,
+    pub title: String,
+    pub author: String,
+    pub description: String,
+    pub cover: String,
+    pub tags: Vec<String>,
+    pub chapters: Vec<Chapter>,
+    /// The approximate length of the story in words, on sites that give one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub words: Option<u64>,
+    /// Whether the story is still being written, on sites that say.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StoryStatus>,
+    /// The story's pages besides its chapters, such as a glossary, character
+    /// sheets or maps, on sites that have them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pages: Vec<Chapter>,
+    /// The language the story is written in, as a tag like `en` or `en-US`:
+    /// what its site marks the page as, until [`detect_language`] or
+    /// `--language` settle it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// The content warnings the site shows for the story, such as
+    /// `Profanity` or `Sexual Content`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Where a story stands, as its site labels it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoryStatus {
+    Ongoing,
+    Completed,
+    Hiatus,
+    /// Most chapters were taken down, usually because the story is being
+    /// published elsewhere, e.g. on Kindle Unlimited.
+    Stub,
+    Dropped,
+    Inactive,
+}
+
+impl StoryStatus {
+    /// The status a label such as `ONGOING` names.
+    pub fn parse(label: &str) -> Option<StoryStatus> {
+        Some(match label.trim().to_ascii_lowercase().as_str() {
+            "ongoing" => StoryStatus::Ongoing,
+            "completed" => StoryStatus::Completed,
+            "hiatus" => StoryStatus::Hiatus,
+            "stub" => StoryStatus::Stub,
+            "dropped" => StoryStatus::Dropped,
+            "inactive" => StoryStatus::Inactive,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StoryStatus::Ongoing => "Ongoing",
+            StoryStatus::Completed => "Completed",
+            StoryStatus::Hiatus => "Hiatus",
+            StoryStatus::Stub => "Stub",
+            StoryStatus::Dropped => "Dropped",
+            StoryStatus::Inactive => "Inactive",
+        }
+    }
+}
+
+impl Story {
+    /// The language the book is marked as.
+    pub fn lang(&self) -> &str {
+        self.language.as_deref().unwrap_or(DEFAULT_LANGUAGE)
+    }
+
+    /// Whether the story has a cover of its own, rather than none or the
+    /// placeholder the site shows instead.
+    pub fn has_cover(&self) -> bool {
+        !self.cover.is_empty() && !self.cover.contains("nocover") && !self.cover.contains("noimage")
+    }
+}
+
+pub const STYLESHEET: &str = r#"
+    @page {
+        margin-bottom: 5pt;
+        margin-top: 5pt;
+    }
+
+    .chapter-inner, .chp_raw {
+        font-size: 1em;
+        line-height: 1.2;
+        margin: 0 5pt;
+    }
+
+    p {
+        text-indent: 1em;
+    }
+
+    .source, .title-page {
+        text-align: center;
+    }
+
+    .front-matter h1, .front-matter .author, .front-matter .tags,
+    .front-matter .warnings, .front-matter .details {
+        text-align: center;
+        text-indent: 0;
+    }
+
+    .front-matter .tags, .front-matter .warnings, .front-matter .details {
+        font-size: 0.9em;
+    }
+
+    .front-matter .details a {
+        word-break: break-all;
+    }
+
+    .title-page .tags, .title-page .warnings {
+        font-size: 0.9em;
+        font-style: italic;
+        text-indent: 0;
+    }
+
+    #toc .chapter-date, #toc .chapter-words {
+        font-size: 0.8em;
+        white-space: nowrap;
+    }
+
+    .source img.qr {
+        width: 40%;
+    }
+
+    .chapter-inner img, .chp_raw img {
+        max-width: 100%;
+    }
+
+    .authors-note {
+        border: 1px solid;
+        font-size: 0.9em;
+        margin: 1em 0;
+        padding: 0 5pt;
+    }
+
+    sup.tn-ref {
+        font-size: 0.7em;
+    }
+
+    .translator-notes {
+        border-top: 1px solid;
+        font-size: 0.9em;
+        margin-top: 2em;
+    }
+
+    .spoiler {
+        border-left: 3px solid;
+        margin: 1em 0;
+        padding-left: 5pt;
+    }
+
+    .spoiler-label, .spoiler summary {
+        font-weight: bold;
+        text-indent: 0;
+    }
+
+    .footnotes {
+        border-top: 1px solid;
+        font-size: 0.9em;
+        margin-top: 2em;
+    }
+
+    table {
+        border-collapse: collapse;
+        font-size: 0.9em;
+        margin: 1em 0;
+        max-width: 100%;
+    }
+
+    td, th {
+        border: 1px solid;
+        overflow-wrap: break-word;
+        padding: 2pt 4pt;
+        vertical-align: top;
+    }
+
+    td p, th p {
+        text-indent: 0;
+    }
+
+    pre.table {
+        font-size: 0.75em;
+        white-space: pre-wrap;
+    }
+
+    hr.scene-break {
+        border: none;
+        border-top: 1px solid;
+        margin: 1em 30%;
+    }
+"#;
+
+pub(crate) fn selector(str: &str) -> Result<Selector> {
+    Selector::parse(str).map_err(|_| eyre!("invalid selector"))
+}
+
+/// Fetches a story's metadata and chapter list. `url` is the story page, or
+/// any chapter of it, on one of the supported [`SITES`].
+pub async fn fetch_story(client: &Client, url: String) -> Result<Story> {
+    fetch_story_archived(client, url, None).await
+}
+
+/// Like [`fetch_story`], also saving the untouched story page to `archive`
+/// as `story.html`, and any further pages of the chapter list as
+/// `story-2.html` and on.
+pub async fn fetch_story_archived(
+    client: &Client,
+    url: String,
+    archive: Option<&Path>,
+) -> Result<Story> {
+    fetch_story_early(client, url, archive, |_, _| {}).await
+}
+
+/// Which part of a story's chapter list the first page of it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListedChapters {
+    /// The list isn't paginated.
+    All,
+    /// The first chapters, the rest being on the pages after it.
+    First,
+    /// The newest chapters, on sites that list those first.
+    Last,
+}
+
+/// Like [`fetch_story_archived`], calling `early` with the chapters on the
+/// first page of the chapter list, in reading order, as soon as it is read,
+/// so their pages can be fetched while the rest of the list is. The story
+/// returned has the final list, which may order them differently.
+pub async fn fetch_story_early(
+    client: &Client,
+    url: String,
+    archive: Option<&Path>,
+    early: impl FnOnce(&[Chapter], ListedChapters),
+) -> Result<Story> {
+    let url = story_url(&url)?;
+    let site = site_for(&url)?;
+    let save = |name: String, html: &str| -> Result<()> {
+        if let Some(dir) = archive {
+            let path = dir.join(name);
+            std::fs::write(&path, html)
+                .wrap_err_with(|| format!("could not write {}", path.display()))?;
+        }
+        Ok(())
+    };
+
+    let mut html = fetch_text(client, url.clone())
+        .await
+        .wrap_err_with(|| format!("could not fetch the story page {}", url))?;
+    // mature stories may sit behind a page asking readers to confirm their age
+    let parsed = site.parse_story(&html);
+    if parsed
+        .as_ref()
+        .map_or(true, |story| story.chapters.is_empty())
+    {
+        if let Some(check) = sites::age_check(&html, &url) {
+            log::info!("{} asks to confirm the reader is an adult, confirming", url);
+            pass_age_check(client, check).await?;
+            html = fetch_text(client, url.clone())
+                .await
+                .wrap_err_with(|| format!("could not fetch the story page {}", url))?;
+            if sites::age_check(&html, &url).is_some() {
+                return Err(ScrapeError::AgeCheck {
+                    url: url.to_string(),
+                }
+                .into());
+            }
+        }
+    }
+    save("story.html".to_string(), &html)?;
+    let mut story = site.parse_story(&html)?;
+
+    let pages = site.toc_pages(&html, &url);
+    let mut listed = story.chapters.clone();
+    site.order_chapters(&mut listed);
+    early(
+        &listed,
+        match (pages.is_empty(), site.lists_newest_first()) {
+            (true, _) => ListedChapters::All,
+            (false, false) => ListedChapters::First,
+            (false, true) => ListedChapters::Last,
+        },
+    );
+
+    for (i, page) in pages.into_iter().enumerate() {
+        let html = fetch_text(client, page.clone())
+            .await
+            .wrap_err_with(|| format!("could not fetch the chapter list page {}", page))?;
+        save(format!("story-{}.html", i + 2), &html)?;
+        story.chapters.extend(site.parse_toc_page(&html)?);
+    }
+    site.order_chapters(&mut story.chapters);
+    sites::check_chapters(site, &url, &mut story.chapters);
+
+    if story.url.is_empty() {
+        story.url = url.to_string();
+    }
+
+    Ok(story)
+}
+
+/// The scores, followers and first `reviews` reviews of the story at `url`,
+/// from its page. `None` on sites that don't show them.
+pub async fn fetch_reception(
+    client: &Client,
+    url: &str,
+    reviews: usize,
+) -> Result<Option<Reception>> {
+    let url = story_url(url)?;
+    let site = site_for(&url)?;
+    let html = fetch_text(client, url.clone())
+        .await
+        .wrap_err_with(|| format!("could not fetch the story page {}", url))?;
+    Ok(site
+        .parse_reception(&html, reviews)
+        .map(|reception| Reception {
+            date: today(),
+            ..reception
+        }))
+}
+
+/// Sends `check`'s form, so the site shows the mature stories it held back.
+async fn pass_age_check(client: &Client, check: sites::AgeCheck) -> Result<()> {
+    let fields: Vec<(&str, &str)> = check
+        .fields
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let resp = if check.post {
+        client.post_form(check.action.clone(), &fields).await?
+    } else {
+        let mut action = check.action.clone();
+        action.query_pairs_mut().extend_pairs(&fields);
+        client.get(action).await?
+    };
+    client
+        .text(resp)
+        .await
+        .wrap_err_with(|| format!("could not confirm the age check at {}", check.action))?;
+    Ok(())
+}
+
+async fn fetch_text(client: &Client, url: Url) -> Result<String> {
+    client.text(client.get(url).await?).await
+}
+
+/// Parses a Royal Road fiction page into a `Story` without touching the
+/// network.
+pub fn parse_story(html: &str) -> Result<Story> {
+    RoyalRoad.parse_story(html)
+}
+
+/// Resolves a chapter link from the chapter table into an absolute URL.
+/// Royal Road links are relative to the site; other sites list absolute
+/// links, which are returned unchanged.
+pub fn chapter_url(link: &str) -> Result<Url> {
+    let base_url = Url::parse("https://www.royalroad.com")?;
+    Ok(base_url.join(link)?)
+}
+
+/// Responses larger than this are spooled to a temporary file and parsed
+/// from there when [`FetchOpts::low_memory`] is set.
+pub const LOW_MEMORY_THRESHOLD: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct FetchOpts {
+    /// Avoid holding large response bodies in memory. Default: false
+    pub low_memory: bool,
+
+    /// Cache chapter pages here and revalidate them instead of downloading
+    /// them again. Default: None
+    pub cache: Option<Cache>,
+
+    /// Use the pages in `cache` as they are, without revalidating them, as
+    /// when picking up an interrupted download. Default: false
+    pub resume: bool,
+
+    /// Download the pages in `cache` again when they can't be revalidated,
+    /// having no `ETag` or `Last-Modified`, as when looking for chapters
+    /// the author revised. Default: false
+    pub refresh: bool,
+
+    /// Take the pages fetched ahead of time here instead of downloading
+    /// them. Default: none
+    pub prefetch: Prefetch,
+}
+
+/// Fetches a chapter page and returns its cleaned content. If `raw` is set,
+/// the untouched page is also saved there.
+pub async fn fetch_chapter_content(
+    client: &Client,
+    url: &str,
+    clean: &CleanOpts,
+    fetch: &FetchOpts,
+    raw: Option<&Path>,
+) -> Result<String> {
+    let clean = clean.clone();
+    let contents = fetch_checked(client, url, fetch, raw, move |doc| {
+        extract_chapter_content(doc, &clean).map(|content| vec![content])
+    })
+    .await?;
+    Ok(contents.into_iter().next().unwrap_or_default())
+}
+
+/// Fetches a page that holds several chapters, marked by the `#fragment`
+/// anchors of their links, and returns the cleaned content of each chapter.
+/// The untouched page is saved to `raw` if set.
+pub async fn fetch_shared_chapters(
+    client: &Client,
+    url: &str,
+    anchors: &[Option<&str>],
+    clean: &CleanOpts,
+    fetch: &FetchOpts,
+    raw: Option<&Path>,
+) -> Result<Vec<String>> {
+    let anchors: Vec<Option<String>> = anchors.iter().map(|a| a.map(String::from)).collect();
+    let clean = clean.clone();
+    fetch_checked(client, url, fetch, raw, move |doc| {
+        let anchors: Vec<Option<&str>> = anchors.iter().map(Option::as_deref).collect();
+        split_chapter_content(doc, &anchors, &clean)
+    })
+    .await
+}
+
+/// Fetches the chapter page at `url` and reads the chapters on it with
+/// `extract`. A page that looks like a wall rather than the chapters is
+/// fetched once more, bypassing the cache, and if it still does is kept
+/// with a warning and counted in [`Client::suspects`].
+async fn fetch_checked(
+    client: &Client,
+    url: &str,
+    fetch: &FetchOpts,
+    raw: Option<&Path>,
+    extract: impl Fn(Html) -> Result<Vec<String>> + Clone + Send + 'static,
+) -> Result<Vec<String>> {
+    let page = fetch_page(client, url, fetch, raw).await?;
+    let contents = read_page(page, url, extract.clone()).await?;
+    let Some(reason) = contents
+        .iter()
+        .find_map(|content| suspect::suspect(content))
+    else {
+        return Ok(contents);
+    };
+    log::warn!(
+        "the chapter at {} looks incomplete ({}), fetching it again",
+        url,
+        reason
+    );
+    if let Some(cache) = &fetch.cache {
+        cache.forget(&chapter_url(url)?);
+    }
+    let fresh = FetchOpts {
+        resume: false,
+        ..fetch.clone()
+    };
+    let page = fetch_page(client, url, &fresh, raw).await?;
+    let contents = read_page(page, url, extract).await?;
+    if let Some(reason) = contents
+        .iter()
+        .find_map(|content| suspect::suspect(content))
+    {
+        log::warn!(
+            "the chapter at {} still looks incomplete ({}); check it in the book, it may need logging in (--username or --cookies-file) or trying again later",
+            url,
+            reason
+        );
+        client.count_suspect();
+    }
+    Ok(contents)
+}
+
+/// Parses `page`, the chapter page at `url`, and reads the chapters on it
+/// with `extract` on a blocking thread, so that parsing and cleaning a long
+/// chapter doesn't hold up the downloads running alongside it.
+async fn read_page(
+    page: Page,
+    url: &str,
+    extract: impl FnOnce(Html) -> Result<Vec<String>> + Send + 'static,
+) -> Result<Vec<String>> {
+    let url = url.to_string();
+    tokio::task::spawn_blocking(move || {
+        let doc = page.parse()?;
+        check_available(&doc, &url)?;
+        extract(doc)
+    })
+    .await?
+}
+
+/// A fetched page, not parsed yet. Unlike the parsed page it can be sent
+/// to another thread.
+enum Page {
+    Text(String),
+    Bytes(Vec<u8>),
+    File(std::fs::File),
+}
+
+impl Page {
+    fn parse(self) -> Result<Html> {
+        match self {
+            Page::Text(html) => Ok(Html::parse_document(&html)),
+            Page::Bytes(buf) => Ok(html5ever::driver::parse_document(
+                Html::new_document(),
+                Default::default(),
+            )
+            .from_utf8()
+            .one(buf.as_slice())),
+            Page::File(file) => parse_document_file(file),
+        }
+    }
+
+    /// Writes the page to `path` as it was fetched.
+    fn save(&mut self, path: &Path) -> Result<()> {
+        match self {
+            Page::Text(html) => std::fs::write(path, html)?,
+            Page::Bytes(buf) => std::fs::write(path, buf)?,
+            Page::File(file) => {
+                std::io::copy(file, &mut std::fs::File::create(path)?)?;
+                file.seek(SeekFrom::Start(0))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn fetch_document(
+    client: &Client,
+    url: &str,
+    fetch: &FetchOpts,
+    raw: Option<&Path>,
+) -> Result<Html> {
+    fetch_page(client, url, fetch, raw).await?.parse()
+}
+
+async fn fetch_page(
+    client: &Client,
+    url: &str,
+    fetch: &FetchOpts,
+    raw: Option<&Path>,
+) -> Result<Page> {
+    let url = chapter_url(url)?;
+    if let Some(page) = fetch.prefetch.take(&url).await {
+        let mut page = page?;
+        if let Some(raw) = raw {
+            page.save(raw)?;
+        }
+        return Ok(page);
+    }
+    if let Some(cache) = &fetch.cache {
+        let path = match cache.page(&url).filter(|_| fetch.resume) {
+            Some(path) => {
+                cache.count_hit(&url);
+                path
+            }
+            None if fetch.refresh => cache
+                .refetch(client, url.clone())
+                .await
+                .map_err(|err| not_found(err, &url))?,
+            None => cache
+                .fetch(client, url.clone())
+                .await
+                .map_err(|err| not_found(err, &url))?,
+        };
+        if let Some(raw) = raw {
+            std::fs::copy(&path, raw)?;
+        }
+        return Ok(Page::File(std::fs::File::open(&path)?));
+    }
+
+    let resp = client
+        .get(url.clone())
+        .await
+        .map_err(|err| not_found(err, &url))?;
+
+    if !fetch.low_memory {
+        let html = client.text(resp).await?;
+        if let Some(raw) = raw {
+            std::fs::write(raw, &html)?;
+        }
+        return Ok(Page::Text(html));
+    }
+
+    fetch_page_low_memory(client, resp, raw).await
+}
+
+/// Turns a 404 or 410 for the chapter page at `url` into a
+/// [`ChapterError::NotFound`].
+fn not_found(err: eyre::Report, url: &Url) -> eyre::Report {
+    match err.downcast_ref::<HttpError>() {
+        Some(HttpError::Status {
+            status: 404 | 410, ..
+        }) => ChapterError::NotFound {
+            url: url.to_string(),
+        }
+        .into(),
+        _ => err,
+    }
+}
+
+/// Fails with a [`ChapterError`] if the chapter page `doc` at `url` has no
+/// chapter text because the chapter was deleted or is locked. A page that
+/// gives no reason is left to fail as a [`ScrapeError`] when it is parsed.
+fn check_available(doc: &Html, url: &str) -> Result<()> {
+    if doc
+        .select(&selector(&sites::content_selector())?)
+        .next()
+        .is_some()
+    {
+        return Ok(());
+    }
+    let url = url.to_string();
+    match sites::unavailable(doc) {
+        Some(Unavailable::Deleted) => Err(ChapterError::NotFound { url }.into()),
+        Some(Unavailable::Locked) => Err(ChapterError::Locked { url }.into()),
+        None => Ok(()),
+    }
+}
+
+/// Reads the response body in chunks, spilling it to a temporary file once
+/// it grows past [`LOW_MEMORY_THRESHOLD`] so the raw body and the parsed
+/// tree never have to be in memory at the same time. If `raw` is set, the
+/// body is spooled there instead, whatever its size.
+async fn fetch_page_low_memory(
+    client: &Client,
+    resp: reqwest::Response,
+    raw: Option<&Path>,
+) -> Result<Page> {
+    let mut body = resp.bytes_stream();
+    let mut buf = Vec::new();
+    let mut spool = match raw {
+        Some(raw) => Some(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(raw)?,
+        ),
+        None => None,
+    };
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        client.count_received(chunk.len());
+
+        match &mut spool {
+            Some(file) => file.write_all(&chunk)?,
+            None if buf.len() + chunk.len() > LOW_MEMORY_THRESHOLD => {
+                log::debug!(
+                    "response larger than {} bytes, spooling to disk",
+                    LOW_MEMORY_THRESHOLD
+                );
+                let mut file = tempfile::tempfile()?;
+                file.write_all(&buf)?;
+                file.write_all(&chunk)?;
+                buf = Vec::new();
+                spool = Some(file);
+            }
+            None => buf.extend_from_slice(&chunk),
+        }
+    }
+
+    match spool {
+        Some(mut file) => {
+            file.seek(SeekFrom::Start(0))?;
+            Ok(Page::File(file))
+        }
+        None => Ok(Page::Bytes(buf)),
+    }
+}
+
+/// Parses a page straight from a file, without reading it into memory first.
+fn parse_document_file(file: std::fs::File) -> Result<Html> {
+    let parser = html5ever::driver::parse_document(Html::new_document(), Default::default());
+    Ok(parser.from_utf8().read_from(&mut BufReader::new(file))?)
+}
+
+/// Extracts the chapter body from a chapter page and serializes it as xhtml.
+pub fn parse_chapter_content(html: &str, opts: &CleanOpts) -> Result<String> {
+    extract_chapter_content(Html::parse_document(html), opts)
+}
+
+fn extract_chapter_content(mut doc: Html, opts: &CleanOpts) -> Result<String> {
+    let content = find_chapter_content(&mut doc, opts)?;
+    sites::strip_junk(&mut doc, content);
+    notes::authors_notes(&mut doc, content, opts.authors_notes);
+    clean::clean(&mut doc, content, opts);
+    serialize_element(&doc, content)
+}
+
+/// Extracts the content of several chapters sharing one chapter page, split
+/// at their `#fragment` anchors. See [`fetch_shared_chapters`].
+pub fn parse_shared_chapters(
+    html: &str,
+    anchors: &[Option<&str>],
+    opts: &CleanOpts,
+) -> Result<Vec<String>> {
+    split_chapter_content(Html::parse_document(html), anchors, opts)
+}
+
+/// Splits the chapter content at the top-level blocks holding each anchor.
+/// A chapter without an anchor starts at the top of the page, and anything
+/// before the first anchor belongs to the first chapter.
+fn split_chapter_content(
+    mut doc: Html,
+    anchors: &[Option<&str>],
+    opts: &CleanOpts,
+) -> Result<Vec<String>> {
+    let content = find_chapter_content(&mut doc, opts)?;
+    sites::strip_junk(&mut doc, content);
+    // the note before the text goes with the first part, the one after it
+    // with the last
+    notes::authors_notes(&mut doc, content, opts.authors_notes);
+    let blocks: Vec<_> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .children()
+        .map(|c| c.id())
+        .collect();
+
+    let mut starts = Vec::with_capacity(anchors.len());
+    for anchor in anchors {
+        let start = match anchor {
+            None => 0,
+            Some(anchor) => {
+                let target = doc
+                    .tree
+                    .get(content)
+                    .unwrap()
+                    .descendants()
+                    .filter_map(ElementRef::wrap)
+                    .find(|el| {
+                        el.value().id() == Some(anchor)
+                            || (el.value().name() == "a" && el.value().attr("name") == Some(anchor))
+                    })
+                    .ok_or_else(|| ChapterError::MissingAnchor {
+                        anchor: anchor.to_string(),
+                    })?;
+
+                let block = target
+                    .ancestors()
+                    .find(|node| node.parent().map(|p| p.id()) == Some(content))
+                    .map(|node| node.id())
+                    .unwrap_or(target.id());
+                blocks.iter().position(|id| *id == block).unwrap()
+            }
+        };
+        starts.push(start);
+    }
+
+    let mut bounds = starts.clone();
+    bounds.sort_unstable();
+    bounds.dedup();
+    bounds[0] = 0;
+
+    // move each run of blocks into its own copy of the content element, so
+    // every part is cleaned and serialized on its own
+    let content_el = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .value()
+        .as_element()
+        .unwrap()
+        .clone();
+    let mut parts = Vec::with_capacity(bounds.len());
+    for (n, &start) in bounds.iter().enumerate() {
+        let end = bounds.get(n + 1).copied().unwrap_or(blocks.len());
+        let mut part = doc.tree.orphan(Node::Element(content_el.clone()));
+        for &block in &blocks[start..end] {
+            part.append_id(block);
+        }
+        let part = part.id();
+
+        clean::clean(&mut doc, part, opts);
+        parts.push(serialize_element(&doc, part)?);
+    }
+
+    Ok(starts
+        .iter()
+        .map(|start| {
+            let n = bounds.iter().rposition(|b| b <= start).unwrap();
+            parts[n].clone()
+        })
+        .collect())
+}
+
+/// Finds the chapter text, using the content selectors of every supported
+/// site since saved pages don't say where they came from. With
+/// [`CleanOpts::generic`], a page none of them match has it guessed.
+fn find_chapter_content(doc: &mut Html, opts: &CleanOpts) -> Result<NodeId> {
+    let content = sites::content_selector();
+    if let Some(el) = doc.select(&selector(&content)?).next() {
+        return Ok(el.id());
+    }
+    let guessed = match opts.generic {
+        true => generic::find_content(doc),
+        false => None,
+    };
+    let guessed =
+        guessed.ok_or_else(|| ScrapeError::selector_changed("chapter content", &content))?;
+    generic::strip_clutter(doc, guessed);
+    Ok(guessed)
+}
+
+/// Serializes the element `id` and its subtree as xhtml.
+fn serialize_element(doc: &Html, id: NodeId) -> Result<String> {
+    let content = ElementRef::wrap(doc.tree.get(id).unwrap()).unwrap();
+
+    let mut buf = Vec::new();
+    serialize::serialize(
+        &mut buf,
+        &content,
+        SerializeOpts {
+            scripting_enabled: false,
+            traversal_scope: TraversalScope::IncludeNode,
+            create_missing_parent: false,
+        },
+    )?;
+
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Groups chapters that link to the same page, differing only in their
+/// `#fragment`, so the page can be fetched once and split with
+/// [`fetch_shared_chapters`]. Groups are in the order of their first
+/// chapter, and every other chapter is a group of its own. A group's
+/// chapters need not be next to each other, so adding them to a book as
+/// their page arrives can put them out of order.
+pub fn shared_pages(chapters: &[Chapter]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut by_page: HashMap<&str, usize> = HashMap::new();
+
+    for (i, chapter) in chapters.iter().enumerate() {
+        let page = page_link(&chapter.link);
+        match by_page.get(page) {
+            Some(&group) => groups[group].push(i),
+            None => {
+                by_page.insert(page, groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+
+    groups
+}
+
+/// The chapter link without its `#fragment`.
+pub fn page_link(link: &str) -> &str {
+    link.split('#').next().unwrap()
+}
+
+/// The `#fragment` of a chapter link, if any.
+pub fn link_anchor(link: &str) -> Option<&str> {
+    link.split_once('#')
+        .map(|(_, anchor)| anchor)
+        .filter(|a| !a.is_empty())
+}
+
+#[derive(Debug, Clone)]
+pub struct Cover {
+    /// File extension, which also determines the mime type.
+    pub ext: String,
+    pub data: Vec<u8>,
+}
+
+impl Cover {
+    /// A cover of the image in `data`, which is told from its content
+    /// rather than trusting a file name or URL. WebP and AVIF images are
+    /// converted to JPEG or PNG.
+    pub fn from_data(data: Vec<u8>) -> Result<Cover> {
+        let ext = if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            "jpg"
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            "png"
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            "gif"
+        } else if images::is_webp(&data) {
+            let (data, ext) = images::convert_webp(&data)?;
+            return Ok(Cover {
+                ext: ext.to_string(),
+                data,
+            });
+        } else if images::is_avif(&data) {
+            let (data, ext) = images::convert_avif(&data)?;
+            return Ok(Cover {
+                ext: ext.to_string(),
+                data,
+            });
+        } else {
+            return Err(eyre!(
+                "the cover is not a JPEG, PNG, GIF, WebP or AVIF image"
+            ));
+        };
+        Ok(Cover {
+            ext: ext.to_string(),
+            data,
+        })
+    }
+
+    /// Reads a cover from an image file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Cover> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+        Cover::from_data(data).wrap_err_with(|| format!("invalid cover {}", path.display()))
+    }
+
+    pub fn mime(&self) -> Result<&'static str> {
+        match self.ext.as_str() {
+            "jpg" | "jpeg" => Ok("image/jpeg"),
+            "png" => Ok("image/png"),
+            "gif" => Ok("image/gif"),
+            _ => Err(eyre!("unsupported cover format")),
+        }
+    }
+
+    /// Loads a cover previously saved as `cover.<ext>` in `dir`, if any.
+    pub fn load_from_dir(dir: impl AsRef<Path>) -> Result<Option<Cover>> {
+        for ext in ["jpg", "jpeg", "png", "gif"] {
+            let path = dir.as_ref().join(format!("cover.{}", ext));
+            if path.exists() {
+                let data = std::fs::read(path)?;
+                return Ok(Some(Cover {
+                    ext: ext.to_string(),
+                    data,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn save_to_dir(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let path = dir.as_ref().join(format!("cover.{}", self.ext));
+        std::fs::write(path, &self.data)?;
+        Ok(())
+    }
+}
+
+/// Whether a `--cover` is a URL rather than a local file.
+pub fn is_remote_cover(cover: &str) -> bool {
+    Url::parse(cover).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// Fetches the cover image at `url`. The format is told from the image
+/// itself, as cover URLs often have no extension or a query string after
+/// it, and servers don't always send the right type; the type they send
+/// only explains what came instead of an image.
+pub async fn fetch_cover(client: &Client, url: &str) -> Result<Cover> {
+    let url = Url::parse(url).wrap_err_with(|| format!("invalid cover url {:?}", url))?;
+    let resp = client
+        .get(url.clone())
+        .await
+        .wrap_err_with(|| format!("could not fetch the cover {}", url))?;
+    let mime = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        });
+    let data = client.bytes(resp).await?;
+    Cover::from_data(data).wrap_err_with(|| match mime {
+        // e.g. an error or login page served where the image should be
+        Some(mime) if !mime.starts_with("image/") => {
+            format!("invalid cover {}: the server sent {}", url, mime)
+        }
+        _ => format!("invalid cover {}", url),
+    })
+}
+
+/// Reads the cover given with `--cover`: fetched if it is a URL, read from
+/// disk otherwise.
+pub async fn load_cover(client: &Client, cover: &str) -> Result<Cover> {
+    if is_remote_cover(cover) {
+        fetch_cover(client, cover).await
+    } else {
+        Cover::load(cover)
+    }
+}
+
+/// The kind of book to write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Epub,
+    /// FictionBook 2, a single XML file with the images embedded
+    Fb2,
+    /// AZW3 for Kindles, converted from the EPUB with Calibre's
+    /// ebook-convert or with kindlegen, whichever is installed
+    Azw3,
+    /// Kobo's flavor of EPUB, with the markup Kobo readers use for reading
+    /// progress and statistics
+    Kepub,
+    /// A web page, or with an `--out` without extension a directory with
+    /// one page per chapter
+    Html,
+    /// A Markdown file, or with an `--out` without extension a directory
+    /// with one file per chapter
+    Markdown,
+    /// Plain text, for text-to-speech: one file, or with an `--out` without
+    /// extension a directory with one file per chapter
+    Txt,
+    /// Experimental: the story read out by a text-to-speech engine, as an
+    /// M4B with chapter marks, or with an `--out` without `.m4b` a
+    /// directory with a file per chapter and a playlist
+    Audiobook,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Epub => "epub",
+            Format::Fb2 => "fb2",
+            Format::Azw3 => "azw3",
+            Format::Kepub => "kepub",
+            Format::Html => "html",
+            Format::Markdown => "markdown",
+            Format::Txt => "txt",
+            Format::Audiobook => "audiobook",
+        }
+    }
+
+    /// The file extension, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Kepub => "kepub.epub",
+            Format::Markdown => "md",
+            Format::Audiobook => "m4b",
+            format => format.name(),
+        }
+    }
+}
+
+/// The version of the EPUB standard EPUB books follow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EpubVersion {
+    /// EPUB 2, which every reader opens
+    #[default]
+    #[value(name = "2")]
+    V2,
+    /// EPUB 3, with a navigation document, landmarks, pop-up footnotes and
+    /// HTML5 content
+    #[value(name = "3")]
+    V3,
+}
+
+/// A book's place in a series, e.g. one volume of a split story.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub name: String,
+    /// The position in the series, starting at 1. Fractions are allowed for
+    /// books between two others.
+    pub index: f64,
+}
+
+/// The pieces of a book shared by every output format.
+pub trait BookWriter {
+    /// Adds the cover image and a cover page. If `in_toc` is false the
+    /// page is still the first in reading order but gets no table of
+    /// contents entry.
+    fn add_cover(&mut self, cover: &Cover, in_toc: bool) -> Result<()>;
+
+    /// Adds the table of contents at this point, for formats where it is a
+    /// page of its own.
+    fn add_toc(&mut self);
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()>;
+
+    /// Where a link inside the book to chapter `nr` points, for links
+    /// between chapters. Formats whose chapters can't be linked to return
+    /// `None`, and such links keep pointing at the site.
+    fn chapter_link(&self, _nr: usize) -> Option<String> {
+        None
+    }
+
+    /// Adds an image a chapter refers to by [`Image::file`]. Adding the same
+    /// image again does nothing.
+    fn add_image(&mut self, image: &Image) -> Result<()>;
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()>;
+
+    /// Adds the colophon at the end of the book, given as paragraphs of
+    /// plain text.
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()>;
+
+    /// Adds an appendix with the story's ratings and reviews.
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()>;
+
+    /// Adds a page with the title, author and tags. Formats whose readers
+    /// make their own title page from the metadata ignore it.
+    fn add_title_page(&mut self, _story: &Story, _in_toc: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stores `manifest` inside the book so a later `update` can tell which
+    /// chapters it holds. Formats that can't be updated ignore it.
+    fn add_manifest(&mut self, _manifest: &Manifest) -> Result<()> {
+        Ok(())
+    }
+
+    /// Marks the title and description of a book that is missing chapters,
+    /// and the book itself where the format has room for a notice.
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize);
+
+    /// Adds a page with the story's details: its title, author,
+    /// description, tags, length, source and when it was downloaded.
+    fn add_front_matter(&mut self, _story: &Story, _in_toc: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Records the book's place in a series. Formats without series
+    /// metadata ignore it.
+    fn set_series(&mut self, _series: &Series) {}
+
+    /// Replaces the default stylesheet. Formats without stylesheets ignore
+    /// it.
+    fn set_style(&mut self, _style: &Style) {}
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()>;
+
+    /// Writes the book to `path`. Formats written as a directory override
+    /// this.
+    fn write_to(&mut self, path: &Path) -> Result<()> {
+        write_atomically(path, |out| self.write(out))
+    }
+
+    /// The files [`write_to`](BookWriter::write_to) writes for `path`.
+    fn files(&self, path: &Path) -> Vec<std::path::PathBuf> {
+        vec![path.to_path_buf()]
+    }
+}
+
+/// Writes a file at `path` with `write` by way of a temporary file next to
+/// it, which only takes the place of any file already there once it is
+/// complete. A failure partway leaves the old file as it was.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut temp = tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(".part")
+        .tempfile_in(dir)
+        .wrap_err_with(|| format!("could not create {}", path.display()))?;
+    {
+        let mut out = std::io::BufWriter::new(temp.as_file_mut());
+        write(&mut out)?;
+        out.flush()
+            .wrap_err_with(|| format!("could not write {}", path.display()))?;
+    }
+    // temporary files are only readable by their owner, where a book
+    // should keep the permissions of the one it replaces, or be readable
+    // like any other new file
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(_) => default_permissions(),
+    };
+    if let Some(permissions) = permissions {
+        std::fs::set_permissions(temp.path(), permissions)
+            .wrap_err_with(|| format!("could not write {}", path.display()))?;
+    }
+    temp.persist(path)
+        .map_err(|err| err.error)
+        .wrap_err_with(|| format!("could not write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn default_permissions() -> Option<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(std::fs::Permissions::from_mode(0o644))
+}
+
+#[cfg(not(unix))]
+fn default_permissions() -> Option<std::fs::Permissions> {
+    None
+}
+
+/// Writes the book as an EPUB.
+pub struct EpubWriter {
+    builder: EpubBuilder<ZipLibrary>,
+    images: HashSet<String>,
+    series: Option<Series>,
+    style: Style,
+    /// Whether the book has front matter, whose word count is filled in
+    /// once every chapter is added.
+    front_matter: bool,
+    words: usize,
+    /// The file and publish date of every chapter whose date is known.
+    published: Vec<(String, u64)>,
+    /// The file and word count of every chapter, if they go in the table of
+    /// contents.
+    toc_words: Option<Vec<(String, usize)>>,
+    /// The chapters and images, which the builder only has stand-ins for.
+    content: ContentSpool,
+    version: EpubVersion,
+    /// Whether a chapter was added yet, which starts the body matter.
+    has_chapters: bool,
+    /// Whether chapters go under their volume in the table of contents.
+    nested_toc: bool,
+    /// The volume of the chapter added last.
+    volume: Option<String>,
+    /// When the book is written, see [`book_time`].
+    time: u64,
+    /// Whether the book is written reproducibly, see
+    /// [`set_reproducible`](Self::set_reproducible).
+    reproducible: bool,
+    /// The chapters added and the story's chapters, if the book is missing
+    /// some, for the notice that ends it.
+    incomplete: Option<(usize, usize)>,
+}
+
+impl EpubWriter {
+    /// Starts a book with the story's metadata and the default stylesheet.
+    pub fn new(story: &Story) -> Result<EpubWriter> {
+        let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
+        builder.set_title(story.title.as_str());
+        builder.add_author(story.author.as_str());
+        builder.add_description(story.description.as_str());
+        for tag in &story.tags {
+            builder.add_subject(tag.as_str());
+        }
+        builder.set_lang(story.lang());
+
+        Ok(EpubWriter {
+            builder,
+            images: HashSet::new(),
+            series: None,
+            style: Style::default(),
+            front_matter: false,
+            words: 0,
+            published: Vec::new(),
+            toc_words: None,
+            content: ContentSpool::new(),
+            version: EpubVersion::default(),
+            has_chapters: false,
+            nested_toc: false,
+            volume: None,
+            time: book_time(story, false),
+            reproducible: false,
+            incomplete: None,
+        })
+    }
+
+    /// Writes the book as `version`. This must come before any page is
+    /// added.
+    pub fn set_version(&mut self, version: EpubVersion) {
+        self.version = version;
+        self.builder.epub_version(match version {
+            EpubVersion::V2 => epub_builder::EpubVersion::V20,
+            EpubVersion::V3 => epub_builder::EpubVersion::V30,
+        });
+    }
+
+    /// Lists each chapter's word count next to it in the table of contents
+    /// page.
+    pub fn set_toc_word_counts(&mut self) {
+        self.toc_words = Some(Vec::new());
+    }
+
+    /// Lists the chapters under an entry for their volume in the table of
+    /// contents, which leads to the volume's first chapter.
+    pub fn set_nested_toc(&mut self) {
+        self.nested_toc = true;
+    }
+
+    /// Compresses the book's entries as `compression` says. This must come
+    /// before any page is added.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.content.set_compression(compression);
+    }
+
+    /// Writes the book of `story` reproducibly, so that the same story with
+    /// the same chapters makes a byte-for-byte identical book: it is dated
+    /// by `SOURCE_DATE_EPOCH`, or else by the story's latest chapter,
+    /// instead of the day it is written, its identifier is made from the
+    /// story instead of at random, and the entries of its archive carry no
+    /// timestamps.
+    pub fn set_reproducible(&mut self, story: &Story) {
+        self.reproducible = true;
+        self.time = book_time(story, true);
+        self.builder.set_uuid(story_uuid(story));
+    }
+
+    /// The underlying builder, for anything not covered by [`BookWriter`].
+    pub fn builder(&mut self) -> &mut EpubBuilder<ZipLibrary> {
+        &mut self.builder
+    }
+}
+
+impl BookWriter for EpubWriter {
+    fn add_cover(&mut self, cover: &Cover, in_toc: bool) -> Result<()> {
+        add_cover(&mut self.builder, self.version, cover, in_toc)
+    }
+
+    fn add_title_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        add_title_page(&mut self.builder, self.version, story, in_toc)
+    }
+
+    fn add_toc(&mut self) {
+        self.builder.inline_toc();
+    }
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        if self.front_matter || self.toc_words.is_some() {
+            let words = word_count(content);
+            self.words += words;
+            if let Some(toc_words) = &mut self.toc_words {
+                toc_words.push((chapter_file(nr), words));
+            }
+        }
+        if let Some(published) = chapter.published {
+            self.published.push((chapter_file(nr), published));
+        }
+        let file = chapter_file(nr);
+        let xhtml = xhtml_document(self.version, &chapter.name, content);
+        self.content
+            .add(&format!("OEBPS/{}", file), xhtml.as_bytes())?;
+        let volume = chapter.volume.as_ref().filter(|_| self.nested_toc);
+        let mut page = EpubContent::new(file.as_str(), [].as_slice());
+        page = match volume {
+            Some(volume) if self.volume.as_ref() != Some(volume) => page
+                .title(volume)
+                .child(TocElement::new(file.as_str(), chapter.name.as_str())),
+            Some(_) => page.title(&chapter.name).level(2),
+            None => page.title(&chapter.name),
+        };
+        self.volume = volume.cloned();
+        if !std::mem::replace(&mut self.has_chapters, true) {
+            page = page.reftype(ReferenceType::Text);
+        }
+        self.builder.add_content(page)?;
+        Ok(())
+    }
+
+    fn chapter_link(&self, nr: usize) -> Option<String> {
+        Some(chapter_file(nr))
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        if self.images.insert(image.file.clone()) {
+            self.content
+                .add(&format!("OEBPS/{}", image.file), &image.data)?;
+            self.builder
+                .add_resource(&image.file, [].as_slice(), image.mime)?;
+        }
+        Ok(())
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        add_source_page(&mut self.builder, self.version, story, in_toc)
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        let body = format!("<h2>{}</h2>\n{}", REVIEWS_TITLE, reviews_page(reception));
+        let xhtml = xhtml_document(self.version, REVIEWS_TITLE, &body);
+        let mut page = EpubContent::new(REVIEWS_FILE, xhtml.as_bytes());
+        if in_toc {
+            page = page.title(REVIEWS_TITLE);
+        }
+        self.builder.add_content(page)?;
+        Ok(())
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        let mut body = String::from("<div class=\"colophon\">\n");
+        for paragraph in paragraphs {
+            body.push_str(&format!("<p>{}</p>\n", escape(paragraph)));
+        }
+        body.push_str("</div>");
+        let xhtml = xhtml_document(self.version, "Colophon", &body);
+        let mut page =
+            EpubContent::new(COLOPHON_FILE, xhtml.as_bytes()).reftype(ReferenceType::Copyright);
+        if in_toc {
+            page = page.title("Colophon");
+        }
+        self.builder.add_content(page)?;
+        Ok(())
+    }
+
+    fn add_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        self.builder.add_resource(
+            EMBEDDED_MANIFEST,
+            manifest.to_json()?.as_bytes(),
+            "application/json",
+        )?;
+        Ok(())
+    }
+
+    fn mark_incomplete(&mut self, story: &Story, added: usize, total: usize) {
+        self.builder
+            .set_title(format!("{} [INCOMPLETE]", story.title));
+        self.builder.add_description(format!(
+            "[INCOMPLETE] This book only contains {} of {} chapters.",
+            added, total
+        ));
+        self.incomplete = Some((added, total));
+    }
+
+    fn add_front_matter(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        let xhtml = xhtml_document(
+            self.version,
+            &story.title,
+            &front_matter(story, WORDS_PLACEHOLDER, &iso_date(self.time)),
+        );
+        let mut page = EpubContent::new(FRONT_MATTER_FILE, xhtml.as_bytes());
+        if in_toc {
+            page = page.title("About this Book");
+        }
+        self.builder.add_content(page)?;
+        self.front_matter = true;
+        Ok(())
+    }
+
+    fn set_series(&mut self, series: &Series) {
+        self.series = Some(series.clone());
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.style = style.clone();
+    }
+
+    fn write(&mut self, out: &mut dyn Write) -> Result<()> {
+        // the stylesheet is at the root of the content, next to `fonts/`
+        let css = self.style.stylesheet(|font| font.file.clone());
+        self.builder.stylesheet(css.as_bytes())?;
+        for font in &self.style.fonts {
+            self.builder
+                .add_resource(&font.file, font.data.as_slice(), font.mime)?;
+        }
+
+        // the title page was added before anything went wrong, so a book
+        // that is missing chapters says so after the last one
+        if let Some((added, total)) = self.incomplete {
+            add_incomplete_notice(&mut self.builder, self.version, added, total)?;
+        }
+
+        // the book is as new as its latest chapter
+        let latest = self
+            .published
+            .iter()
+            .map(|(_, published)| *published)
+            .max()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs as i64, 0));
+        if let Some(latest) = latest {
+            self.builder.set_publication_date(latest);
+            self.builder.set_modified_date(latest);
+        }
+        // a reproducible book with no dated chapters is dated like the
+        // rest of it
+        if latest.is_none() && self.reproducible {
+            if let Some(time) = chrono::DateTime::from_timestamp(self.time as i64, 0) {
+                self.builder.set_modified_date(time);
+            }
+        }
+
+        let meta = self
+            .series
+            .as_ref()
+            .map(epub_meta::series)
+            .unwrap_or_default();
+
+        // the generated book gets the spooled chapters and images in place
+        // of their stand-ins. epub-builder has no series metadata, so it is
+        // added to the package document; the word count is only known now,
+        // and the dates (and word counts) go next to the chapters in the
+        // inline table of contents
+        let mut epub = spool();
+        self.builder.generate(&mut epub)?;
+        let words = thousands(self.words);
+        let mut epub = epub_meta::rewrite(
+            epub,
+            &mut self.content,
+            self.reproducible,
+            |name| {
+                (name.ends_with(".opf") && !meta.is_empty())
+                    || name.ends_with(FRONT_MATTER_FILE)
+                    || (name.ends_with("/toc.xhtml")
+                        && !(self.published.is_empty() && self.toc_words.is_none()))
+            },
+            |name, text| {
+                if name.ends_with(".opf") {
+                    epub_meta::add_to_package(&text, &meta)
+                } else if name.ends_with(FRONT_MATTER_FILE) {
+                    Ok(text.replace(WORDS_PLACEHOLDER, &words))
+                } else {
+                    Ok(annotated_toc(
+                        text,
+                        &self.published,
+                        self.toc_words.as_deref().unwrap_or_default(),
+                    ))
+                }
+            },
+        )?;
+        copy_spool(&mut epub, out)
+    }
+}
+
+/// Adds the publish date, and the word count if there is one, after each
+/// chapter's entry in the inline table of contents `toc`.
+fn annotated_toc(
+    mut toc: String,
+    published: &[(String, u64)],
+    words: &[(String, usize)],
+) -> String {
+    // each note goes right after the entry, so the last one added comes
+    // first
+    let words = words.iter().map(|(file, words)| {
+        (
+            file,
+            format!(
+                " <span class=\"chapter-words\">{} words</span>",
+                thousands(*words)
+            ),
+        )
+    });
+    let dates = published.iter().map(|(file, secs)| {
+        (
+            file,
+            format!(" <span class=\"chapter-date\">{}</span>", iso_date(*secs)),
+        )
+    });
+    for (file, note) in words.chain(dates) {
+        // a volume's entry links to its first chapter too, and comes first
+        let link = format!("<a href=\"{}\">", file);
+        let Some(end) = toc
+            .rfind(&link)
+            .and_then(|start| toc[start..].find("</a>").map(|end| start + end + 4))
+        else {
+            continue;
+        };
+        toc.insert_str(end, &note);
+    }
+    toc
+}
+
+fn add_cover(
+    builder: &mut EpubBuilder<ZipLibrary>,
+    version: EpubVersion,
+    cover: &Cover,
+    in_toc: bool,
+) -> Result<()> {
+    builder.add_cover_image(
+        format!("cover.{}", cover.ext),
+        cover.data.as_slice(),
+        cover.mime()?,
+    )?;
+
+    let cover_page = xhtml_document(
+        version,
+        "Cover",
+        &format!(r#"<img src="cover.{}" alt="Cover"/>"#, cover.ext),
+    );
+    let mut page =
+        EpubContent::new("cover.xhtml", cover_page.as_bytes()).reftype(ReferenceType::Cover);
+    if in_toc {
+        page = page.title("Cover");
+    }
+    builder.add_content(page)?;
+
+    Ok(())
+}
+
+fn add_title_page(
+    builder: &mut EpubBuilder<ZipLibrary>,
+    version: EpubVersion,
+    story: &Story,
+    in_toc: bool,
+) -> Result<()> {
+    let tags = if story.tags.is_empty() {
+        String::new()
+    } else {
+        format!(r#"<p class="tags">{}</p>"#, escape(&story.tags.join(" · ")))
+    };
+    let warnings = if story.warnings.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"<p class="warnings">Content warnings: {}</p>"#,
+            escape(&story.warnings.join(" · "))
+        )
+    };
+    let body = format!(
+        r#"<div class="title-page">
+            <h1>{}</h1>
+            <p>by {}</p>
+            {}
+            {}
+        </div>"#,
+        escape(&story.title),
+        escape(&story.author),
+        tags,
+        warnings
+    );
+
+    let xhtml = xhtml_document(version, &story.title, &body);
+    let mut page =
+        EpubContent::new("title.xhtml", xhtml.as_bytes()).reftype(ReferenceType::TitlePage);
+    if in_toc {
+        page = page.title("Title Page");
+    }
+    builder.add_content(page)?;
+
+    Ok(())
+}
+
+/// Adds the page that tells a book is missing chapters: only `added` of
+/// the story's `total` made it in.
+fn add_incomplete_notice(
+    builder: &mut EpubBuilder<ZipLibrary>,
+    version: EpubVersion,
+    added: usize,
+    total: usize,
+) -> Result<()> {
+    let body = format!(
+        r#"<div class="incomplete">
+            <h1>[INCOMPLETE]</h1>
+            <p>This book only contains {} of {} chapters: the download stopped before the rest. Update the book to add the {} missing.</p>
+        </div>"#,
+        added,
+        total,
+        total.saturating_sub(added)
+    );
+    let xhtml = xhtml_document(version, "Incomplete", &body);
+    builder
+        .add_content(EpubContent::new("incomplete.xhtml", xhtml.as_bytes()).title("Incomplete"))?;
+    Ok(())
+}
+
+const FRONT_MATTER_FILE: &str = "front.xhtml";
+
+const COLOPHON_FILE: &str = "colophon.xhtml";
+
+const REVIEWS_FILE: &str = "reviews.xhtml";
+
+/// Stands in for the word count until every chapter is added.
+pub(crate) const WORDS_PLACEHOLDER: &str = "<!--words-->";
+
+/// The body of the front matter page, with `words` as the word count and
+/// `date` as the day the book was downloaded.
+pub(crate) fn front_matter(story: &Story, words: &str, date: &str) -> String {
+    let mut body = format!(
+        "<div class=\"front-matter\">\n<h1>{}</h1>\n<p class=\"author\">by {}</p>\n",
+        escape(&story.title),
+        escape(&story.author)
+    );
+    if !story.tags.is_empty() {
+        body.push_str(&format!(
+            "<p class=\"tags\">{}</p>\n",
+            escape(&story.tags.join(" · "))
+        ));
+    }
+    if !story.warnings.is_empty() {
+        body.push_str(&format!(
+            "<p class=\"warnings\">Content warnings: {}</p>\n",
+            escape(&story.warnings.join(" · "))
+        ));
+    }
+    body.push_str("<div class=\"description\">\n");
+    for line in story.description.lines().map(str::trim) {
+        if !line.is_empty() {
+            body.push_str(&format!("<p>{}</p>\n", escape(line)));
+        }
+    }
+    body.push_str(&format!(
+        "</div>\n<p class=\"details\">{} words · {}downloaded {}</p>\n",
+        words,
+        story
+            .status
+            .map(|status| format!("{} · ", status.name()))
+            .unwrap_or_default(),
+        date
+    ));
+    if !story.url.is_empty() {
+        body.push_str(&format!(
+            "<p class=\"details\"><a href=\"{0}\">{0}</a></p>\n",
+            escape(&story.url)
+        ));
+    }
+    body.push_str("</div>");
+    body
+}
+
+/// The file name a raw chapter page is saved under by `--save-raw`.
+pub fn raw_chapter_file(nr: usize) -> String {
+    format!("chapter_{}.html", nr)
+}
+
+/// The file name used for the chapter at index `nr` in the book.
+pub fn chapter_file(nr: usize) -> String {
+    format!("chapter_{}.xhtml", nr)
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD` (UTC).
+pub fn iso_date(secs: u64) -> String {
+    // days since 1970-01-01 to a civil date, after Howard Hinnant's
+    // `civil_from_days`
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// When the book of `story` is written, as a unix timestamp: now, or if
+/// `reproducible`, `SOURCE_DATE_EPOCH` or the time of its latest chapter.
+pub(crate) fn book_time(story: &Story, reproducible: bool) -> u64 {
+    if !reproducible {
+        return SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+    }
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .or_else(|| story.chapters.iter().filter_map(|c| c.published).max())
+        .unwrap_or_default()
+}
+
+/// [`book_time`] as `YYYY-MM-DD`.
+pub(crate) fn book_date(story: &Story, reproducible: bool) -> String {
+    iso_date(book_time(story, reproducible))
+}
+
+/// The identifier of reproducible EPUBs of `story`, the same every time.
+fn story_uuid(story: &Story) -> uuid::Uuid {
+    let key = format!("{}\n{}\n{}", story.url, story.title, story.author);
+    uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, key.as_bytes())
+}
+
+/// Today's date as `YYYY-MM-DD` (UTC).
+pub(crate) fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    iso_date(secs)
+}
+
+/// Formats `n` with thousands separators, e.g. `123,456`.
+pub fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escapes text for use in xhtml content or attribute values.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Wraps `body` (already serialized xhtml) in a complete xhtml document.
+pub fn xhtml_page(title: &str, body: &str) -> String {
+    xhtml_document(EpubVersion::V2, title, body)
+}
+
+/// The same for the EPUB `version`: XHTML 1.1 for EPUB 2, and the XML
+/// syntax of HTML5 for EPUB 3.
+fn xhtml_document(version: EpubVersion, title: &str, body: &str) -> String {
+    let (doctype, charset) = match version {
+        EpubVersion::V2 => (
+            r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">"#,
+            r#"<meta http-equiv="Content-Type" content="text/html; charset=utf-8"/>"#,
+        ),
+        EpubVersion::V3 => ("<!DOCTYPE html>", r#"<meta charset="utf-8"/>"#),
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+{}
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+    <head>
+        <title>{}</title>
+        {}
+        <link rel="stylesheet" type="text/css" href="stylesheet.css"/>
+    </head>
+    <body>
+        {}
+    </body>
+</html>
+"#,
+        doctype,
+        escape(title),
+        charset,
+        body
+    )
+}
+
+/// Adds a back-matter page pointing at the story's source, with a QR code of
+/// the story URL so printed or shared copies can lead back to it.
+fn add_source_page(
+    builder: &mut EpubBuilder<ZipLibrary>,
+    version: EpubVersion,
+    story: &Story,
+    in_toc: bool,
+) -> Result<()> {
+    let qr = qrcode::QrCode::new(story.url.as_bytes())?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    builder.add_resource("source-qr.svg", qr.as_bytes(), "image/svg+xml")?;
+
+    let body = format!(
+        r#"<div class="source">
+            <h2>{title}</h2>
+            <p>by {author}</p>
+            <p>Read the original at <a href="{url}">{url}</a></p>
+            <p><img class="qr" src="source-qr.svg" alt="QR code linking to {url}"/></p>
+        </div>"#,
+        title = escape(&story.title),
+        author = escape(&story.author),
+        url = escape(&story.url),
+    );
+
+    let xhtml = xhtml_document(version, "Source", &body);
+    let mut page =
+        EpubContent::new("source.xhtml", xhtml.as_bytes()).reftype(ReferenceType::Colophon);
+    if in_toc {
+        page = page.title("Source");
+    }
+    builder.add_content(page)?;
+
+    Ok(())
+}
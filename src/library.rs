@@ -0,0 +1,161 @@
+//! A record of the books that have been written, so they can be updated
+//! without giving their story again.
+//!
+//! The library is a SQLite database with one row per book, keyed by the
+//! book's absolute path. Several books can come from the same story.
+
+use eyre::{Result, WrapErr};
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Story;
+
+/// The directory rustyroad keeps its data in: `$XDG_DATA_HOME/rustyroad`,
+/// falling back to `~/.local/share/rustyroad` (or the app data directory on
+/// Windows).
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share"))
+        })
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("rustyroad"))
+}
+
+pub struct Library {
+    conn: Connection,
+}
+
+#[derive(Debug, Clone)]
+pub struct LibraryEntry {
+    /// The book's absolute path.
+    pub path: PathBuf,
+    pub url: String,
+    pub title: String,
+    pub author: String,
+    /// The format's name, e.g. `epub`.
+    pub format: String,
+    /// The number of chapters in the book.
+    pub chapters: usize,
+    /// The name of the book's last chapter.
+    pub last_chapter: String,
+    /// Unix timestamp of the first time the book was written.
+    pub added: u64,
+    /// Unix timestamp of the last time the book was written.
+    pub updated: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl Library {
+    pub fn open(path: impl AsRef<Path>) -> Result<Library> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        }
+
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("could not open the library {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS books (
+                path TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                author TEXT NOT NULL,
+                format TEXT NOT NULL,
+                chapters INTEGER NOT NULL,
+                last_chapter TEXT NOT NULL,
+                added INTEGER NOT NULL,
+                updated INTEGER NOT NULL
+            )",
+        )?;
+        Ok(Library { conn })
+    }
+
+    /// `library.db` in the [`data_dir`].
+    pub fn default_path() -> Option<PathBuf> {
+        Some(data_dir()?.join("library.db"))
+    }
+
+    /// Records that `path` was written from `story`, in `format`.
+    pub fn record(&self, path: &Path, story: &Story, format: &str) -> Result<()> {
+        let last_chapter = story
+            .chapters
+            .last()
+            .map(|chapter| chapter.name.as_str())
+            .unwrap_or_default();
+        let now = now();
+
+        self.conn.execute(
+            "INSERT INTO books
+                (path, url, title, author, format, chapters, last_chapter, added, updated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8)
+             ON CONFLICT (path) DO UPDATE SET
+                url = excluded.url,
+                title = excluded.title,
+                author = excluded.author,
+                format = excluded.format,
+                chapters = excluded.chapters,
+                last_chapter = excluded.last_chapter,
+                updated = excluded.updated",
+            params![
+                absolute(path).to_string_lossy(),
+                story.url,
+                story.title,
+                story.author,
+                format,
+                story.chapters.len() as i64,
+                last_chapter,
+                now as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Every book in the library, by path.
+    pub fn entries(&self) -> Result<Vec<LibraryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT path, url, title, author, format, chapters, last_chapter, added, updated
+             FROM books ORDER BY path",
+        )?;
+        let entries = stmt
+            .query_map([], |row| {
+                Ok(LibraryEntry {
+                    path: PathBuf::from(row.get::<_, String>(0)?),
+                    url: row.get(1)?,
+                    title: row.get(2)?,
+                    author: row.get(3)?,
+                    format: row.get(4)?,
+                    chapters: row.get::<_, i64>(5)? as usize,
+                    last_chapter: row.get(6)?,
+                    added: row.get::<_, i64>(7)? as u64,
+                    updated: row.get::<_, i64>(8)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Forgets the book at `path`.
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM books WHERE path = ?1",
+            params![absolute(path).to_string_lossy()],
+        )?;
+        Ok(())
+    }
+}
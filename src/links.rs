@@ -0,0 +1,104 @@
+//! Links between the chapters of a story, which point at the site and are
+//! pointed at the book's own chapters instead when those are in it.
+
+use scraper::{Html, Node};
+use std::collections::HashMap;
+
+use crate::clean::{find, new_element};
+use crate::{chapter_url, link_anchor, serialize_element, sites, Chapter};
+
+/// The chapters of a book by where they are on the site.
+pub struct ChapterLinks {
+    /// The [`sites::chapter_key`] of every chapter, with the `#anchor` of
+    /// chapters sharing a page, and its number in the book.
+    chapters: HashMap<String, usize>,
+}
+
+impl ChapterLinks {
+    /// The chapters of a book holding `chapters`, numbered from 0.
+    pub fn new(chapters: &[Chapter]) -> ChapterLinks {
+        let mut links = HashMap::new();
+        for (nr, chapter) in chapters.iter().enumerate() {
+            let Some(key) = chapter_url(&chapter.link)
+                .ok()
+                .and_then(|url| sites::chapter_key(&url))
+            else {
+                continue;
+            };
+            if let Some(anchor) = link_anchor(&chapter.link) {
+                links.entry(format!("{}#{}", key, anchor)).or_insert(nr);
+            }
+            links.entry(key).or_insert(nr);
+        }
+        ChapterLinks { chapters: links }
+    }
+
+    /// Where the link `href` goes in the book, given where each chapter is
+    /// by `link`, or `None` if its chapter isn't in the book.
+    fn target(&self, href: &str, link: &impl Fn(usize) -> Option<String>) -> Option<String> {
+        let key = sites::chapter_key(&chapter_url(href).ok()?)?;
+        let anchor = link_anchor(href);
+        if let Some(nr) =
+            anchor.and_then(|anchor| self.chapters.get(&format!("{}#{}", key, anchor)))
+        {
+            return link(*nr);
+        }
+        // formats that link to chapters by anchor have none for the parts
+        // of a chapter
+        let target = link(*self.chapters.get(&key)?)?;
+        Some(match anchor {
+            Some(anchor) if !target.contains('#') => format!("{}#{}", target, anchor),
+            _ => target,
+        })
+    }
+
+    /// Points the links in `content` to chapters in the book at those
+    /// chapters, which are linked to as `link` says. Returns `None` if
+    /// there are none.
+    pub fn rewrite(&self, content: &str, link: impl Fn(usize) -> Option<String>) -> Option<String> {
+        if self.chapters.is_empty() || !content.contains("<a ") {
+            return None;
+        }
+
+        let mut fragment = Html::parse_fragment(content);
+        let root = fragment.root_element().id();
+
+        let mut rewritten = 0;
+        for id in find(&fragment, root, |el| el.value().name() == "a") {
+            let Node::Element(el) = fragment.tree.get(id).unwrap().value() else {
+                continue;
+            };
+            let Some(target) = el.attr("href").and_then(|href| self.target(href, &link)) else {
+                continue;
+            };
+
+            let attrs: Vec<(String, String)> = el
+                .attrs()
+                .map(|(name, value)| {
+                    let value = if name == "href" { &target } else { value };
+                    (name.to_string(), value.to_string())
+                })
+                .collect();
+            let attrs: Vec<(&str, &str)> = attrs
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            *fragment.tree.get_mut(id).unwrap().value() = new_element("a", &attrs);
+            rewritten += 1;
+        }
+        if rewritten == 0 {
+            return None;
+        }
+        log::debug!("pointed {} link(s) at chapters in the book", rewritten);
+
+        // the content is a single element, which parsing wrapped in <html>
+        let content = fragment
+            .tree
+            .get(root)
+            .unwrap()
+            .children()
+            .find(|node| node.value().is_element())?
+            .id();
+        serialize_element(&fragment, content).ok()
+    }
+}
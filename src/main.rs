@@ -1,270 +1,4969 @@
-mod serialize;
-
-use clap::Parser;
-use epub_builder::EpubBuilder;
-use epub_builder::EpubContent;
-use epub_builder::ReferenceType;
-use epub_builder::ZipLibrary;
-use eyre::{eyre, Result};
+use chrono::{DateTime, Local, TimeZone};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Subcommand};
+use eyre::{eyre, Result, WrapErr};
 use futures::TryStreamExt;
 use futures::{stream, StreamExt};
-use reqwest::Url;
-use scraper::Html;
-use scraper::Selector;
-use serialize::SerializeOpts;
-use std::fs::File;
-use xml5ever::serialize::TraversalScope;
-
-#[derive(Parser, Debug)]
-#[command()]
+use indicatif::{ProgressBar, ProgressStyle};
+use regex::Regex;
+use rustyroad::{
+    anthology, arrange_side_chapters, chapter_file, chapter_url, choose_chapters, crawl_story,
+    detect_language, detect_volumes, embed_images, escape, estimate_size, exit_code,
+    feed_has_new_chapters, fetch_chapter_content, fetch_cover, fetch_reception,
+    fetch_shared_chapters, fetch_story, fetch_story_early, generate_cover, human_size,
+    is_remote_cover, iso_date, link_anchor, load_cover, parse_chapter_content,
+    parse_shared_chapters, parse_size, raw_chapter_file, same_story, send_to_kindle, serve_opds,
+    set_selectors, shared_pages, side_chapter_regex, story_url, thousands, validate_epub,
+    verify_epub, verify_fb2, volume_path, word_count, AudiobookWriter, Azw3Writer, BookStatus,
+    BookWriter, Cache, CalibreBook, Chapter, ChapterError, ChapterLinks, ChapterScript,
+    ChapterSelection, CleanOpts, Client, ClientOpts, Colophon, Compression, Config, Cover,
+    EpubVersion, EpubWriter, ExistingBook, ExportWriter, Fb2Builder, FetchOpts, Footnotes, Format,
+    Image, ImageOpts, KepubWriter, Library, LibraryEntry, ListedChapters, Manifest, NewChapters,
+    NotePolicy, Prefetch, ReadingList, Reception, Replacements, RoyalRoad, Schedule, SearchResult,
+    SearchSort, Series, SideChapters, SmtpConfig, Split, SplitWriter, Spoilers, Story, StoryConfig,
+    StoryFilter, StoryStatus, Style, TableMode, TitleMap, TitleTemplate, TtsEngine, TtsOpts,
+    TuiOptions, Typography, WatchStatus, WebhookFormat, DEFAULT_USER_AGENT, ESTIMATE_SAMPLES,
+    EXIT_FAILURE, EXIT_PARTIAL, SIDE_VOLUME,
+};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(clap::Parser, Debug)]
 struct Args {
+    /// Read defaults for the options from this file instead of
+    /// `rustyroad.toml` in the user's config directory, e.g.
+    /// `~/.config/rustyroad/rustyroad.toml`
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Don't read a config file
+    #[arg(long, global = true, conflicts_with = "config")]
+    no_config: bool,
+
+    /// Only log warnings and errors; twice for errors alone
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
+
+    /// Log more: once for rustyroad's debug messages, twice for those of
+    /// the libraries it uses (such as the HTTP client) too, three times for
+    /// everything
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Write the log to this file instead of stderr, added to the end of it
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download one or more stories into books
+    Download(DownloadArgs),
+    /// Download the chapters a book at `--out` doesn't have yet and rewrite
+    /// it
+    Update(UpdateArgs),
+    /// Keep books up to date: check their stories for new chapters every
+    /// `--interval` or on a `--schedule` and update the books that have
+    /// some
+    Watch(WatchArgs),
+    /// Print how many new chapters a book's story has, without updating
+    /// it. Exits with 0 if the book is up to date, 1 if it isn't and 8 if
+    /// the check failed
+    Check(CheckArgs),
+    /// Print a story's details, length and dated chapter list without
+    /// downloading it
+    Info(InfoArgs),
+    /// Assemble an EPUB offline from pages saved with `--save-raw` and
+    /// `--metadata-json`
+    Build(BuildArgs),
+    /// Write a story's book again from the cache, without the network, e.g.
+    /// with other chapters, options or another format
+    Rebuild(RebuildArgs),
+    /// Browse a story's details and chapters in the terminal, pick the
+    /// chapters and the book's options, and download them
+    Tui(TuiArgs),
+    /// Search Royal Road for stories by title
+    Search(SearchArgs),
+    /// List the stories on a Royal Road author's profile and download the
+    /// ones picked, or all of them with `--all`, into the library
+    Author(AuthorArgs),
+    /// Download every story on the Royal Road account's Follow List (or
+    /// Read Later list), and update the books of those already downloaded
+    SyncFollows(SyncFollowsArgs),
+    /// Serve a directory of books as an OPDS catalog, for reading apps to
+    /// browse and download them over the local network
+    Serve(ServeArgs),
+    /// Show what the cache of chapter pages holds, or trim it
+    Cache(CacheArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct DownloadArgs {
+    /// The story to download. Give it more than once to download several
+    /// stories, each into its own book
+    #[arg(short, long, required_unless_present = "input")]
+    url: Vec<String>,
+
+    /// Download every story listed in this file, one URL per line. Blank
+    /// lines and lines starting with `#` are skipped. Each book is named
+    /// after its story, e.g. `The Title - The Author.epub`
+    #[arg(
+        long,
+        conflicts_with_all = ["manifest", "save_raw", "archive", "metadata_json"]
+    )]
+    input: Option<PathBuf>,
+
+    /// Fetch the story page and report what would be downloaded, with an
+    /// estimate of the EPUB size, without downloading the whole story or
+    /// writing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write a manifest of the book's chapters to this path
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// Read the manifest written by the previous run and only download
+    /// chapters that are not in it, reusing the rest from the existing book
+    /// at `--out`
+    #[arg(long, requires = "manifest")]
+    new_since_manifest: bool,
+
+    /// Only download stories whose author matches this regex
+    #[arg(long)]
+    author_filter: Option<String>,
+
+    /// Only download stories with a tag matching this regex
+    #[arg(long)]
+    tag_filter: Option<String>,
+
+    /// Only download stories whose title matches this regex
+    #[arg(long)]
+    title_filter: Option<String>,
+
+    /// Write the scraped story metadata and chapter list, with every
+    /// chapter's publish date and word count, to this JSON file
+    #[arg(long)]
+    metadata_json: Option<PathBuf>,
+
+    /// Bind all the stories given with --url and --input into one book,
+    /// each story's chapters under its title in the table of contents
+    #[arg(long, conflicts_with_all = ["archive", "include_reviews"])]
+    anthology: bool,
+
+    /// The anthology's title. Defaults to the titles of its stories
+    #[arg(long, value_name = "TITLE", requires = "anthology")]
+    anthology_title: Option<String>,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct UpdateArgs {
+    /// The story the book is from. Can be left out for books written by
+    /// `update`, which record it
     #[arg(short, long)]
+    url: Option<String>,
+
+    /// Update every book in the library instead of the one given by --out
+    #[arg(long, conflicts_with = "url")]
+    all: bool,
+
+    /// With --all, check every story page for new chapters instead of their
+    /// feeds first
+    #[arg(long, requires = "all")]
+    no_feed: bool,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    /// The books to keep up to date, written by `download` or `update`
+    #[arg(required_unless_present = "status")]
+    books: Vec<String>,
+
+    /// How long to wait between checks, e.g. `30m`, `1h` or `1d`
+    #[arg(long, default_value = "1h", value_parser = parse_interval)]
+    interval: Duration,
+
+    /// Check the books on this cron schedule instead of every --interval,
+    /// e.g. `0 6 * * *` for 6 in the morning. A story's `schedule` in the
+    /// config file wins over it
+    #[arg(long, value_name = "CRON", conflicts_with = "interval")]
+    schedule: Option<Schedule>,
+
+    /// Start each scheduled check up to this much later, at random, so
+    /// books on the same schedule aren't all checked at once
+    #[arg(long, default_value = "5m", value_parser = parse_jitter)]
+    jitter: Duration,
+
+    /// Print when the running `watch` checks each book next, and how its
+    /// last check went, instead of watching. Given books, only those
+    #[arg(long)]
+    status: bool,
+
+    /// A shell command to run after a book was updated. It gets the path of
+    /// the book in `RUSTYROAD_BOOK` and the number of new chapters in
+    /// `RUSTYROAD_NEW_CHAPTERS`
+    #[arg(long)]
+    hook: Option<String>,
+
+    /// Show a desktop notification with the new chapters after a book was
+    /// updated, with `notify-send` on Linux or Notification Center on macOS
+    #[arg(long)]
+    notify: bool,
+
+    /// Post the story's title and new chapters to this URL after a book
+    /// was updated, e.g. a Discord webhook or an ntfy topic
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// What to post to --webhook
+    #[arg(long, value_enum, default_value_t = WebhookFormat::Json, requires = "webhook")]
+    webhook_format: WebhookFormat,
+
+    /// Check the story pages for new chapters every time instead of the
+    /// stories' feeds
+    #[arg(long)]
+    no_feed: bool,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CheckArgs {
+    /// The book to check, or a story URL or ID to check the library's books of
+    book: String,
+
+    /// Check the story page for new chapters instead of the story's feed
+    /// first
+    #[arg(long)]
+    no_feed: bool,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    url: String,
+
+    /// Print the details as JSON, in the format `--metadata-json` writes
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    net: NetArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct TuiArgs {
     url: String,
 
-    #[arg(short, long)]
-    out: String,
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct SearchArgs {
+    /// The words to look for in the titles
+    query: String,
+
+    /// How to order the results
+    #[arg(long, value_enum, default_value_t = SearchSort::Relevance)]
+    sort: SearchSort,
+
+    /// Which page of results to show, 20 stories each
+    #[arg(long, default_value_t = 1)]
+    page: usize,
+
+    /// Download the story listed as number N instead of listing them
+    #[arg(long, value_name = "N")]
+    download: Option<usize>,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct AuthorArgs {
+    /// The author's profile, e.g. `https://www.royalroad.com/profile/12345`,
+    /// or its number
+    profile: String,
+
+    /// Download every story of the author without asking
+    #[arg(long)]
+    all: bool,
+
+    /// Download the stories listed with these numbers, e.g. `1,3-5`,
+    /// without asking
+    #[arg(long, value_name = "NUMBERS", conflicts_with = "all")]
+    download: Option<String>,
+
+    /// Only list the stories
+    #[arg(long, conflicts_with_all = ["all", "download"])]
+    list: bool,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct SyncFollowsArgs {
+    /// Which of the account's lists to sync
+    #[arg(long, value_enum, default_value_t = ReadingList::Follows)]
+    list: ReadingList,
+
+    /// Leave out this story, e.g. one read elsewhere. Give it more than once
+    /// to leave out several; `sync = false` in the story's table of the
+    /// config file does the same for good
+    #[arg(long, value_name = "URL")]
+    skip: Vec<String>,
+
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// The directory of books to serve, with those in its subdirectories.
+    /// Default: `output-dir` from the config file, or the current directory
+    dir: Option<PathBuf>,
+
+    /// The port to listen on
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// The address to listen on. The default takes connections from the
+    /// whole network; `127.0.0.1` only from this computer
+    #[arg(long, default_value = "0.0.0.0")]
+    bind: std::net::IpAddr,
+}
+
+#[derive(clap::Args, Debug)]
+struct CacheArgs {
+    /// The cache to work on. Default: the user's cache directory, e.g.
+    /// `~/.cache/rustyroad`
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Print how many pages and stories the cache holds, the room they
+    /// take and when they were last used
+    Stats {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove pages from the cache: every one, or only those the options
+    /// pick
+    Purge(PurgeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct PurgeArgs {
+    /// Only remove the pages that weren't used for this long, e.g. `30d`
+    #[arg(long, value_name = "AGE", value_parser = parse_interval)]
+    older_than: Option<Duration>,
+
+    /// Only remove the pages of this story, along with its kept details
+    #[arg(long, value_name = "URL")]
+    story: Option<String>,
+
+    /// Remove the pages used longest ago until the rest take no more than
+    /// this, e.g. `2G`
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = ["older_than", "story"]
+    )]
+    max_size: Option<u64>,
+}
+
+// Options for fetching and assembling chapters, shared by `download` and
+// `update`.
+#[derive(clap::Args, Debug, Clone)]
+struct FetchArgs {
+    #[arg(short, long, default_value_t = 5)]
+    concurrent: usize,
+
+    /// When downloading or updating several stories, work on this many at
+    /// once. Their chapter fetches then share `--concurrent` requests at a
+    /// time (and `--delay`) between them, so the site sees no more than it
+    /// would from one story, and the progress bar is left out
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    story_concurrent: u64,
+
+    /// Only download these chapters, e.g. `1-50,60,70-`. Chapters are
+    /// numbered from 1 in the order of the story's chapter list
+    #[arg(long, conflicts_with_all = ["from", "to"])]
+    chapters: Option<String>,
+
+    /// The first chapter to download
+    #[arg(long)]
+    from: Option<usize>,
+
+    /// The last chapter to download
+    #[arg(long)]
+    to: Option<usize>,
+
+    /// Leave out the chapters whose titles match this regex, e.g.
+    /// `^(Art|Announcement)`
+    #[arg(long, value_name = "REGEX")]
+    exclude_title_regex: Option<Regex>,
+
+    /// Only download the chapters whose titles match this regex
+    #[arg(long, value_name = "REGEX")]
+    include_title_regex: Option<Regex>,
+
+    /// Start fetching this many leading chapters as soon as the first page
+    /// of the chapter list is read, while the rest of it and the cover are
+    /// still being fetched. At most `--concurrent`; 0 disables it
+    #[arg(long, default_value_t = 2)]
+    prefetch_window: usize,
+
+    /// If the download fails partway, still write an EPUB containing the
+    /// chapters fetched so far (marked as incomplete)
+    #[arg(long)]
+    partial_on_error: bool,
+
+    /// Keep going when a chapter can't be downloaded or parsed: the book
+    /// gets a placeholder noting the failure instead, and the skipped
+    /// chapters are listed at the end. Exits with status 2 if any were
+    /// skipped. `update` fetches them again
+    #[arg(long)]
+    skip_failed: bool,
+
+    /// Like `--skip-failed`, but only for chapters that were deleted or are
+    /// locked; any other failure still stops the download
+    #[arg(long)]
+    skip_unavailable: bool,
+
+    /// When updating, fetch the chapters the book already has again too,
+    /// and replace those the author revised since. The revised chapters
+    /// are listed at the end
+    #[arg(long)]
+    refresh_changed: bool,
+
+    /// Fetch the most recent chapters first. The book keeps its normal
+    /// chapter order, but chapters are held in memory until every fetch has
+    /// finished instead of being written as they arrive
+    #[arg(long)]
+    newest_first: bool,
+
+    /// Add an appendix with the story's scores, followers and first N
+    /// reviews as they stand when it is downloaded. `0` leaves out the
+    /// reviews
+    #[arg(long, value_name = "N")]
+    include_reviews: Option<usize>,
+
+    /// Add the story's pages besides its chapters, such as a glossary,
+    /// character sheets or maps, after the chapters, in a section of their
+    /// own in the table of contents
+    #[arg(long)]
+    include_pages: bool,
+
+    /// Don't read or write the cache of chapter pages
+    #[arg(long, conflicts_with = "cache_dir")]
+    no_cache: bool,
+
+    /// Pick up a download that was interrupted: the chapter pages it got to
+    /// are taken from the cache as they are, and only the rest are fetched
+    #[arg(long, conflicts_with = "no_cache")]
+    resume: bool,
+
+    /// Where to cache chapter pages. Default: the user's cache directory,
+    /// e.g. `~/.cache/rustyroad`
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Keep the cache of chapter pages under this size, e.g. `2G`: once a
+    /// story is downloaded, the pages used longest ago are removed until
+    /// the rest fit
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "no_cache")]
+    cache_max_size: Option<u64>,
+
+    /// Don't record the book in the library that `update --all` updates
+    #[arg(long, conflicts_with = "library")]
+    no_library: bool,
+
+    /// The library of written books. Default: the user's data directory,
+    /// e.g. `~/.local/share/rustyroad/library.db`
+    #[arg(long)]
+    library: Option<PathBuf>,
+
+    /// Mail the finished book to this Send-to-Kindle address. See
+    /// --smtp-config for the mail server
+    #[arg(long, value_name = "ADDRESS")]
+    send_to_kindle: Option<String>,
+
+    /// Add the finished book to Calibre with `calibredb`, into the given
+    /// library or Calibre's default one. A book of the story already there,
+    /// e.g. when updating, is replaced rather than added again
+    #[arg(long, value_name = "LIBRARY", num_args = 0..=1, require_equals = true)]
+    add_to_calibre: Option<Option<PathBuf>>,
+
+    /// The SMTP settings for --send-to-kindle. Default: `smtp.json` in the
+    /// user's config directory, e.g. `~/.config/rustyroad/smtp.json`.
+    /// `RUSTYROAD_SMTP_*` environment variables override it
+    #[arg(long, requires = "send_to_kindle")]
+    smtp_config: Option<PathBuf>,
+
+    /// Reduce peak memory use. Chapter pages over 1 MiB are spooled to a
+    /// temporary file instead of being held in memory while parsing
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Save the untouched chapter pages and the cover to this directory, for
+    /// use with `build`
+    #[arg(long)]
+    save_raw: Option<PathBuf>,
+
+    /// Keep a snapshot of the story as the site has it in this directory, in
+    /// case it is taken down: the story page, every chapter page untouched,
+    /// the cover and the story's details, from which `build` can write the
+    /// book again
+    #[arg(long, value_name = "DIR", conflicts_with = "save_raw")]
+    archive: Option<PathBuf>,
+
+    /// The link to the next chapter on each chapter page, for --generic,
+    /// e.g. `a.next-chapter` or `div.nav-next` for the block it is in. Left
+    /// out, a link marked `rel="next"` or reading "Next" is followed
+    #[arg(long, value_name = "SELECTOR", requires = "generic")]
+    next_selector: Option<String>,
+
+    /// Leave chapter images pointing at where they are hosted instead of
+    /// downloading them into the book
+    #[arg(long)]
+    remote_images: bool,
+
+    /// Log every chapter fetch instead of showing a progress bar. The bar
+    /// is also left out when stderr isn't a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// `json` reports progress as one JSON object per line on stdout in
+    /// place of the bar, for programs that run rustyroad: `story_resolved`,
+    /// `chapter_fetched`, `chapter_failed` and `epub_written` events
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Bar)]
+    progress_format: ProgressFormat,
+
+    /// What to print once the book is written: `text` sums up the chapters
+    /// fetched, cached, retried and skipped, the words, the book's size and
+    /// the time it took, `json` the same for scripts
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    summary: SummaryFormat,
+
+    #[command(flatten)]
+    net: NetArgs,
+
+    #[command(flatten)]
+    book: BookArgs,
+
+    #[arg(skip)]
+    settings: Settings,
+}
+
+// Options for the HTTP client, shared by every command that uses the
+// network.
+#[derive(clap::Args, Debug, Clone)]
+struct NetArgs {
+    /// The longest pause, in seconds, after the site starts refusing
+    /// requests. If it is still refusing after that, the download fails
+    #[arg(long, default_value_t = 600)]
+    max_cooldown: u64,
+
+    /// How often to retry a request that failed with a network or server
+    /// error
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Seconds a request may take, from connecting to the last byte of the
+    /// page, before it is abandoned (and retried)
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    /// Seconds connecting to the site may take
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+
+    /// The User-Agent header sent with every request
+    #[arg(long, default_value = DEFAULT_USER_AGENT)]
+    user_agent: String,
+
+    /// Seconds to wait before the first retry. Each further retry waits
+    /// about twice as long
+    #[arg(long, default_value_t = 1.0)]
+    retry_delay: f64,
+
+    /// Send the cookies in this file (Netscape `cookies.txt` format, as
+    /// exported by browser extensions) with every request, e.g. to use the
+    /// session of a browser logged in to the site
+    #[arg(long)]
+    cookies_file: Option<PathBuf>,
+
+    /// Send the site's cookies from this browser cookie database (Firefox's
+    /// `cookies.sqlite` or Chrome's `Cookies` file in the browser profile),
+    /// e.g. after passing a Cloudflare challenge in the browser
+    #[arg(long)]
+    browser_cookies: Option<PathBuf>,
+
+    /// Send this `cf_clearance` cookie, copied from a browser that passed
+    /// the site's Cloudflare challenge. Use the browser's `--user-agent`
+    /// with it
+    #[arg(long)]
+    cf_clearance: Option<String>,
+
+    /// Send every request through this proxy, e.g. `http://proxy:8080` or
+    /// `socks5h://127.0.0.1:1080`. Without it the `HTTPS_PROXY` and
+    /// `HTTP_PROXY` environment variables are used
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Milliseconds to wait between requests, shared by all concurrent
+    /// chapter fetches. Slows a download down in exchange for staying well
+    /// under the site's rate limits
+    #[arg(long, default_value_t = 0)]
+    delay: u64,
+
+    /// With `--delay`, how many requests may go out back to back after a
+    /// pause before the delay applies
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    burst: u32,
+
+    /// Send at most this many requests a minute to any one host, counting
+    /// those of other rustyroad runs before and alongside this one, e.g.
+    /// when a script downloads story after story. 0 doesn't limit them
+    #[arg(long, value_name = "PER_MINUTE", default_value_t = 0)]
+    rate_limit: u32,
+
+    /// Requests-per-minute limits for particular hosts, from the config
+    /// file's `rate-limits`.
+    #[arg(skip)]
+    host_rate_limits: BTreeMap<String, u32>,
+
+    /// Keep to each site's robots.txt, for large archival jobs: pages it
+    /// disallows fail instead of being fetched, requests to a site wait the
+    /// `Crawl-delay` it asks for (or a second), at most two go out at once,
+    /// and the default User-Agent says what they are for
+    #[arg(long)]
+    polite: bool,
+
+    /// Stop once this much has been downloaded, e.g. `500M`, to spare a
+    /// metered connection: the chapters being fetched are finished and an
+    /// incomplete book is written. On a terminal, asks first whether to
+    /// download as much again
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_download_size: Option<u64>,
+
+    /// Log in to Royal Road with this account email before downloading, to
+    /// get advance chapters and stories only open to the account. The
+    /// password is taken from `--password` or `RUSTYROAD_PASSWORD`
+    #[arg(long)]
+    username: Option<String>,
+
+    /// The password of the `--username` account. Other users of the
+    /// machine can read a password given here in the process list (`ps`),
+    /// so prefer setting `RUSTYROAD_PASSWORD`
+    #[arg(long, requires = "username")]
+    password: Option<String>,
+
+    /// How many requests may be out at once, set from `--story-concurrent`.
+    #[arg(skip)]
+    max_in_flight: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildArgs {
+    /// Directory of raw pages saved with `--save-raw`
+    #[arg(long)]
+    raw_dir: PathBuf,
+
+    /// Story metadata saved with `--metadata-json`
+    #[arg(long)]
+    metadata: PathBuf,
+
+    #[command(flatten)]
+    book: BookArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct RebuildArgs {
+    /// The story: its URL or Royal Road number, or a book written from it
+    story: String,
+
+    /// Only put these chapters in the book, e.g. `1-50,60,70-`
+    #[arg(long, conflicts_with_all = ["from", "to"])]
+    chapters: Option<String>,
+
+    /// The first chapter to put in the book
+    #[arg(long)]
+    from: Option<usize>,
+
+    /// The last chapter to put in the book
+    #[arg(long)]
+    to: Option<usize>,
+
+    /// The cache the story was downloaded through. Default: the user's
+    /// cache directory, e.g. `~/.cache/rustyroad`
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    book: BookArgs,
+}
+
+// Options controlling how the book is put together, shared by every command
+// that writes one.
+#[derive(clap::Args, Debug, Clone)]
+struct BookArgs {
+    /// Where to write the book. May be a template filled in from the
+    /// story, e.g. `{title} - {author}.{ext}`. Required unless several
+    /// stories are downloaded at once
+    #[arg(short, long)]
+    out: Option<String>,
+
+    /// Replace the book at --out if there is one already. `update` always
+    /// replaces the book it updates
+    #[arg(long)]
+    force: bool,
+
+    /// Write the same book, byte for byte, every time for the same story
+    /// and chapters: dated by `SOURCE_DATE_EPOCH` or the latest chapter
+    /// instead of today, with an identifier made from the story
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Directory to write the book to. `--out` is relative to it
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// The format of the book written to `--out`
+    #[arg(long, value_enum, default_value_t = Format::Epub)]
+    format: Format,
+
+    /// The EPUB version of EPUB and kepub books. EPUB 3 books have a
+    /// navigation document and landmarks, pop-up footnotes and collapsible
+    /// spoilers
+    #[arg(long, value_enum, default_value_t = EpubVersion::V2)]
+    epub_version: EpubVersion,
+
+    /// The text-to-speech engine `--format audiobook` reads the story out
+    /// with
+    #[arg(long, value_enum, value_name = "ENGINE", default_value_t = TtsEngine::Espeak)]
+    tts: TtsEngine,
+
+    /// The voice audiobooks are read in: an espeak voice like `en-us`, the
+    /// model file for piper, or what the HTTP API calls it
+    #[arg(long, value_name = "VOICE")]
+    tts_voice: Option<String>,
+
+    /// The HTTP text-to-speech API for `--tts http`, posted the text as
+    /// `{"text": ..., "voice": ...}` and answering with the audio
+    #[arg(long, value_name = "URL")]
+    tts_url: Option<String>,
+
+    /// Keep the table of contents page in EPUB 3 books, for readers that
+    /// don't show the navigation document. EPUB 2 books always have one
+    #[arg(long)]
+    inline_toc: bool,
+
+    /// List each chapter's word count next to it on the table of contents
+    /// page of EPUB and kepub books
+    #[arg(long)]
+    toc_word_counts: bool,
+
+    /// Print the word count and reading time of every chapter, and of the
+    /// whole book, once it is written
+    #[arg(long)]
+    stats: bool,
+
+    /// List the chapters of EPUB and kepub books under their volume in the
+    /// table of contents: the volume the site files them under, or else one
+    /// named in their titles, as in `Volume 2, Chapter 13` or `[Arc 3]`
+    #[arg(long)]
+    toc_volumes: bool,
+
+    /// Tell the chapters' volumes from their titles with this regex, for
+    /// --toc-volumes and --split-by-volume. The group named `volume`, or the
+    /// first group, names the volume; chapters it doesn't match stay in the
+    /// volume before them
+    #[arg(long, value_name = "REGEX")]
+    volume_regex: Option<Regex>,
+
+    /// What to do with interludes, side stories and bonus chapters, told
+    /// from their titles
+    #[arg(long, value_enum, default_value_t = SideChapters::Keep)]
+    side_chapters: SideChapters,
+
+    /// Tell the side chapters for --side-chapters by their titles matching
+    /// this regex, in place of `Interlude`, `Side Story`, `Bonus` and the
+    /// like
+    #[arg(long, value_name = "REGEX")]
+    side_chapter_regex: Option<Regex>,
+
+    /// JSON file mapping chapter indices (1-based) or original titles to
+    /// replacement chapter titles
+    #[arg(long)]
+    title_map: Option<String>,
+
+    /// Title the chapters after this template, in their pages and the table
+    /// of contents: `{index}` is the chapter's number in the book, from 1,
+    /// and `{title}` its title, e.g. `{index}. {title}`
+    #[arg(long, value_name = "TEMPLATE")]
+    chapter_title_template: Option<String>,
+
+    /// Put the chapter's title at the top of every chapter whose text
+    /// doesn't start with a heading, as Royal Road's often don't
+    #[arg(long)]
+    chapter_headings: bool,
+
+    /// Remove the promotional blocks at the start and end of every chapter:
+    /// Patreon and Ko-fi links, Discord invites, "read ahead on..." and
+    /// the like
+    #[arg(long)]
+    strip_plugs: bool,
+
+    /// Take the blocks matching this regex for plugs too, with
+    /// --strip-plugs. May be given more than once
+    #[arg(long, value_name = "REGEX")]
+    plug_pattern: Vec<Regex>,
+
+    /// Mark the book as written in this language, e.g. `de` or `pt-BR`,
+    /// rather than the one its description reads as
+    #[arg(long, value_name = "TAG", value_parser = parse_language)]
+    language: Option<String>,
+
+    /// Replace scene break markers (`* * *`, `~~~`, repeated horizontal
+    /// rules, ...) with one consistently styled break
+    #[arg(long)]
+    normalize_breaks: bool,
+
+    /// Normalize quotes and dashes in the chapter text
+    #[arg(long, value_enum, default_value_t = Typography::Off)]
+    typography: Typography,
+
+    /// Curly quotes, em dashes for `--` and ellipses for `...` in the
+    /// chapter text, leaving code alone. The same as `--typography smart`
+    #[arg(long, conflicts_with = "typography")]
+    smart_punctuation: bool,
+
+    /// How tables are written: `fit` keeps them, styled to fit the page;
+    /// `text` writes those too wide for a small screen as preformatted text
+    #[arg(long, value_enum, default_value_t = TableMode::Fit)]
+    table_mode: TableMode,
+
+    /// What to do with translator notes ("TL Note:", "T/N:", translator
+    /// note boxes). Handled independently of author notes
+    #[arg(long, value_enum, default_value_t = NotePolicy::Keep)]
+    translator_notes: NotePolicy,
+
+    /// Keep the author's notes before and after each chapter, set apart at
+    /// the start and end of the chapter
+    #[arg(long)]
+    include_authors_notes: bool,
+
+    /// Drop the author's notes (the default)
+    #[arg(long, conflicts_with = "include_authors_notes")]
+    exclude_authors_notes: bool,
+
+    /// Keep the chapter markup as the site serves it, instead of stripping
+    /// scripts, tracking pixels, event handlers and any other elements and
+    /// attributes e-readers may choke on
+    #[arg(long)]
+    no_sanitize: bool,
+
+    /// Leave the chapter text's characters as the site serves them, instead
+    /// of decoding stray entities like `&nbsp;`, repairing mojibake like
+    /// `donâ€™t`, dropping zero-width spaces, collapsing runs of spaces and
+    /// composing accents to NFC
+    #[arg(long)]
+    no_normalize_text: bool,
+
+    /// Strip the site's classes and inline styles from the chapters, which
+    /// bloat the book and fight its stylesheet, and unwrap the spans and
+    /// divs that only held them. rustyroad's own classes and `text-align`
+    /// are kept
+    #[arg(long)]
+    strip_styles: bool,
+
+    /// A class --strip-styles keeps, e.g. one your own --css styles.
+    /// Give it more than once for several
+    #[arg(long, value_name = "CLASS")]
+    keep_class: Vec<String>,
+
+    /// A CSS property --strip-styles keeps in inline styles, e.g. `color`.
+    /// Give it more than once for several
+    #[arg(long, value_name = "PROPERTY")]
+    keep_style: Vec<String>,
+
+    /// Fetch chapters that link to the same page with different `#anchors`
+    /// separately, each getting the whole page, instead of fetching the page
+    /// once and splitting it at the anchors
+    #[arg(long)]
+    no_anchor_split: bool,
+
+    /// Download from a site rustyroad has no backend for, best-effort:
+    /// `--url` is the first chapter, the others are found by following the
+    /// link to the next one, and each chapter's text is guessed from its
+    /// page. Also reads pages saved that way for `build`
+    #[arg(long)]
+    generic: bool,
+
+    /// Use this image, a local file or a URL, as the cover instead of the
+    /// story's. JPEG, PNG, GIF and WebP images are supported, and AVIF ones
+    /// when built with the `avif` feature
+    #[arg(long, value_name = "PATH_OR_URL")]
+    cover: Option<String>,
+
+    /// Leave the cover out
+    #[arg(long, conflicts_with = "cover")]
+    no_cover: bool,
+
+    /// Use a plain cover with the title and author instead of the story's.
+    /// Stories without a cover get one anyway
+    #[arg(long, conflicts_with = "cover")]
+    generate_cover: bool,
+
+    /// Use this stylesheet instead of the built-in one
+    #[arg(long, value_name = "PATH")]
+    css: Option<PathBuf>,
+
+    /// Run this Rhai script on every chapter before it is added. Its
+    /// `fn chapter(title, html)` can change the chapter's title and content
+    /// or leave the chapter out
+    #[arg(long, value_name = "PATH")]
+    script: Option<PathBuf>,
+
+    /// Bundle this font file (.ttf, .otf, .woff or .woff2) with the book and
+    /// declare it with `@font-face`. Give it once for every face; the
+    /// family and face come from the file name, e.g.
+    /// `Literata-BoldItalic.ttf`. The built-in stylesheet sets the text in
+    /// the first font, a `--css` of your own refers to the families itself
+    #[arg(long, value_name = "PATH")]
+    embed_font: Vec<PathBuf>,
+
+    /// Scale the cover and images wider than this many pixels down to this
+    /// width
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_image_width: Option<u32>,
+
+    /// Recompress JPEG covers and images at this quality, from 1 to 100.
+    /// Images are only replaced if that makes them smaller
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: Option<u8>,
+
+    /// Deflate the entries of EPUB, kepub and AZW3 books at this level,
+    /// from 0, which stores them uncompressed, to 9, the smallest and
+    /// slowest
+    #[arg(long, value_name = "LEVEL", default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    compression: u32,
+
+    /// Store JPEG, PNG, GIF and WebP images uncompressed, since they are
+    /// compressed already
+    #[arg(long)]
+    store_images: bool,
+
+    /// Add a page at the end of the book with a QR code linking to the story
+    #[arg(long)]
+    qr_source: bool,
+
+    /// Add a colophon at the end of the book with the source, the author,
+    /// the chapter count, the date it was downloaded and a notice that it
+    /// is not for redistribution
+    #[arg(long)]
+    colophon: bool,
+
+    /// The colophon's text, with `{title}`, `{author}`, `{url}`, `{date}`
+    /// and `{chapters}` filled in. Blank lines separate paragraphs
+    #[arg(long, value_name = "PATH")]
+    colophon_template: Option<PathBuf>,
+
+    /// Reopen the generated EPUB and check that its structure is intact
+    #[arg(long)]
+    verify: bool,
+
+    /// Like --verify, and also check that every chapter is well-formed
+    /// XHTML and the images it shows are in the book, and with epubcheck
+    /// if it is on the PATH
+    #[arg(long)]
+    validate: bool,
+
+    /// A shell command to run for every file written once the book is
+    /// done, e.g. `calibredb add {file}`. `{file}` is replaced by the
+    /// file's path, quoted for the shell, which is also in `RUSTYROAD_BOOK`.
+    /// A failing command fails the run
+    #[arg(long, value_name = "COMMAND")]
+    exec: Option<String>,
+
+    /// Leave the cover, title, front matter and source pages out of the
+    /// table of contents. They stay in reading order, and readers that
+    /// build their own navigation from the guide (e.g. Kindle's "Go to
+    /// Cover") can still find them
+    #[arg(long)]
+    hide_extras_in_toc: bool,
+
+    /// Add a title page after the cover, with the title, author and the
+    /// story's tags
+    #[arg(long)]
+    title_page: bool,
+
+    /// Add a page before the table of contents with the story's
+    /// description, tags, word count, source and the date it was downloaded
+    #[arg(long)]
+    front_matter: bool,
+
+    /// Write the story as several books, starting a new volume every this
+    /// many chapters (e.g. `500`) or, with a `w`, once a volume has this
+    /// many words (e.g. `250000w`). Volumes are named like `Story Vol. 2.epub`
+    #[arg(long, value_name = "N")]
+    split_every: Option<String>,
+
+    /// Write a book for every volume the author filed the chapters under
+    #[arg(long, conflicts_with = "split_every")]
+    split_by_volume: bool,
+
+    /// Write a book for every part of the story this regex names in the
+    /// chapter titles, e.g. `Arc (\d+)`: its group named `volume`, or its
+    /// first group, names the part. Parts numbered in the titles keep their
+    /// number as the book's series index
+    #[arg(
+        long,
+        value_name = "REGEX",
+        conflicts_with_all = ["split_every", "split_by_volume"]
+    )]
+    split_by_regex: Option<Regex>,
+
+    /// The series the book belongs to, for Calibre and readers that group
+    /// books by series. Split books default to the story's title
+    #[arg(long)]
+    series: Option<String>,
+
+    /// The book's position in --series, e.g. `2`. Split books number their
+    /// volumes from it
+    #[arg(long, requires = "series", default_value_t = 1.0)]
+    series_index: f64,
+
+    /// The `[replacements]` of the config file, with the story's own.
+    #[arg(skip)]
+    replacements: Replacements,
+}
+
+impl BookArgs {
+    fn template(&self) -> Result<&str> {
+        self.out
+            .as_deref()
+            .ok_or_else(|| eyre!("--out is required"))
+    }
+
+    fn in_output_dir(&self, name: &str) -> Result<String> {
+        match &self.output_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)
+                    .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+                Ok(dir.join(name).to_string_lossy().into_owned())
+            }
+            None => Ok(name.to_string()),
+        }
+    }
+
+    /// The book's path, for when it is needed before the story is known.
+    fn out(&self, template: &str) -> Result<String> {
+        let out = template;
+        if is_template(out) {
+            return Err(eyre!(
+                "--out can't be a template here, give the path of the book"
+            ));
+        }
+        self.in_output_dir(out)
+    }
+
+    /// The book's path, with the `template` filled in from `story`.
+    fn out_for(&self, template: &str, story: &Story) -> Result<String> {
+        self.in_output_dir(&render_template(template, story, self.format)?)
+    }
+
+    /// Fails if the book would replace one at `out`, unless `--force` was
+    /// given.
+    fn check_overwrite(&self, out: &str) -> Result<()> {
+        if self.force {
+            return Ok(());
+        }
+        let path = match self.split()? {
+            Some(_) => volume_path(Path::new(out), 1),
+            None => PathBuf::from(out),
+        };
+        if path.exists() {
+            return Err(eyre!(
+                "{} already exists, give --force to replace it",
+                path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    fn split(&self) -> Result<Option<Split>> {
+        if self.split_by_volume {
+            return Ok(Some(Split::Volume));
+        }
+        if let Some(pattern) = &self.split_by_regex {
+            return Ok(Some(Split::Pattern(pattern.clone())));
+        }
+        self.split_every.as_deref().map(Split::every).transpose()
+    }
+
+    fn series(&self) -> Option<Series> {
+        Some(Series {
+            name: self.series.clone()?,
+            index: self.series_index,
+        })
+    }
+
+    fn tts(&self, client: Option<&Client>) -> TtsOpts {
+        TtsOpts {
+            engine: self.tts,
+            voice: self.tts_voice.clone(),
+            url: self.tts_url.clone(),
+            client: client.cloned(),
+        }
+    }
+
+    fn image_opts(&self) -> ImageOpts {
+        ImageOpts {
+            max_width: self.max_image_width,
+            quality: self.image_quality,
+        }
+    }
+
+    fn compression(&self) -> Compression {
+        Compression {
+            level: self.compression,
+            store_images: self.store_images,
+        }
+    }
+
+    /// The stylesheet and fonts given with `--css` and `--embed-font`, if
+    /// any.
+    fn style(&self) -> Result<Option<Style>> {
+        if self.css.is_none() && self.embed_font.is_empty() {
+            return Ok(None);
+        }
+        Style::load(self.css.as_deref(), &self.embed_font).map(Some)
+    }
+
+    /// The colophon to add with `--colophon`, if any.
+    fn colophon(&self) -> Result<Option<Colophon>> {
+        if !self.colophon {
+            return Ok(None);
+        }
+        Colophon::load(self.colophon_template.as_deref()).map(Some)
+    }
+
+    /// The script given with `--script`, if any.
+    fn script(&self) -> Result<Option<ChapterScript>> {
+        self.script.as_deref().map(ChapterScript::load).transpose()
+    }
+
+    fn clean_opts(&self) -> CleanOpts {
+        CleanOpts {
+            normalize_text: !self.no_normalize_text,
+            normalize_breaks: self.normalize_breaks,
+            typography: self.typography,
+            translator_notes: self.translator_notes,
+            authors_notes: self.include_authors_notes && !self.exclude_authors_notes,
+            strip_plugs: self.strip_plugs,
+            plug_patterns: self.plug_pattern.clone(),
+            sanitize: !self.no_sanitize,
+            // EPUB 2 has no <details>
+            spoilers: match self.format {
+                Format::Html => Spoilers::Details,
+                Format::Epub | Format::Kepub if self.epub3() => Spoilers::Details,
+                _ => Spoilers::Inline,
+            },
+            footnotes: match self.format {
+                Format::Epub | Format::Kepub if self.epub3() => Footnotes::Epub3,
+                _ => Footnotes::Section,
+            },
+            tables: self.table_mode,
+            generic: self.generic,
+            strip_styles: self.strip_styles,
+            keep_classes: self.keep_class.clone(),
+            keep_styles: self.keep_style.clone(),
+        }
+    }
+
+    fn epub3(&self) -> bool {
+        self.epub_version == EpubVersion::V3
+    }
+
+    /// Whether the book gets a table of contents page. EPUB 3 books have
+    /// the navigation document instead, unless asked for both.
+    fn inline_toc(&self) -> bool {
+        !self.epub3() || self.inline_toc
+    }
+
+    /// Files the chapters under the volumes their titles name, with
+    /// --toc-volumes or --volume-regex.
+    fn detect_volumes(&self, chapters: &mut [Chapter]) {
+        if !self.toc_volumes && self.volume_regex.is_none() {
+            return;
+        }
+        let volumes = detect_volumes(chapters, self.volume_regex.as_ref());
+        if volumes == 0 {
+            log::warn!("found no volumes to group the chapters under");
+        } else {
+            log::info!("grouping the chapters under {} volumes", volumes);
+        }
+    }
+
+    /// Moves the side chapters out of the main story's way, or leaves them
+    /// out, as --side-chapters says.
+    fn arrange_side_chapters(&self, chapters: &mut Vec<Chapter>) -> Result<()> {
+        if self.side_chapters == SideChapters::Keep {
+            return Ok(());
+        }
+        let pattern = self
+            .side_chapter_regex
+            .as_ref()
+            .unwrap_or_else(|| side_chapter_regex());
+        let total = chapters.len();
+        let side = arrange_side_chapters(chapters, self.side_chapters, pattern);
+        if chapters.is_empty() {
+            return Err(eyre!("all {} chapters are side chapters", total));
+        }
+        match self.side_chapters {
+            _ if side == 0 => log::info!("found no side chapters"),
+            SideChapters::Exclude => log::info!("leaving out {} side chapter(s)", side),
+            _ => log::info!("moving {} side chapter(s) after the main story", side),
+        }
+        Ok(())
+    }
+
+    /// The chapters to fetch with each page request, by index.
+    fn page_groups(&self, chapters: &[Chapter]) -> Vec<Vec<usize>> {
+        if self.no_anchor_split {
+            return (0..chapters.len()).map(|i| vec![i]).collect();
+        }
+
+        let groups = shared_pages(chapters);
+        if groups.len() < chapters.len() {
+            log::info!(
+                "{} chapter(s) share a page with other chapters; splitting those pages at their anchors",
+                chapters.len() - groups.iter().filter(|g| g.len() == 1).count()
+            );
+        }
+        groups
+    }
+
+    fn title_map(&self) -> Result<Option<TitleMap>> {
+        self.title_map.as_ref().map(TitleMap::load).transpose()
+    }
+
+    fn title_template(&self) -> Result<Option<TitleTemplate>> {
+        self.chapter_title_template
+            .as_deref()
+            .map(TitleTemplate::parse)
+            .transpose()
+    }
+
+    /// Settles the language `story` is marked as: --language, or what the
+    /// story reads as.
+    fn set_language(&self, story: &mut Story) {
+        let language = match &self.language {
+            Some(language) => language.clone(),
+            None => detect_language(story),
+        };
+        log::debug!("marking the book as {:?}", language);
+        story.language = Some(language);
+    }
+
+    /// `content` with `chapter`'s title as a heading on top, with
+    /// --chapter-headings, unless it starts with a heading already.
+    fn with_heading(&self, chapter: &Chapter, content: String) -> String {
+        if !self.chapter_headings || starts_with_heading(&content) {
+            return content;
+        }
+        format!("<h1>{}</h1>\n{}", escape(&chapter.name), content)
+    }
+}
+
+/// The config file, and which options were given on the command line and so
+/// win over it.
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    config: Config,
+    given: HashSet<String>,
+}
+
+impl Settings {
+    fn new(config: Config, matches: &ArgMatches) -> Settings {
+        let given = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        Settings { config, given }
+    }
+
+    fn given(&self, id: &str) -> bool {
+        self.given.contains(id)
+    }
+
+    /// Sets the option `id` to `value` from the config, unless it was given
+    /// on the command line.
+    fn set<T: Clone>(&self, id: &str, field: &mut T, value: &Option<T>) {
+        if let Some(value) = value {
+            if !self.given(id) {
+                *field = value.clone();
+            }
+        }
+    }
+
+    fn set_some<T: Clone>(&self, id: &str, field: &mut Option<T>, value: &Option<T>) {
+        if value.is_some() && !self.given(id) {
+            *field = value.clone();
+        }
+    }
+}
+
+impl NetArgs {
+    fn apply_config(&mut self, settings: &Settings) {
+        let config = &settings.config;
+        settings.set("user_agent", &mut self.user_agent, &config.user_agent);
+        settings.set_some("cookies_file", &mut self.cookies_file, &config.cookies_file);
+        settings.set_some(
+            "browser_cookies",
+            &mut self.browser_cookies,
+            &config.browser_cookies,
+        );
+        settings.set_some("cf_clearance", &mut self.cf_clearance, &config.cf_clearance);
+        settings.set_some("proxy", &mut self.proxy, &config.proxy);
+        settings.set("max_retries", &mut self.max_retries, &config.max_retries);
+        settings.set("timeout", &mut self.timeout, &config.timeout);
+        settings.set(
+            "connect_timeout",
+            &mut self.connect_timeout,
+            &config.connect_timeout,
+        );
+        settings.set("delay", &mut self.delay, &config.delay);
+        settings.set("burst", &mut self.burst, &config.burst);
+        settings.set("rate_limit", &mut self.rate_limit, &config.rate_limit);
+        self.host_rate_limits = config.rate_limits.clone();
+        settings.set("polite", &mut self.polite, &config.polite);
+        settings.set_some(
+            "max_download_size",
+            &mut self.max_download_size,
+            &config.max_download_size,
+        );
+    }
+}
+
+impl BookArgs {
+    fn apply_config(&mut self, settings: &Settings) {
+        let config = &settings.config;
+        settings.set_some("output_dir", &mut self.output_dir, &config.output_dir);
+        settings.set("format", &mut self.format, &config.format);
+        settings.set("epub_version", &mut self.epub_version, &config.epub_version);
+        settings.set("tts", &mut self.tts, &config.tts);
+        settings.set_some("tts_voice", &mut self.tts_voice, &config.tts_voice);
+        settings.set_some("tts_url", &mut self.tts_url, &config.tts_url);
+        if self.smart_punctuation {
+            self.typography = Typography::Smart;
+        } else {
+            settings.set("typography", &mut self.typography, &config.typography);
+        }
+        settings.set("table_mode", &mut self.table_mode, &config.table_mode);
+        settings.set_some("css", &mut self.css, &config.css);
+        settings.set_some("script", &mut self.script, &config.script);
+        settings.set_some(
+            "colophon_template",
+            &mut self.colophon_template,
+            &config.colophon_template,
+        );
+        settings.set_some("exec", &mut self.exec, &config.exec);
+        settings.set("reproducible", &mut self.reproducible, &config.reproducible);
+        settings.set_some(
+            "chapter_title_template",
+            &mut self.chapter_title_template,
+            &config.chapter_title_template,
+        );
+        settings.set(
+            "chapter_headings",
+            &mut self.chapter_headings,
+            &config.chapter_headings,
+        );
+        settings.set(
+            "side_chapters",
+            &mut self.side_chapters,
+            &config.side_chapters,
+        );
+        settings.set_some(
+            "side_chapter_regex",
+            &mut self.side_chapter_regex,
+            &config.side_chapter_regex,
+        );
+        settings.set("strip_plugs", &mut self.strip_plugs, &config.strip_plugs);
+        settings.set(
+            "plug_pattern",
+            &mut self.plug_pattern,
+            &config.plug_patterns,
+        );
+        settings.set("embed_font", &mut self.embed_font, &config.embed_font);
+        settings.set("strip_styles", &mut self.strip_styles, &config.strip_styles);
+        settings.set("keep_class", &mut self.keep_class, &config.keep_classes);
+        settings.set("keep_style", &mut self.keep_style, &config.keep_styles);
+        settings.set_some(
+            "max_image_width",
+            &mut self.max_image_width,
+            &config.max_image_width,
+        );
+        settings.set_some(
+            "image_quality",
+            &mut self.image_quality,
+            &config.image_quality,
+        );
+        settings.set("compression", &mut self.compression, &config.compression);
+        settings.set("store_images", &mut self.store_images, &config.store_images);
+        self.replacements = config.replacements.clone();
+    }
+
+    fn apply_story(&mut self, settings: &Settings, story: &StoryConfig) {
+        settings.set_some("out", &mut self.out, &story.out);
+        settings.set("format", &mut self.format, &story.format);
+        settings.set_some("title_map", &mut self.title_map, &story.title_map);
+        settings.set_some("cover", &mut self.cover, &story.cover);
+        if !self.smart_punctuation {
+            settings.set("typography", &mut self.typography, &story.typography);
+        }
+        if !settings.given("exclude_authors_notes") {
+            settings.set(
+                "include_authors_notes",
+                &mut self.include_authors_notes,
+                &story.include_authors_notes,
+            );
+        }
+        settings.set_some("series", &mut self.series, &story.series);
+        settings.set("series_index", &mut self.series_index, &story.series_index);
+        settings.set_some("language", &mut self.language, &story.language);
+        settings.set("generic", &mut self.generic, &story.generic);
+        settings.set(
+            "side_chapters",
+            &mut self.side_chapters,
+            &story.side_chapters,
+        );
+        settings.set_some(
+            "side_chapter_regex",
+            &mut self.side_chapter_regex,
+            &story.side_chapter_regex,
+        );
+        self.replacements = settings.config.replacements.with(&story.replacements);
+    }
+}
+
+impl FetchArgs {
+    /// Where the untouched chapter pages and cover go: `--save-raw`, or the
+    /// `--archive`.
+    fn raw_dir(&self) -> Option<&PathBuf> {
+        self.save_raw.as_ref().or(self.archive.as_ref())
+    }
+
+    fn filters_titles(&self) -> bool {
+        self.exclude_title_regex.is_some() || self.include_title_regex.is_some()
+    }
+
+    /// Whether the chapter titled `name` gets past `--exclude-title-regex`
+    /// and `--include-title-regex`.
+    fn title_allowed(&self, name: &str) -> bool {
+        self.exclude_title_regex
+            .as_ref()
+            .is_none_or(|regex| !regex.is_match(name))
+            && self
+                .include_title_regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(name))
+    }
+
+    fn apply_config(&mut self, settings: Settings) {
+        let config = &settings.config;
+        settings.set("concurrent", &mut self.concurrent, &config.concurrent);
+        settings.set(
+            "story_concurrent",
+            &mut self.story_concurrent,
+            &config.story_concurrent,
+        );
+        settings.set_some("cache_dir", &mut self.cache_dir, &config.cache_dir);
+        settings.set_some(
+            "cache_max_size",
+            &mut self.cache_max_size,
+            &config.cache_max_size,
+        );
+        settings.set_some("library", &mut self.library, &config.library);
+        self.net.apply_config(&settings);
+        self.book.apply_config(&settings);
+        self.settings = settings;
+    }
+}
+
+impl DownloadArgs {
+    /// The options for the story at `url`, with its overrides from the
+    /// config file.
+    fn for_story(&self, url: &str) -> Cow<'_, DownloadArgs> {
+        let settings = &self.fetch.settings;
+        let Some(story) = settings.config.story(url) else {
+            return Cow::Borrowed(self);
+        };
+
+        let mut args = self.clone();
+        if !["chapters", "from", "to"]
+            .iter()
+            .any(|id| settings.given(id))
+        {
+            settings.set_some("chapters", &mut args.fetch.chapters, &story.chapters);
+        }
+        settings.set_some(
+            "next_selector",
+            &mut args.fetch.next_selector,
+            &story.next_selector,
+        );
+        args.fetch.book.apply_story(settings, story);
+        Cow::Owned(args)
+    }
+}
+
+fn write_book(builder: &mut dyn BookWriter, out: &str) -> Result<()> {
+    builder.write_to(Path::new(out))
+}
+
+/// A writer for the book of `story`. `client` reaches the audiobook's
+/// HTTP TTS API, if there is one to reach it with.
+fn new_builder(
+    story: &Story,
+    book: &BookArgs,
+    client: Option<&Client>,
+) -> Result<Box<dyn BookWriter>> {
+    let format = book.format;
+    let mut builder: Box<dyn BookWriter> = match book.split()? {
+        Some(split) => {
+            let book = book.clone();
+            let client = client.cloned();
+            Box::new(SplitWriter::new(story, split, move |story| {
+                format_builder(story, &book, client.as_ref())
+            }))
+        }
+        None => format_builder(story, book, client)?,
+    };
+    if let Some(series) = book.series() {
+        builder.set_series(&series);
+    }
+    if let Some(style) = book.style()? {
+        if matches!(
+            format,
+            Format::Fb2 | Format::Markdown | Format::Txt | Format::Audiobook
+        ) {
+            log::warn!(
+                "{} books have no stylesheet, ignoring --css and --embed-font",
+                format.name()
+            );
+        }
+        builder.set_style(&style);
+    }
+    // a mistake in the colophon template fails before the chapters are
+    // downloaded rather than after
+    book.colophon()?;
+    Ok(builder)
+}
+
+/// The volume the story's pages are filed under with `--include-pages`.
+const PAGES_VOLUME: &str = "Pages";
+
+/// Adds the story's pages after its chapters, under [`PAGES_VOLUME`].
+fn add_pages(story: &mut Story) {
+    if story.pages.is_empty() {
+        log::info!("{} has no pages besides its chapters", story.title);
+        return;
+    }
+    log::info!("adding {} page(s) after the chapters", story.pages.len());
+    let pages = story.pages.iter().cloned().map(|page| Chapter {
+        volume: Some(PAGES_VOLUME.to_string()),
+        ..page
+    });
+    story.chapters.extend(pages);
+}
+
+/// Whether the table of contents lists the chapters under their volumes:
+/// when asked to, and for the sections of the story's pages and side
+/// chapters.
+fn nested_toc(story: &Story, book: &BookArgs) -> bool {
+    book.toc_volumes
+        || book.volume_regex.is_some()
+        || story
+            .chapters
+            .iter()
+            .any(|chapter| matches!(chapter.volume.as_deref(), Some(PAGES_VOLUME | SIDE_VOLUME)))
+}
+
+fn format_builder(
+    story: &Story,
+    book: &BookArgs,
+    client: Option<&Client>,
+) -> Result<Box<dyn BookWriter>> {
+    Ok(match book.format {
+        Format::Epub => {
+            let mut writer = EpubWriter::new(story)?;
+            writer.set_version(book.epub_version);
+            if book.toc_word_counts {
+                writer.set_toc_word_counts();
+            }
+            if nested_toc(story, book) {
+                writer.set_nested_toc();
+            }
+            writer.set_compression(book.compression());
+            if book.reproducible {
+                writer.set_reproducible(story);
+            }
+            Box::new(writer)
+        }
+        Format::Fb2 => {
+            let mut writer = Fb2Builder::new(story);
+            if book.reproducible {
+                writer.set_reproducible(story);
+            }
+            Box::new(writer)
+        }
+        Format::Azw3 => {
+            let mut writer = Azw3Writer::new(story)?;
+            writer.set_version(book.epub_version);
+            if book.toc_word_counts {
+                writer.set_toc_word_counts();
+            }
+            if nested_toc(story, book) {
+                writer.set_nested_toc();
+            }
+            writer.set_compression(book.compression());
+            if book.reproducible {
+                writer.set_reproducible(story);
+            }
+            Box::new(writer)
+        }
+        Format::Kepub => {
+            let mut writer = KepubWriter::new(story)?;
+            writer.set_version(book.epub_version);
+            if book.toc_word_counts {
+                writer.set_toc_word_counts();
+            }
+            if nested_toc(story, book) {
+                writer.set_nested_toc();
+            }
+            writer.set_compression(book.compression());
+            if book.reproducible {
+                writer.set_reproducible(story);
+            }
+            Box::new(writer)
+        }
+        Format::Html | Format::Markdown | Format::Txt => {
+            let mut writer = match book.format {
+                Format::Html => ExportWriter::html(story),
+                Format::Markdown => ExportWriter::markdown(story),
+                _ => ExportWriter::text(story),
+            };
+            if book.reproducible {
+                writer.set_reproducible(story);
+            }
+            Box::new(writer)
+        }
+        Format::Audiobook => Box::new(AudiobookWriter::new(story, &book.tts(client))?),
+    })
+}
+
+fn verify(out: &str, format: Format, validate: bool) -> Result<()> {
+    log::info!("verifying {}...", format.name());
+    let report = match format {
+        Format::Epub | Format::Kepub if validate => validate_epub(out)?,
+        Format::Epub | Format::Kepub => verify_epub(out)?,
+        Format::Fb2 => verify_fb2(out)?,
+        Format::Azw3 | Format::Html | Format::Markdown | Format::Txt | Format::Audiobook => {
+            log::warn!("--verify doesn't check {} books", format.name());
+            return Ok(());
+        }
+    };
+    for check in &report.checks {
+        log::info!("checked: {}", check);
+    }
+    for problem in &report.problems {
+        log::error!("verify: {}", problem);
+    }
+    if !report.is_ok() {
+        return Err(eyre!(
+            "verification of {} found {} problem(s)",
+            out,
+            report.problems.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// How fetching a book's chapters went, for [`finish_book`].
+struct Fetched<'a> {
+    result: Result<()>,
+    added: usize,
+    /// The chapters replaced by placeholders with `--skip-failed`.
+    skipped: &'a [usize],
+}
+
+/// Adds the back matter and writes the book once all chapters are in.
+/// `result` is the outcome of adding the chapters. Returns the files
+/// written, which are several for a split book.
+fn finish_book(
+    mut builder: Box<dyn BookWriter>,
+    story: &Story,
+    book: &BookArgs,
+    out: &str,
+    fetched: Fetched,
+    reception: Option<&Reception>,
+    partial_on_error: bool,
+) -> Result<Vec<PathBuf>> {
+    let Fetched {
+        result,
+        added,
+        skipped,
+    } = fetched;
+    let total = story.chapters.len();
+    let result = result.and_then(|_| {
+        if let Some(reception) = reception {
+            builder.add_reviews(reception, !book.hide_extras_in_toc)?;
+        }
+        if book.qr_source {
+            builder.add_source_page(story, !book.hide_extras_in_toc)?;
+        }
+        if let Some(colophon) = book.colophon()? {
+            let paragraphs = colophon.render(story, added, book.reproducible)?;
+            builder.add_colophon(&paragraphs, !book.hide_extras_in_toc)?;
+        }
+        Ok(())
+    });
+
+    if let Err(err) = result {
+        if !partial_on_error {
+            return Err(err);
+        }
+
+        log::error!(
+            "download failed after {} of {} chapters: {:#}",
+            added,
+            total,
+            err
+        );
+        log::error!("writing INCOMPLETE {} to {}", book.format.name(), out);
+
+        builder.mark_incomplete(story, added, total);
+
+        write_book(builder.as_mut(), out)?;
+        return Err(err.wrap_err(format!("wrote a partial {}", book.format.name())));
+    }
+
+    builder.add_manifest(&book_manifest(story, skipped, book.reproducible))?;
+
+    log::info!("generating {}...", book.format.name());
+    write_book(builder.as_mut(), out)?;
+
+    let files = builder.files(Path::new(out));
+    if book.verify || book.validate {
+        for file in &files {
+            verify(&file.to_string_lossy(), book.format, book.validate)?;
+        }
+    }
+    if let Some(exec) = &book.exec {
+        for file in &files {
+            run_exec(exec, file)?;
+        }
+    }
+
+    Ok(files)
+}
+
+/// Runs the `--exec` command for `file`, which was just written.
+fn run_exec(exec: &str, file: &Path) -> Result<()> {
+    let path = file.to_string_lossy();
+    let command = exec.replace("{file}", &shell_quote(&path));
+    log::info!("running {}", command);
+    let status = shell(&command)
+        .env("RUSTYROAD_BOOK", file)
+        .status()
+        .wrap_err_with(|| format!("could not run the --exec command for {}", path))?;
+    if !status.success() {
+        return Err(eyre!("the --exec command for {} failed ({})", path, status));
+    }
+    Ok(())
+}
+
+/// `text` as one word for the shell [`shell`] runs.
+fn shell_quote(text: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", text)
+    } else {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+/// The manifest of a book, leaving out the chapters that are only
+/// placeholders so a later update fetches them again.
+fn book_manifest(story: &Story, skipped: &[usize], reproducible: bool) -> Manifest {
+    let mut manifest = Manifest::new(story, reproducible);
+    let skipped: HashSet<String> = skipped.iter().map(|&i| chapter_file(i)).collect();
+    manifest
+        .chapters
+        .retain(|chapter| !skipped.contains(&chapter.file));
+    manifest
+}
+
+/// A chapter's text without its markup and with its whitespace collapsed,
+/// for telling whether the author revised it.
+fn chapter_text(content: &str) -> String {
+    let fragment = scraper::Html::parse_fragment(content);
+    let words: Vec<&str> = fragment
+        .root_element()
+        .text()
+        .flat_map(str::split_whitespace)
+        .collect();
+    words.join(" ")
+}
+
+/// The content standing in for a chapter skipped with `--skip-failed`.
+fn failed_chapter(chapter: &Chapter, err: &str) -> String {
+    let url = chapter_url(&chapter.link)
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| chapter.link.clone());
+    format!(
+        "<p class=\"failed-chapter\">This chapter could not be downloaded: {}</p>\n<p class=\"failed-chapter\">Read it at <a href=\"{url}\">{url}</a>.</p>",
+        escape(err),
+        url = escape(&url)
+    )
+}
+
+/// Returned when the book was written, but with chapters skipped by
+/// `--skip-failed`.
+#[derive(Debug)]
+struct SkippedChapters(usize);
+
+impl std::fmt::Display for SkippedChapters {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} chapter(s) could not be downloaded and were replaced by placeholders",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for SkippedChapters {}
+
+/// Returned when a download was stopped with Ctrl-C.
+#[derive(Debug)]
+struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The exit status after Ctrl-C, the one shells give a process it kills.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Set by the first Ctrl-C of a download: no chapter or story is started
+/// after it, but those being fetched are finished and written.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Catches Ctrl-C, setting [`INTERRUPTED`] the first time and quitting
+/// right away the second.
+fn stop_on_ctrl_c() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        log::warn!(
+            "interrupted: finishing the chapters being fetched and writing what's \
+             done; press Ctrl-C again to quit now"
+        );
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    });
+}
+
+/// Returned when a download was stopped by `--max-download-size`.
+#[derive(Debug)]
+struct OverDownloadLimit;
+
+impl std::fmt::Display for OverDownloadLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "stopped by --max-download-size")
+    }
+}
+
+impl std::error::Error for OverDownloadLimit {}
+
+/// The exit status after `--max-download-size` stopped a download.
+const EXIT_DOWNLOAD_LIMIT: i32 = 7;
+
+/// Set once `--max-download-size` stops the run, with the same effect as
+/// [`INTERRUPTED`].
+static OVER_DOWNLOAD_LIMIT: AtomicBool = AtomicBool::new(false);
+
+/// Whether the run is over `--max-download-size`, which stops it. On a
+/// terminal the user is asked first, and may let it download as much again.
+fn over_download_limit(client: &Client, limit: Option<u64>) -> bool {
+    // asked once at a time, however many stories are downloaded at once
+    static ASKING: Mutex<()> = Mutex::new(());
+
+    if OVER_DOWNLOAD_LIMIT.load(Ordering::Relaxed) {
+        return true;
+    }
+    let Some(limit) = limit.filter(|_| client.over_limit()) else {
+        return false;
+    };
+    let _asking = ASKING.lock().unwrap();
+    if !client.over_limit() {
+        return false;
+    }
+
+    let downloaded = human_size(client.received());
+    if std::io::stdin().is_terminal() && std::io::stderr().is_terminal() {
+        let ask = || -> bool {
+            eprint!(
+                "Downloaded {}, more than --max-download-size {}. Download another {}? [y/N] ",
+                downloaded,
+                human_size(limit),
+                human_size(limit)
+            );
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer).is_ok()
+                && matches!(answer.trim(), "y" | "Y" | "yes")
+        };
+        let bar = PROGRESS_BAR.lock().unwrap().clone();
+        let more = match bar {
+            Some(bar) => bar.suspend(ask),
+            None => ask(),
+        };
+        if more {
+            client.raise_limit(limit);
+            return false;
+        }
+    }
+
+    OVER_DOWNLOAD_LIMIT.store(true, Ordering::Relaxed);
+    log::warn!(
+        "downloaded {}, more than --max-download-size {}: finishing the chapters being \
+         fetched and writing what's done",
+        downloaded,
+        human_size(limit)
+    );
+    true
+}
+
+/// Whether the run should stop starting chapters and stories, after
+/// Ctrl-C or past `--max-download-size`.
+fn stopping(client: &Client, net: &NetArgs) -> bool {
+    interrupted() || over_download_limit(client, net.max_download_size)
+}
+
+/// Why the run stopped, once [`stopping`] said it should.
+fn stopped_error() -> eyre::Report {
+    match OVER_DOWNLOAD_LIMIT.load(Ordering::Relaxed) {
+        true => OverDownloadLimit.into(),
+        false => Interrupted.into(),
+    }
+}
+
+/// Whether the run was stopped, by Ctrl-C or `--max-download-size`.
+fn was_stopped() -> bool {
+    interrupted() || OVER_DOWNLOAD_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Fails with [`SkippedChapters`] if the book has placeholders.
+fn check_skipped(downloaded: Option<Downloaded>) -> Result<()> {
+    match downloaded {
+        Some(book) if book.skipped > 0 => Err(SkippedChapters(book.skipped).into()),
+        _ => Ok(()),
+    }
+}
+
+/// Reads a list of story URLs, one per line.
+fn read_url_list(path: &Path) -> Result<Vec<String>> {
+    let list = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("could not read {}", path.display()))?;
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// How books downloaded together are named if `--out` doesn't say.
+const BATCH_TEMPLATE: &str = "{title} - {author}.{ext}";
+
+/// The story's details in an `--archive`, for `build --metadata`.
+const ARCHIVE_METADATA: &str = "metadata.json";
+
+fn is_template(out: &str) -> bool {
+    out.contains('{')
+}
+
+/// Replaces characters that aren't allowed in file names.
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim_end_matches(['.', ' ']).to_string()
+}
+
+/// Fills in `{title}`, `{author}` and `{ext}` (the format's extension) in
+/// an `--out` template. The values are sanitized so they can't add
+/// directories or characters that aren't allowed in file names.
+fn render_template(template: &str, story: &Story, format: Format) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("unclosed {{ in --out {:?}", template))?;
+        let value = match &rest[start + 1..start + end] {
+            "title" => sanitize_file_name(&story.title),
+            "author" => sanitize_file_name(&story.author),
+            "ext" => format.extension().to_string(),
+            name => return Err(eyre!("unknown placeholder {{{}}} in --out", name)),
+        };
+        out.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+async fn new_client(args: &NetArgs) -> Result<Client> {
+    let client = Client::new(ClientOpts {
+        max_cooldown: Duration::from_secs(args.max_cooldown),
+        max_retries: args.max_retries,
+        retry_delay: Duration::try_from_secs_f64(args.retry_delay)
+            .map_err(|_| eyre!("invalid --retry-delay {}", args.retry_delay))?,
+        timeout: Duration::from_secs(args.timeout),
+        connect_timeout: Duration::from_secs(args.connect_timeout),
+        user_agent: args.user_agent.clone(),
+        cookies_file: args.cookies_file.clone(),
+        browser_cookies: args.browser_cookies.clone(),
+        cf_clearance: args.cf_clearance.clone(),
+        proxy: args.proxy.clone(),
+        delay: Duration::from_millis(args.delay),
+        burst: args.burst,
+        max_in_flight: args.max_in_flight,
+        rate_limit: args.rate_limit,
+        host_rate_limits: args.host_rate_limits.clone(),
+        rate_limit_file: None,
+        polite: args.polite,
+        download_limit: args.max_download_size,
+    })?;
+
+    if let Some(username) = &args.username {
+        let password = match &args.password {
+            Some(password) => password.clone(),
+            None => std::env::var("RUSTYROAD_PASSWORD")
+                .map_err(|_| eyre!("--username needs --password or RUSTYROAD_PASSWORD"))?,
+        };
+        RoyalRoad.login(&client, username, &password).await?;
+    }
+
+    Ok(client)
+}
+
+/// A book written by [`download_story`].
+struct Downloaded {
+    out: String,
+    chapters: usize,
+    /// Chapters replaced by placeholders with `--skip-failed`.
+    skipped: usize,
+}
+
+async fn download(mut args: DownloadArgs) -> Result<()> {
+    let mut urls = args.url.clone();
+    if let Some(path) = &args.input {
+        urls.extend(read_url_list(path)?);
+    }
+
+    if args.anthology {
+        if urls.len() < 2 {
+            return Err(eyre!("an anthology needs at least two stories"));
+        }
+        let client = new_client(&args.fetch.net).await?;
+        args.url = urls;
+        // the stories' titles are the volumes their chapters nest under
+        args.fetch.book.toc_volumes = true;
+        let template = args.fetch.book.template()?;
+        return download_story(&args, false, &client, None, template)
+            .await
+            .and_then(check_skipped);
+    }
+
+    if urls.len() <= 1 && args.input.is_none() {
+        let client = new_client(&args.fetch.net).await?;
+        let url = urls.pop();
+        let args = match &url {
+            Some(url) => args.for_story(url),
+            None => Cow::Borrowed(&args),
+        };
+        let template = args.fetch.book.template()?;
+        return download_story(&args, false, &client, url, template)
+            .await
+            .and_then(check_skipped);
+    }
+
+    let at_once = args.fetch.stories_at_once();
+    let template = args.fetch.book.out.as_deref().unwrap_or(BATCH_TEMPLATE);
+    if !is_template(template) {
+        return Err(eyre!(
+            "--out names a single book; use a template such as {:?} to name each story's book",
+            BATCH_TEMPLATE
+        ));
+    }
+    if args.manifest.is_some() || args.fetch.raw_dir().is_some() || args.metadata_json.is_some() {
+        return Err(eyre!(
+            "--manifest, --save-raw, --archive and --metadata-json only work with a single story"
+        ));
+    }
+
+    let client = new_client(&args.fetch.net).await?;
+    let (args, client, total) = (&args, &client, urls.len());
+    let results: Vec<_> = stream::iter(urls.iter().enumerate())
+        .take_while(|_| std::future::ready(!stopping(client, &args.fetch.net)))
+        .map(|(i, url)| async move {
+            log::info!("story {} of {}: {}", i + 1, total, url);
+            let args = args.for_story(url);
+            let template = args.fetch.book.out.as_deref().unwrap_or(template);
+            let result = download_story(&args, false, client, Some(url.clone()), template).await;
+            if let Err(err) = &result {
+                log::error!("{}: {:#}", url, err);
+            }
+            result
+        })
+        .buffered(at_once)
+        .collect()
+        .await;
+
+    let summary = summarize(&urls, &results);
+    if was_stopped() {
+        return Err(stopped_error());
+    }
+    summary
+}
+
+impl FetchArgs {
+    /// How many of several stories are worked on at once. With more than
+    /// one, the client is set up to share `--concurrent` requests between
+    /// them, and the progress bar, which can only show one story, is left
+    /// out.
+    fn stories_at_once(&mut self) -> usize {
+        let stories = self.story_concurrent as usize;
+        if stories > 1 {
+            self.net.max_in_flight = self.concurrent;
+            self.no_progress = true;
+        }
+        stories
+    }
+
+    /// The library books are recorded in, unless `--no-library` was given.
+    fn library(&self) -> Result<Option<Library>> {
+        if self.no_library {
+            return Ok(None);
+        }
+        let path = match &self.library {
+            Some(path) => path.clone(),
+            None => match Library::default_path() {
+                Some(path) => path,
+                None => {
+                    log::warn!("no data directory found, books won't be recorded in the library");
+                    return Ok(None);
+                }
+            },
+        };
+        Library::open(path).map(Some)
+    }
+}
+
+impl DownloadArgs {
+    /// The options of `update` and `watch`, which only fetch chapters.
+    fn fetch_only(fetch: FetchArgs) -> DownloadArgs {
+        DownloadArgs {
+            url: Vec::new(),
+            input: None,
+            dry_run: false,
+            manifest: None,
+            new_since_manifest: false,
+            author_filter: None,
+            tag_filter: None,
+            title_filter: None,
+            metadata_json: None,
+            anthology: false,
+            anthology_title: None,
+            fetch,
+        }
+    }
+}
+
+async fn update(args: UpdateArgs) -> Result<()> {
+    let (url, all, no_feed) = (args.url, args.all, args.no_feed);
+    let args = DownloadArgs::fetch_only(args.fetch);
+    if all {
+        return update_all(args, !no_feed).await;
+    }
+
+    let client = new_client(&args.fetch.net).await?;
+    let args = match &url {
+        Some(url) => args.for_story(url),
+        None => Cow::Borrowed(&args),
+    };
+    let template = args.fetch.book.template()?;
+    download_story(&args, true, &client, url, template)
+        .await
+        .and_then(check_skipped)
+}
+
+/// Updates every epub book in the library.
+async fn update_all(mut args: DownloadArgs, feed: bool) -> Result<()> {
+    if args.fetch.book.out.is_some() {
+        return Err(eyre!(
+            "--all updates the books in the library, leave out --out"
+        ));
+    }
+    let library = args
+        .fetch
+        .library()?
+        .ok_or_else(|| eyre!("--all needs a library, give --library"))?;
+
+    let mut books = Vec::new();
+    for entry in library.entries()? {
+        if entry.format != Format::Epub.name() {
+            log::info!(
+                "skipping {}, only epub books can be updated",
+                entry.path.display()
+            );
+        } else if !entry.path.is_file() {
+            log::warn!(
+                "{} no longer exists, removing it from the library",
+                entry.path.display()
+            );
+            library.remove(&entry.path)?;
+        } else {
+            books.push(entry);
+        }
+    }
+    if books.is_empty() {
+        log::info!("the library has no books to update");
+        return Ok(());
+    }
+
+    let at_once = args.fetch.stories_at_once();
+    let client = new_client(&args.fetch.net).await?;
+    let (args, client, total) = (&args, &client, books.len());
+    let results: Vec<_> = stream::iter(books.iter().enumerate())
+        .take_while(|_| std::future::ready(!stopping(client, &args.fetch.net)))
+        .map(|(i, entry)| async move {
+            let path = entry.path.to_string_lossy();
+            log::info!("book {} of {}: {}", i + 1, total, path);
+            if feed && book_up_to_date(client, &path).await {
+                log::info!("no new chapters for {}", path);
+                return Ok(None);
+            }
+            let args = args.for_story(&entry.url);
+            let result = download_story(&args, true, client, Some(entry.url.clone()), &path).await;
+            if let Err(err) = &result {
+                log::error!("{}: {:#}", path, err);
+            }
+            result
+        })
+        .buffered(at_once)
+        .collect()
+        .await;
+
+    let names: Vec<String> = books.iter().map(|entry| entry.title.clone()).collect();
+    let summary = summarize(&names, &results);
+    if was_stopped() {
+        return Err(stopped_error());
+    }
+    summary
+}
+
+/// Prints how each of several downloads went, and fails if any did.
+fn summarize(names: &[String], results: &[Result<Option<Downloaded>>]) -> Result<()> {
+    println!();
+    println!("{} stories:", names.len());
+    let mut failed = 0;
+    let mut skipped = 0;
+    for (name, result) in names.iter().zip(results) {
+        match result {
+            Ok(Some(book)) if book.skipped > 0 => {
+                skipped += book.skipped;
+                println!(
+                    "  partial  {} -> {} ({} chapters, {} skipped)",
+                    name, book.out, book.chapters, book.skipped
+                )
+            }
+            Ok(Some(book)) => println!(
+                "  ok       {} -> {} ({} chapters)",
+                name, book.out, book.chapters
+            ),
+            Ok(None) => println!("  skipped  {}", name),
+            Err(err) => {
+                failed += 1;
+                println!("  failed   {}: {:#}", name, err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(eyre!("{} of {} stories failed", failed, names.len()));
+    }
+    if skipped > 0 {
+        return Err(SkippedChapters(skipped).into());
+    }
+    Ok(())
+}
+
+/// Parses an interval such as `90s`, `15m`, `1h` or `1d`. A bare number is
+/// in seconds.
+fn parse_interval(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid interval {:?}", text))?;
+    let unit = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown unit in interval {:?}, use s, m, h or d",
+                text
+            ))
+        }
+    };
+    let secs = number
+        .checked_mul(unit)
+        .ok_or_else(|| format!("invalid interval {:?}", text))?;
+    if secs == 0 {
+        return Err("the interval can't be zero".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Reads a `--jitter`, which unlike an interval may be `0`.
+fn parse_jitter(text: &str) -> Result<Duration, String> {
+    match text.trim() {
+        "0" => Ok(Duration::ZERO),
+        text => parse_interval(text),
+    }
+}
+
+/// Checks that `text` looks like a language tag such as `en` or `pt-BR`.
+fn parse_language(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    let valid = text.split('-').all(|part| {
+        (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+    });
+    if !valid {
+        return Err(format!("invalid language tag {:?}, e.g. en or pt-BR", text));
+    }
+    Ok(text.to_string())
+}
+
+/// A command running `command` in the system's shell.
+fn shell(command: &str) -> std::process::Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Runs the `--hook` command for a book that was just updated.
+fn run_hook(hook: &str, book: &str, new: usize) {
+    let status = shell(hook)
+        .env("RUSTYROAD_BOOK", book)
+        .env("RUSTYROAD_NEW_CHAPTERS", new.to_string())
+        .status();
+
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => log::error!("the hook for {} failed ({})", book, status),
+        Err(err) => log::error!("could not run the hook for {}: {}", book, err),
+    }
+}
+
+/// Whether the story's feed shows that the book `previous` describes has
+/// all of the latest chapters. If the story has no feed, or it can't be
+/// read, the story page has to be checked.
+async fn feed_up_to_date(client: &Client, previous: &Manifest) -> bool {
+    match feed_has_new_chapters(client, previous).await {
+        Ok(Some(new)) => !new,
+        Ok(None) => false,
+        Err(err) => {
+            log::warn!(
+                "could not read the feed of {}, checking the story page: {:#}",
+                previous.url,
+                err
+            );
+            false
+        }
+    }
+}
+
+/// The same for the book at `path`, going by the manifest it carries.
+async fn book_up_to_date(client: &Client, path: &str) -> bool {
+    let previous = ExistingBook::open(path).and_then(|mut book| book.manifest());
+    match previous {
+        Ok(Some(previous)) if !previous.url.is_empty() => feed_up_to_date(client, &previous).await,
+        _ => false,
+    }
+}
+
+/// Updates `book` if its story has new chapters, and returns them.
+async fn watch_book(
+    args: &DownloadArgs,
+    client: &Client,
+    book: &str,
+    feed: bool,
+) -> Result<Option<NewChapters>> {
+    let previous = ExistingBook::open(book)?
+        .manifest()?
+        .filter(|manifest| !manifest.url.is_empty())
+        .ok_or_else(|| {
+            eyre!(
+                "{} doesn't record its story, run `rustyroad update --url <story-url> --out {}` once",
+                book,
+                book
+            )
+        })?;
+
+    if feed && feed_up_to_date(client, &previous).await {
+        log::info!("no new chapters for {}", book);
+        return Ok(None);
+    }
+
+    let story = fetch_story(client, previous.url.clone()).await?;
+    let new: Vec<String> = story
+        .chapters
+        .iter()
+        .filter(|chapter| previous.chapter(&chapter.link).is_none())
+        .map(|chapter| chapter.name.clone())
+        .collect();
+    if new.is_empty() {
+        log::info!("no new chapters for {}", book);
+        return Ok(None);
+    }
+
+    log::info!("{} new chapter(s) for {}", new.len(), book);
+    download_story(&args.for_story(&previous.url), true, client, None, book).await?;
+    Ok(Some(NewChapters {
+        book: book.to_string(),
+        title: story.title,
+        url: story.url,
+        chapters: new,
+    }))
+}
+
+/// A book `watch` keeps up to date.
+struct Watched {
+    schedule: Option<Schedule>,
+    status: BookStatus,
+}
+
+impl Watched {
+    /// When to check the book after `now`: at its schedule's next time,
+    /// up to `jitter` later, or else `interval` from now. A time past what
+    /// a date can hold is never.
+    fn plan(&mut self, now: DateTime<Local>, interval: Duration, jitter: Duration) {
+        let after = |time: DateTime<Local>, delay: Duration| {
+            chrono::Duration::from_std(delay)
+                .ok()
+                .and_then(|delay| time.checked_add_signed(delay))
+        };
+        let next = match &self.schedule {
+            Some(schedule) => schedule.next_after(now).and_then(|next| {
+                let jitter = fastrand::u64(0..=jitter.as_millis().min(u64::MAX as u128) as u64);
+                after(next, Duration::from_millis(jitter))
+            }),
+            None => after(now, interval),
+        };
+        self.status.next_check = next.map(|next| next.timestamp());
+    }
+
+    fn due(&self, now: DateTime<Local>) -> bool {
+        self.status
+            .next_check
+            .is_some_and(|next| next <= now.timestamp())
+    }
+}
+
+/// `time`, a Unix timestamp, in local time to the minute.
+fn local_time(time: i64) -> String {
+    match Local.timestamp_opt(time, 0) {
+        chrono::LocalResult::Single(time) | chrono::LocalResult::Ambiguous(time, _) => {
+            time.format("%Y-%m-%d %H:%M").to_string()
+        }
+        chrono::LocalResult::None => time.to_string(),
+    }
+}
+
+/// Prints the status file of the running `watch`, for `watch --status`.
+fn print_watch_status(books: &[String]) -> Result<()> {
+    let path =
+        WatchStatus::default_path().ok_or_else(|| eyre!("could not find the data directory"))?;
+    if !path.is_file() {
+        return Err(eyre!("no `watch` has written {} yet", path.display()));
+    }
+    let status = WatchStatus::load(&path)?;
+    println!(
+        "watch (pid {}), as of {}",
+        status.pid,
+        local_time(status.updated)
+    );
+    for book in &status.books {
+        if !books.is_empty() && !books.contains(&book.book) {
+            continue;
+        }
+        println!("{}", book.book);
+        match (&book.schedule, book.interval) {
+            (Some(schedule), _) => println!("  schedule:   {}", schedule),
+            (None, Some(interval)) => println!("  interval:   {}s", interval),
+            (None, None) => (),
+        }
+        match book.next_check {
+            Some(next) => println!("  next check: {}", local_time(next)),
+            None => println!("  next check: never"),
+        }
+        if let (Some(last), Some(result)) = (book.last_check, &book.last_result) {
+            println!("  last check: {}, {}", local_time(last), result);
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps until `time`, a little at a time, so a clock changed or a
+/// machine suspended in the meantime doesn't push the check back.
+async fn sleep_until(time: i64) {
+    loop {
+        let left = time - Local::now().timestamp();
+        if left <= 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(left.min(60) as u64)).await;
+    }
+}
+
+async fn watch(args: WatchArgs) -> Result<()> {
+    if args.status {
+        return print_watch_status(&args.books);
+    }
+    if args.fetch.book.out.is_some() {
+        return Err(eyre!(
+            "`watch` takes the books to update as arguments, not --out"
+        ));
+    }
+    for book in &args.books {
+        if is_template(book) {
+            return Err(eyre!("{} is a template, give the path of the book", book));
+        }
+    }
+
+    let (books, hook, interval, feed) = (args.books, args.hook, args.interval, !args.no_feed);
+    let (notify, webhook, webhook_format) = (args.notify, args.webhook, args.webhook_format);
+    let (schedule, jitter) = (args.schedule, args.jitter);
+    let args = DownloadArgs::fetch_only(args.fetch);
+    let client = new_client(&args.fetch.net).await?;
+
+    let now = Local::now();
+    let mut watched = Vec::new();
+    for book in books {
+        // a book whose story can't be told yet fails its first check
+        let story = ExistingBook::open(&book)
+            .and_then(|mut existing| existing.manifest())
+            .ok()
+            .flatten()
+            .and_then(|manifest| args.fetch.settings.config.story(&manifest.url).cloned());
+        let schedule = story
+            .and_then(|story| story.schedule)
+            .or_else(|| schedule.clone());
+        if let Some(schedule) = &schedule {
+            if schedule.next_after(now).is_none() {
+                return Err(eyre!("the schedule {} of {} never comes", schedule, book));
+            }
+        }
+
+        let mut book = Watched {
+            status: BookStatus {
+                book,
+                schedule: schedule.as_ref().map(|schedule| schedule.to_string()),
+                interval: schedule.is_none().then_some(interval.as_secs()),
+                next_check: None,
+                last_check: None,
+                last_result: None,
+            },
+            schedule,
+        };
+        // books checked every --interval are checked right away
+        match book.schedule {
+            Some(_) => book.plan(now, interval, jitter),
+            None => book.status.next_check = Some(now.timestamp()),
+        }
+        watched.push(book);
+    }
+
+    let status_path = WatchStatus::default_path();
+    loop {
+        if let Some(path) = &status_path {
+            let status = WatchStatus {
+                pid: std::process::id(),
+                updated: Local::now().timestamp(),
+                books: watched.iter().map(|book| book.status.clone()).collect(),
+            };
+            if let Err(err) = status.save(path) {
+                log::warn!("could not write the watch status: {:#}", err);
+            }
+        }
+
+        let next = watched
+            .iter()
+            .filter_map(|book| book.status.next_check)
+            .min()
+            .unwrap_or_default();
+        if next > Local::now().timestamp() {
+            log::info!("checking again at {}", local_time(next));
+        }
+        sleep_until(next).await;
+
+        let now = Local::now();
+        for watched in watched.iter_mut().filter(|book| book.due(now)) {
+            let book = &watched.status.book;
+            let result = watch_book(&args, &client, book, feed).await;
+            watched.status.last_check = Some(Local::now().timestamp());
+            watched.status.last_result = Some(match &result {
+                Ok(None) => "up to date".to_string(),
+                Ok(Some(new)) => format!("{} new chapter(s)", new.chapters.len()),
+                Err(err) => format!("failed: {:#}", err),
+            });
+            match result {
+                Ok(None) => (),
+                Ok(Some(new)) => {
+                    if let Some(hook) = &hook {
+                        run_hook(hook, book, new.chapters.len());
+                    }
+                    // the book is updated either way, so a notification
+                    // that can't be sent isn't fatal
+                    if notify {
+                        if let Err(err) = new.notify_desktop() {
+                            log::warn!("could not show a notification: {:#}", err);
+                        }
+                    }
+                    if let Some(webhook) = &webhook {
+                        if let Err(err) = new.post(webhook, webhook_format).await {
+                            log::warn!("could not notify the webhook: {:#}", err);
+                        }
+                    }
+                }
+                Err(err) => log::error!("could not update {}: {:#}", book, err),
+            }
+            watched.plan(Local::now(), interval, jitter);
+        }
+    }
+}
+
+/// The exit status of `check` when a book has new chapters.
+const EXIT_NEW_CHAPTERS: i32 = 1;
+/// The exit status of a `check` that failed for no particular reason, in
+/// place of [`EXIT_FAILURE`], which would read as new chapters.
+const EXIT_CHECK_FAILED: i32 = 8;
+
+/// The new chapters of the book at `path`, whose library entry, if any, is
+/// `entry`, by name.
+async fn new_chapters(
+    client: &Client,
+    path: &str,
+    entry: Option<&LibraryEntry>,
+    feed: bool,
+) -> Result<Vec<String>> {
+    let previous = ExistingBook::open(path)?
+        .manifest()?
+        .filter(|manifest| !manifest.url.is_empty());
+    let url = match (&previous, entry) {
+        (Some(previous), _) => previous.url.clone(),
+        (None, Some(entry)) if !entry.url.is_empty() => entry.url.clone(),
+        _ => {
+            return Err(eyre!(
+                "{} doesn't record its story and isn't in the library",
+                path
+            ))
+        }
+    };
+    if let Some(previous) = &previous {
+        if feed && feed_up_to_date(client, previous).await {
+            return Ok(Vec::new());
+        }
+    }
+
+    let story = fetch_story(client, url).await?;
+    // without a manifest, the library's count of the chapters is all there
+    // is to go by
+    let had = entry.map_or(0, |entry| entry.chapters);
+    Ok(story
+        .chapters
+        .iter()
+        .enumerate()
+        .filter(|(i, chapter)| match &previous {
+            Some(previous) => previous.chapter(&chapter.link).is_none(),
+            None => *i >= had,
+        })
+        .map(|(_, chapter)| chapter.name.clone())
+        .collect())
+}
+
+/// Prints the new chapters of the book, or of the library's books of the
+/// story, in `args`. Returns whether there are any.
+async fn check(args: CheckArgs) -> Result<bool> {
+    let entries = match args.fetch.library()? {
+        Some(library) => library.entries()?,
+        None => Vec::new(),
+    };
+    let is_story = !Path::new(&args.book).is_file() && story_url(&args.book).is_ok();
+    let books: Vec<(String, Option<&LibraryEntry>)> = if is_story {
+        let books: Vec<_> = entries
+            .iter()
+            .filter(|entry| same_story(&entry.url, &args.book) && entry.path.is_file())
+            .map(|entry| (entry.path.to_string_lossy().into_owned(), Some(entry)))
+            .collect();
+        if books.is_empty() {
+            return Err(eyre!(
+                "the library has no book of {}; give the path of the book",
+                args.book
+            ));
+        }
+        books
+    } else {
+        let path = std::fs::canonicalize(&args.book).unwrap_or_else(|_| PathBuf::from(&args.book));
+        let entry = entries.iter().find(|entry| entry.path == path);
+        vec![(args.book.clone(), entry)]
+    };
+
+    let client = new_client(&args.fetch.net).await?;
+    let mut outdated = false;
+    for (path, entry) in books {
+        let new = new_chapters(&client, &path, entry, !args.no_feed).await?;
+        if new.is_empty() {
+            println!("{}: up to date", path);
+            continue;
+        }
+        outdated = true;
+        println!("{}: {} new chapter(s)", path, new.len());
+        for name in new {
+            println!("  {}", name);
+        }
+    }
+    Ok(outdated)
+}
+
+async fn info(args: InfoArgs) -> Result<()> {
+    let client = new_client(&args.net).await?;
+    let story = fetch_story(&client, args.url).await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&story)?);
+        return Ok(());
+    }
+
+    println!("{} by {}", story.title, story.author);
+    println!("{}", story.url);
+    if let Some(status) = story.status {
+        println!("Status: {}", status.name());
+    }
+    if !story.tags.is_empty() {
+        println!("Tags: {}", story.tags.join(", "));
+    }
+    if !story.description.is_empty() {
+        println!();
+        println!("{}", story.description);
+    }
+    println!();
+    print_chapters(&story);
+    Ok(())
+}
+
+async fn tui(args: TuiArgs) -> Result<()> {
+    if !std::io::stdout().is_terminal() {
+        return Err(eyre!("tui needs a terminal"));
+    }
+    let url = args.url;
+    let args = DownloadArgs::fetch_only(args.fetch);
+    let mut args = args.for_story(&url).into_owned();
+
+    let client = new_client(&args.fetch.net).await?;
+    log::info!("fetching story...");
+    let story = fetch_story(&client, url.clone()).await?;
+
+    let book = &args.fetch.book;
+    let mut options = TuiOptions {
+        format: book.format,
+        epub_version: book.epub_version,
+        typography: book.typography,
+        authors_notes: book.include_authors_notes && !book.exclude_authors_notes,
+    };
+    let selection = match (&args.fetch.chapters, args.fetch.from, args.fetch.to) {
+        (Some(spec), _, _) => Some(ChapterSelection::parse(spec)?),
+        (None, None, None) => None,
+        (None, from, to) => Some(ChapterSelection::between(from, to)?),
+    };
+    let Some(selection) = choose_chapters(&story, selection.as_ref(), &mut options)? else {
+        return Ok(());
+    };
+    let book = &mut args.fetch.book;
+    book.format = options.format;
+    book.epub_version = options.epub_version;
+    book.typography = options.typography;
+    book.include_authors_notes = options.authors_notes;
+    book.exclude_authors_notes = !options.authors_notes;
+
+    args.fetch.chapters = Some(selection.to_string());
+    args.fetch.from = None;
+    args.fetch.to = None;
+
+    let template = args.fetch.book.out.as_deref().unwrap_or(BATCH_TEMPLATE);
+    download_story(&args, false, &client, Some(story.url), template)
+        .await
+        .and_then(check_skipped)
+}
+
+async fn search(args: SearchArgs) -> Result<()> {
+    if args.page == 0 {
+        return Err(eyre!("pages are numbered from 1"));
+    }
+    let client = new_client(&args.fetch.net).await?;
+    let results = RoyalRoad
+        .search(&client, &args.query, args.sort, args.page)
+        .await?;
+    if results.is_empty() {
+        return Err(eyre!("no stories found for {:?}", args.query));
+    }
+
+    let Some(nr) = args.download else {
+        print_results(&results);
+        return Ok(());
+    };
+
+    let result = nr
+        .checked_sub(1)
+        .and_then(|i| results.get(i))
+        .ok_or_else(|| eyre!("--download {} is not in the {} results", nr, results.len()))?;
+    log::info!("downloading {}", result.title);
+    let args = DownloadArgs::fetch_only(args.fetch);
+    let args = args.for_story(&result.url);
+    let template = args.fetch.book.out.as_deref().unwrap_or(BATCH_TEMPLATE);
+    download_story(&args, false, &client, Some(result.url.clone()), template)
+        .await
+        .and_then(check_skipped)
+}
+
+/// Prints a numbered list of stories found on Royal Road.
+fn print_results(results: &[SearchResult]) {
+    for (i, result) in results.iter().enumerate() {
+        let mut stats = Vec::new();
+        if let Some(rating) = result.rating {
+            stats.push(format!("rated {:.2}", rating));
+        }
+        if let Some(chapters) = result.chapters {
+            stats.push(format!("{} chapter(s)", thousands(chapters)));
+        }
+        if let Some(followers) = result.followers {
+            stats.push(format!("{} followers", thousands(followers)));
+        }
+        println!("{:>3}. {} [{}]", i + 1, result.title, result.id);
+        if !stats.is_empty() {
+            println!("     {}", stats.join(", "));
+        }
+        println!("     {}", result.url);
+    }
+}
+
+/// Updates the library's book of the story at `url` if it has one, or else
+/// downloads the story into a new book named after `template`. `args` are
+/// the story's options.
+async fn download_into_library(
+    args: &DownloadArgs,
+    client: &Client,
+    books: &[LibraryEntry],
+    url: &str,
+    template: &str,
+) -> Result<Option<Downloaded>> {
+    // only epubs carry what an update needs, other books are written anew
+    let book = books.iter().find(|book| {
+        same_story(&book.url, url) && book.format == Format::Epub.name() && book.path.is_file()
+    });
+    match book {
+        Some(book) => {
+            let path = book.path.to_string_lossy();
+            if book_up_to_date(client, &path).await {
+                log::info!("no new chapters for {}", path);
+                Ok(None)
+            } else {
+                download_story(args, true, client, Some(url.to_string()), &path).await
+            }
+        }
+        None => {
+            let template = args.fetch.book.out.as_deref().unwrap_or(template);
+            download_story(args, false, client, Some(url.to_string()), template).await
+        }
+    }
+}
+
+/// Which of the `total` stories listed to download, asked on the terminal.
+/// `None` if none.
+fn ask_stories(total: usize) -> Result<Option<ChapterSelection>> {
+    loop {
+        eprint!("Download which stories? (e.g. 1,3-5 or all; nothing to quit): ");
+        std::io::Write::flush(&mut std::io::stderr())?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        match answer.trim() {
+            "" => return Ok(None),
+            "all" => return Ok(ChapterSelection::of(1..=total)),
+            answer => match ChapterSelection::parse(answer) {
+                Ok(selection) => return Ok(Some(selection)),
+                Err(_) => eprintln!("{:?} is not a list of numbers like 1,3-5", answer),
+            },
+        }
+    }
+}
+
+/// Lists the stories of a Royal Road author and downloads those picked into
+/// the library, updating the books it already has of them.
+async fn author(args: AuthorArgs) -> Result<()> {
+    let (profile, all, download, list) = (args.profile, args.all, args.download, args.list);
+    let mut args = DownloadArgs::fetch_only(args.fetch);
+    let at_once = args.fetch.stories_at_once();
+    let template = args.fetch.book.out.as_deref().unwrap_or(BATCH_TEMPLATE);
+    if !is_template(template) {
+        return Err(eyre!(
+            "--out names a single book; use a template such as {:?} to name each story's book",
+            BATCH_TEMPLATE
+        ));
+    }
+
+    let client = new_client(&args.fetch.net).await?;
+    let stories = RoyalRoad.author_fictions(&client, &profile).await?;
+    if stories.is_empty() {
+        return Err(eyre!("no stories found on the profile {}", profile));
+    }
+    print_results(&stories);
+
+    let selection = if all {
+        ChapterSelection::of(1..=stories.len())
+    } else if let Some(download) = download {
+        Some(ChapterSelection::parse(&download).wrap_err("invalid --download")?)
+    } else if !list && std::io::stdin().is_terminal() {
+        println!();
+        ask_stories(stories.len())?
+    } else {
+        None
+    };
+    let Some(selection) = selection else {
+        return Ok(());
+    };
+    let picked: Vec<&SearchResult> = stories
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| selection.contains(i + 1))
+        .map(|(_, story)| story)
+        .collect();
+    if picked.is_empty() {
+        return Err(eyre!(
+            "those numbers are not in the {} stories",
+            stories.len()
+        ));
+    }
+
+    let books = match args.fetch.library()? {
+        Some(library) => library.entries()?,
+        None => Vec::new(),
+    };
+    let (args, client, books, template) = (&args, &client, &books, &template);
+    let total = picked.len();
+    let results: Vec<_> = stream::iter(picked.iter().enumerate())
+        .map(|(i, story)| async move {
+            log::info!("story {} of {}: {}", i + 1, total, story.title);
+            let result = download_into_library(
+                &args.for_story(&story.url),
+                client,
+                books,
+                &story.url,
+                template,
+            )
+            .await;
+            if let Err(err) = &result {
+                log::error!("{}: {:#}", story.title, err);
+            }
+            result
+        })
+        .buffered(at_once)
+        .collect()
+        .await;
+
+    let names: Vec<String> = picked.iter().map(|story| story.title.clone()).collect();
+    summarize(&names, &results)
+}
+
+/// Downloads the stories on the account's reading list that the library
+/// has no book of, and updates the books of the rest.
+async fn sync_follows(args: SyncFollowsArgs) -> Result<()> {
+    let (list, skip) = (args.list, args.skip);
+    let mut args = DownloadArgs::fetch_only(args.fetch);
+    let at_once = args.fetch.stories_at_once();
+    let template = args.fetch.book.out.as_deref().unwrap_or(BATCH_TEMPLATE);
+    if !is_template(template) {
+        return Err(eyre!(
+            "--out names a single book; use a template such as {:?} to name each story's book",
+            BATCH_TEMPLATE
+        ));
+    }
+    let books = match args.fetch.library()? {
+        Some(library) => library.entries()?,
+        None => Vec::new(),
+    };
+
+    let client = new_client(&args.fetch.net).await?;
+    let stories = RoyalRoad.reading_list(&client, list).await?;
+    if stories.is_empty() {
+        log::info!("the {} is empty", list.name());
+        return Ok(());
+    }
+    log::info!("{} stories on the {}", stories.len(), list.name());
+
+    let config = &args.fetch.settings.config;
+    let (args, client, books, skip) = (&args, &client, &books, &skip);
+    let total = stories.len();
+    let results: Vec<_> = stream::iter(stories.iter().enumerate())
+        .map(|(i, story)| async move {
+            let opted_out = skip.iter().any(|url| same_story(url, &story.url))
+                || config.story(&story.url).and_then(|story| story.sync) == Some(false);
+            if opted_out {
+                log::info!("skipping {}, it is left out of syncing", story.title);
+                return Ok(None);
+            }
+
+            log::info!("story {} of {}: {}", i + 1, total, story.title);
+            let args = args.for_story(&story.url);
+            let result = download_into_library(&args, client, books, &story.url, template).await;
+            if let Err(err) = &result {
+                log::error!("{}: {:#}", story.title, err);
+            }
+            result
+        })
+        .buffered(at_once)
+        .collect()
+        .await;
+
+    let names: Vec<String> = stories.iter().map(|story| story.title.clone()).collect();
+    summarize(&names, &results)
+}
+
+/// Warns when most of `story` has been taken down, as a book of it will only
+/// hold the chapters the author left up.
+fn warn_if_stub(story: &Story) {
+    if story.status == Some(StoryStatus::Stub) {
+        log::warn!(
+            "{} is a STUB: its author has removed most of its chapters,",
+            story.title
+        );
+        log::warn!("usually to publish it elsewhere, e.g. on Kindle Unlimited.");
+        log::warn!(
+            "The book will only hold the {} chapter(s) still up.",
+            story.chapters.len()
+        );
+    }
+}
+
+/// Prints the story's length and its chapters with their publish dates.
+fn print_chapters(story: &Story) {
+    match story.words {
+        Some(words) => println!(
+            "{} chapter(s), about {} words:",
+            story.chapters.len(),
+            thousands(words as usize)
+        ),
+        None => println!("{} chapter(s):", story.chapters.len()),
+    }
+    for (i, chapter) in story.chapters.iter().enumerate() {
+        let date = chapter.published.map(iso_date).unwrap_or_default();
+        println!("{:>5}. {:<10}  {}", i + 1, date, chapter.name);
+    }
+}
+
+/// Reading speed for the reading times of `--stats`, in words a minute.
+const READING_SPEED: u64 = 250;
+
+fn reading_time(words: u64) -> String {
+    let minutes = words.div_ceil(READING_SPEED);
+    match minutes {
+        0..60 => format!("{}m", minutes),
+        _ => format!("{}h {:02}m", minutes / 60, minutes % 60),
+    }
+}
+
+/// Prints a table of the `words` of every chapter in `chapters`, with the
+/// running total and the reading time, and the totals of each volume and
+/// the book. Chapters that weren't added have no count.
+fn print_stats(chapters: &[Chapter], words: &[Option<u64>]) {
+    println!();
+    println!(
+        "{:>5}  {:>9}  {:>10}  {:>8}  chapter",
+        "", "words", "total", "reading"
+    );
+    let mut total = 0;
+    let mut volume: Option<(&str, u64)> = None;
+    let print_volume = |volume: Option<(&str, u64)>| {
+        if let Some((name, words)) = volume {
+            println!(
+                "{:>5}  {:>9}  {:>10}  {:>8}  {} (volume)",
+                "",
+                thousands(words as usize),
+                "",
+                reading_time(words),
+                name
+            );
+        }
+    };
+    for (i, (chapter, words)) in chapters.iter().zip(words).enumerate() {
+        if chapter.volume.as_deref() != volume.map(|(name, _)| name) {
+            print_volume(volume);
+            volume = chapter.volume.as_deref().map(|name| (name, 0));
+        }
+        let Some(words) = *words else {
+            println!(
+                "{:>5}. {:>9}  {:>10}  {:>8}  {}",
+                i + 1,
+                "-",
+                "",
+                "",
+                chapter.name
+            );
+            continue;
+        };
+        total += words;
+        if let Some((_, volume_words)) = &mut volume {
+            *volume_words += words;
+        }
+        println!(
+            "{:>5}. {:>9}  {:>10}  {:>8}  {}",
+            i + 1,
+            thousands(words as usize),
+            thousands(total as usize),
+            reading_time(words),
+            chapter.name
+        );
+    }
+    print_volume(volume);
+
+    let counted = words.iter().flatten().count();
+    println!(
+        "{} chapter(s), {} words, about {} to read at {} words a minute",
+        counted,
+        thousands(total as usize),
+        reading_time(total),
+        READING_SPEED
+    );
+}
+
+/// Downloads one story into the book named by the `--out` template. Returns
+/// `None` if nothing was written, e.g. because the story didn't match the
+/// filters.
+async fn download_story(
+    args: &DownloadArgs,
+    update: bool,
+    client: &Client,
+    url: Option<String>,
+    template: &str,
+) -> Result<Option<Downloaded>> {
+    let book = &args.fetch.book;
+    let title_map = book.title_map()?;
+    let title_template = book.title_template()?;
+    let script = book.script()?;
+    let (started, retries, received) = (Instant::now(), client.retries(), client.received());
+    let suspects = client.suspects();
+
+    let filter = StoryFilter::new(
+        args.author_filter.as_deref(),
+        args.tag_filter.as_deref(),
+        args.title_filter.as_deref(),
+    )?;
+
+    let selection = match (&args.fetch.chapters, args.fetch.from, args.fetch.to) {
+        (Some(spec), _, _) => Some(ChapterSelection::parse(spec)?),
+        (None, None, None) => None,
+        (None, from, to) => Some(ChapterSelection::between(from, to)?),
+    };
+
+    if (update || args.new_since_manifest) && book.format != Format::Epub {
+        return Err(eyre!("only epub books can be updated"));
+    }
+    if (update || args.new_since_manifest) && book.split()?.is_some() {
+        return Err(eyre!("split books can't be updated"));
+    }
+
+    let mut previous = match &args.manifest {
+        Some(path) if args.new_since_manifest => Some(Manifest::load(path)?),
+        _ => None,
+    };
+
+    // books written by `update` carry their own manifest; older ones are
+    // matched against the story once it has been fetched
+    if update {
+        let out = book.out(template)?;
+        previous = ExistingBook::open(&out)?.manifest()?;
+        if previous.is_none() {
+            log::warn!(
+                "{} has no embedded manifest, matching chapters by title",
+                out
+            );
+        }
+    }
+
+    // an anthology is made of every story in --url
+    let url = match (url, &previous) {
+        (Some(url), _) => Some(url),
+        (None, Some(previous)) if !previous.url.is_empty() => Some(previous.url.clone()),
+        _ if args.anthology => None,
+        _ => return Err(eyre!("--url is required, {} doesn't record one", template)),
+    };
+
+    let kindle = match &args.fetch.send_to_kindle {
+        Some(_) if book.format != Format::Epub => {
+            return Err(eyre!("--send-to-kindle only sends epub books"));
+        }
+        Some(address) => Some((
+            address,
+            SmtpConfig::load(args.fetch.smtp_config.as_deref())?,
+        )),
+        None => None,
+    };
+    if args.fetch.add_to_calibre.is_some() {
+        CalibreBook::calibredb()?;
+    }
+
+    if let Some(dir) = args.fetch.raw_dir() {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+    }
+
+    let cache = match (&args.fetch.cache_dir, args.fetch.no_cache) {
+        (_, true) => None,
+        (Some(dir), false) => Some(Cache::new(dir)?),
+        (None, false) => match Cache::default_dir() {
+            Some(dir) => Some(Cache::new(dir)?),
+            None if args.fetch.resume => {
+                return Err(eyre!(
+                    "no cache directory found to resume from, give --cache-dir"
+                ))
+            }
+            None => {
+                log::warn!("no cache directory found, chapter pages won't be cached");
+                None
+            }
+        },
+    };
+    let cache_hits = cache.as_ref().map_or(0, Cache::hits);
+
+    let fetch = FetchOpts {
+        low_memory: args.fetch.low_memory,
+        cache: cache.clone(),
+        resume: args.fetch.resume,
+        refresh: args.fetch.refresh_changed,
+        prefetch: Prefetch::default(),
+    };
+    let window = args.fetch.prefetch_window.min(args.fetch.concurrent);
+
+    let mut story = match url {
+        Some(url) => {
+            log::info!("fetching story...");
+            let story = match book.generic {
+                true => {
+                    crawl_story(client, &url, args.fetch.next_selector.as_deref(), &fetch).await?
+                }
+                false => {
+                    // start on the first chapters while the rest of the
+                    // chapter list is read, when nothing but the title
+                    // filters can keep them out of the book; any the full
+                    // list drops are stopped once it is in
+                    let early = |listed: &[Chapter], part| {
+                        let wanted = match args.fetch.newest_first {
+                            true => ListedChapters::Last,
+                            false => ListedChapters::First,
+                        };
+                        if window == 0
+                            || update
+                            || previous.is_some()
+                            || selection.is_some()
+                            || !filter.is_empty()
+                            || args.dry_run
+                            || (part != ListedChapters::All && part != wanted)
+                        {
+                            return;
+                        }
+                        let mut listed: Vec<&Chapter> = listed
+                            .iter()
+                            .filter(|chapter| args.fetch.title_allowed(&chapter.name))
+                            .collect();
+                        if args.fetch.newest_first {
+                            listed.reverse();
+                        }
+                        let mut started = 0;
+                        for chapter in listed {
+                            if started == window {
+                                break;
+                            }
+                            if !fetch.prefetch.contains(&chapter.link)
+                                && fetch.prefetch.start(client, &chapter.link, &fetch).is_ok()
+                            {
+                                started += 1;
+                            }
+                        }
+                    };
+                    fetch_story_early(client, url, args.fetch.archive.as_deref(), early).await?
+                }
+            };
+            if let Some(cache) = &cache {
+                cache.save_story(&story)?;
+            }
+            if !filter.is_empty() && !filter.matches(&story) {
+                log::info!("{} doesn't match the filters, skipping it", story.title);
+                return Ok(None);
+            }
+            warn_if_stub(&story);
+            story
+        }
+        None => fetch_anthology(client, args, &filter, cache.as_ref()).await?,
+    };
+
+    if let Some(selection) = &selection {
+        let total = story.chapters.len();
+        story.chapters = selection.apply(std::mem::take(&mut story.chapters));
+        if story.chapters.is_empty() {
+            return Err(eyre!("none of the {} chapters were selected", total));
+        }
+        log::info!("selected {} of {} chapters", story.chapters.len(), total);
+    }
+
+    if args.fetch.filters_titles() {
+        let total = story.chapters.len();
+        story
+            .chapters
+            .retain(|chapter| args.fetch.title_allowed(&chapter.name));
+        if story.chapters.is_empty() {
+            return Err(eyre!(
+                "none of the {} chapters match the title filters",
+                total
+            ));
+        }
+        log::info!(
+            "{} of {} chapters match the title filters",
+            story.chapters.len(),
+            total
+        );
+    }
+
+    if args.fetch.progress_format == ProgressFormat::Json {
+        emit(Event::StoryResolved {
+            url: &story.url,
+            title: &story.title,
+            author: &story.author,
+            chapters: story.chapters.len(),
+        });
+    }
+
+    if args.dry_run {
+        dry_run(client, &story, book).await?;
+        return Ok(None);
+    }
+
+    let out = book.out_for(template, &story)?;
+    if !update && !args.new_since_manifest {
+        book.check_overwrite(&out)?;
+    }
 
-    #[arg(short, long, default_value_t = 5)]
-    concurrent: usize,
-}
+    // the appendix is extra, so the book doesn't fail without it
+    let reception = match args.fetch.include_reviews {
+        Some(reviews) => match fetch_reception(client, &story.url, reviews).await {
+            Ok(Some(reception)) if !reception.is_empty() => Some(reception),
+            Ok(_) => {
+                log::warn!("found no ratings or reviews for {}", story.title);
+                None
+            }
+            Err(err) => {
+                log::warn!("could not fetch the ratings and reviews: {:#}", err);
+                None
+            }
+        },
+        None => None,
+    };
 
-#[derive(Debug, Clone)]
-pub struct Chapter {
-    name: String,
-    link: String,
-}
+    // the metadata keeps the chapter names as scraped, and gets the word
+    // counts once the chapters are in
+    let mut metadata =
+        (args.metadata_json.is_some() || args.fetch.archive.is_some()).then(|| story.clone());
 
-#[derive(Debug)]
-pub struct Story {
-    title: String,
-    author: String,
-    description: String,
-    cover: String,
-    chapters: Vec<Chapter>,
-}
+    book.detect_volumes(&mut story.chapters);
+    book.arrange_side_chapters(&mut story.chapters)?;
+    if let Some(metadata) = &mut metadata {
+        // in the book's order, as the pages saved with it are numbered
+        let mut scraped: HashMap<String, Chapter> = std::mem::take(&mut metadata.chapters)
+            .into_iter()
+            .map(|chapter| (chapter.link.clone(), chapter))
+            .collect();
+        metadata.chapters = story
+            .chapters
+            .iter()
+            .filter_map(|chapter| scraped.remove(&chapter.link))
+            .collect();
+    }
+    if let Some(title_map) = &title_map {
+        title_map.apply(&mut story.chapters);
+    }
+    if let Some(title_template) = &title_template {
+        title_template.apply(&mut story.chapters);
+    }
+    book.set_language(&mut story);
+    if args.fetch.include_pages {
+        add_pages(&mut story);
+    }
 
-fn selector(str: &str) -> Result<Selector> {
-    Selector::parse(str).map_err(|_| eyre!("invalid selector"))
-}
-
-async fn fetch_story(url: String) -> Result<Story> {
-    let url = url.split("/chapter/").next().unwrap();
-    let resp = reqwest::get(url).await?.text().await?;
-
-    let doc = Html::parse_document(resp.as_str());
-
-    let cover = doc
-        .select(&selector(r#"meta[name="twitter:image"]"#)?)
-        .next()
-        .unwrap()
-        .attr("content")
-        .ok_or_else(|| eyre!("could not find cover image"))?
-        .to_string();
-
-    let author = doc
-        .select(&selector(r#"meta[name="twitter:creator"]"#)?)
-        .next()
-        .unwrap()
-        .attr("content")
-        .ok_or_else(|| eyre!("could not find author"))?
-        .to_string();
-
-    let title = doc
-        .select(&selector(r#"meta[name="twitter:title"]"#)?)
-        .next()
-        .unwrap()
-        .attr("content")
-        .ok_or_else(|| eyre!("could not find title"))?
-        .to_string();
-
-    let description = doc
-        .select(&selector(r#"meta[name="twitter:description"]"#)?)
-        .next()
-        .unwrap()
-        .attr("content")
-        .ok_or_else(|| eyre!("could not find description"))?
-        .to_string();
-
-    let table = doc
-        .select(&selector(r#"table[id="chapters"]"#)?)
-        .next()
-        .ok_or_else(|| eyre!("could not find chapters"))?;
-
-    let mut chapters = Vec::new();
-
-    for chap in table.select(&selector(
-        "#chapters > tbody > tr.chapter-row > td:first-child > a",
-    )?) {
-        let link = chap.attr("href").unwrap();
-        let name = chap.text().next().unwrap().trim();
-
-        chapters.push(Chapter {
-            name: name.to_string(),
-            link: link.to_string(),
-        });
+    let clean_opts = book.clean_opts();
+    let page_opts = PageOpts {
+        clean: clean_opts,
+        fetch: FetchOpts {
+            // a crawl has just fetched every chapter page into the cache
+            resume: args.fetch.resume || book.generic,
+            ..fetch.clone()
+        },
+        save_raw: args.fetch.raw_dir().cloned(),
+        // text books have nowhere to put them
+        embed_images: !args.fetch.remote_images
+            && !matches!(book.format, Format::Txt | Format::Audiobook),
+        images: book.image_opts(),
+        progress: Progress::new(
+            !args.fetch.no_progress && std::io::stderr().is_terminal(),
+            args.fetch.progress_format == ProgressFormat::Json,
+            &story.chapters,
+            client,
+        ),
+    };
+    let progress = page_opts.progress.clone();
+
+    let groups = book.page_groups(&story.chapters);
+    if let (true, Some(cache)) = (args.fetch.resume, &cache) {
+        let cached = groups
+            .iter()
+            .filter_map(|group| chapter_url(&story.chapters[group[0]].link).ok())
+            .filter(|url| cache.page(url).is_some())
+            .count();
+        log::info!(
+            "resuming: {} of {} chapter pages are already downloaded",
+            cached,
+            groups.len()
+        );
+    }
+    let order: Vec<usize> = if args.fetch.newest_first {
+        (0..groups.len()).rev().collect()
+    } else {
+        (0..groups.len()).collect()
+    };
+
+    // only the chapters left in the book are wanted of those started early
+    fetch.prefetch.keep(
+        groups
+            .iter()
+            .map(|group| story.chapters[group[0]].link.as_str()),
+    );
+
+    // start on the first chapters, or carry on with them, while the cover
+    // and the previous book are handled below; the pipeline picks the
+    // results up in place of a fetch
+    let mut prefetched: Vec<_> = (0..groups.len()).map(|_| None).collect();
+    for &g in order
+        .iter()
+        .take(args.fetch.prefetch_window.min(args.fetch.concurrent))
+    {
+        prefetched[g] = Some(tokio::spawn(fetch_page(
+            client.clone(),
+            groups[g][0],
+            group_chapters(&story.chapters, &groups[g]),
+            page_opts.clone(),
+        )));
     }
 
-    Ok(Story {
-        title,
-        author,
-        description,
-        cover,
-        chapters,
-    })
-}
+    let mut builder = new_builder(&story, book, Some(client))?;
+
+    // add the cover image, which Calibre is given too
+    let mut calibre_cover = None;
+    if let Some(cover) = book_cover(client, &story, book, cache.as_ref()).await? {
+        if let Some(dir) = args.fetch.raw_dir() {
+            cover.save_to_dir(dir)?;
+        }
+        let cover = book.image_opts().shrink_cover(cover);
+        builder.add_cover(&cover, !book.hide_extras_in_toc)?;
+        if args.fetch.add_to_calibre.is_some() {
+            calibre_cover = Some(cover);
+        }
+    }
+    if book.title_page {
+        builder.add_title_page(&story, !book.hide_extras_in_toc)?;
+    }
+    if book.front_matter {
+        builder.add_front_matter(&story, !book.hide_extras_in_toc)?;
+    }
+
+    // build the table of contents
+    if book.inline_toc() {
+        builder.add_toc();
+    }
+
+    // fetch and add the chapters
+    let total = story.chapters.len();
+
+    if update && previous.is_none() {
+        previous = Some(ExistingBook::open(&out)?.manifest_from_toc(&story)?);
+    }
+
+    // chapters already in the previous book don't need to be downloaded
+    // again; they are read from it as they are added, so the previous book
+    // never has to fit in memory
+    let mut reused = vec![None; total];
+    let mut existing = None;
+    if let Some(previous) = &previous {
+        existing = Some(ExistingBook::open(&out)?);
+        for (i, chapter) in story.chapters.iter().enumerate() {
+            if let Some(old) = previous.chapter(&chapter.link) {
+                reused[i] = Some(old.file.clone());
+            }
+        }
+
+        let new = reused.iter().filter(|c| c.is_none()).count();
+        log::info!(
+            "found {} new chapter(s) of {} since the previous book",
+            new,
+            total
+        );
+        if args.fetch.refresh_changed {
+            log::info!(
+                "fetching the {} earlier chapter(s) again to look for revisions",
+                total - new
+            );
+        }
+    }
+    let chapters = &story.chapters;
+    let groups = &groups;
+    let reused = &reused;
+    let skip_failed = args.fetch.skip_failed;
+    let skip_unavailable = args.fetch.skip_unavailable;
+    let refresh = args.fetch.refresh_changed && previous.is_some();
+    // (chapter, error) of the chapters skipped with --skip-failed or
+    // --skip-unavailable
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    // whether Ctrl-C or --max-download-size left chapters unfetched
+    let stopped = std::cell::Cell::new(false);
+    let fetched = stream::iter(order.into_iter().map(|g| (g, prefetched[g].take())))
+        .take_while(|_| {
+            stopped.set(stopping(client, &args.fetch.net));
+            std::future::ready(!stopped.get())
+        })
+        .map(|(g, prefetch)| {
+            let client = client.clone();
+            let page_opts = page_opts.clone();
+            let failures = failures.clone();
+            async move {
+                let group = &groups[g];
+                if !refresh && group.iter().all(|&i| reused[i].is_some()) {
+                    page_opts.progress.fetched(group);
+                    return Ok(group
+                        .iter()
+                        .map(|&i| (i, Page::Reused(reused[i].clone().unwrap())))
+                        .collect());
+                }
+
+                let progress = page_opts.progress.clone();
+                let contents = match prefetch {
+                    Some(handle) => handle.await?,
+                    None => {
+                        fetch_page(client, group[0], group_chapters(chapters, group), page_opts)
+                            .await
+                    }
+                };
+                match &contents {
+                    Ok(_) => progress.fetched(group),
+                    Err(err) => progress.failed(group, &format!("{:#}", err)),
+                }
+                let failed = contents.is_err();
+                let contents = match contents {
+                    Err(err)
+                        if skip_failed
+                            || (skip_unavailable && ChapterError::is_unavailable(&err)) =>
+                    {
+                        let err = format!("{:#}", err);
+                        log::warn!("skipping {}: {}", chapters[group[0]].name, err);
+                        let mut failures = failures.lock().unwrap();
+                        group
+                            .iter()
+                            .map(|&i| {
+                                // an earlier chapter keeps what the book has
+                                if reused[i].is_none() {
+                                    failures.push((i, err.clone()));
+                                }
+                                (failed_chapter(&chapters[i], &err), Vec::new())
+                            })
+                            .collect()
+                    }
+                    contents => contents?,
+                };
+
+                Ok::<_, eyre::Error>(
+                    group
+                        .iter()
+                        .zip(contents)
+                        .map(|(&i, (content, images))| match &reused[i] {
+                            Some(file) if !refresh || failed => (i, Page::Reused(file.clone())),
+                            _ => (i, Page::Fetched(content, images)),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+        })
+        .buffered(args.fetch.concurrent)
+        .map_ok(|contents| stream::iter(contents.into_iter().map(Ok)))
+        .try_flatten();
+
+    let mut added = 0;
+    // the chapters fetched, from the site or the cache, and those taken
+    // from the previous book
+    let (mut from_site, mut from_book) = (0, 0);
+    let mut dropped = Vec::new();
+    // the earlier chapters whose text changed since the previous book
+    let mut revised = Vec::new();
+    let mut words = vec![None; total];
+    let links = ChapterLinks::new(chapters);
+    let mut add = |i: usize, page: Page| -> Result<()> {
+        let (content, images) = match page {
+            Page::Fetched(content, images) => {
+                from_site += 1;
+                (content, images)
+            }
+            Page::Reused(file) => {
+                from_book += 1;
+                let existing = existing.as_mut().unwrap();
+                let content = existing.chapter_content(&file)?;
+                let images = existing.chapter_images(&content)?;
+                (content, images)
+            }
+        };
+        let content = book.replacements.apply(&content).into_owned();
+        let content = links
+            .rewrite(&content, |nr| builder.chapter_link(nr))
+            .unwrap_or(content);
+        let Some((chapter, content)) = run_script(script.as_ref(), i, &chapters[i], content)?
+        else {
+            dropped.push(i);
+            return Ok(());
+        };
+        let content = book.with_heading(&chapter, content);
+        if let (true, Some(file)) = (refresh, &reused[i]) {
+            let old = existing.as_mut().unwrap().chapter_content(file)?;
+            if chapter_text(&old) != chapter_text(&content) {
+                revised.push(i);
+            }
+        }
+        add_page(builder.as_mut(), i, &chapter, &content, &images)?;
+        words[i] = Some(word_count(&content) as u64);
+        added += 1;
+        Ok(())
+    };
+    // `buffered` yields chapters in fetch order, which isn't book order with
+    // --newest-first or when chapters sharing a page are apart in the list,
+    // so each is held until the chapters before it are added
+    let mut pending: Vec<Option<Page>> = (0..total).map(|_| None).collect();
+    let mut next = 0;
+    let mut add_failed = false;
+    let result = fetched
+        .try_for_each(|(i, page)| {
+            pending[i] = Some(page);
+            let mut result = Ok(());
+            while let Some(page) = pending.get_mut(next).and_then(Option::take) {
+                result = add(next, page);
+                next += 1;
+                if result.is_err() {
+                    add_failed = true;
+                    break;
+                }
+            }
+            std::future::ready(result)
+        })
+        .await;
+    // what was fetched before a failed fetch or a stop still goes in the book
+    let result = match add_failed {
+        true => result,
+        false => pending
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, page)| Some((i, page?)))
+            .try_for_each(|(i, page)| add(i, page))
+            .and(result),
+    };
+
+    progress.finish();
+
+    let mut skipped = std::mem::take(&mut *failures.lock().unwrap());
+    skipped.sort();
+    // chapters the script left out are omitted from the manifest like
+    // skipped ones, so they go through the script again on update
+    let mut skipped_chapters: Vec<usize> = skipped.iter().map(|(i, _)| *i).collect();
+    skipped_chapters.extend(dropped);
+    skipped_chapters.sort();
+
+    for &i in &skipped_chapters {
+        words[i] = None;
+    }
+    if let Some(metadata) = &mut metadata {
+        for (chapter, words) in metadata.chapters.iter_mut().zip(words.iter().copied()) {
+            chapter.words = words;
+        }
+        let archived = args
+            .fetch
+            .archive
+            .as_ref()
+            .map(|dir| dir.join(ARCHIVE_METADATA));
+        for path in args.metadata_json.iter().chain(&archived) {
+            std::fs::write(path, serde_json::to_string_pretty(metadata)?)
+                .wrap_err_with(|| format!("could not write {}", path.display()))?;
+        }
+    }
+
+    if stopped.get() {
+        let resume = match args.fetch.no_cache {
+            true => "again",
+            false => "again with --resume",
+        };
+        log::warn!(
+            "stopped after {} of {} chapters; run the same command {} to fetch the rest",
+            added,
+            total,
+            resume
+        );
+        // an update would replace a complete book with an incomplete one
+        if added == 0 || update {
+            return Err(stopped_error());
+        }
+    }
+    let result = result.and_then(|_| match stopped.get() {
+        true => Err(stopped_error()),
+        false => Ok(()),
+    });
 
-async fn fetch_chapter_content(url: &str) -> Result<String> {
-    let base_url = Url::parse("https://www.royalroad.com")?;
-    let url = base_url.join(url)?;
-    let resp = reqwest::get(url).await?.text().await?;
-    let doc = Html::parse_document(resp.as_str());
-
-    let content = doc
-        .select(&selector("div.chapter-content")?)
-        .next()
-        .ok_or(eyre!("couldn't find chapter content"))?;
-
-    // serialize as xhtml
-    let mut buf = Vec::new();
-    serialize::serialize(
-        &mut buf,
-        &content,
-        SerializeOpts {
-            scripting_enabled: false,
-            traversal_scope: TraversalScope::IncludeNode,
-            create_missing_parent: false,
+    // the previous book is closed before the new one takes its place, which
+    // not every system allows for open files
+    drop(existing);
+    let complete = result.is_ok();
+    let files = finish_book(
+        builder,
+        &story,
+        book,
+        &out,
+        Fetched {
+            result,
+            added,
+            skipped: &skipped_chapters,
         },
+        reception.as_ref(),
+        args.fetch.partial_on_error || stopped.get(),
     )?;
 
-    Ok(String::from_utf8(buf)?)
+    if let (Some(path), true) = (&args.manifest, complete) {
+        book_manifest(&story, &skipped_chapters, book.reproducible).save(path)?;
+    }
+
+    if args.fetch.progress_format == ProgressFormat::Json {
+        for file in &files {
+            emit(Event::EpubWritten {
+                path: file,
+                format: book.format.name(),
+                chapters: added,
+                skipped: skipped.len(),
+                complete,
+            });
+        }
+    }
+
+    if book.stats {
+        print_stats(&story.chapters, &words);
+    }
+
+    // the book is written either way, so a broken library isn't fatal.
+    // Split books and anthologies can't be updated, so they aren't recorded
+    let recorded = args.fetch.library().and_then(|library| match library {
+        Some(library) if files.len() == 1 && !args.anthology => {
+            library.record(&files[0], &story, book.format.name())
+        }
+        _ => Ok(()),
+    });
+    if let Err(err) = recorded {
+        log::warn!("could not record {} in the library: {:#}", out, err);
+    }
+
+    if let Some(library) = &args.fetch.add_to_calibre {
+        let series = book.series();
+        for (i, file) in files.iter().enumerate() {
+            // split books are a series of their own, like in the books
+            let (volume, series) = match files.len() {
+                1 => (None, series.clone()),
+                _ => (
+                    Some(i + 1),
+                    Some(Series {
+                        name: series
+                            .as_ref()
+                            .map_or_else(|| story.title.clone(), |series| series.name.clone()),
+                        index: series.as_ref().map_or(1.0, |series| series.index) + i as f64,
+                    }),
+                ),
+            };
+            let id = CalibreBook {
+                library: library.as_deref(),
+                file,
+                story: &story,
+                volume,
+                series: series.as_ref(),
+                cover: calibre_cover.as_ref(),
+            }
+            .add()?;
+            log::info!("added {} to Calibre as book {}", file.display(), id);
+        }
+    }
+
+    if let Some((address, config)) = &kindle {
+        for file in &files {
+            log::info!("sending {} to {}...", file.display(), address);
+            send_to_kindle(config, address, file, &story.title).await?;
+        }
+    }
+
+    if refresh {
+        if revised.is_empty() {
+            log::info!("none of the earlier chapters were revised");
+        } else {
+            revised.sort();
+            println!(
+                "{} earlier chapter(s) of {} were revised and replaced:",
+                revised.len(),
+                story.title
+            );
+            for &i in &revised {
+                println!("  {}. {}", i + 1, chapters[i].name);
+            }
+        }
+    }
+
+    if !skipped.is_empty() {
+        println!(
+            "{} chapter(s) of {} could not be downloaded and were skipped:",
+            skipped.len(),
+            story.title
+        );
+        for (i, err) in &skipped {
+            println!("  {}. {}: {}", i + 1, chapters[*i].name, err);
+        }
+    }
+
+    let summary = Summary {
+        title: &story.title,
+        out: &out,
+        complete,
+        chapters: added,
+        fetched: from_site,
+        cached: cache.as_ref().map_or(0, |cache| cache.hits() - cache_hits),
+        reused: from_book,
+        retries: client.retries() - retries,
+        skipped: skipped.len(),
+        suspect: client.suspects() - suspects,
+        words: words.iter().flatten().sum(),
+        bytes: files.iter().map(|file| disk_size(file)).sum(),
+        downloaded: client.received() - received,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    };
+    summary.print(args.fetch.summary);
+
+    if let (Some(cache), Some(max)) = (&cache, args.fetch.cache_max_size) {
+        match cache.evict(max) {
+            Ok(purged) if purged.pages > 0 => log::info!(
+                "removed {} pages ({}) from the cache to keep it under {}",
+                purged.pages,
+                human_size(purged.bytes),
+                human_size(max)
+            ),
+            Ok(_) => {}
+            Err(err) => log::warn!("could not trim the cache: {:#}", err),
+        }
+    }
+
+    Ok(Some(Downloaded {
+        out,
+        chapters: added,
+        skipped: skipped.len(),
+    }))
+}
+
+/// What a download did, printed at the end of it.
+#[derive(serde::Serialize)]
+struct Summary<'a> {
+    title: &'a str,
+    out: &'a str,
+    complete: bool,
+    chapters: usize,
+    /// Chapters fetched, from the site or the cache.
+    fetched: usize,
+    /// Pages taken from the cache without downloading them again.
+    cached: usize,
+    /// Chapters taken from the previous book.
+    reused: usize,
+    retries: usize,
+    skipped: usize,
+    /// Chapters that still looked like a login or JavaScript wall when
+    /// fetched again, which are in the book as they are.
+    suspect: usize,
+    words: u64,
+    bytes: u64,
+    /// Bytes downloaded for the book, its chapters, images and pages.
+    downloaded: u64,
+    elapsed_secs: f64,
+}
+
+impl Summary<'_> {
+    /// Prints the summary as `format` says: text on stderr, in color on a
+    /// terminal unless `NO_COLOR` is set, and left out with `-q`; JSON as
+    /// one line on stdout.
+    fn print(&self, format: SummaryFormat) {
+        match format {
+            SummaryFormat::None => (),
+            SummaryFormat::Json => match serde_json::to_string(self) {
+                Ok(json) => println!("{}", json),
+                Err(err) => log::warn!("could not write the summary: {}", err),
+            },
+            SummaryFormat::Text if log::max_level() < log::LevelFilter::Info => (),
+            SummaryFormat::Text => {
+                let color =
+                    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+                let paint = |text: &str, code: &str| {
+                    if color {
+                        format!("\x1b[{}m{}\x1b[0m", code, text)
+                    } else {
+                        text.to_string()
+                    }
+                };
+                let status = match (self.complete, self.skipped + self.suspect) {
+                    (true, 0) => paint("done", "1;32"),
+                    (true, _) => paint("partial", "1;33"),
+                    (false, _) => paint("incomplete", "1;31"),
+                };
+                let mut chapters = format!("{} fetched", self.fetched);
+                if self.cached > 0 {
+                    chapters.push_str(&format!(" ({} from the cache)", self.cached));
+                }
+                if self.reused > 0 {
+                    chapters.push_str(&format!(", {} from the previous book", self.reused));
+                }
+                if self.retries > 0 {
+                    chapters.push_str(&format!(", {} retries", self.retries));
+                }
+                if self.skipped > 0 {
+                    let skipped = format!("{} skipped", self.skipped);
+                    chapters.push_str(&format!(", {}", paint(&skipped, "33")));
+                }
+                if self.suspect > 0 {
+                    let suspect = format!("{} suspect", self.suspect);
+                    chapters.push_str(&format!(", {}", paint(&suspect, "33")));
+                }
+                eprintln!(
+                    "{} {} -> {} ({}, {} downloaded, {})",
+                    status,
+                    self.title,
+                    self.out,
+                    human_size(self.bytes),
+                    human_size(self.downloaded),
+                    elapsed(self.elapsed_secs)
+                );
+                eprintln!("  {} chapters: {}", self.chapters, chapters);
+                eprintln!("  {} words", thousands(self.words as usize));
+            }
+        }
+    }
 }
 
-async fn fetch_and_add_cover(builder: &mut EpubBuilder<ZipLibrary>, url: &str) -> Result<()> {
-    let url = Url::parse(url)?;
-    let ext = url.path().split(".").last().unwrap().to_owned();
+/// `secs` for people, e.g. `4.2s` or `3m 07s`.
+fn elapsed(secs: f64) -> String {
+    if secs < 60.0 {
+        return format!("{:.1}s", secs);
+    }
+    let secs = secs.round() as u64;
+    match secs / 3600 {
+        0 => format!("{}m {:02}s", secs / 60, secs % 60),
+        hours => format!("{}h {:02}m", hours, secs / 60 % 60),
+    }
+}
 
-    let mime = match ext.as_str() {
-        "jpg" | "jpeg" => "image/jpeg",
-        "png" => "image/png",
-        _ => Err(eyre!("unsupported cover format"))?,
+/// The size of the file at `path`, or of everything in the directory.
+fn disk_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return 0;
     };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| disk_size(&entry.path()))
+        .sum()
+}
 
-    let data = reqwest::get(url).await?.bytes().await?;
-    builder.add_cover_image(format!("cover.{}", ext), data.as_ref(), mime)?;
+/// Fetches the stories of `--anthology` that match `filter` and binds them
+/// into one.
+async fn fetch_anthology(
+    client: &Client,
+    args: &DownloadArgs,
+    filter: &StoryFilter,
+    cache: Option<&Cache>,
+) -> Result<Story> {
+    let mut stories = Vec::with_capacity(args.url.len());
+    for (i, url) in args.url.iter().enumerate() {
+        log::info!("fetching story {} of {}: {}", i + 1, args.url.len(), url);
+        let story = fetch_story(client, url.clone()).await?;
+        if let Some(cache) = cache {
+            cache.save_story(&story)?;
+        }
+        if !filter.is_empty() && !filter.matches(&story) {
+            log::info!("{} doesn't match the filters, leaving it out", story.title);
+            continue;
+        }
+        warn_if_stub(&story);
+        log::info!(
+            "{} by {}: {} chapters",
+            story.title,
+            story.author,
+            story.chapters.len()
+        );
+        stories.push(story);
+    }
+    if stories.is_empty() {
+        return Err(eyre!(
+            "none of the {} stories match the filters",
+            args.url.len()
+        ));
+    }
+    Ok(anthology(stories, args.anthology_title.as_deref()))
+}
 
-    let cover_page = format!(
-        r#"<?xml version="1.0" encoding="utf-8"?>
-<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
-<html xmlns="http://www.w3.org/1999/xhtml">
-    <head>
-        <title>Cover</title>
-        <meta http-equiv="Content-Type" content="text/html; charset=utf-8"/>
-        <link rel="stylesheet" type="text/css" href="stylesheet.css"/>
-    </head>
-    <body>
-        <img src="cover.{}" />
-    </body>
-</html>"#,
-        ext
-    );
-    builder.add_content(
-        EpubContent::new("cover.xhtml", cover_page.as_bytes())
-            .title("Cover")
-            .reftype(ReferenceType::Cover),
-    )?;
+/// The book's cover: `--cover`, a generated one, or the story's own, which
+/// is kept in `cache`.
+async fn book_cover(
+    client: &Client,
+    story: &Story,
+    book: &BookArgs,
+    cache: Option<&Cache>,
+) -> Result<Option<Cover>> {
+    if let Some(cover) = &book.cover {
+        log::info!("using the cover {}", cover);
+        return load_cover(client, cover).await.map(Some);
+    }
+    if book.generate_cover {
+        return generate_cover(story).map(Some);
+    }
+    if book.no_cover {
+        return Ok(None);
+    }
+    if !story.has_cover() {
+        log::info!("the story has no cover, generating one");
+        return generate_cover(story).map(Some);
+    }
 
-    Ok(())
+    log::info!("fetching cover...");
+    let cover = fetch_cover(client, &story.cover).await?;
+    if let Some(cache) = cache {
+        cache.save_cover(&story.url, &cover)?;
+    }
+    Ok(Some(cover))
+}
+
+/// Whether the chapter text `content` starts with a heading, inside any
+/// wrapping `<div>`s.
+fn starts_with_heading(content: &str) -> bool {
+    let mut rest = content.trim_start();
+    while rest.starts_with("<div") || rest.starts_with("<section") {
+        let Some(end) = rest.find('>') else {
+            return false;
+        };
+        rest = rest[end + 1..].trim_start();
+    }
+    let bytes = rest.as_bytes();
+    bytes.len() > 3
+        && bytes[0] == b'<'
+        && bytes[1].eq_ignore_ascii_case(&b'h')
+        && (b'1'..=b'6').contains(&bytes[2])
+}
+
+/// A chapter's content on its way into the book.
+enum Page {
+    /// Downloaded, with the images it embeds.
+    Fetched(String, Vec<Image>),
+    /// The chapter in this file of the previous book, read once it is added.
+    Reused(String),
 }
 
-fn add_chapter(
-    builder: &mut EpubBuilder<ZipLibrary>,
+/// Adds a chapter and the images it refers to.
+fn add_page(
+    builder: &mut dyn BookWriter,
     nr: usize,
     chapter: &Chapter,
     content: &str,
+    images: &[Image],
 ) -> Result<()> {
-    let xhtml = format!(
-        r#"<?xml version="1.0" encoding="utf-8"?>
-<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
-<html xmlns="http://www.w3.org/1999/xhtml">
-    <head>
-        <title>{}</title>
-        <meta http-equiv="Content-Type" content="text/html; charset=utf-8"/>
-        <link rel="stylesheet" type="text/css" href="stylesheet.css"/>
-    </head>
-    <body>
-        {}
-    </body>
-</html>
-        "#,
-        chapter.name, content
+    for image in images {
+        builder.add_image(image)?;
+    }
+    builder.add_chapter(nr, chapter, content)
+}
+
+/// Runs `script`, if any, on the chapter `nr`. Returns the chapter with the
+/// title and content the script leaves it, or `None` if it is left out.
+fn run_script<'a>(
+    script: Option<&ChapterScript>,
+    nr: usize,
+    chapter: &'a Chapter,
+    content: String,
+) -> Result<Option<(Cow<'a, Chapter>, String)>> {
+    let Some(script) = script else {
+        return Ok(Some((Cow::Borrowed(chapter), content)));
+    };
+    let Some((name, content)) = script.apply(&chapter.name, &content)? else {
+        log::info!("the script left out chapter {}: {}", nr + 1, chapter.name);
+        return Ok(None);
+    };
+    if name == chapter.name {
+        return Ok(Some((Cow::Borrowed(chapter), content)));
+    }
+    Ok(Some((
+        Cow::Owned(Chapter {
+            name,
+            ..chapter.clone()
+        }),
+        content,
+    )))
+}
+
+fn group_chapters(chapters: &[Chapter], group: &[usize]) -> Vec<Chapter> {
+    group.iter().map(|&i| chapters[i].clone()).collect()
+}
+
+/// The state of the chapter downloads, shown as a progress bar or, without
+/// one, logged as each fetch starts.
+#[derive(Clone)]
+struct Progress {
+    bar: Option<ProgressBar>,
+    failed: Arc<AtomicUsize>,
+    /// The chapter titles, when progress is reported as JSON events.
+    events: Option<Arc<Vec<String>>>,
+}
+
+/// What is printed once a download is done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum SummaryFormat {
+    /// A few lines on stderr: the chapters fetched, the words, the size of
+    /// the book and the time it took
+    Text,
+    /// The same as one JSON object on stdout
+    Json,
+    /// Nothing
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ProgressFormat {
+    /// A progress bar on stderr, when it is a terminal
+    Bar,
+    /// Newline-delimited JSON events on stdout
+    Json,
+}
+
+/// What `--progress-format json` reports. Chapters are numbered from 1.
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    StoryResolved {
+        url: &'a str,
+        title: &'a str,
+        author: &'a str,
+        chapters: usize,
+    },
+    ChapterFetched {
+        chapter: usize,
+        title: &'a str,
+    },
+    ChapterFailed {
+        chapter: usize,
+        title: &'a str,
+        error: &'a str,
+    },
+    EpubWritten {
+        path: &'a Path,
+        format: &'a str,
+        chapters: usize,
+        skipped: usize,
+        complete: bool,
+    },
+}
+
+/// Prints `event` as one line of JSON.
+fn emit(event: Event) {
+    match serde_json::to_string(&event) {
+        Ok(json) => println!("{}", json),
+        Err(err) => log::warn!("could not report progress: {}", err),
+    }
+}
+
+/// The bar currently on screen, which log messages are printed above.
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+impl Progress {
+    fn new(show: bool, json: bool, chapters: &[Chapter], client: &Client) -> Progress {
+        let failed = Arc::new(AtomicUsize::new(0));
+        if json {
+            let names = chapters
+                .iter()
+                .map(|chapter| chapter.name.clone())
+                .collect();
+            return Progress {
+                bar: None,
+                failed,
+                events: Some(Arc::new(names)),
+            };
+        }
+        if !show {
+            return Progress {
+                bar: None,
+                failed,
+                events: None,
+            };
+        }
+
+        let bar = ProgressBar::new(chapters.len() as u64).with_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:30} {pos}/{len} chapters  {msg}",
+            )
+            .unwrap(),
+        );
+        *PROGRESS_BAR.lock().unwrap() = Some(bar.clone());
+
+        // the retry count and throughput change between chapters, so the
+        // message is refreshed on a timer
+        let (ticker, client, counted) = (bar.clone(), client.clone(), failed.clone());
+        let start = (Instant::now(), client.received());
+        tokio::spawn(async move {
+            while !ticker.is_finished() {
+                let secs = start.0.elapsed().as_secs_f64().max(0.001);
+                let rate = ((client.received() - start.1) as f64 / secs) as u64;
+                ticker.set_message(format!(
+                    "{} failed, {} retrying, {}/s",
+                    counted.load(Ordering::Relaxed),
+                    client.retrying(),
+                    human_size(rate)
+                ));
+                tokio::time::sleep(Duration::from_millis(250)).await;
+            }
+        });
+
+        Progress {
+            bar: Some(bar),
+            failed,
+            events: None,
+        }
+    }
+
+    fn fetching(&self, nr: usize) {
+        if self.bar.is_none() {
+            log::info!("fetching chapter {}...", nr);
+        }
+    }
+
+    /// The chapters at `chapters` (indices) were fetched or reused.
+    fn fetched(&self, chapters: &[usize]) {
+        if let Some(bar) = &self.bar {
+            bar.inc(chapters.len() as u64);
+        }
+        if let Some(names) = &self.events {
+            for &i in chapters {
+                emit(Event::ChapterFetched {
+                    chapter: i + 1,
+                    title: &names[i],
+                });
+            }
+        }
+    }
+
+    fn failed(&self, chapters: &[usize], err: &str) {
+        self.failed.fetch_add(chapters.len(), Ordering::Relaxed);
+        if let Some(names) = &self.events {
+            for &i in chapters {
+                emit(Event::ChapterFailed {
+                    chapter: i + 1,
+                    title: &names[i],
+                    error: err,
+                });
+            }
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+            *PROGRESS_BAR.lock().unwrap() = None;
+        }
+    }
+}
+
+/// Prints log messages above the progress bar instead of through it.
+struct ProgressLogger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for ProgressLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let bar = PROGRESS_BAR.lock().unwrap().clone();
+        match bar {
+            Some(bar) => bar.suspend(|| self.inner.log(record)),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[derive(Clone)]
+struct PageOpts {
+    clean: CleanOpts,
+    fetch: FetchOpts,
+    /// Save the raw pages to this directory
+    save_raw: Option<PathBuf>,
+    embed_images: bool,
+    images: ImageOpts,
+    progress: Progress,
+}
+
+/// Fetches the page shared by `chapters`, the first of which is chapter
+/// `first`, and returns their content along with the images to embed.
+/// Takes owned arguments so it can be spawned for `--prefetch-window`.
+async fn fetch_page(
+    client: Client,
+    first: usize,
+    chapters: Vec<Chapter>,
+    opts: PageOpts,
+) -> Result<Vec<(String, Vec<Image>)>> {
+    let chapter = &chapters[0];
+    opts.progress.fetching(first);
+
+    let raw = opts.save_raw.map(|dir| dir.join(raw_chapter_file(first)));
+    let contents = if chapters.len() == 1 {
+        fetch_chapter_content(
+            &client,
+            &chapter.link,
+            &opts.clean,
+            &opts.fetch,
+            raw.as_deref(),
+        )
+        .await
+        .map(|content| vec![content])
+    } else {
+        let anchors: Vec<_> = chapters
+            .iter()
+            .map(|chapter| link_anchor(&chapter.link))
+            .collect();
+        fetch_shared_chapters(
+            &client,
+            &chapter.link,
+            &anchors,
+            &opts.clean,
+            &opts.fetch,
+            raw.as_deref(),
+        )
+        .await
+    };
+
+    let url = chapter_url(&chapter.link);
+    let contents = contents.wrap_err_with(|| {
+        let url = url
+            .as_ref()
+            .map(|url| url.to_string())
+            .unwrap_or_else(|_| chapter.link.clone());
+        format!("chapter {} ({:?}) at {}", first + 1, chapter.name, url)
+    })?;
+
+    let mut pages = Vec::with_capacity(contents.len());
+    for content in contents {
+        pages.push(match (&url, opts.embed_images) {
+            (Ok(url), true) => {
+                let (content, images) = embed_images(&client, url, &content).await;
+                let images = images
+                    .into_iter()
+                    .map(|image| opts.images.shrink_image(image))
+                    .collect();
+                (content, images)
+            }
+            _ => (content, Vec::new()),
+        });
+    }
+    Ok(pages)
+}
+
+/// Reports the story and an estimate of the EPUB size. A few chapters are
+/// downloaded for the estimate; nothing is written.
+async fn dry_run(client: &Client, story: &Story, book: &BookArgs) -> Result<()> {
+    println!("{} by {}", story.title, story.author);
+    print_chapters(story);
+    println!();
+
+    log::info!(
+        "estimating the EPUB size from up to {} chapters...",
+        ESTIMATE_SAMPLES
     );
+    let estimate = estimate_size(client, story, &book.clean_opts()).await?;
+    println!("EPUB size: {}", estimate);
 
-    builder.add_content(
-        EpubContent::new(format!("chapter_{}.xhtml", nr), xhtml.as_bytes()).title(&chapter.name),
-    )?;
+    Ok(())
+}
+
+/// Checks that `dir` holds a raw page for every page in `groups`.
+fn check_raw_dir(dir: &Path, story: &Story, groups: &[Vec<usize>]) -> Result<()> {
+    let missing: Vec<String> = groups
+        .iter()
+        .map(|group| raw_chapter_file(group[0]))
+        .filter(|file| !dir.join(file).is_file())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(eyre!(
+            "{} is missing {} of {} chapter pages, starting with {}",
+            dir.display(),
+            missing.len(),
+            groups.len(),
+            missing[0]
+        ));
+    }
+
+    let extra = (story.chapters.len()..)
+        .map(raw_chapter_file)
+        .take_while(|file| dir.join(file).is_file())
+        .count();
+    if extra > 0 {
+        log::warn!(
+            "{} has {} chapter page(s) beyond the {} listed in the metadata; ignoring them",
+            dir.display(),
+            extra,
+            story.chapters.len()
+        );
+    }
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("error,rustyroad=info"));
+/// Builds a book from a `--save-raw` directory without touching the network.
+fn build(args: BuildArgs) -> Result<()> {
+    let book = &args.book;
+    let metadata = std::fs::read_to_string(&args.metadata)
+        .wrap_err_with(|| format!("could not read {}", args.metadata.display()))?;
+    let story: Story = serde_json::from_str(&metadata)
+        .wrap_err_with(|| format!("invalid metadata {}", args.metadata.display()))?;
 
-    let args = Args::parse();
+    check_raw_dir(&args.raw_dir, &story, &book.page_groups(&story.chapters))?;
+    let saved = Cover::load_from_dir(&args.raw_dir)?;
+    let cover = offline_cover(
+        "build",
+        book,
+        &story,
+        saved,
+        &args.raw_dir.display().to_string(),
+    )?;
+    build_book(book, story, cover, |i| {
+        Ok(args.raw_dir.join(raw_chapter_file(i)))
+    })
+}
 
-    log::info!("fetching story...");
-    let story = fetch_story(args.url).await?;
+/// The cover of a book written offline: a local `--cover`, a generated one,
+/// or the `saved` cover of the story, which was kept in `source`.
+fn offline_cover(
+    command: &str,
+    book: &BookArgs,
+    story: &Story,
+    saved: Option<Cover>,
+    source: &str,
+) -> Result<Option<Cover>> {
+    Ok(match &book.cover {
+        Some(cover) if is_remote_cover(cover) => {
+            return Err(eyre!(
+                "`{}` works offline, give --cover as a local file",
+                command
+            ))
+        }
+        Some(cover) => Some(Cover::load(cover)?),
+        None if book.generate_cover => Some(generate_cover(story)?),
+        None if book.no_cover => None,
+        None => match saved {
+            Some(cover) => Some(cover),
+            None => {
+                log::warn!("no cover found in {}, using a generated one", source);
+                Some(generate_cover(story)?)
+            }
+        },
+    })
+}
 
-    let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
-    builder.set_title(story.title);
-    builder.add_author(story.author);
-    builder.add_description(story.description);
+/// Writes `story` from chapter pages that are already on disk, for `build`
+/// and `rebuild`. `page` gives the file of the page chapter `i` starts.
+fn build_book(
+    book: &BookArgs,
+    mut story: Story,
+    cover: Option<Cover>,
+    page: impl Fn(usize) -> Result<PathBuf>,
+) -> Result<()> {
+    let title_map = book.title_map()?;
+    let title_template = book.title_template()?;
+    let script = book.script()?;
+    let out = book.out_for(book.template()?, &story)?;
+    book.check_overwrite(&out)?;
 
-    builder.stylesheet(
-        r#"
-            @page {
-                margin-bottom: 5pt;
-                margin-top: 5pt;
+    // the pages are numbered by where their chapters were before side
+    // chapters are moved, each holding the chapters grouped with its first
+    let mut saved_page: HashMap<String, usize> = HashMap::new();
+    if book.side_chapters != SideChapters::Keep {
+        for group in book.page_groups(&story.chapters) {
+            for &i in &group {
+                saved_page.insert(story.chapters[i].link.clone(), group[0]);
             }
-            
-            .chapter-inner {
-                font-size: 1em;
-                line-height: 1.2;
-                margin: 0 5pt;
+        }
+    }
+
+    book.detect_volumes(&mut story.chapters);
+    book.arrange_side_chapters(&mut story.chapters)?;
+    if let Some(title_map) = &title_map {
+        title_map.apply(&mut story.chapters);
+    }
+    if let Some(title_template) = &title_template {
+        title_template.apply(&mut story.chapters);
+    }
+    book.set_language(&mut story);
+
+    let mut builder = new_builder(&story, book, None)?;
+    if let Some(cover) = cover {
+        let cover = book.image_opts().shrink_cover(cover);
+        builder.add_cover(&cover, !book.hide_extras_in_toc)?;
+    }
+    if book.title_page {
+        builder.add_title_page(&story, !book.hide_extras_in_toc)?;
+    }
+    if book.front_matter {
+        builder.add_front_matter(&story, !book.hide_extras_in_toc)?;
+    }
+
+    if book.inline_toc() {
+        builder.add_toc();
+    }
+
+    let clean_opts = book.clean_opts();
+    let links = ChapterLinks::new(&story.chapters);
+    let mut added = 0;
+    let mut dropped = Vec::new();
+    let mut words = vec![None; story.chapters.len()];
+    // chapters sharing a page can be apart in the list, so each is held
+    // until the chapters before it are added
+    let mut pending: Vec<Option<String>> = vec![None; story.chapters.len()];
+    let mut next = 0;
+    let result = book
+        .page_groups(&story.chapters)
+        .iter()
+        .try_for_each(|group| {
+            let first = &story.chapters[group[0]].link;
+            let path = page(saved_page.get(first).copied().unwrap_or(group[0]))?;
+            let html = std::fs::read_to_string(&path)
+                .wrap_err_with(|| format!("could not read {}", path.display()))?;
+            let contents = if group.len() == 1 {
+                parse_chapter_content(&html, &clean_opts).map(|content| vec![content])
+            } else {
+                let anchors: Vec<_> = group
+                    .iter()
+                    .map(|&i| link_anchor(&story.chapters[i].link))
+                    .collect();
+                parse_shared_chapters(&html, &anchors, &clean_opts)
             }
+            .wrap_err_with(|| format!("could not parse {}", path.display()))?;
 
-            p {
-                text-indent: 1em;
+            for (&i, content) in group.iter().zip(contents) {
+                pending[i] = Some(content);
             }
-        "#
-        .as_bytes(),
-    )?;
+            while let Some(content) = pending.get_mut(next).and_then(Option::take) {
+                let i = next;
+                next += 1;
+                let content = book.replacements.apply(&content).into_owned();
+                let content = links
+                    .rewrite(&content, |nr| builder.chapter_link(nr))
+                    .unwrap_or(content);
+                let Some((chapter, content)) =
+                    run_script(script.as_ref(), i, &story.chapters[i], content)?
+                else {
+                    dropped.push(i);
+                    continue;
+                };
+                let content = book.with_heading(&chapter, content);
+                builder.add_chapter(i, &chapter, &content)?;
+                if book.stats {
+                    words[i] = Some(word_count(&content) as u64);
+                }
+                added += 1;
+            }
+            Ok(())
+        });
 
-    // add the cover image
-    log::info!("fetching cover...");
-    fetch_and_add_cover(&mut builder, &story.cover).await?;
+    let fetched = Fetched {
+        result,
+        added,
+        skipped: &dropped,
+    };
+    finish_book(builder, &story, book, &out, fetched, None, false)?;
+    if book.stats {
+        print_stats(&story.chapters, &words);
+    }
+    Ok(())
+}
 
-    // build the table of contents
-    builder.inline_toc();
+/// The story `input` names for `rebuild`: a book written from it, or the
+/// story's URL as `--url` takes it.
+fn rebuild_story(input: &str) -> Result<String> {
+    if !Path::new(input).is_file() {
+        return Ok(input.to_string());
+    }
+    match ExistingBook::open(input)?.manifest()? {
+        Some(manifest) if !manifest.url.is_empty() => Ok(manifest.url),
+        _ => Err(eyre!(
+            "{} doesn't record the story it was written from, give its URL",
+            input
+        )),
+    }
+}
 
-    // fetch and add the chapters
-    stream::iter(story.chapters.iter().enumerate())
-        .map(|(i, chapter)| async move {
-            log::info!("fetching chapter {}...", i);
+fn rebuild(mut args: RebuildArgs, settings: &Settings) -> Result<()> {
+    let dir = match &args.cache_dir {
+        Some(dir) => dir.clone(),
+        None => Cache::default_dir().ok_or_else(|| eyre!("no cache directory found"))?,
+    };
+    let cache = Cache::new(&dir)?;
+    let url = rebuild_story(&args.story)?;
+    let Some((mut story, saved)) = cache.story(&url)? else {
+        return Err(eyre!(
+            "{} isn't in the cache {}, download it first",
+            url,
+            dir.display()
+        ));
+    };
+    if let Some(overrides) = settings.config.story(&story.url) {
+        args.book.apply_story(settings, overrides);
+    }
+    let book = &args.book;
 
-            let content = fetch_chapter_content(&chapter.link).await?;
+    let selection = match (&args.chapters, args.from, args.to) {
+        (Some(spec), _, _) => Some(ChapterSelection::parse(spec)?),
+        (None, None, None) => None,
+        (None, from, to) => Some(ChapterSelection::between(from, to)?),
+    };
+    if let Some(selection) = &selection {
+        let total = story.chapters.len();
+        story.chapters = selection.apply(std::mem::take(&mut story.chapters));
+        if story.chapters.is_empty() {
+            return Err(eyre!("none of the {} chapters were selected", total));
+        }
+        log::info!("selected {} of {} chapters", story.chapters.len(), total);
+    }
 
-            Ok::<_, eyre::Error>((i, chapter, content))
-        })
-        .buffered(args.concurrent)
-        .try_for_each(|(i, chapter, content)| {
-            std::future::ready(add_chapter(&mut builder, i, chapter, &content))
-        })
-        .await?;
+    let pages: Vec<Option<PathBuf>> = story
+        .chapters
+        .iter()
+        .map(|chapter| cache.page(&chapter_url(&chapter.link).ok()?))
+        .collect();
+    let groups = book.page_groups(&story.chapters);
+    let missing: Vec<usize> = groups
+        .iter()
+        .map(|group| group[0])
+        .filter(|&i| pages[i].is_none())
+        .collect();
+    if let Some(&first) = missing.first() {
+        return Err(eyre!(
+            "{} of {} chapter pages aren't in the cache, starting with chapter {} ({:?}); \
+             download the story again to fetch them",
+            missing.len(),
+            groups.len(),
+            first + 1,
+            story.chapters[first].name
+        ));
+    }
+
+    let cover = offline_cover("rebuild", book, &story, saved, "the cache")?;
+    build_book(book, story, cover, |i| {
+        pages[i]
+            .clone()
+            .ok_or_else(|| eyre!("chapter {} isn't in the cache", i + 1))
+    })
+}
 
-    log::info!("generating epub...");
-    let mut out = File::create(args.out)?;
-    builder.generate(&mut out)?;
+fn cache(args: CacheArgs) -> Result<()> {
+    let dir = match args.cache_dir {
+        Some(dir) => dir,
+        None => Cache::default_dir().ok_or_else(|| eyre!("no cache directory found"))?,
+    };
+    let cache = Cache::new(&dir)?;
+    let purge = match args.command {
+        CacheCommand::Stats { json: true } => {
+            println!("{}", serde_json::to_string(&cache.stats()?)?);
+            return Ok(());
+        }
+        CacheCommand::Stats { json: false } => {
+            let stats = cache.stats()?;
+            println!("{}", dir.display());
+            println!(
+                "  {} pages and {} stories, {}",
+                thousands(stats.pages),
+                thousands(stats.stories),
+                human_size(stats.bytes)
+            );
+            if let (Some(oldest), Some(newest)) = (stats.oldest, stats.newest) {
+                println!(
+                    "  pages last used between {} and {}",
+                    &iso_date(oldest)[..10],
+                    &iso_date(newest)[..10]
+                );
+            }
+            return Ok(());
+        }
+        CacheCommand::Purge(purge) => purge,
+    };
 
+    let purged = match purge.max_size {
+        Some(max) => cache.evict(max)?,
+        None => cache.purge(purge.older_than, purge.story.as_deref())?,
+    };
+    println!(
+        "removed {} pages and {} stories, {}",
+        thousands(purged.pages),
+        thousands(purged.stories),
+        human_size(purged.bytes)
+    );
     Ok(())
 }
+
+/// The log filter `-q` or `-v`, given `quiet` and `verbose` times, set.
+fn log_filter(quiet: u8, verbose: u8) -> Option<&'static str> {
+    Some(match (quiet, verbose) {
+        (0, 0) => return None,
+        (1, _) => "error,rustyroad=warn",
+        (_, 0) => "error",
+        (_, 1) => "error,rustyroad=debug",
+        (_, 2) => "debug",
+        _ => "trace",
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let mut logger = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("error,rustyroad=info"),
+    );
+    // -q and -v win over RUST_LOG
+    if let Some(filter) = log_filter(args.quiet, args.verbose) {
+        logger.parse_filters(filter);
+    }
+    if let Some(path) = &args.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("could not open the log file {}", path.display()))?;
+        logger
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never);
+    }
+    let logger = logger.build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(ProgressLogger { inner: logger }))?;
+    let config = if args.no_config {
+        Config::default()
+    } else {
+        Config::load(args.config.as_deref())?
+    };
+    set_selectors(&config.selectors).wrap_err("invalid [selectors] in the config file")?;
+    let (_, matches) = matches.subcommand().expect("a command is required");
+    let settings = Settings::new(config, matches);
+
+    let result = match args.command {
+        Command::Download(mut args) => {
+            args.fetch.apply_config(settings);
+            stop_on_ctrl_c();
+            download(args).await
+        }
+        Command::Update(mut args) => {
+            args.fetch.apply_config(settings);
+            stop_on_ctrl_c();
+            update(args).await
+        }
+        Command::Watch(mut args) => {
+            args.fetch.apply_config(settings);
+            watch(args).await
+        }
+        Command::Check(mut args) => {
+            args.fetch.apply_config(settings);
+            match check(args).await {
+                Ok(true) => std::process::exit(EXIT_NEW_CHAPTERS),
+                Err(err)
+                    if exit_code(&err) == EXIT_FAILURE
+                        && err.downcast_ref::<OverDownloadLimit>().is_none() =>
+                {
+                    eprintln!("Error: {:?}", err);
+                    std::process::exit(EXIT_CHECK_FAILED);
+                }
+                result => result.map(|_| ()),
+            }
+        }
+        Command::Info(mut args) => {
+            args.net.apply_config(&settings);
+            info(args).await
+        }
+        Command::Build(mut args) => {
+            args.book.apply_config(&settings);
+            build(args)
+        }
+        Command::Rebuild(mut args) => {
+            args.book.apply_config(&settings);
+            settings.set_some("cache_dir", &mut args.cache_dir, &settings.config.cache_dir);
+            if args.book.out.is_none() {
+                args.book.out = Some(BATCH_TEMPLATE.to_string());
+            }
+            rebuild(args, &settings)
+        }
+        Command::Tui(mut args) => {
+            args.fetch.apply_config(settings);
+            tui(args).await
+        }
+        Command::Search(mut args) => {
+            args.fetch.apply_config(settings);
+            search(args).await
+        }
+        Command::Author(mut args) => {
+            args.fetch.apply_config(settings);
+            author(args).await
+        }
+        Command::SyncFollows(mut args) => {
+            args.fetch.apply_config(settings);
+            sync_follows(args).await
+        }
+        Command::Cache(mut args) => {
+            settings.set_some("cache_dir", &mut args.cache_dir, &settings.config.cache_dir);
+            cache(args)
+        }
+        Command::Serve(mut args) => {
+            settings.set_some("dir", &mut args.dir, &settings.config.output_dir);
+            let dir = args.dir.unwrap_or_else(|| PathBuf::from("."));
+            serve_opds(&dir, (args.bind, args.port).into()).await
+        }
+    };
+
+    if let Err(err) = &result {
+        if err.downcast_ref::<Interrupted>().is_some() {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        if err.downcast_ref::<OverDownloadLimit>().is_some() {
+            eprintln!("Error: {:#}", err);
+            std::process::exit(EXIT_DOWNLOAD_LIMIT);
+        }
+        if let Some(skipped) = err.downcast_ref::<SkippedChapters>() {
+            log::error!("{}", skipped);
+            std::process::exit(EXIT_PARTIAL);
+        }
+        let code = exit_code(err);
+        if code != EXIT_FAILURE {
+            eprintln!("Error: {:?}", err);
+            std::process::exit(code);
+        }
+    }
+    result
+}
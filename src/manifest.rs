@@ -0,0 +1,98 @@
+//! A record of what went into a generated book, used to work out which
+//! chapters are new on the next run.
+
+use crate::{book_time, Story};
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Bumped whenever the manifest format changes incompatibly.
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestChapter {
+    pub name: String,
+    pub link: String,
+    /// The chapter's file inside the EPUB, e.g. `chapter_3.xhtml`.
+    pub file: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub title: String,
+    pub author: String,
+    /// The story's URL. Missing from manifests written before it was added.
+    #[serde(default)]
+    pub url: String,
+    /// Unix timestamp of the run that wrote this manifest.
+    pub generated: u64,
+    pub chapters: Vec<ManifestChapter>,
+}
+
+impl Manifest {
+    /// Builds a manifest for a book containing all of `story.chapters`,
+    /// written with [`chapter_file`](crate::chapter_file) names, and dated
+    /// like a `reproducible` book if it is one.
+    pub fn new(story: &Story, reproducible: bool) -> Manifest {
+        let generated = book_time(story, reproducible);
+
+        Manifest {
+            version: MANIFEST_VERSION,
+            title: story.title.clone(),
+            author: story.author.clone(),
+            url: story.url.clone(),
+            generated,
+            chapters: story
+                .chapters
+                .iter()
+                .enumerate()
+                .map(|(i, chapter)| ManifestChapter {
+                    name: chapter.name.clone(),
+                    link: chapter.link.clone(),
+                    file: crate::chapter_file(i),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Manifest> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read manifest {}", path.display()))?;
+        Manifest::parse(&data, &path.display().to_string())
+    }
+
+    /// Parses a manifest read from `source`, which is only used in errors.
+    pub fn parse(data: &str, source: &str) -> Result<Manifest> {
+        let manifest: Manifest =
+            serde_json::from_str(data).wrap_err_with(|| format!("invalid manifest {}", source))?;
+
+        if manifest.version > MANIFEST_VERSION {
+            return Err(eyre!(
+                "manifest {} has version {}, but only versions up to {} are supported",
+                source,
+                manifest.version,
+                MANIFEST_VERSION
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data = self.to_json()?;
+        std::fs::write(path, data)
+            .wrap_err_with(|| format!("could not write manifest {}", path.display()))
+    }
+
+    /// Looks up the chapter with the given link.
+    pub fn chapter(&self, link: &str) -> Option<&ManifestChapter> {
+        self.chapters.iter().find(|chapter| chapter.link == link)
+    }
+}
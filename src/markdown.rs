@@ -0,0 +1,234 @@
+//! Conversion of chapter content to Markdown.
+
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+
+/// Escapes the characters Markdown would read as formatting.
+pub(crate) fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '[' | ']' | '`' | '<' | '>' | '~' | '|'
+        ) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escapes what would start a heading, list or rule at the start of a
+/// paragraph, such as a line of dialogue beginning with a dash.
+fn escape_line_start(block: &str) -> String {
+    let digits = block.chars().take_while(char::is_ascii_digit).count();
+    let starts_list = digits > 0 && block[digits..].starts_with(['.', ')']);
+    if starts_list {
+        format!("{}\\{}", &block[..digits], &block[digits..])
+    } else if block.starts_with(['#', '-', '+', '=']) {
+        format!("\\{}", block)
+    } else {
+        block.to_string()
+    }
+}
+
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Collects Markdown blocks. Inline content accumulates in `line` until the
+/// next block starts.
+#[derive(Default)]
+struct Converter {
+    blocks: Vec<String>,
+    line: String,
+}
+
+impl Converter {
+    fn flush(&mut self) {
+        let line = self.line.trim();
+        if !line.is_empty() {
+            self.blocks.push(escape_line_start(line));
+        }
+        self.line.clear();
+    }
+
+    fn text(&mut self, text: &str) {
+        let mut last_space = self.line.is_empty() || self.line.ends_with([' ', '\n']);
+        for c in text.chars() {
+            if c.is_whitespace() {
+                if !last_space {
+                    self.line.push(' ');
+                }
+                last_space = true;
+            } else {
+                self.line.push_str(&escape_markdown(&c.to_string()));
+                last_space = false;
+            }
+        }
+    }
+
+    /// Wraps the node's content in `open` and `close`, keeping surrounding
+    /// whitespace outside the markers so they still count as formatting.
+    fn inline(&mut self, node: NodeRef<Node>, open: &str, close: &str) {
+        let blocks = self.blocks.len();
+        let start = self.line.len();
+        self.children(node);
+        if self.blocks.len() != blocks || self.line.len() < start {
+            // the content was split into blocks, the markers can't span it
+            return;
+        }
+
+        let inner = self.line.split_off(start);
+        let trimmed = inner.trim();
+        if trimmed.is_empty() {
+            self.line.push_str(&inner);
+            return;
+        }
+        let lead = &inner[..inner.len() - inner.trim_start().len()];
+        let trail = &inner[inner.trim_end().len()..];
+        self.line
+            .push_str(&format!("{}{}{}{}{}", lead, open, trimmed, close, trail));
+    }
+
+    fn children(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            self.node(child);
+        }
+    }
+
+    /// The blocks of `node`'s content on their own, for containers that
+    /// prefix them.
+    fn nested(node: NodeRef<Node>) -> String {
+        let mut converter = Converter::default();
+        converter.children(node);
+        converter.flush();
+        converter.blocks.join("\n\n")
+    }
+
+    fn list(&mut self, node: NodeRef<Node>, ordered: bool) {
+        self.flush();
+        let items: Vec<String> = node
+            .children()
+            .filter(|child| {
+                child
+                    .value()
+                    .as_element()
+                    .is_some_and(|el| el.name() == "li")
+            })
+            .enumerate()
+            .map(|(i, item)| {
+                let marker = if ordered {
+                    format!("{}. ", i + 1)
+                } else {
+                    "- ".to_string()
+                };
+                let indent = " ".repeat(marker.len());
+                prefix_lines(&Converter::nested(item), &marker, &indent)
+            })
+            .collect();
+        if !items.is_empty() {
+            self.blocks.push(items.join("\n"));
+        }
+    }
+
+    fn node(&mut self, node: NodeRef<Node>) {
+        let el = match node.value() {
+            Node::Text(text) => return self.text(text),
+            Node::Element(el) => el,
+            _ => return,
+        };
+
+        match el.name() {
+            "em" | "i" | "cite" | "dfn" => self.inline(node, "*", "*"),
+            "strong" | "b" => self.inline(node, "**", "**"),
+            "s" | "strike" | "del" => self.inline(node, "~~", "~~"),
+            "sub" => self.inline(node, "<sub>", "</sub>"),
+            "sup" => self.inline(node, "<sup>", "</sup>"),
+            "code" | "kbd" | "samp" | "tt" => self.inline(node, "`", "`"),
+            // links inside the chapter lose their targets, which have no ids
+            // in Markdown, so only links leaving the book are kept
+            "a" => match el.attr("href") {
+                Some(href) if !href.starts_with('#') => {
+                    self.inline(node, "[", &format!("]({})", href.replace(' ', "%20")))
+                }
+                _ => self.children(node),
+            },
+            "br" => {
+                if !self.line.trim().is_empty() {
+                    let trimmed = self.line.trim_end().len();
+                    self.line.truncate(trimmed);
+                    self.line.push_str("\\\n");
+                }
+            }
+            "hr" => {
+                self.flush();
+                self.blocks.push("* * *".to_string());
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                self.children(node);
+                let level: usize = el.name()[1..].parse().unwrap();
+                let text = self.line.trim().replace("\\\n", " ");
+                if !text.is_empty() {
+                    self.blocks.push(format!("{} {}", "#".repeat(level), text));
+                }
+                self.line.clear();
+            }
+            "blockquote" => {
+                self.flush();
+                let quoted = Converter::nested(node);
+                if !quoted.is_empty() {
+                    self.blocks.push(prefix_lines(&quoted, "> ", "> "));
+                }
+            }
+            "ul" => self.list(node, false),
+            "ol" => self.list(node, true),
+            "pre" => {
+                self.flush();
+                let text: String = node
+                    .descendants()
+                    .filter_map(|node| node.value().as_text().map(|text| text.to_string()))
+                    .collect();
+                self.blocks
+                    .push(format!("```\n{}\n```", text.trim_matches('\n')));
+            }
+            "img" => {
+                if let Some(src) = el.attr("src") {
+                    let alt = escape_markdown(el.attr("alt").unwrap_or_default());
+                    self.line
+                        .push_str(&format!("![{}]({})", alt, src.replace(' ', "%20")));
+                }
+            }
+            "script" | "style" => (),
+            "span" | "u" | "small" | "big" | "font" | "abbr" | "q" | "mark" => self.children(node),
+            // everything else is treated as a block
+            _ => {
+                self.flush();
+                self.children(node);
+                self.flush();
+            }
+        }
+    }
+}
+
+/// Converts cleaned chapter xhtml into Markdown, with blocks separated by
+/// blank lines.
+pub fn html_to_markdown(content: &str) -> String {
+    let fragment = Html::parse_fragment(content);
+    let mut converter = Converter::default();
+    converter.children(fragment.tree.root());
+    converter.flush();
+    converter.blocks.join("\n\n")
+}
@@ -0,0 +1,204 @@
+//! Stripping of the site's styling from the chapter content. Royal Road
+//! gives paragraphs utility classes and inline styles of its own, which
+//! bloat the book and fight its stylesheet; with them gone, the spans and
+//! divs that only held them are unwrapped too.
+
+use ego_tree::NodeId;
+use scraper::{Html, Node};
+
+use crate::clean::{find, is_blank_text, new_element};
+
+/// The classes the cleaning passes give elements, which the stylesheet
+/// styles and are always kept.
+const OWN_CLASSES: &[&str] = &[
+    "authors-note",
+    "footnote",
+    "footnote-ref",
+    "footnotes",
+    "scene-break",
+    "spoiler",
+    "spoiler-label",
+    "table",
+    "tn-ref",
+    "translator-note",
+    "translator-notes",
+];
+
+/// The CSS properties kept in `style` attributes besides those asked for,
+/// since authors center text with them.
+const OWN_STYLES: &[&str] = &["text-align"];
+
+/// The block elements. Bare divs holding only these are unwrapped, and
+/// whitespace next to them is dropped.
+fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div"
+            | "blockquote"
+            | "ul"
+            | "ol"
+            | "dl"
+            | "table"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "hr"
+            | "pre"
+            | "figure"
+            | "aside"
+            | "details"
+    )
+}
+
+/// Strips the classes and inline styles below `content`, keeping the
+/// classes in `keep_classes` and the CSS properties in `keep_styles`, then
+/// unwraps the spans left without attributes and the divs without any
+/// that only hold blocks, and drops the whitespace between blocks.
+pub(crate) fn minify(
+    doc: &mut Html,
+    content: NodeId,
+    keep_classes: &[String],
+    keep_styles: &[String],
+) {
+    strip_attributes(doc, content, keep_classes, keep_styles);
+
+    let bare = |el: scraper::ElementRef| el.value().attrs().next().is_none();
+    let wrappers = find(doc, content, |el| {
+        bare(el)
+            && match el.value().name() {
+                "span" => true,
+                "div" => el.children().all(|child| match child.value() {
+                    Node::Element(child) => is_block(child.name()),
+                    node => is_blank_text(node),
+                }),
+                _ => false,
+            }
+    });
+    // innermost first, so a chain of wrappers collapses entirely
+    for id in wrappers.into_iter().rev() {
+        unwrap(doc, id);
+    }
+
+    drop_blank_between_blocks(doc, content);
+}
+
+/// Replaces the element `id` by its children.
+fn unwrap(doc: &mut Html, id: NodeId) {
+    let children: Vec<NodeId> = doc
+        .tree
+        .get(id)
+        .unwrap()
+        .children()
+        .map(|c| c.id())
+        .collect();
+    let mut node = doc.tree.get_mut(id).unwrap();
+    for child in children {
+        node.insert_id_before(child);
+    }
+    node.detach();
+}
+
+fn strip_attributes(
+    doc: &mut Html,
+    content: NodeId,
+    keep_classes: &[String],
+    keep_styles: &[String],
+) {
+    let elements: Vec<NodeId> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .descendants()
+        .skip(1)
+        .filter(|node| node.value().is_element())
+        .map(|node| node.id())
+        .collect();
+    for id in elements {
+        let Node::Element(el) = doc.tree.get(id).unwrap().value() else {
+            continue;
+        };
+        if el.attr("class").is_none() && el.attr("style").is_none() {
+            continue;
+        }
+
+        let mut attrs = Vec::new();
+        for (attr, value) in el.attrs() {
+            let value = match attr {
+                "class" => value
+                    .split_whitespace()
+                    .filter(|class| {
+                        OWN_CLASSES.contains(class) || keep_classes.iter().any(|keep| keep == class)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                "style" => value
+                    .split(';')
+                    .filter(|declaration| {
+                        let (property, _) = declaration.split_once(':').unwrap_or_default();
+                        let property = property.trim().to_ascii_lowercase();
+                        OWN_STYLES.contains(&property.as_str())
+                            || keep_styles
+                                .iter()
+                                .any(|keep| keep.eq_ignore_ascii_case(&property))
+                    })
+                    .map(str::trim)
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                _ => value.to_string(),
+            };
+            if !value.is_empty() {
+                attrs.push((attr.to_string(), value));
+            }
+        }
+
+        let name = el.name().to_string();
+        let attrs: Vec<(&str, &str)> = attrs
+            .iter()
+            .map(|(attr, value)| (attr.as_str(), value.as_str()))
+            .collect();
+        *doc.tree.get_mut(id).unwrap().value() = new_element(&name, &attrs);
+    }
+}
+
+/// Whether `node` is text of ASCII whitespace alone.
+fn is_space(node: &Node) -> bool {
+    matches!(node, Node::Text(text) if text.chars().all(|c| c.is_ascii_whitespace()))
+}
+
+/// Drops the whitespace between blocks below `content`, which shows as
+/// nothing. Non-breaking spaces are kept, since they hold empty paragraphs
+/// open.
+fn drop_blank_between_blocks(doc: &mut Html, content: NodeId) {
+    // whether the nearest node that isn't whitespace is a block, if there
+    // is one
+    let block = |mut siblings: Box<dyn Iterator<Item = ego_tree::NodeRef<Node>>>| {
+        siblings.find(|node| !is_space(node.value())).map(|node| {
+            node.value()
+                .as_element()
+                .is_some_and(|el| is_block(el.name()))
+        })
+    };
+    let blank: Vec<NodeId> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .descendants()
+        .filter(|node| is_space(node.value()))
+        .filter(|node| {
+            let prev = block(Box::new(node.prev_siblings()));
+            let next = block(Box::new(node.next_siblings()));
+            // next to a block, with another or nothing on the other side
+            matches!(
+                (prev, next),
+                (Some(true), Some(true) | None) | (None, Some(true))
+            )
+        })
+        .map(|node| node.id())
+        .collect();
+    for id in blank {
+        doc.tree.get_mut(id).unwrap().detach();
+    }
+}
@@ -0,0 +1,140 @@
+//! Normalization of the characters in chapter text: entities left escaped,
+//! mojibake, zero-width characters, runs of spaces and mixed Unicode forms,
+//! all of which some e-ink readers show as boxes, gaps or garbage.
+
+use ego_tree::NodeId;
+use html5ever::tendril::StrTendril;
+use markup5ever::data::NAMED_ENTITIES;
+use regex::{Captures, Regex};
+use scraper::{Html, Node};
+use std::sync::OnceLock;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::typography::is_verbatim;
+
+/// Normalizes every text node below `content` with [`normalize_text`],
+/// leaving code alone.
+pub(crate) fn normalize(doc: &mut Html, content: NodeId) {
+    let nodes: Vec<NodeId> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .descendants()
+        .filter(|node| node.value().is_text())
+        .filter(|node| {
+            !node
+                .ancestors()
+                .filter_map(|ancestor| ancestor.value().as_element())
+                .any(|el| is_verbatim(el.name()))
+        })
+        .map(|node| node.id())
+        .collect();
+
+    for id in nodes {
+        let mut node = doc.tree.get_mut(id).unwrap();
+        let Node::Text(text) = node.value() else {
+            continue;
+        };
+        let normalized = normalize_text(&text.text);
+        if normalized != *text.text {
+            text.text = StrTendril::from(normalized);
+        }
+    }
+}
+
+/// `text` with entities that were escaped twice decoded, UTF-8 that was
+/// read as Windows-1252 repaired, zero-width spaces and byte order marks
+/// dropped, runs of ASCII whitespace collapsed to one space (runs of
+/// no-break spaces line up stat blocks, so they stay), and everything in
+/// Unicode's composed form (NFC). Zero-width joiners are kept, since
+/// emoji and some scripts need them.
+pub fn normalize_text(text: &str) -> String {
+    let text = decode_entities(text);
+    let text = repair_mojibake(&text);
+    let text: String = text
+        .chars()
+        .filter(|c| !matches!(c, '\u{200B}' | '\u{2060}' | '\u{FEFF}' | '\u{180E}'))
+        .nfc()
+        .collect();
+    whitespace().replace_all(&text, " ").into_owned()
+}
+
+fn whitespace() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"[ \t\r\n]{2,}").unwrap())
+}
+
+fn entity() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"&(?:#([0-9]{1,7})|#[xX]([0-9a-fA-F]{1,6})|([A-Za-z][A-Za-z0-9]{1,31}));")
+            .unwrap()
+    })
+}
+
+/// Decodes the character references in `text`, which the parser already
+/// decoded once, so they were escaped twice (`&amp;nbsp;`) on the site.
+/// `&amp;amp;nbsp;` and worse are decoded as many times as it takes.
+fn decode_entities(text: &str) -> String {
+    let mut text = text.to_string();
+    // each pass undoes one level of escaping
+    for _ in 0..4 {
+        if !text.contains('&') {
+            break;
+        }
+        let decoded = entity().replace_all(&text, |caps: &Captures| {
+            let chars = if let Some(dec) = caps.get(1) {
+                dec.as_str().parse().ok().map(|c| (c, 0))
+            } else if let Some(hex) = caps.get(2) {
+                u32::from_str_radix(hex.as_str(), 16).ok().map(|c| (c, 0))
+            } else {
+                NAMED_ENTITIES.get(&format!("{};", &caps[3])).copied()
+            };
+            let decoded: Option<String> = match chars {
+                Some((first, 0)) => char::from_u32(first).map(String::from),
+                Some((first, second)) => [first, second].into_iter().map(char::from_u32).collect(),
+                None => None,
+            };
+            decoded
+                .filter(|decoded| !decoded.contains('\0'))
+                .unwrap_or_else(|| caps[0].to_string())
+        });
+        if decoded == text {
+            break;
+        }
+        text = decoded.into_owned();
+    }
+    text
+}
+
+/// The Windows-1252 characters for the bytes 0x80 to 0x9F. The bytes it
+/// leaves undefined read as the control characters of the same number.
+const CP1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+/// The byte Windows-1252, or Latin-1, writes `c` as.
+fn cp1252_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        0..=0xFF => Some(c as u8),
+        _ => CP1252
+            .iter()
+            .position(|&known| known == c)
+            .map(|i| 0x80 + i as u8),
+    }
+}
+
+/// Repairs text that was UTF-8 but read as Windows-1252, as in `donâ€™t`
+/// for `don’t` or `cafÃ©` for `café`. Only a text node that shows the
+/// telltale `Ã` or `â€` and decodes to valid UTF-8 as a whole is
+/// repaired, since correct text like `café…` has the same characters.
+fn repair_mojibake(text: &str) -> String {
+    if !text.contains('Ã') && !text.contains("â€") {
+        return text.to_string();
+    }
+    let bytes: Option<Vec<u8>> = text.chars().map(cp1252_byte).collect();
+    bytes
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .unwrap_or_else(|| text.to_string())
+}
@@ -0,0 +1,193 @@
+//! Detection and handling of translator and author notes in a chapter.
+
+use ego_tree::NodeId;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use std::sync::OnceLock;
+
+use crate::clean::{find, is_blank_text, new_element};
+use crate::sites;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NotePolicy {
+    /// Leave the notes where they are
+    #[default]
+    Keep,
+    /// Remove the notes
+    Strip,
+    /// Move the notes to the end of the chapter, linked from where they were
+    Footnote,
+}
+
+/// Containers that translators and translation groups use for their notes.
+const TRANSLATOR_NOTE_SELECTORS: &str =
+    ".translator-note, .translator-notes, .tl-note, .tl-notes, .tn, [data-note=\"translator\"]";
+
+/// Paragraphs starting with e.g. "TL Note:", "T/N:", "[Translator's note]".
+fn translator_note_prefix() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"(?i)^\s*[\[(]?\s*(tl|t/n|tn|translator'?s?)\s*(note|notes|n)?\s*[\]):\-]")
+            .unwrap()
+    })
+}
+
+fn is_translator_note(el: ElementRef, selectors: &Selector) -> bool {
+    if selectors.matches(&el) {
+        return true;
+    }
+
+    el.value().name() == "p" && translator_note_prefix().is_match(&el.text().collect::<String>())
+}
+
+pub(crate) fn translator_notes(doc: &mut Html, content: NodeId, policy: NotePolicy) {
+    if policy == NotePolicy::Keep {
+        return;
+    }
+
+    let selectors = Selector::parse(TRANSLATOR_NOTE_SELECTORS).unwrap();
+    let notes = find(doc, content, |el| {
+        is_translator_note(el, &selectors)
+            && !el
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .take_while(|a| a.id() != content)
+                .any(|a| is_translator_note(a, &selectors))
+    });
+
+    if notes.is_empty() {
+        return;
+    }
+
+    if policy == NotePolicy::Strip {
+        for id in notes {
+            doc.tree.get_mut(id).unwrap().detach();
+        }
+        return;
+    }
+
+    // footnotes: leave a numbered reference in place and move the note into
+    // a section at the end of the chapter
+    let section = doc
+        .tree
+        .get_mut(content)
+        .unwrap()
+        .append(new_element("div", &[("class", "translator-notes")]))
+        .id();
+    doc.tree
+        .get_mut(section)
+        .unwrap()
+        .append(new_element("h4", &[]))
+        .append(scraper::Node::Text(scraper::node::Text {
+            text: "Translator's notes".into(),
+        }));
+
+    for (i, id) in notes.into_iter().enumerate() {
+        let nr = i + 1;
+        let note_id = format!("tn-{}", nr);
+        let ref_id = format!("tnref-{}", nr);
+
+        // attach the reference to the end of the preceding paragraph, so it
+        // reads like a footnote marker rather than a stray line
+        let preceding = doc
+            .tree
+            .get(id)
+            .unwrap()
+            .prev_siblings()
+            .find(|node| !is_blank_text(node.value()))
+            .and_then(ElementRef::wrap)
+            .filter(|el| el.value().name() == "p")
+            .map(|el| el.id());
+
+        let sup = new_element("sup", &[("class", "tn-ref")]);
+        let sup = match preceding {
+            Some(p) => doc.tree.get_mut(p).unwrap().append(sup).id(),
+            None => doc.tree.get_mut(id).unwrap().insert_before(sup).id(),
+        };
+        doc.tree
+            .get_mut(sup)
+            .unwrap()
+            .append(new_element(
+                "a",
+                &[("id", &ref_id), ("href", &format!("#{}", note_id))],
+            ))
+            .append(scraper::Node::Text(scraper::node::Text {
+                text: format!("[TN{}]", nr).into(),
+            }));
+
+        let mut list = doc.tree.get_mut(section).unwrap();
+        let mut entry = list.append(new_element(
+            "div",
+            &[("class", "translator-note"), ("id", &note_id)],
+        ));
+        entry
+            .append(new_element("a", &[("href", &format!("#{}", ref_id))]))
+            .append(scraper::Node::Text(scraper::node::Text {
+                text: format!("[TN{}]", nr).into(),
+            }));
+        let entry = entry.id();
+        doc.tree.get_mut(entry).unwrap().append_id(id);
+    }
+}
+
+/// Moves the author's notes before and after the chapter text into the
+/// start and end of `content`, each in a `div.authors-note`, or removes any
+/// that are part of the text if `include` is false. Notes outside the text
+/// are left alone then, since only `content` ends up in the book.
+pub(crate) fn authors_notes(doc: &mut Html, content: NodeId, include: bool) {
+    let selectors = Selector::parse(&sites::author_note_selector()).unwrap();
+    let notes: Vec<NodeId> = doc.select(&selectors).map(|el| el.id()).collect();
+    let inside: Vec<NodeId> = find(doc, content, |el| notes.contains(&el.id()));
+
+    if !include {
+        for id in inside {
+            doc.tree.get_mut(id).unwrap().detach();
+        }
+        return;
+    }
+
+    let order: Vec<NodeId> = doc
+        .tree
+        .root()
+        .descendants()
+        .map(|node| node.id())
+        .collect();
+    let position = |id: NodeId| order.iter().position(|other| *other == id);
+    let start = position(content);
+    let first = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .first_child()
+        .map(|node| node.id());
+
+    for id in notes.into_iter().filter(|id| !inside.contains(id)) {
+        let note = doc
+            .tree
+            .orphan(new_element("div", &[("class", "authors-note")]))
+            .id();
+        let children: Vec<NodeId> = doc
+            .tree
+            .get(id)
+            .unwrap()
+            .children()
+            .map(|c| c.id())
+            .collect();
+        for child in children {
+            doc.tree.get_mut(note).unwrap().append_id(child);
+        }
+        doc.tree.get_mut(id).unwrap().detach();
+
+        match first {
+            Some(first) if position(id) < start => {
+                doc.tree.get_mut(first).unwrap().insert_id_before(note);
+            }
+            None if position(id) < start => {
+                doc.tree.get_mut(content).unwrap().prepend_id(note);
+            }
+            _ => {
+                doc.tree.get_mut(content).unwrap().append_id(note);
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Telling someone that `watch` found new chapters: a desktop notification,
+//! or a message posted to a webhook such as a Discord channel, a Gotify
+//! server or an ntfy topic.
+
+use eyre::{eyre, Result, WrapErr};
+use serde_json::json;
+use std::process::Command;
+
+/// How many chapter names a notification lists before it counts the rest.
+const LISTED_CHAPTERS: usize = 10;
+
+/// What a webhook expects to be posted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WebhookFormat {
+    /// A JSON object with the book, story title, URL and new chapter names
+    #[default]
+    Json,
+    /// A Discord webhook's message
+    Discord,
+    /// A Gotify message, posted to `/message?token=...`
+    Gotify,
+    /// An ntfy message, posted to the topic's URL
+    Ntfy,
+}
+
+/// The new chapters `watch` added to a book.
+#[derive(Debug, Clone)]
+pub struct NewChapters {
+    /// The book's path.
+    pub book: String,
+    pub title: String,
+    pub url: String,
+    /// The names of the new chapters, in order.
+    pub chapters: Vec<String>,
+}
+
+impl NewChapters {
+    /// A one-line summary, e.g. `The Story: 2 new chapters`.
+    pub fn summary(&self) -> String {
+        match self.chapters.len() {
+            1 => format!("{}: 1 new chapter", self.title),
+            n => format!("{}: {} new chapters", self.title, n),
+        }
+    }
+
+    /// The names of the new chapters, one a line, leaving out all but the
+    /// first few of a long list.
+    pub fn body(&self) -> String {
+        let mut lines: Vec<String> = self
+            .chapters
+            .iter()
+            .take(LISTED_CHAPTERS)
+            .cloned()
+            .collect();
+        if self.chapters.len() > LISTED_CHAPTERS {
+            lines.push(format!(
+                "and {} more",
+                self.chapters.len() - LISTED_CHAPTERS
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// Shows a desktop notification, with `notify-send` on Linux and the
+    /// BSDs and with AppleScript on macOS.
+    pub fn notify_desktop(&self) -> Result<()> {
+        let (summary, body) = (self.summary(), self.body());
+        let mut command = if cfg!(target_os = "macos") {
+            let mut command = Command::new("osascript");
+            command.arg("-e").arg(format!(
+                "display notification {} with title \"rustyroad\" subtitle {}",
+                applescript_string(&body),
+                applescript_string(&summary)
+            ));
+            command
+        } else if cfg!(unix) {
+            let mut command = Command::new("notify-send");
+            command.arg("--app-name=rustyroad").arg(summary).arg(body);
+            command
+        } else {
+            return Err(eyre!("--notify isn't supported on this system"));
+        };
+        let program = command.get_program().to_string_lossy().into_owned();
+        let status = command
+            .status()
+            .wrap_err_with(|| format!("could not run {} for --notify", program))?;
+        if !status.success() {
+            return Err(eyre!("{} failed ({})", program, status));
+        }
+        Ok(())
+    }
+
+    /// Posts the news to the webhook at `url`, in `format`.
+    pub async fn post(&self, url: &str, format: WebhookFormat) -> Result<()> {
+        let client = reqwest::Client::new();
+        let (summary, body) = (self.summary(), self.body());
+        let request = match format {
+            WebhookFormat::Json => client.post(url).json(&json!({
+                "book": self.book,
+                "title": self.title,
+                "url": self.url,
+                "chapters": self.chapters,
+            })),
+            WebhookFormat::Discord => client.post(url).json(&json!({
+                "content": format!("**{}**\n{}\n{}", summary, body, self.url),
+            })),
+            WebhookFormat::Gotify => client.post(url).json(&json!({
+                "title": summary,
+                "message": body,
+                "extras": { "client::notification": { "click": { "url": self.url } } },
+            })),
+            // headers can't hold every title, the query can
+            WebhookFormat::Ntfy => client
+                .post(url)
+                .query(&[("title", summary.as_str()), ("click", self.url.as_str())])
+                .body(body),
+        };
+        let resp = request
+            .send()
+            .await
+            .wrap_err_with(|| format!("could not post to the webhook {}", url))?;
+        if !resp.status().is_success() {
+            return Err(eyre!(
+                "the webhook {} answered with HTTP {}",
+                url,
+                resp.status().as_u16()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `text` as an AppleScript string literal.
+fn applescript_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
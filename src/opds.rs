@@ -0,0 +1,403 @@
+//! An OPDS catalog of a directory of books, so reading apps such as KOReader
+//! or Moon+ Reader can browse and download them over the local network.
+//!
+//! The server speaks just enough HTTP for that: `GET` (and `HEAD`) for the
+//! catalog at `/opds`, the books at `/books/<path>` and their covers at
+//! `/covers/<path>`, where `path` is where the book is below the directory.
+
+use eyre::{Result, WrapErr};
+use reqwest::Url;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{escape, iso_date, ExistingBook};
+
+const CATALOG_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+/// The most of a request that is read, request line and headers together.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// A book in the catalog.
+#[derive(Debug, Clone)]
+struct Book {
+    path: PathBuf,
+    /// The path below the served directory, which identifies the book.
+    name: String,
+    title: String,
+    author: Option<String>,
+    url: Option<String>,
+    mime: &'static str,
+    /// The cover's type, for books with a cover.
+    cover: Option<&'static str>,
+    modified: u64,
+}
+
+/// The mime type of a book file the catalog lists.
+fn book_mime(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    if name.ends_with(".epub") {
+        Some("application/epub+zip")
+    } else if name.ends_with(".fb2") {
+        Some("application/x-fictionbook+xml")
+    } else if name.ends_with(".azw3") {
+        Some("application/vnd.amazon.ebook")
+    } else {
+        None
+    }
+}
+
+/// The books in `dir` and below, newest first. Hidden files, such as books
+/// still being written, are left out.
+fn scan(dir: &Path) -> Result<Vec<Book>> {
+    let mut books = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        let entries = std::fs::read_dir(&current)
+            .wrap_err_with(|| format!("could not read {}", current.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let Some(mime) = book_mime(&file_name) else {
+                continue;
+            };
+            books.push(describe(dir, path, mime, &metadata));
+        }
+    }
+    books.sort_by(|a, b| b.modified.cmp(&a.modified).then(a.name.cmp(&b.name)));
+    Ok(books)
+}
+
+/// The file of the book `name` below `dir`, as the catalog names it, and
+/// its mime type, if it is a book and neither hidden nor outside `dir`.
+fn book_file(dir: &Path, name: &str) -> Option<(PathBuf, &'static str)> {
+    let mime = book_mime(name)?;
+    let mut path = dir.to_path_buf();
+    for part in name.split('/') {
+        // `.` and `..` are hidden too
+        if part.is_empty() || part.starts_with('.') || part.contains(['\\', ':']) {
+            return None;
+        }
+        path.push(part);
+    }
+    path.is_file().then_some((path, mime))
+}
+
+/// The book at `path`, with the title, author and cover of an EPUB written
+/// by rustyroad, and its file name for the title otherwise.
+fn describe(dir: &Path, path: PathBuf, mime: &'static str, metadata: &std::fs::Metadata) -> Book {
+    let name = path
+        .strip_prefix(dir)
+        .unwrap_or(&path)
+        .to_string_lossy()
+        .replace('\\', "/");
+    let stem = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = stem
+        .trim_end_matches(".epub")
+        .trim_end_matches(".kepub")
+        .trim_end_matches(".fb2")
+        .trim_end_matches(".azw3")
+        .to_string();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let mut book = Book {
+        path,
+        name,
+        title: stem,
+        author: None,
+        url: None,
+        mime,
+        cover: None,
+        modified,
+    };
+    if mime == "application/epub+zip" {
+        if let Ok(mut epub) = ExistingBook::open(&book.path) {
+            if let Ok(Some(manifest)) = epub.manifest() {
+                book.title = manifest.title;
+                book.author = Some(manifest.author);
+                book.url = Some(manifest.url).filter(|url| !url.is_empty());
+            }
+            book.cover = epub
+                .cover()
+                .ok()
+                .flatten()
+                .and_then(|cover| cover.mime().ok());
+        }
+    }
+    book
+}
+
+/// `secs` as an Atom date and time.
+fn atom_time(secs: u64) -> String {
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        iso_date(secs),
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// The link to `book` below `root`, e.g. `/books/sub/The%20Story.epub`.
+fn link(root: &str, book: &Book) -> String {
+    let mut url = Url::parse("http://catalog/").unwrap();
+    url.path_segments_mut()
+        .unwrap()
+        .push(root)
+        .extend(book.name.split('/'));
+    url.path().to_string()
+}
+
+/// `text` with its `%` escapes decoded.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The acquisition feed listing `books`.
+fn catalog(books: &[Book]) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let mut feed = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog">
+<id>urn:rustyroad:catalog</id>
+<title>rustyroad</title>
+<updated>{}</updated>
+<link rel="self" href="/opds" type="{type}"/>
+<link rel="start" href="/opds" type="{type}"/>
+"#,
+        atom_time(now),
+        type = CATALOG_TYPE
+    );
+    for book in books {
+        feed.push_str(&format!(
+            "<entry>\n<title>{}</title>\n<id>urn:rustyroad:book:{}</id>\n<updated>{}</updated>\n",
+            escape(&book.title),
+            escape(&book.name),
+            atom_time(book.modified)
+        ));
+        if let Some(author) = &book.author {
+            feed.push_str(&format!(
+                "<author><name>{}</name></author>\n",
+                escape(author)
+            ));
+        }
+        if let Some(url) = &book.url {
+            feed.push_str(&format!(
+                "<summary type=\"text\">{}</summary>\n",
+                escape(url)
+            ));
+        }
+        feed.push_str(&format!(
+            "<link rel=\"http://opds-spec.org/acquisition\" href=\"{}\" type=\"{}\"/>\n",
+            escape(&link("books", book)),
+            book.mime
+        ));
+        if let Some(mime) = book.cover {
+            for rel in [
+                "http://opds-spec.org/image",
+                "http://opds-spec.org/image/thumbnail",
+            ] {
+                feed.push_str(&format!(
+                    "<link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n",
+                    rel,
+                    escape(&link("covers", book)),
+                    mime
+                ));
+            }
+        }
+        feed.push_str("</entry>\n");
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// Serves the books in `dir` as an OPDS catalog at `addr` until the process
+/// is stopped. The directory is read again for every request for the
+/// catalog, so books written in the meantime show up.
+pub async fn serve_opds(dir: &Path, addr: SocketAddr) -> Result<()> {
+    // the catalog has to exist before anything can be served from it
+    scan(dir)?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .wrap_err_with(|| format!("could not listen on {}", addr))?;
+    log::info!(
+        "serving the books in {} at http://{}/opds",
+        dir.display(),
+        listener.local_addr()?
+    );
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let dir = dir.to_path_buf();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &dir).await {
+                log::debug!("request from {} failed: {:#}", peer, err);
+            }
+        });
+    }
+}
+
+/// Answers one request, then closes the connection.
+async fn respond(stream: TcpStream, dir: &Path) -> Result<()> {
+    let mut stream = BufReader::new(stream.take(MAX_REQUEST));
+    let mut request = String::new();
+    stream.read_line(&mut request).await?;
+    // the headers don't matter, but have to be read before answering
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut stream = stream.into_inner().into_inner();
+    if !request.ends_with('\n') {
+        return send(&mut stream, "400 Bad Request", "text/plain", b"", false).await;
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    if method != "GET" && method != "HEAD" {
+        return send(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"",
+            false,
+        )
+        .await;
+    }
+    let head = method == "HEAD";
+    let path = target.split('?').next().unwrap_or("");
+    log::info!("{} {}", method, path);
+
+    let path = percent_decode(path);
+    let path = path.trim_matches('/');
+    if path.is_empty() || path == "opds" {
+        let books = scan(dir)?;
+        return send(
+            &mut stream,
+            "200 OK",
+            CATALOG_TYPE,
+            catalog(&books).as_bytes(),
+            head,
+        )
+        .await;
+    }
+    // only the books the catalog could list are served, however the path
+    // is spelled
+    let (root, name) = path.split_once('/').unwrap_or((path, ""));
+    let Some((path, mime)) = book_file(dir, name) else {
+        return not_found(&mut stream).await;
+    };
+    match root {
+        "books" => {
+            let data = tokio::fs::read(&path).await?;
+            send(&mut stream, "200 OK", mime, &data, head).await
+        }
+        "covers" if mime == "application/epub+zip" => match ExistingBook::open(&path)?.cover()? {
+            Some(cover) => send(&mut stream, "200 OK", cover.mime()?, &cover.data, head).await,
+            None => not_found(&mut stream).await,
+        },
+        _ => not_found(&mut stream).await,
+    }
+}
+
+async fn not_found(stream: &mut TcpStream) -> Result<()> {
+    send(stream, "404 Not Found", "text/plain", b"not found\n", false).await
+}
+
+async fn send(
+    stream: &mut TcpStream,
+    status: &str,
+    mime: &str,
+    body: &[u8],
+    head: bool,
+) -> Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        mime,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    if !head {
+        stream.write_all(body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn book_file_stays_in_the_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("books");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        // each file exists, so only the name can keep it from being served
+        for file in [
+            "x.epub",
+            "books/sub/book.epub",
+            "books/.hidden.epub",
+            "books/a\\b.epub",
+            "books/C:x.epub",
+        ] {
+            std::fs::write(root.path().join(file), b"").unwrap();
+        }
+
+        assert_eq!(
+            book_file(&dir, "sub/book.epub"),
+            Some((dir.join("sub/book.epub"), "application/epub+zip"))
+        );
+        for name in [
+            "../x.epub",
+            &percent_decode("%2e%2e/x.epub"),
+            ".hidden.epub",
+            "a\\b.epub",
+            "C:x.epub",
+        ] {
+            assert_eq!(book_file(&dir, name), None, "{} was served", name);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Removal of the promotional blocks authors put around their chapters:
+//! Patreon and Ko-fi links, Discord invites, "read ahead on..." and votes
+//! asked for on Top Web Fiction.
+//!
+//! Only the blocks at the very start and end of the chapter are looked at,
+//! so a character who mentions a discord in the story keeps their line.
+
+use ego_tree::NodeId;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use std::sync::OnceLock;
+
+use crate::clean::find;
+
+/// What a plug says, matched against its text.
+const PLUG_PATTERNS: &[&str] = &[
+    r"(?i)\bpatreon\b",
+    r"(?i)\bdiscord\b",
+    r"(?i)\bko-?fi\b",
+    r"(?i)\bsubscribestar\b",
+    r"(?i)\bbuy\s+me\s+a\s+coffee\b",
+    r"(?i)\b(read|chapters?)\s+ahead\b",
+    r"(?i)\badvanced?\s+chapters?\b",
+    r"(?i)\b(support|donate\s+to)\s+(me|the\s+author|my\s+work|the\s+story)\b",
+    r"(?i)\btop\s*web\s*fiction\b",
+];
+
+/// The sites plugs link to.
+const PLUG_HOSTS: &[&str] = &[
+    "patreon.com",
+    "discord.gg",
+    "discord.com",
+    "ko-fi.com",
+    "subscribestar.com",
+    "buymeacoffee.com",
+    "paypal.me",
+    "topwebfiction.com",
+];
+
+/// Blocks longer than this are taken for the story, whatever they mention.
+const MAX_PLUG: usize = 400;
+
+/// The elements the chapter's text is split into blocks by.
+const BLOCKS: &str = "p, div, blockquote, h1, h2, h3, h4, h5, h6, li, hr, table";
+
+fn patterns() -> &'static [Regex] {
+    static RE: OnceLock<Vec<Regex>> = OnceLock::new();
+    RE.get_or_init(|| {
+        PLUG_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect()
+    })
+}
+
+fn links_to_plug(el: ElementRef) -> bool {
+    let links = Selector::parse("a[href]").unwrap();
+    std::iter::once(el)
+        .chain(el.select(&links))
+        .filter_map(|a| a.value().attr("href"))
+        .filter_map(|href| reqwest::Url::parse(href).ok())
+        .filter_map(|url| url.host_str().map(|host| host.to_ascii_lowercase()))
+        .any(|host| {
+            PLUG_HOSTS.iter().any(|plug| {
+                host == *plug
+                    || host
+                        .strip_suffix(plug)
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+        })
+}
+
+/// What the block `el` is: `Some(true)` for a plug, `Some(false)` for one
+/// that only separates, like a rule or a blank line, and `None` for text of
+/// the story.
+fn plug(el: ElementRef, extra: &[Regex]) -> Option<bool> {
+    let text = el.text().collect::<String>();
+    let text = text.trim();
+    let links = links_to_plug(el);
+    if text.is_empty() && !links {
+        return Some(false);
+    }
+    if text.chars().count() > MAX_PLUG {
+        return None;
+    }
+    let is_plug = links
+        || patterns()
+            .iter()
+            .chain(extra)
+            .any(|pattern| pattern.is_match(text));
+    is_plug.then_some(true)
+}
+
+/// Removes the plugs at the start and the end of the chapter text below
+/// `content`, and the rules and blank lines between them. Blocks matching
+/// one of the `extra` patterns are plugs too.
+pub(crate) fn strip_plugs(doc: &mut Html, content: NodeId, extra: &[Regex]) {
+    let blocks = Selector::parse(BLOCKS).unwrap();
+    // the innermost blocks, which hold the text
+    let leaves = find(doc, content, |el| {
+        blocks.matches(&el) && el.select(&blocks).next().is_none()
+    });
+    let kinds: Vec<Option<bool>> = leaves
+        .iter()
+        .map(|id| plug(ElementRef::wrap(doc.tree.get(*id).unwrap()).unwrap(), extra))
+        .collect();
+
+    // a run of plugs and separators, up to the last plug in it
+    let run = |indices: &mut dyn Iterator<Item = usize>| -> Vec<usize> {
+        let mut run = Vec::new();
+        let mut plugs = 0;
+        for i in indices {
+            match kinds[i] {
+                Some(is_plug) => {
+                    run.push(i);
+                    if is_plug {
+                        plugs = run.len();
+                    }
+                }
+                None => break,
+            }
+        }
+        run.truncate(plugs);
+        run
+    };
+    let mut strip = run(&mut (0..leaves.len()));
+    for i in run(&mut (0..leaves.len()).rev()) {
+        if !strip.contains(&i) {
+            strip.push(i);
+        }
+    }
+
+    if !strip.is_empty() {
+        log::debug!("removing {} promotional block(s)", strip.len());
+    }
+    for i in strip {
+        doc.tree.get_mut(leaves[i]).unwrap().detach();
+    }
+}
@@ -0,0 +1,107 @@
+//! Chapter pages fetched before the book asks for them, such as the first
+//! chapters of a story while the rest of its chapter list is still being
+//! read.
+
+use eyre::Result;
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::{chapter_url, fetch_page, Client, FetchOpts, Page};
+
+/// Chapter pages being fetched ahead of time, by the page they are on. A
+/// fetch of one of them through [`FetchOpts::prefetch`] takes the result
+/// instead of downloading the page again. Whatever is left when the last
+/// clone is dropped is stopped.
+#[derive(Clone, Default)]
+pub struct Prefetch {
+    pages: Arc<Pages>,
+}
+
+#[derive(Default)]
+struct Pages(Mutex<HashMap<String, JoinHandle<Result<Page>>>>);
+
+impl Drop for Pages {
+    fn drop(&mut self) {
+        for handle in self.0.get_mut().unwrap().values() {
+            handle.abort();
+        }
+    }
+}
+
+impl fmt::Debug for Prefetch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prefetch")
+            .field("pages", &self.pages.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// The page the chapter at `url` is on, leaving out the anchor of a chapter
+/// that shares it.
+fn page_key(mut url: Url) -> String {
+    url.set_fragment(None);
+    url.into()
+}
+
+impl Prefetch {
+    /// Starts fetching the page of the chapter at `link` with `fetch`'s
+    /// options, unless it is already being fetched.
+    pub fn start(&self, client: &Client, link: &str, fetch: &FetchOpts) -> Result<()> {
+        let key = page_key(chapter_url(link)?);
+        let mut pages = self.pages.0.lock().unwrap();
+        if pages.contains_key(&key) {
+            return Ok(());
+        }
+        let client = client.clone();
+        let url = key.clone();
+        // a fetch of its own page would wait on itself
+        let fetch = FetchOpts {
+            prefetch: Prefetch::default(),
+            ..fetch.clone()
+        };
+        let handle = tokio::spawn(async move { fetch_page(&client, &url, &fetch, None).await });
+        pages.insert(key, handle);
+        Ok(())
+    }
+
+    /// Whether the page of the chapter at `link` is being fetched.
+    pub fn contains(&self, link: &str) -> bool {
+        chapter_url(link)
+            .map(|url| self.pages.0.lock().unwrap().contains_key(&page_key(url)))
+            .unwrap_or(false)
+    }
+
+    /// Stops fetching the pages none of the chapters at `links` are on, as
+    /// when the full chapter list turns out not to hold them.
+    pub fn keep<'a>(&self, links: impl IntoIterator<Item = &'a str>) {
+        let keep: HashSet<String> = links
+            .into_iter()
+            .filter_map(|link| chapter_url(link).ok())
+            .map(page_key)
+            .collect();
+        self.pages.0.lock().unwrap().retain(|key, handle| {
+            let kept = keep.contains(key);
+            if !kept {
+                handle.abort();
+            }
+            kept
+        });
+    }
+
+    /// The page at `url`, if it was fetched ahead, once it is in.
+    pub(crate) async fn take(&self, url: &Url) -> Option<Result<Page>> {
+        let handle = self
+            .pages
+            .0
+            .lock()
+            .unwrap()
+            .remove(&page_key(url.clone()))?;
+        Some(match handle.await {
+            Ok(page) => page,
+            Err(err) => Err(err.into()),
+        })
+    }
+}
@@ -0,0 +1,148 @@
+//! A requests-per-minute limit for each host that holds across runs, so a
+//! script calling rustyroad story after story stays under it as well as
+//! one download does.
+//!
+//! The times of the last minute's requests to each host are kept in a
+//! small JSON file, locked while a request takes its turn, which every
+//! rustyroad running at the same time shares.
+
+use eyre::{Result, WrapErr};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The window the limits count requests in.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The name of the file the requests are kept in, in the cache directory.
+pub(crate) const RATE_LIMIT_FILE: &str = "rate-limit.json";
+
+/// The requests-per-minute limits, and the file they are kept track of in.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimits {
+    path: PathBuf,
+    /// The limit for hosts without one of their own. Zero doesn't limit
+    /// them.
+    default: u32,
+    /// Limits by host, which count for its subdomains as well.
+    hosts: BTreeMap<String, u32>,
+}
+
+/// Milliseconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl RateLimits {
+    /// The limits, or `None` if nothing is limited.
+    pub(crate) fn new(
+        path: PathBuf,
+        default: u32,
+        hosts: BTreeMap<String, u32>,
+    ) -> Result<Option<RateLimits>> {
+        if default == 0 && hosts.values().all(|limit| *limit == 0) {
+            return Ok(None);
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        }
+        Ok(Some(RateLimits {
+            path,
+            default,
+            hosts,
+        }))
+    }
+
+    /// The limit for `host`: that of the most specific host configured for
+    /// it, or the default.
+    fn limit(&self, host: &str) -> u32 {
+        self.hosts
+            .iter()
+            .filter(|(limited, _)| {
+                host == limited.as_str()
+                    || host
+                        .strip_suffix(limited.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .max_by_key(|(limited, _)| limited.len())
+            .map_or(self.default, |(_, limit)| *limit)
+    }
+
+    /// Waits until a request to `host` is within its limit, and counts it.
+    pub(crate) async fn take(&self, host: &str) -> Result<()> {
+        let limit = self.limit(host);
+        if limit == 0 {
+            return Ok(());
+        }
+        loop {
+            // the lock is waited for on a thread of its own
+            let (path, owned) = (self.path.clone(), host.to_string());
+            let wait =
+                tokio::task::spawn_blocking(move || take_or_wait(&path, &owned, limit)).await??;
+            match wait {
+                None => return Ok(()),
+                Some(wait) => {
+                    log::debug!(
+                        "{} requests to {} in the last minute, waiting {:.1}s",
+                        limit,
+                        host,
+                        wait.as_secs_f64()
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+    }
+}
+
+/// Counts a request to `host` in the file at `path` if it is within
+/// `limit`, or returns how long until it would be.
+fn take_or_wait(path: &Path, host: &str, limit: u32) -> Result<Option<Duration>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .wrap_err_with(|| format!("could not open {}", path.display()))?;
+    file.lock()
+        .wrap_err_with(|| format!("could not lock {}", path.display()))?;
+    let mut json = String::new();
+    file.read_to_string(&mut json)?;
+    // a damaged file only forgets the requests in it
+    let mut requests: BTreeMap<String, Vec<u64>> = serde_json::from_str(&json).unwrap_or_default();
+
+    let now = now();
+    let since = now.saturating_sub(WINDOW.as_millis() as u64);
+    for times in requests.values_mut() {
+        times.retain(|time| *time > since && *time <= now);
+    }
+
+    let times = requests.entry(host.to_string()).or_default();
+    let wait = if times.len() < limit as usize {
+        times.push(now);
+        None
+    } else {
+        // the request that leaves the window first makes room
+        let oldest = times.iter().min().copied().unwrap_or(now);
+        Some(Duration::from_millis(
+            oldest + WINDOW.as_millis() as u64 - now,
+        ))
+    };
+    requests.retain(|_, times| !times.is_empty());
+    write(&mut file, &requests).wrap_err_with(|| format!("could not write {}", path.display()))?;
+    Ok(wait)
+}
+
+fn write(file: &mut File, requests: &BTreeMap<String, Vec<u64>>) -> Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(serde_json::to_string(requests)?.as_bytes())?;
+    Ok(())
+}
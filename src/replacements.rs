@@ -0,0 +1,81 @@
+use regex::Regex;
+use serde::de::{Error as _, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Regex find/replace rules from the `[replacements]` section of the config
+/// file, run over every chapter's html in the order they are written:
+///
+/// ```toml
+/// [replacements]
+/// "Jonh" = "John"
+/// '<p>[^<]*[Pp]atreon[^<]*</p>' = ""
+/// ```
+///
+/// Replacements may refer to the pattern's groups as `$1` or `${name}`.
+#[derive(Debug, Clone, Default)]
+pub struct Replacements {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Replacements {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// These rules with a story's `overrides`: a rule for a pattern that is
+    /// already here takes that rule's place, any other comes after them.
+    pub fn with(&self, overrides: &Replacements) -> Replacements {
+        let mut rules = self.rules.clone();
+        for (pattern, replacement) in &overrides.rules {
+            match rules
+                .iter_mut()
+                .find(|(existing, _)| existing.as_str() == pattern.as_str())
+            {
+                Some(rule) => rule.1 = replacement.clone(),
+                None => rules.push((pattern.clone(), replacement.clone())),
+            }
+        }
+        Replacements { rules }
+    }
+
+    /// `html` with every rule applied, one after the other.
+    pub fn apply<'a>(&self, html: &'a str) -> Cow<'a, str> {
+        let mut html = Cow::Borrowed(html);
+        for (pattern, replacement) in &self.rules {
+            if let Cow::Owned(replaced) = pattern.replace_all(&html, replacement.as_str()) {
+                html = Cow::Owned(replaced);
+            }
+        }
+        html
+    }
+}
+
+impl<'de> Deserialize<'de> for Replacements {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RulesVisitor;
+
+        impl<'de> Visitor<'de> for RulesVisitor {
+            type Value = Replacements;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a table of patterns and their replacements")
+            }
+
+            // a map would lose the order the rules are written in
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Replacements, A::Error> {
+                let mut rules = Vec::new();
+                while let Some((pattern, replacement)) = map.next_entry::<String, String>()? {
+                    let regex = Regex::new(&pattern).map_err(|err| {
+                        A::Error::custom(format!("invalid pattern {:?}: {}", pattern, err))
+                    })?;
+                    rules.push((regex, replacement));
+                }
+                Ok(Replacements { rules })
+            }
+        }
+
+        deserializer.deserialize_map(RulesVisitor)
+    }
+}
@@ -0,0 +1,67 @@
+//! A story's ratings, followers and reviews, for the appendix that
+//! `--include-reviews` adds to the book.
+
+use crate::{escape, thousands};
+
+/// What readers made of a story when it was downloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Reception {
+    /// The story's scores out of 5, such as `("Overall", 4.6)`.
+    pub scores: Vec<(String, f32)>,
+    pub followers: Option<usize>,
+    /// The reviews the story page shows, in its order.
+    pub reviews: Vec<Review>,
+    /// When the reception was read, as `YYYY-MM-DD`.
+    pub date: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Review {
+    pub title: String,
+    pub author: String,
+    /// The reviewer's overall score out of 5.
+    pub score: Option<f32>,
+    /// The review as paragraphs of plain text.
+    pub paragraphs: Vec<String>,
+}
+
+impl Reception {
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty() && self.followers.is_none() && self.reviews.is_empty()
+    }
+}
+
+pub(crate) const REVIEWS_TITLE: &str = "Ratings and Reviews";
+
+/// The body of the reviews appendix, below its title.
+pub(crate) fn reviews_page(reception: &Reception) -> String {
+    let mut body = String::from("<div class=\"reviews\">\n");
+    if !reception.scores.is_empty() {
+        let scores: Vec<String> = reception
+            .scores
+            .iter()
+            .map(|(name, score)| format!("{} {:.2} / 5", escape(name), score))
+            .collect();
+        body.push_str(&format!("<p>{}</p>\n", scores.join(" · ")));
+    }
+    let mut details = Vec::new();
+    if let Some(followers) = reception.followers {
+        details.push(format!("{} followers", thousands(followers)));
+    }
+    details.push(format!("as of {}", reception.date));
+    body.push_str(&format!("<p>{}</p>\n", details.join(" · ")));
+
+    for review in &reception.reviews {
+        body.push_str(&format!("<h3>{}</h3>\n", escape(&review.title)));
+        let mut by = format!("by {}", escape(&review.author));
+        if let Some(score) = review.score {
+            by.push_str(&format!(" · {:.1} / 5", score));
+        }
+        body.push_str(&format!("<p><em>{}</em></p>\n", by));
+        for paragraph in &review.paragraphs {
+            body.push_str(&format!("<p>{}</p>\n", escape(paragraph)));
+        }
+    }
+    body.push_str("</div>");
+    body
+}
@@ -0,0 +1,115 @@
+//! robots.txt, which `--polite` downloads follow: the paths a site asks
+//! crawlers to stay out of, and how long it asks them to wait between
+//! requests. Rules are read as RFC 9309 has them, with the common
+//! `Crawl-delay` extension.
+
+use regex::Regex;
+use std::time::Duration;
+
+/// The product token rustyroad looks for in `User-agent` lines.
+pub(crate) const AGENT: &str = "rustyroad";
+
+/// An `Allow` or `Disallow` line.
+#[derive(Clone, Debug)]
+struct Rule {
+    allow: bool,
+    /// The length of the path pattern, the longest matching one winning.
+    len: usize,
+    pattern: Regex,
+}
+
+/// The part of a site's robots.txt that applies to rustyroad: the group
+/// naming it, or else the one for `*`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Robots {
+    rules: Vec<Rule>,
+    pub(crate) crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    pub(crate) fn parse(text: &str) -> Robots {
+        // (agents, rules, crawl delay) of each group
+        let mut groups: Vec<(Vec<String>, Vec<Rule>, Option<Duration>)> = Vec::new();
+        let mut in_agents = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "user-agent" => {
+                    if !in_agents {
+                        groups.push((Vec::new(), Vec::new(), None));
+                    }
+                    in_agents = true;
+                    groups
+                        .last_mut()
+                        .unwrap()
+                        .0
+                        .push(value.to_ascii_lowercase());
+                }
+                key @ ("allow" | "disallow") => {
+                    in_agents = false;
+                    let (Some(group), false) = (groups.last_mut(), value.is_empty()) else {
+                        continue;
+                    };
+                    group.1.push(Rule {
+                        allow: key == "allow",
+                        len: value.len(),
+                        pattern: pattern(value),
+                    });
+                }
+                "crawl-delay" => {
+                    in_agents = false;
+                    let Some(group) = groups.last_mut() else {
+                        continue;
+                    };
+                    group.2 = value
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                }
+                _ => (),
+            }
+        }
+
+        let named = groups
+            .iter()
+            .any(|(agents, ..)| agents.iter().any(|agent| agent == AGENT));
+        let mut robots = Robots::default();
+        for (agents, rules, crawl_delay) in groups {
+            let applies = match named {
+                true => agents.iter().any(|agent| agent == AGENT),
+                false => agents.iter().any(|agent| agent == "*"),
+            };
+            if applies {
+                robots.rules.extend(rules);
+                robots.crawl_delay = robots.crawl_delay.max(crawl_delay);
+            }
+        }
+        robots
+    }
+
+    /// Whether rustyroad may fetch `path`, with its query if any: the
+    /// longest rule matching it decides, `Allow` winning a tie, and paths
+    /// no rule matches are allowed.
+    pub(crate) fn allows(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|rule| rule.pattern.is_match(path))
+            .max_by_key(|rule| (rule.len, rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+/// The regex for a path pattern, in which `*` stands for anything and a
+/// `$` at the end for the end of the path.
+fn pattern(path: &str) -> Regex {
+    let (path, anchored) = match path.strip_suffix('$') {
+        Some(path) => (path, "$"),
+        None => (path, ""),
+    };
+    let parts: Vec<String> = path.split('*').map(regex::escape).collect();
+    Regex::new(&format!("^{}{}", parts.join(".*"), anchored)).unwrap()
+}
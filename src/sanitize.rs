@@ -0,0 +1,204 @@
+//! Reduces the chapter content to markup e-readers are known to handle.
+//!
+//! Chapter pages carry scripts, tracking pixels, inline event handlers and
+//! attributes of the site's own that strict readers (and epubcheck) reject.
+//! Only the elements and attributes below are kept: other elements are
+//! replaced by their content, and the ones whose content is of no use in a
+//! book are removed along with it.
+
+use ego_tree::NodeId;
+use markup5ever::{namespace_url, ns};
+use scraper::{ElementRef, Html, Node};
+
+use crate::clean::{find, new_element};
+
+/// The elements that are kept.
+const ELEMENTS: &[&str] = &[
+    "a",
+    "abbr",
+    "aside",
+    "b",
+    "bdi",
+    "bdo",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "del",
+    "details",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "mark",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "rp",
+    "rt",
+    "ruby",
+    "s",
+    "samp",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "summary",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "time",
+    "tr",
+    "u",
+    "ul",
+    "var",
+    "wbr",
+];
+
+/// The elements that are removed with their content.
+const REMOVED: &[&str] = &[
+    "applet", "audio", "base", "button", "canvas", "embed", "form", "frame", "frameset", "iframe",
+    "input", "link", "map", "meta", "noscript", "object", "script", "select", "style", "template",
+    "textarea", "video",
+];
+
+/// The attributes every kept element may have.
+const ATTRIBUTES: &[&str] = &["class", "dir", "id", "lang", "style", "title"];
+
+/// The attributes some elements may have as well.
+fn allowed_attr(element: &str, attr: &str) -> bool {
+    ATTRIBUTES.contains(&attr)
+        || matches!(
+            (element, attr),
+            ("a", "href")
+                | ("img", "src" | "alt" | "width" | "height")
+                | ("td" | "th", "colspan" | "rowspan")
+                | ("col" | "colgroup", "span")
+                | ("ol", "start" | "reversed" | "type")
+                | ("li", "value")
+                | ("blockquote" | "q" | "del" | "ins", "cite")
+                | ("time" | "del" | "ins", "datetime")
+                | ("details", "open")
+                | ("a" | "aside", "epub:type")
+        )
+}
+
+/// An image too small to see, which is only there to track its readers.
+fn is_tracking_pixel(el: ElementRef) -> bool {
+    let tiny = |attr| {
+        el.value()
+            .attr(attr)
+            .and_then(|size| size.trim().trim_end_matches("px").parse::<u32>().ok())
+            .is_some_and(|size| size <= 1)
+    };
+    el.value().name() == "img" && tiny("width") && tiny("height")
+}
+
+fn is_script_url(url: &str) -> bool {
+    let url: String = url.chars().filter(|c| !c.is_whitespace()).collect();
+    let url = url.to_ascii_lowercase();
+    url.starts_with("javascript:") || url.starts_with("vbscript:")
+}
+
+fn is_removed(el: ElementRef) -> bool {
+    // svg and math are parsed into namespaces of their own
+    el.value().name.ns != ns!(html) || REMOVED.contains(&el.value().name()) || is_tracking_pixel(el)
+}
+
+/// Sanitizes the subtree rooted at `content`, which is kept itself.
+pub(crate) fn sanitize(doc: &mut Html, content: NodeId) {
+    let removed = find(doc, content, is_removed);
+    let comments: Vec<NodeId> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .descendants()
+        .filter(|node| {
+            matches!(
+                node.value(),
+                Node::Comment(_) | Node::ProcessingInstruction(_)
+            )
+        })
+        .map(|node| node.id())
+        .collect();
+    if !removed.is_empty() {
+        log::debug!("removing {} unsafe element(s)", removed.len());
+    }
+    for id in removed.into_iter().chain(comments) {
+        doc.tree.get_mut(id).unwrap().detach();
+    }
+
+    // replace the remaining unknown elements by their content
+    let unknown = find(doc, content, |el| !ELEMENTS.contains(&el.value().name()));
+    for id in unknown {
+        let children: Vec<NodeId> = doc
+            .tree
+            .get(id)
+            .unwrap()
+            .children()
+            .map(|c| c.id())
+            .collect();
+        let mut node = doc.tree.get_mut(id).unwrap();
+        for child in children {
+            node.insert_id_before(child);
+        }
+        node.detach();
+    }
+
+    let elements: Vec<NodeId> = doc
+        .tree
+        .get(content)
+        .unwrap()
+        .descendants()
+        .filter(|node| node.value().is_element())
+        .map(|node| node.id())
+        .collect();
+    for id in elements {
+        let Node::Element(el) = doc.tree.get(id).unwrap().value() else {
+            continue;
+        };
+        let name = el.name();
+        let attrs: Vec<(String, String)> = el
+            .attrs()
+            .filter(|(attr, value)| {
+                allowed_attr(name, attr)
+                    && !(matches!(*attr, "href" | "src") && is_script_url(value))
+            })
+            .map(|(attr, value)| (attr.to_string(), value.to_string()))
+            .collect();
+        if attrs.len() == el.attrs().count() {
+            continue;
+        }
+
+        let name = name.to_string();
+        let attrs: Vec<(&str, &str)> = attrs
+            .iter()
+            .map(|(attr, value)| (attr.as_str(), value.as_str()))
+            .collect();
+        *doc.tree.get_mut(id).unwrap().value() = new_element(&name, &attrs);
+    }
+}
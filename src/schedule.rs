@@ -0,0 +1,310 @@
+//! When `watch` checks each book: cron schedules like `0 6 * * *`, given
+//! with `--schedule` or per story in the config file, and the status file
+//! in which a running `watch` records its next checks for `watch --status`.
+
+use chrono::{DateTime, Datelike, Duration, Local, LocalResult, NaiveDateTime, TimeZone, Timelike};
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::library::data_dir;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A cron schedule in the usual five fields, minute, hour, day of the month,
+/// month and day of the week, in local time. Fields take `*`, numbers,
+/// ranges like `1-5`, steps like `*/15` and lists of those, and months and
+/// days of the week may be named (`jan`, `mon`). `@hourly`, `@daily`,
+/// `@weekly`, `@monthly` and `@yearly` stand for the schedules they name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    text: String,
+    // one bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month or of the week was `*`. As in cron, a
+    /// day matches either field when both are restricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Schedule, String> {
+        let text = text.trim();
+        let expanded = match text {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            _ => text,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "invalid schedule {:?}, expected five fields: minute, hour, day of the month, month and day of the week",
+                text
+            ));
+        };
+        let err =
+            |name: &str, err: String| format!("invalid {} in schedule {:?}: {}", name, text, err);
+
+        let mut weekdays = field(weekday, 0, 7, DAYS).map_err(|e| err("day of the week", e))?;
+        // 7 is Sunday too
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Schedule {
+            text: text.to_string(),
+            minutes: field(minute, 0, 59, &[]).map_err(|e| err("minute", e))?,
+            hours: field(hour, 0, 23, &[]).map_err(|e| err("hour", e))?,
+            days: field(day, 1, 31, &[]).map_err(|e| err("day of the month", e))?,
+            months: field(month, 1, 12, MONTHS).map_err(|e| err("month", e))?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The values a cron field allows, as bits, for values from `min` to `max`
+/// named `names` from `min` on.
+fn field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let value = match names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(text))
+        {
+            Some(i) => min + i as u32,
+            None => text
+                .parse()
+                .map_err(|_| format!("{:?} is not a number", text))?,
+        };
+        if !(min..=max).contains(&value) {
+            return Err(format!("{} is not between {} and {}", value, min, max));
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("invalid step {:?}", step)),
+            },
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // `5/10` runs from 5 to the end
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("the range {:?} is backwards", range));
+        }
+        for value in (first..=last).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Schedule {
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days & 1 << time.day() != 0;
+        let weekday = self.weekdays & 1 << time.weekday().num_days_from_sunday() != 0;
+        match self.any_day || self.any_weekday {
+            true => day && weekday,
+            false => day || weekday,
+        }
+    }
+
+    /// The first time after `after` the schedule fires, or `None` if it
+    /// never does, as with `0 0 30 2 *`. A time skipped by a change to
+    /// daylight saving time is skipped by the schedule too.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local();
+        let mut time =
+            start.date().and_hms_opt(start.hour(), start.minute(), 0)? + Duration::minutes(1);
+        // any schedule that fires at all does so within a leap year's cycle
+        let end = time + Duration::days(366 * 8);
+        while time < end {
+            if self.months & 1 << time.month() == 0 {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(&time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & 1 << time.hour() == 0 {
+                time = time.date().and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if self.minutes & 1 << time.minute() == 0 {
+                time += Duration::minutes(1);
+            } else {
+                match Local.from_local_datetime(&time) {
+                    LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+                        return Some(time)
+                    }
+                    LocalResult::None => time += Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+}
+
+/// What a running `watch` last did with a book and when it checks it next,
+/// as Unix timestamps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookStatus {
+    pub book: String,
+    /// The book's cron schedule, if it has one.
+    pub schedule: Option<String>,
+    /// How many seconds apart the book is checked, without a schedule.
+    pub interval: Option<u64>,
+    pub next_check: Option<i64>,
+    pub last_check: Option<i64>,
+    /// E.g. `up to date`, `3 new chapter(s)` or the error the check failed
+    /// with.
+    pub last_result: Option<String>,
+}
+
+/// The status file of a running `watch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchStatus {
+    /// The process id of the `watch`.
+    pub pid: u32,
+    /// Unix timestamp of when the status was written.
+    pub updated: i64,
+    pub books: Vec<BookStatus>,
+}
+
+impl WatchStatus {
+    /// `$XDG_DATA_HOME/rustyroad/watch.json`, next to the library. The
+    /// latest `watch` to start writes it.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(data_dir()?.join("watch.json"))
+    }
+
+    pub fn load(path: &Path) -> Result<WatchStatus> {
+        let data = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read {}", path.display()))?;
+        serde_json::from_str(&data).wrap_err_with(|| format!("could not parse {}", path.display()))
+    }
+
+    /// Writes the status to `path`, replacing it at once so `watch
+    /// --status` never reads half of it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = path
+            .parent()
+            .ok_or_else(|| eyre!("{} has no directory", path.display()))?;
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("could not create {}", dir.display()))?;
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.persist(path)
+            .wrap_err_with(|| format!("could not write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn next(schedule: &str, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        schedule.parse::<Schedule>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn reads_ranges_steps_and_names() {
+        let schedule: Schedule = "*/15 9-17 1,5/10 jan,jun mon-fri".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(
+            schedule.hours,
+            (9..=17).fold(0, |bits, hour| bits | 1 << hour)
+        );
+        assert_eq!(schedule.days, 1 << 1 | 1 << 5 | 1 << 15 | 1 << 25);
+        assert_eq!(schedule.months, 1 << 1 | 1 << 6);
+        assert_eq!(schedule.weekdays, 0b11_1110);
+
+        assert!("0 6 * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("0 17-9 * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("0 0 * foo *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        let schedule: Schedule = "0 12 * * 7".parse().unwrap();
+        assert_eq!(schedule.weekdays, 1);
+        // 2024-01-06 is a Saturday
+        assert_eq!(
+            next("0 12 * * 7", at(2024, 1, 6, 13, 0)),
+            Some(at(2024, 1, 7, 12, 0))
+        );
+    }
+
+    #[test]
+    fn restricted_days_match_either_field() {
+        // the first Friday comes before the 13th, and the 13th is a Saturday
+        let schedule = "0 12 13 * fri";
+        assert_eq!(
+            next(schedule, at(2024, 1, 1, 0, 0)),
+            Some(at(2024, 1, 5, 12, 0))
+        );
+        assert_eq!(
+            next(schedule, at(2024, 1, 12, 12, 0)),
+            Some(at(2024, 1, 13, 12, 0))
+        );
+        // with the day of the month left open, only Fridays match
+        assert_eq!(
+            next("0 12 * * fri", at(2024, 1, 12, 12, 0)),
+            Some(at(2024, 1, 19, 12, 0))
+        );
+    }
+
+    #[test]
+    fn daily_is_midnight() {
+        assert_eq!(
+            next("@daily", at(2024, 3, 10, 15, 30)),
+            Some(at(2024, 3, 11, 0, 0))
+        );
+        assert_eq!(
+            next("@daily", at(2024, 12, 31, 23, 59)),
+            Some(at(2025, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn impossible_dates_never_come() {
+        assert_eq!(next("0 0 30 2 *", at(2024, 1, 1, 0, 0)), None);
+    }
+}
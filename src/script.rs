@@ -0,0 +1,109 @@
+//! Chapter scripts: a Rhai script run on every chapter before it goes into
+//! the book, for cleanup particular to a story, such as removing recaps or
+//! renaming chapters.
+//!
+//! The script defines `fn chapter(title, html)`. What it returns decides
+//! what happens to the chapter:
+//!
+//! - nothing, or `true`: the chapter is added as it is
+//! - `false`: the chapter is left out
+//! - a string: the chapter's new html
+//! - a map such as `#{ title: "...", html: "..." }`: the chapter's new title
+//!   and html, either of which may be left out
+
+use eyre::{eyre, Result, WrapErr};
+use rhai::{Dynamic, Engine, Map, AST};
+use std::path::Path;
+
+/// The function a chapter script defines.
+const FUNCTION: &str = "chapter";
+
+pub struct ChapterScript {
+    engine: Engine,
+    ast: AST,
+    name: String,
+}
+
+impl ChapterScript {
+    /// Compiles the script at `path`.
+    pub fn load(path: &Path) -> Result<ChapterScript> {
+        let mut engine = Engine::new();
+        let name = path.display().to_string();
+        let source = name.clone();
+        engine.on_print(move |text| log::info!("{}: {}", source, text));
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|err| eyre!("{}", err))
+            .wrap_err_with(|| format!("could not load the script {}", name))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == FUNCTION && f.params.len() == 2)
+        {
+            return Err(eyre!(
+                "the script {} doesn't define fn {}(title, html)",
+                name,
+                FUNCTION
+            ));
+        }
+        Ok(ChapterScript { engine, ast, name })
+    }
+
+    /// Runs the script on the chapter `title` with the content `html`.
+    /// Returns its title and content as the script leaves them, or `None` if
+    /// the script leaves the chapter out.
+    pub fn apply(&self, title: &str, html: &str) -> Result<Option<(String, String)>> {
+        let result: Dynamic = self
+            .engine
+            .call_fn(
+                &mut rhai::Scope::new(),
+                &self.ast,
+                FUNCTION,
+                (title.to_string(), html.to_string()),
+            )
+            .map_err(|err| eyre!("{}", err))
+            .wrap_err_with(|| format!("the script {} failed on {:?}", self.name, title))?;
+
+        let (mut title, mut html) = (title.to_string(), html.to_string());
+        if result.is_unit() {
+            return Ok(Some((title, html)));
+        }
+        if let Ok(keep) = result.as_bool() {
+            return Ok(keep.then_some((title, html)));
+        }
+        if result.is_string() {
+            return Ok(Some((title, result.into_string().unwrap())));
+        }
+        if let Some(map) = result.clone().try_cast::<Map>() {
+            for (key, value) in map {
+                let value = value.into_string().map_err(|kind| {
+                    eyre!(
+                        "the script {} returned a {} as the {} of {:?}, not a string",
+                        self.name,
+                        kind,
+                        key,
+                        title
+                    )
+                })?;
+                match key.as_str() {
+                    "title" => title = value,
+                    "html" => html = value,
+                    _ => {
+                        return Err(eyre!(
+                            "the script {} returned {:?} for {:?}, expected title or html",
+                            self.name,
+                            key.as_str(),
+                            title
+                        ))
+                    }
+                }
+            }
+            return Ok(Some((title, html)));
+        }
+        Err(eyre!(
+            "the script {} returned a {} for {:?}; return nothing, a bool, a string or a map",
+            self.name,
+            result.type_name(),
+            title
+        ))
+    }
+}
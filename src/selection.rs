@@ -0,0 +1,133 @@
+use crate::Chapter;
+use eyre::{eyre, Result, WrapErr};
+
+/// A set of chapter numbers (1-based), e.g. `1-50,60,70-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChapterSelection {
+    /// Inclusive ranges. An open end runs to the last chapter.
+    ranges: Vec<(usize, Option<usize>)>,
+}
+
+fn number(text: &str) -> Result<usize> {
+    let nr: usize = text
+        .trim()
+        .parse()
+        .wrap_err_with(|| format!("invalid chapter number {:?}", text.trim()))?;
+    if nr == 0 {
+        return Err(eyre!("chapter numbers start at 1"));
+    }
+    Ok(nr)
+}
+
+impl ChapterSelection {
+    /// Parses a comma-separated list of chapter numbers and ranges. A range
+    /// may leave out its start (`-10`) or end (`70-`).
+    pub fn parse(spec: &str) -> Result<ChapterSelection> {
+        let mut ranges = Vec::new();
+
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let range = match part.split_once('-') {
+                Some((start, end)) => {
+                    let start = if start.trim().is_empty() {
+                        1
+                    } else {
+                        number(start)?
+                    };
+                    let end = if end.trim().is_empty() {
+                        None
+                    } else {
+                        Some(number(end)?)
+                    };
+                    (start, end)
+                }
+                None => {
+                    let nr = number(part)?;
+                    (nr, Some(nr))
+                }
+            };
+
+            if matches!(range, (start, Some(end)) if end < start) {
+                return Err(eyre!("chapter range {:?} ends before it starts", part));
+            }
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return Err(eyre!("empty chapter selection"));
+        }
+
+        Ok(ChapterSelection { ranges })
+    }
+
+    /// The chapters from `from` to `to`, either of which may be open.
+    pub fn between(from: Option<usize>, to: Option<usize>) -> Result<ChapterSelection> {
+        let start = from.unwrap_or(1);
+        if start == 0 || to == Some(0) {
+            return Err(eyre!("chapter numbers start at 1"));
+        }
+        if matches!(to, Some(to) if to < start) {
+            return Err(eyre!("--to is before --from"));
+        }
+
+        Ok(ChapterSelection {
+            ranges: vec![(start, to)],
+        })
+    }
+
+    /// The chapters numbered `numbers`, in ascending order, or `None` if
+    /// there are none.
+    pub fn of(numbers: impl IntoIterator<Item = usize>) -> Option<ChapterSelection> {
+        let mut ranges: Vec<(usize, Option<usize>)> = Vec::new();
+        for nr in numbers {
+            match ranges.last_mut() {
+                Some((_, Some(end))) if *end + 1 == nr => *end = nr,
+                _ => ranges.push((nr, Some(nr))),
+            }
+        }
+        (!ranges.is_empty()).then_some(ChapterSelection { ranges })
+    }
+
+    pub fn contains(&self, nr: usize) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| nr >= start && end.is_none_or(|end| nr <= end))
+    }
+
+    /// Keeps the selected chapters, in their original order.
+    pub fn apply(&self, chapters: Vec<Chapter>) -> Vec<Chapter> {
+        let total = chapters.len();
+        for &(start, _) in &self.ranges {
+            if start > total {
+                log::warn!(
+                    "chapter {} was selected, but the story only has {} chapters",
+                    start,
+                    total
+                );
+            }
+        }
+
+        chapters
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| self.contains(i + 1))
+            .map(|(_, chapter)| chapter)
+            .collect()
+    }
+}
+
+/// Writes the selection the way [`ChapterSelection::parse`] reads it.
+impl std::fmt::Display for ChapterSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, &(start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match end {
+                Some(end) if end == start => write!(f, "{}", start)?,
+                Some(end) => write!(f, "{}-{}", start, end)?,
+                None => write!(f, "{}-", start)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! The CSS selectors the sites are scraped with, by name, each with
+//! fallbacks that are tried in order when the markup it looks for is gone.
+//!
+//! Every name can be given other selectors in the config file's
+//! `[selectors]` table, which take the place of the built-in ones, so a
+//! change to a site's layout can be worked around without a new release.
+//!
+//! What a selector yields depends on the element it matches: the `content`
+//! of a `<meta>`, the `src` of an `<img>`, the `href` of a `<link>`, and
+//! the text of anything else, a line for each paragraph.
+
+use ego_tree::iter::Edge;
+use eyre::{eyre, Result, WrapErr};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::{spoilers, ScrapeError, Spoilers};
+
+/// The built-in selectors for each name, in the order they are tried.
+pub const SELECTORS: &[(&str, &[&str])] = &[
+    (
+        "royalroad.title",
+        &[
+            r#"meta[name="twitter:title"]"#,
+            r#"meta[property="og:title"]"#,
+            "div.fic-header h1",
+        ],
+    ),
+    (
+        "royalroad.author",
+        &[
+            r#"meta[name="twitter:creator"]"#,
+            r#"meta[property="books:author"]"#,
+            r#"div.fic-header h4 a[href*="/profile/"]"#,
+        ],
+    ),
+    // the meta tags cut the description short
+    (
+        "royalroad.description",
+        &[
+            "div.description",
+            r#"meta[name="twitter:description"]"#,
+            r#"meta[property="og:description"]"#,
+        ],
+    ),
+    (
+        "royalroad.cover",
+        &[
+            r#"meta[name="twitter:image"]"#,
+            r#"meta[property="og:image"]"#,
+            "div.cover-art-container img",
+        ],
+    ),
+    (
+        "royalroad.url",
+        &[r#"meta[property="og:url"]"#, r#"link[rel="canonical"]"#],
+    ),
+    ("royalroad.tags", &["span.tags a.fiction-tag"]),
+    (
+        "royalroad.warnings",
+        &[
+            "div.font-red-sunglo ul.list-inline li",
+            "ul.content-warnings li",
+        ],
+    ),
+    ("royalroad.chapters", &[r#"table[id="chapters"]"#]),
+    ("royalroad.chapter-rows", &["tbody > tr.chapter-row"]),
+    (
+        "royalroad.content",
+        &["div.chapter-content", "div.fiction-page-content"],
+    ),
+    ("royalroad.author-notes", &["div.author-note-portlet"]),
+    (
+        "scribblehub.title",
+        &["div.fic_title", r#"meta[property="og:title"]"#],
+    ),
+    ("scribblehub.author", &["span.auth_name_fic"]),
+    ("scribblehub.description", &["div.wi_fic_desc p"]),
+    (
+        "scribblehub.cover",
+        &["div.fic_image img", r#"meta[property="og:image"]"#],
+    ),
+    ("scribblehub.url", &[r#"meta[property="og:url"]"#]),
+    ("scribblehub.tags", &["a.fic_genre, a.stag"]),
+    ("scribblehub.chapters", &["li.toc_w"]),
+    ("scribblehub.content", &["div#chp_raw"]),
+    ("scribblehub.author-notes", &["div.wi_authornotes"]),
+    ("ao3.title", &["h2.title.heading"]),
+    ("ao3.author", &[r#"h3.byline a[rel="author"]"#, "h3.byline"]),
+    (
+        "ao3.description",
+        &["div.preface div.summary blockquote.userstuff"],
+    ),
+    (
+        "ao3.tags",
+        &["dd.fandom a.tag, dd.relationship a.tag, dd.character a.tag, dd.freeform a.tag"],
+    ),
+    ("ao3.warnings", &["dd.rating a.tag, dd.warning a.tag"]),
+    ("ao3.chapters", &["div#chapters > div.chapter"]),
+    ("ao3.content", &["div#chapters"]),
+    ("ao3.author-notes", &["div.notes.module"]),
+    (
+        "ao3.junk",
+        &["h3.landmark, p.jump, div.chapter > div.preface > h3.title"],
+    ),
+    (
+        "ao3.works",
+        &[r#"ul.series.work li.work h4.heading a[href^="/works/"]"#],
+    ),
+    ("ao3.series-title", &["h2.heading"]),
+    ("ao3.series-author", &[r#"dl.series.meta a[rel="author"]"#]),
+    (
+        "ao3.series-description",
+        &["dl.series.meta blockquote.userstuff"],
+    ),
+];
+
+/// The selectors the config file gives in place of the built-in ones.
+static OVERRIDES: RwLock<BTreeMap<String, Vec<String>>> = RwLock::new(BTreeMap::new());
+
+/// Scrapes with `overrides` in place of the built-in selectors of the same
+/// names. Fails on a name that isn't one of [`SELECTORS`] or a selector
+/// that doesn't parse.
+pub fn set_selectors(overrides: &BTreeMap<String, Vec<String>>) -> Result<()> {
+    for (name, selectors) in overrides {
+        if !SELECTORS.iter().any(|(known, _)| known == name) {
+            let known: Vec<&str> = SELECTORS.iter().map(|(known, _)| *known).collect();
+            return Err(eyre!(
+                "unknown selector {:?}, use one of {}",
+                name,
+                known.join(", ")
+            ));
+        }
+        if selectors.is_empty() {
+            return Err(eyre!("no selectors given for {:?}", name));
+        }
+        for selector in selectors {
+            Selector::parse(selector)
+                .map_err(|err| eyre!("{}", err))
+                .wrap_err_with(|| format!("invalid selector {:?} for {:?}", selector, name))?;
+        }
+    }
+    *OVERRIDES.write().unwrap() = overrides.clone();
+    Ok(())
+}
+
+/// The selectors for `name`, in the order they are tried.
+pub(crate) fn fallbacks(name: &str) -> Vec<String> {
+    if let Some(selectors) = OVERRIDES.read().unwrap().get(name) {
+        return selectors.clone();
+    }
+    SELECTORS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, selectors)| selectors.iter().map(|s| s.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// All of `name`'s selectors as one, matching what any of them does.
+pub(crate) fn joined(name: &str) -> String {
+    fallbacks(name).join(", ")
+}
+
+/// The elements in `scope` that the first of `name`'s selectors to match
+/// anything matches.
+pub(crate) fn select_all<'a>(scope: ElementRef<'a>, name: &str) -> Vec<ElementRef<'a>> {
+    for (i, fallback) in fallbacks(name).iter().enumerate() {
+        let Ok(selector) = Selector::parse(fallback) else {
+            continue;
+        };
+        let found: Vec<ElementRef> = scope.select(&selector).collect();
+        if !found.is_empty() {
+            if i > 0 {
+                log::debug!("{} found with the fallback `{}`", name, fallback);
+            }
+            return found;
+        }
+    }
+    Vec::new()
+}
+
+/// The first element in `scope` that `name`'s selectors match.
+pub(crate) fn select_first<'a>(scope: ElementRef<'a>, name: &str) -> Option<ElementRef<'a>> {
+    select_all(scope, name).into_iter().next()
+}
+
+/// What `el` holds for scraping: see the module docs.
+pub(crate) fn element_value(el: ElementRef) -> String {
+    let attr = match el.value().name() {
+        "meta" => el.value().attr("content"),
+        "img" => el.value().attr("src"),
+        "link" => el.value().attr("href"),
+        _ => None,
+    };
+    match attr {
+        Some(value) => value.trim().to_string(),
+        None => block_text(el),
+    }
+}
+
+/// The elements that start a line of [`block_text`].
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "br",
+    "li",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "tr",
+    "hr",
+];
+
+/// `el`'s text with a line for each paragraph, and its spoilers' hidden
+/// text shown after their caption.
+fn block_text(el: ElementRef) -> String {
+    let mut doc = Html::parse_fragment(&el.html());
+    let root = doc.root_element().id();
+    spoilers::convert(&mut doc, root, Spoilers::Inline);
+
+    let mut lines = vec![String::new()];
+    for edge in doc.root_element().traverse() {
+        let starts_line = |node: ego_tree::NodeRef<Node>| {
+            node.value()
+                .as_element()
+                .is_some_and(|el| BLOCKS.contains(&el.name()))
+        };
+        match edge {
+            Edge::Open(node) if starts_line(node) => lines.push(String::new()),
+            Edge::Close(node) if starts_line(node) => lines.push(String::new()),
+            Edge::Open(node) => {
+                let Node::Text(text) = node.value() else {
+                    continue;
+                };
+                // text in scripts, styles and buttons isn't read
+                let hidden = node
+                    .ancestors()
+                    .filter_map(|node| node.value().as_element())
+                    .any(|el| matches!(el.name(), "script" | "style" | "button" | "noscript"));
+                if !hidden {
+                    lines.last_mut().unwrap().push_str(text);
+                }
+            }
+            Edge::Close(_) => (),
+        }
+    }
+    lines
+        .iter()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The non-empty values of the elements in `scope` that the first of
+/// `name`'s selectors to yield any matches.
+pub(crate) fn values(scope: ElementRef, name: &str) -> Vec<String> {
+    for (i, fallback) in fallbacks(name).iter().enumerate() {
+        let Ok(selector) = Selector::parse(fallback) else {
+            continue;
+        };
+        let found: Vec<String> = scope
+            .select(&selector)
+            .map(element_value)
+            .filter(|value| !value.is_empty())
+            .collect();
+        if !found.is_empty() {
+            if i > 0 {
+                log::debug!("{} found with the fallback `{}`", name, fallback);
+            }
+            return found;
+        }
+    }
+    Vec::new()
+}
+
+/// The first non-empty value `name`'s selectors yield in `scope`.
+pub(crate) fn value(scope: ElementRef, name: &str) -> Option<String> {
+    values(scope, name).into_iter().next()
+}
+
+/// [`value`], failing with a [`ScrapeError::SelectorChanged`] that names
+/// `what` if there is none.
+pub(crate) fn required(
+    scope: ElementRef,
+    name: &'static str,
+    what: &'static str,
+) -> Result<String> {
+    value(scope, name).ok_or_else(|| ScrapeError::named_selector_changed(what, name).into())
+}
@@ -122,6 +122,8 @@ impl<Wr: Write> HtmlSerializer<Wr> {
 }
 
 impl<Wr: Write> Serializer for HtmlSerializer<Wr> {
+    // kept as html5ever writes it, so changes there are easy to bring over
+    #[allow(clippy::match_like_matches_macro)]
     fn start_elem<'a, AttrIter>(&mut self, name: QualName, attrs: AttrIter) -> io::Result<()>
     where
         AttrIter: Iterator<Item = AttrRef<'a>>,
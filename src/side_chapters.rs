@@ -0,0 +1,68 @@
+//! Side chapters: interludes, side stories and bonus chapters, told from
+//! their titles, which readers who only follow the main story may want out
+//! of its way.
+
+use regex::Regex;
+use std::sync::OnceLock;
+
+use crate::Chapter;
+
+/// What to do with the side chapters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SideChapters {
+    /// Leave them where they are
+    #[default]
+    Keep,
+    /// Move them after the main story, listed under their own section of
+    /// the table of contents
+    Group,
+    /// Move them after the main story, as chapters of their own
+    Back,
+    /// Leave them out of the book
+    Exclude,
+}
+
+/// The section the side chapters are listed under with
+/// [`SideChapters::Group`].
+pub const SIDE_VOLUME: &str = "Side Stories";
+
+/// The titles that make a side chapter unless a story's own pattern is
+/// given: interludes, intermissions, side stories, bonus chapters and omake.
+pub fn side_chapter_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"(?i)\b(interlude|intermission|side[ -]?stor(y|ies)|bonus|omake)\b").unwrap()
+    })
+}
+
+/// Does what `mode` says with the chapters whose titles match `pattern`,
+/// keeping the main chapters, and the side chapters among themselves, in
+/// order. Returns the number of side chapters.
+pub fn arrange_side_chapters(
+    chapters: &mut Vec<Chapter>,
+    mode: SideChapters,
+    pattern: &Regex,
+) -> usize {
+    if mode == SideChapters::Keep {
+        return chapters
+            .iter()
+            .filter(|chapter| pattern.is_match(&chapter.name))
+            .count();
+    }
+
+    let (mut side, main): (Vec<Chapter>, Vec<Chapter>) = std::mem::take(chapters)
+        .into_iter()
+        .partition(|chapter| pattern.is_match(&chapter.name));
+    let count = side.len();
+    *chapters = match mode {
+        SideChapters::Keep | SideChapters::Exclude => main,
+        SideChapters::Group | SideChapters::Back => {
+            let volume = (mode == SideChapters::Group).then(|| SIDE_VOLUME.to_string());
+            for chapter in &mut side {
+                chapter.volume = volume.clone();
+            }
+            main.into_iter().chain(side).collect()
+        }
+    };
+    count
+}
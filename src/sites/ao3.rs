@@ -0,0 +1,278 @@
+use eyre::Result;
+use regex::Regex;
+use reqwest::Url;
+use scraper::{ElementRef, Html};
+use std::sync::OnceLock;
+
+use super::{unix_time, SiteScraper, Unavailable};
+use crate::{selector, selectors, Chapter, ScrapeError, Story, StoryStatus};
+
+pub struct Ao3;
+
+const HOST: &str = "https://archiveofourown.org";
+
+/// The page with every chapter of the work `id`, past the question whether
+/// the reader is willing to see adult content.
+fn full_work_url(id: &str) -> String {
+    format!("{}/works/{}?view_adult=true&view_full_work=true", HOST, id)
+}
+
+/// The text of `el` on one line.
+fn text(el: ElementRef) -> String {
+    el.text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reads a date as the work's statistics give it, e.g. `2020-08-05`, into
+/// a unix timestamp.
+fn date(date: &str) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    unix_time(year, month, day, 0, 0)
+}
+
+/// The number of the work on the page `doc`: what its kudos are given to,
+/// or else the first link to it.
+fn work_id(doc: &Html) -> Option<String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re =
+        RE.get_or_init(|| Regex::new(r"^(?:https://archiveofourown\.org)?/works/(\d+)").unwrap());
+    let kudos = selector(r#"input[name="kudo[commentable_id]"]"#).ok()?;
+    if let Some(id) = doc
+        .select(&kudos)
+        .find_map(|input| input.value().attr("value"))
+    {
+        return Some(id.trim().to_string());
+    }
+    let links = selector("a[href]").ok()?;
+    doc.select(&links)
+        .filter_map(|a| a.value().attr("href"))
+        .find_map(|href| Some(re.captures(href)?[1].to_string()))
+}
+
+/// The statistic of the work on the page `doc` named by `class`, e.g.
+/// `words`.
+fn stat(doc: &Html, class: &str) -> Option<String> {
+    let dd = selector(&format!("dl.stats dd.{}", class)).ok()?;
+    doc.select(&dd).next().map(text)
+}
+
+/// The chapters of the work on the page `doc`, filed under `volume`. Each
+/// links to its part of the page with every chapter, so they all come
+/// with one download; a work of one chapter is that page.
+fn work_chapters(doc: &Html, volume: Option<String>) -> Result<Vec<Chapter>> {
+    let id = work_id(doc)
+        .ok_or_else(|| ScrapeError::selector_changed("work", "a[href^=\"/works/\"]"))?;
+    let page = full_work_url(&id);
+    let heading = selector("h3.title")?;
+    let mut chapters: Vec<Chapter> = selectors::select_all(doc.root_element(), "ao3.chapters")
+        .into_iter()
+        .filter_map(|div| {
+            let anchor = div.value().id()?;
+            let order = anchor.strip_prefix("chapter-")?.parse().ok();
+            Some(Chapter {
+                name: div
+                    .select(&heading)
+                    .next()
+                    .map(text)
+                    .unwrap_or_else(|| anchor.to_string()),
+                link: format!("{}#{}", page, anchor),
+                volume: volume.clone(),
+                published: None,
+                words: None,
+                // the works of a series each count from 1
+                order: order.filter(|_| volume.is_none()),
+            })
+        })
+        .collect();
+    if chapters.is_empty() {
+        chapters.push(Chapter {
+            name: selectors::required(doc.root_element(), "ao3.title", "title")?,
+            link: page,
+            volume,
+            published: None,
+            words: None,
+            order: None,
+        });
+    }
+    // the statistics only date the first chapter and the latest
+    chapters[0].published = stat(doc, "published").as_deref().and_then(date);
+    if chapters.len() > 1 {
+        let last = chapters.len() - 1;
+        chapters[last].published = stat(doc, "status").as_deref().and_then(date);
+    }
+    Ok(chapters)
+}
+
+/// Whether the work on the page `doc` is finished: all of its chapters are
+/// posted, out of a number the author gave.
+fn status(doc: &Html) -> Option<StoryStatus> {
+    let chapters = stat(doc, "chapters")?;
+    let (posted, planned) = chapters.split_once('/')?;
+    Some(if planned.trim() == posted.trim() {
+        StoryStatus::Completed
+    } else {
+        StoryStatus::Ongoing
+    })
+}
+
+impl Ao3 {
+    fn parse_work(&self, doc: &Html) -> Result<Story> {
+        let root = doc.root_element();
+        let language = selector("dd.language[lang]")?;
+        Ok(Story {
+            url: work_id(doc)
+                .map(|id| format!("{}/works/{}", HOST, id))
+                .unwrap_or_default(),
+            title: selectors::required(root, "ao3.title", "title")?,
+            author: selectors::values(root, "ao3.author").join(", "),
+            description: selectors::value(root, "ao3.description").unwrap_or_default(),
+            cover: String::new(),
+            tags: selectors::values(root, "ao3.tags"),
+            chapters: work_chapters(doc, None)?,
+            words: stat(doc, "words").and_then(|words| words.replace(',', "").parse().ok()),
+            status: status(doc),
+            pages: Vec::new(),
+            language: doc
+                .select(&language)
+                .next()
+                .and_then(|dd| dd.value().attr("lang"))
+                .map(str::to_string),
+            warnings: selectors::values(root, "ao3.warnings"),
+        })
+    }
+
+    /// A series, whose works are read from their own pages as the
+    /// [`toc_pages`](SiteScraper::toc_pages).
+    fn parse_series(&self, doc: &Html) -> Result<Story> {
+        let root = doc.root_element();
+        Ok(Story {
+            url: String::new(),
+            title: selectors::required(root, "ao3.series-title", "series title")?,
+            author: selectors::values(root, "ao3.series-author").join(", "),
+            description: selectors::value(root, "ao3.series-description").unwrap_or_default(),
+            cover: String::new(),
+            tags: Vec::new(),
+            chapters: Vec::new(),
+            words: None,
+            status: None,
+            pages: Vec::new(),
+            language: None,
+            warnings: Vec::new(),
+        })
+    }
+}
+
+impl SiteScraper for Ao3 {
+    fn name(&self) -> &'static str {
+        "Archive of Our Own"
+    }
+
+    fn domains(&self) -> &'static [&'static str] {
+        &["archiveofourown.org", "ao3.org"]
+    }
+
+    /// Works (`/works/<id>`, also within collections and from any of their
+    /// chapters) are read from the page with every chapter; series
+    /// (`/series/<id>`) from their list of works.
+    fn story_url(&self, url: &Url) -> Result<Url> {
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(Iterator::collect)
+            .unwrap_or_default();
+        let work = segments
+            .iter()
+            .position(|segment| *segment == "works")
+            .and_then(|i| segments.get(i + 1))
+            .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()));
+
+        match (segments.as_slice(), work) {
+            (["series", id, ..], _) if id.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(Url::parse(&format!("{}/series/{}", HOST, id))?)
+            }
+            (_, Some(id)) => Ok(Url::parse(&full_work_url(id))?),
+            _ => Err(ScrapeError::NotAStory {
+                url: url.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    fn parse_story(&self, html: &str) -> Result<Story> {
+        let doc = Html::parse_document(html);
+        if selectors::select_first(doc.root_element(), "ao3.works").is_some() {
+            return self.parse_series(&doc);
+        }
+        self.parse_work(&doc)
+    }
+
+    /// The works of a series, each on the page with all its chapters.
+    fn toc_pages(&self, html: &str, url: &Url) -> Vec<Url> {
+        let doc = Html::parse_document(html);
+        selectors::select_all(doc.root_element(), "ao3.works")
+            .into_iter()
+            .filter_map(|a| url.join(a.value().attr("href")?).ok())
+            .filter_map(|work| self.story_url(&work).ok())
+            .collect()
+    }
+
+    /// The chapters of one of a series' works, filed under its title.
+    fn parse_toc_page(&self, html: &str) -> Result<Vec<Chapter>> {
+        let doc = Html::parse_document(html);
+        let title = selectors::required(doc.root_element(), "ao3.title", "title")?;
+        work_chapters(&doc, Some(title))
+    }
+
+    /// The work and the number of the chapter in it, e.g. `123/4`, or of
+    /// the chapter's own page.
+    fn chapter_id(&self, url: &Url) -> Option<String> {
+        let segments: Vec<_> = url.path_segments()?.collect();
+        match segments.as_slice() {
+            [.., "chapters", id] => Some(id.to_string()),
+            [.., "works", id] => Some(
+                match url.fragment().and_then(|f| f.strip_prefix("chapter-")) {
+                    Some(nr) => format!("{}/{}", id, nr),
+                    None => id.to_string(),
+                },
+            ),
+            _ => None,
+        }
+    }
+
+    fn content_selector(&self) -> String {
+        selectors::joined("ao3.content")
+    }
+
+    fn unavailable(&self, text: &str) -> Option<Unavailable> {
+        const LOCKED: [&str; 2] = [
+            "only available to registered users of the archive",
+            "this work is part of an ongoing challenge and will be revealed soon",
+        ];
+        const DELETED: [&str; 2] = [
+            "the page you were looking for doesn't exist",
+            "this work has been deleted",
+        ];
+        if LOCKED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Locked)
+        } else if DELETED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Deleted)
+        } else {
+            None
+        }
+    }
+
+    fn author_note_selector(&self) -> String {
+        selectors::joined("ao3.author-notes")
+    }
+
+    /// The chapters' own headings, those only screen readers see, and the
+    /// links to the notes at the end.
+    fn junk_selector(&self) -> Option<String> {
+        Some(selectors::joined("ao3.junk"))
+    }
+}
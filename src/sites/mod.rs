@@ -0,0 +1,385 @@
+//! The sites stories can be downloaded from.
+//!
+//! Each site knows where its story page is, how to read the metadata and
+//! chapter list from it, and where the chapter text sits on a chapter page.
+//! Everything else (fetching, cleaning, writing) is shared.
+
+mod ao3;
+mod royalroad;
+mod scribblehub;
+
+use ego_tree::NodeId;
+use eyre::{Result, WrapErr};
+use reqwest::Url;
+use scraper::Html;
+use std::collections::HashSet;
+
+use crate::{selector, Chapter, Reception, ScrapeError, Story};
+
+pub use ao3::Ao3;
+pub use royalroad::{ReadingList, RoyalRoad, SearchResult, SearchSort};
+pub use scribblehub::ScribbleHub;
+
+pub trait SiteScraper: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The domains the site is on, subdomains included.
+    fn domains(&self) -> &'static [&'static str];
+
+    /// Whether `url` belongs to this site.
+    fn handles(&self, url: &Url) -> bool {
+        self.domains().iter().any(|domain| on_domain(url, domain))
+    }
+
+    /// The story page for `url`, which may also be one of its chapters.
+    fn story_url(&self, url: &Url) -> Result<Url>;
+
+    /// Reads the metadata, and as much of the chapter list as it holds,
+    /// from the story page.
+    fn parse_story(&self, html: &str) -> Result<Story>;
+
+    /// Further pages of the chapter list, for sites that paginate it.
+    fn toc_pages(&self, _html: &str, _url: &Url) -> Vec<Url> {
+        Vec::new()
+    }
+
+    /// Reads the chapters from one of the [`toc_pages`](Self::toc_pages).
+    fn parse_toc_page(&self, _html: &str) -> Result<Vec<Chapter>> {
+        Ok(Vec::new())
+    }
+
+    /// Puts the chapters into reading order once every page is read.
+    fn order_chapters(&self, _chapters: &mut Vec<Chapter>) {}
+
+    /// Whether the chapter list starts at the newest chapter, so that its
+    /// first page holds the last ones.
+    fn lists_newest_first(&self) -> bool {
+        false
+    }
+
+    /// What tells the chapter at `url` apart from the site's others, however
+    /// the link is spelled. Both sites number their chapters in the path,
+    /// e.g. `/fiction/123/title/chapter/4567/name`.
+    fn chapter_id(&self, url: &Url) -> Option<String> {
+        let mut segments = url.path_segments()?;
+        segments.find(|segment| *segment == "chapter")?;
+        segments
+            .next()
+            .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+            .map(str::to_string)
+    }
+
+    /// The feed of the latest chapters of the story at `story`, for sites
+    /// that have one.
+    fn feed_url(&self, _story: &Url) -> Option<Url> {
+        None
+    }
+
+    /// The scores, followers and first `reviews` reviews on the story page,
+    /// for sites that show them.
+    fn parse_reception(&self, _html: &str, _reviews: usize) -> Option<Reception> {
+        None
+    }
+
+    /// Selects the chapter text on a chapter page.
+    fn content_selector(&self) -> String;
+
+    /// Why a chapter page without any chapter text has none, going by its
+    /// `text` in lower case, if the page says.
+    fn unavailable(&self, _text: &str) -> Option<Unavailable> {
+        None
+    }
+
+    /// Selects the author's notes around the chapter text.
+    fn author_note_selector(&self) -> String;
+
+    /// Selects what sits in the chapter text without being part of it, for
+    /// sites that put anything there.
+    fn junk_selector(&self) -> Option<String> {
+        None
+    }
+}
+
+pub static SITES: &[&dyn SiteScraper] = &[&RoyalRoad, &ScribbleHub, &Ao3];
+
+/// Why a chapter page holds no chapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// The author deleted or unpublished the chapter.
+    Deleted,
+    /// The chapter is only open to some readers.
+    Locked,
+}
+
+/// Picks the site for `url` by its host.
+pub fn site_for(url: &Url) -> Result<&'static dyn SiteScraper> {
+    SITES
+        .iter()
+        .copied()
+        .find(|site| site.handles(url))
+        .ok_or_else(|| {
+            let names: Vec<_> = SITES.iter().map(|site| site.name()).collect();
+            ScrapeError::UnsupportedSite {
+                url: url.to_string(),
+                sites: names.join(", "),
+            }
+            .into()
+        })
+}
+
+/// The story page for `input`: the URL of a story or one of its chapters on
+/// any of the sites, with or without `https://`, or just the number of a
+/// Royal Road fiction.
+pub fn story_url(input: &str) -> Result<Url> {
+    let input = input.trim();
+    let url = if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) {
+        format!("https://www.royalroad.com/fiction/{}", input)
+    } else if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{}", input)
+    };
+    let url = Url::parse(&url).wrap_err_with(|| format!("invalid url {:?}", input))?;
+    site_for(&url)?.story_url(&url)
+}
+
+/// `url` as the story page it leads to, if it does, for comparing.
+fn normalize(url: &str) -> String {
+    let url = match story_url(url) {
+        Ok(story) => story.to_string(),
+        Err(_) => url.to_string(),
+    };
+    url.trim_end_matches('/').to_string()
+}
+
+/// Whether `a` and `b` lead to the same story. Either may be written any way
+/// `--url` takes it, and leave out the story's title after its id.
+pub fn same_story(a: &str, b: &str) -> bool {
+    let (a, b) = (normalize(a), normalize(b));
+    let below = |url: &str, key: &str| {
+        url.strip_prefix(key)
+            .is_some_and(|rest| rest.starts_with('/'))
+    };
+    a == b || below(&a, &b) || below(&b, &a)
+}
+
+/// Drops the chapters `site` lists twice in `chapters`, the list of the
+/// story at `story`, and sorts them by the site's numbering where it has
+/// one for each, warning about both and about gaps in the numbering.
+pub(crate) fn check_chapters(site: &dyn SiteScraper, story: &Url, chapters: &mut Vec<Chapter>) {
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    chapters.retain(|chapter| {
+        // chapters sharing a page by its anchors aren't the same chapter
+        let key = story
+            .join(&chapter.link)
+            .ok()
+            .and_then(|url| {
+                let id = site.chapter_id(&url)?;
+                Some(match url.fragment() {
+                    Some(anchor) => format!("{}#{}", id, anchor),
+                    None => id,
+                })
+            })
+            .unwrap_or_else(|| chapter.link.trim_end_matches('/').to_string());
+        let first = seen.insert(key);
+        if !first {
+            duplicates.push(format!("{:?}", chapter.name));
+        }
+        first
+    });
+    if !duplicates.is_empty() {
+        log::warn!(
+            "the chapter list has {} chapter(s) twice, keeping where each is first: {}",
+            duplicates.len(),
+            duplicates.join(", ")
+        );
+    }
+
+    let Some(mut orders) = chapters
+        .iter()
+        .map(|chapter| chapter.order)
+        .collect::<Option<Vec<i64>>>()
+    else {
+        return;
+    };
+    if let Some(i) = (1..chapters.len()).find(|&i| orders[i - 1] > orders[i]) {
+        log::warn!(
+            "the chapter list isn't in the site's order ({:?}, number {}, is listed before {:?}, number {}), sorting it",
+            chapters[i - 1].name,
+            orders[i - 1],
+            chapters[i].name,
+            orders[i]
+        );
+        chapters.sort_by_key(|chapter| chapter.order);
+        orders.sort();
+    }
+    for (i, pair) in orders.windows(2).enumerate() {
+        if pair[0] == pair[1] {
+            log::warn!(
+                "{:?} and {:?} are both number {} in the chapter list",
+                chapters[i].name,
+                chapters[i + 1].name,
+                pair[0]
+            );
+        } else if pair[1] > pair[0] + 1 {
+            log::warn!(
+                "the chapter list skips from number {} ({:?}) to {} ({:?}); chapters may be missing from it",
+                pair[0],
+                chapters[i].name,
+                pair[1],
+                chapters[i + 1].name
+            );
+        }
+    }
+}
+
+/// The chapter at `url`, on whichever site, e.g. `Royal Road/4567`.
+pub(crate) fn chapter_key(url: &Url) -> Option<String> {
+    let site = site_for(url).ok()?;
+    Some(format!("{}/{}", site.name(), site.chapter_id(url)?))
+}
+
+/// A selector matching the chapter text of any site.
+pub(crate) fn content_selector() -> String {
+    SITES
+        .iter()
+        .map(|site| site.content_selector())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Why the chapter page `doc` has no chapter text, according to any site.
+pub(crate) fn unavailable(doc: &Html) -> Option<Unavailable> {
+    let text = doc.root_element().text().collect::<String>().to_lowercase();
+    SITES.iter().find_map(|site| site.unavailable(&text))
+}
+
+/// A selector matching the author's notes of any site.
+pub(crate) fn author_note_selector() -> String {
+    SITES
+        .iter()
+        .map(|site| site.author_note_selector())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Removes what any site puts in the chapter text below `content` without
+/// it being part of it.
+pub(crate) fn strip_junk(doc: &mut Html, content: NodeId) {
+    let junk: Vec<String> = SITES
+        .iter()
+        .filter_map(|site| site.junk_selector())
+        .collect();
+    let Ok(junk) = selector(&junk.join(", ")) else {
+        return;
+    };
+    for id in crate::clean::find(doc, content, |el| junk.matches(&el)) {
+        doc.tree.get_mut(id).unwrap().detach();
+    }
+}
+
+/// A form a site shows in place of a story marked as mature, for readers to
+/// confirm they are adults with.
+#[derive(Debug, Clone)]
+pub(crate) struct AgeCheck {
+    /// Where the form is sent.
+    pub action: Url,
+    pub post: bool,
+    pub fields: Vec<(String, String)>,
+}
+
+/// What an age check's form says, in lower case.
+const AGE_CHECK_PHRASES: &[&str] = &[
+    "18 or older",
+    "over 18",
+    "18+",
+    "18 years",
+    "mature content",
+    "adult content",
+    "i am an adult",
+];
+
+/// The age check on the page `html` at `url`, if it shows one, with its
+/// form filled in to confirm it.
+pub(crate) fn age_check(html: &str, url: &Url) -> Option<AgeCheck> {
+    let doc = Html::parse_document(html);
+    let forms = selector("form").ok()?;
+    let inputs = selector("input[name], button[name]").ok()?;
+    doc.select(&forms).find_map(|form| {
+        let mut text = form.text().collect::<String>();
+        for input in form.select(&inputs) {
+            text.push(' ');
+            text.push_str(input.value().attr("value").unwrap_or_default());
+        }
+        let text = text.to_lowercase();
+        if !AGE_CHECK_PHRASES.iter().any(|phrase| text.contains(phrase)) {
+            return None;
+        }
+
+        let mut fields = Vec::new();
+        let mut submitted = false;
+        for input in form.select(&inputs) {
+            let el = input.value();
+            let name = el.attr("name").unwrap_or_default().to_string();
+            let kind = el.attr("type").unwrap_or("text").to_ascii_lowercase();
+            let value = match (el.name(), kind.as_str()) {
+                // the box that says the reader is old enough
+                ("input", "checkbox") => el.attr("value").unwrap_or("on"),
+                ("input", "radio") if el.attr("checked").is_none() => continue,
+                // only the button that is pressed is sent
+                ("button", _) | ("input", "submit") if submitted => continue,
+                ("button", _) | ("input", "submit") => {
+                    submitted = true;
+                    el.attr("value").unwrap_or_default()
+                }
+                _ => el.attr("value").unwrap_or_default(),
+            };
+            fields.push((name, value.to_string()));
+        }
+        let action = form.value().attr("action").unwrap_or_default();
+        Some(AgeCheck {
+            action: url.join(action).ok()?,
+            post: form
+                .value()
+                .attr("method")
+                .is_some_and(|method| method.eq_ignore_ascii_case("post")),
+            fields,
+        })
+    })
+}
+
+/// The language the page `doc` is marked as, e.g. `en-US`.
+pub(crate) fn page_language(doc: &Html) -> Option<String> {
+    doc.root_element()
+        .value()
+        .attr("lang")
+        .map(str::trim)
+        .filter(|lang| !lang.is_empty())
+        .map(str::to_string)
+}
+
+/// The unix timestamp of a UTC date and time.
+pub(crate) fn unix_time(year: i64, month: i64, day: i64, hour: i64, minute: i64) -> Option<u64> {
+    // a civil date to days since 1970-01-01, after Howard Hinnant's
+    // `days_from_civil`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60).ok()
+}
+
+/// Whether `url`'s host is `domain` or one of its subdomains.
+fn on_domain(url: &Url, domain: &str) -> bool {
+    url.host_str().is_some_and(|host| {
+        host == domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
@@ -0,0 +1,720 @@
+use eyre::{eyre, Result};
+use regex::Regex;
+use reqwest::Url;
+use scraper::Html;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use super::{on_domain, page_language, SiteScraper, Unavailable};
+use crate::{
+    selector, selectors, Chapter, Client, Reception, Review, ScrapeError, Story, StoryStatus,
+};
+
+pub struct RoyalRoad;
+
+#[derive(Deserialize)]
+struct Volume {
+    id: u64,
+    title: String,
+}
+
+/// The titles of the story's volumes by id, from the `window.volumes` list
+/// the fiction page's script sets up.
+fn volumes(html: &str) -> HashMap<u64, String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"(?s)window\.volumes\s*=\s*(\[.*?\]);").unwrap());
+
+    let Some(list) = re.captures(html) else {
+        return HashMap::new();
+    };
+    match serde_json::from_str::<Vec<Volume>>(&list[1]) {
+        Ok(volumes) => volumes
+            .into_iter()
+            .map(|volume| (volume.id, volume.title))
+            .collect(),
+        Err(err) => {
+            log::warn!("could not read the story's volumes: {}", err);
+            HashMap::new()
+        }
+    }
+}
+
+/// A chapter in the `window.chapters` list of the fiction page.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ScriptChapter {
+    title: String,
+    url: String,
+    #[serde(default)]
+    volume_id: Option<u64>,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    order: Option<i64>,
+}
+
+/// The chapters in the `window.chapters` list the fiction page's script
+/// sets up, which has every chapter even where the table doesn't show them
+/// all.
+fn script_chapters(html: &str) -> Option<Vec<ScriptChapter>> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"(?s)window\.chapters\s*=\s*(\[.*?\]);").unwrap());
+
+    let list = re.captures(html)?;
+    match serde_json::from_str::<Vec<ScriptChapter>>(&list[1]) {
+        Ok(mut chapters) => {
+            chapters.sort_by_key(|chapter| chapter.order);
+            Some(chapters)
+        }
+        Err(err) => {
+            log::warn!("could not read the story's chapter data: {}", err);
+            None
+        }
+    }
+}
+
+/// How search results are ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchSort {
+    /// Best match first
+    #[default]
+    Relevance,
+    Popularity,
+    Rating,
+    Followers,
+    Views,
+    /// Longest first
+    Length,
+    /// Most recently updated first
+    LastUpdate,
+    /// Newest first
+    ReleaseDate,
+    Title,
+}
+
+impl SearchSort {
+    /// The `orderBy` of the search page.
+    fn order_by(self) -> &'static str {
+        match self {
+            SearchSort::Relevance => "relevance",
+            SearchSort::Popularity => "popularity",
+            SearchSort::Rating => "rating",
+            SearchSort::Followers => "followers",
+            SearchSort::Views => "views",
+            SearchSort::Length => "length",
+            SearchSort::LastUpdate => "last_update",
+            SearchSort::ReleaseDate => "release_date",
+            SearchSort::Title => "title",
+        }
+    }
+}
+
+/// One of the lists of stories a Royal Road account keeps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReadingList {
+    /// The stories the account follows
+    #[default]
+    Follows,
+    /// The stories the account saved to read later
+    ReadLater,
+}
+
+impl ReadingList {
+    /// The list's page.
+    fn path(self) -> &'static str {
+        match self {
+            ReadingList::Follows => "/my/follows",
+            ReadingList::ReadLater => "/my/readlater",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ReadingList::Follows => "Follow List",
+            ReadingList::ReadLater => "Read Later list",
+        }
+    }
+}
+
+/// How many pages of a reading list or an author's fictions are read at
+/// most, in case the site keeps showing the last one.
+const MAX_LIST_PAGES: usize = 100;
+
+/// A story found by [`RoyalRoad::search`], on a [`ReadingList`] or on an
+/// author's profile.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    /// The fiction's number, as in `/fiction/36049/...`.
+    pub id: u64,
+    pub title: String,
+    pub url: String,
+    /// The overall rating, out of 5.
+    pub rating: Option<f32>,
+    pub chapters: Option<usize>,
+    pub followers: Option<usize>,
+}
+
+/// Adds the stories of a list's `page` that aren't in `stories` yet, and
+/// whether there were any. Past the last page the site shows an empty list,
+/// or the last page again.
+fn add_page(stories: &mut Vec<SearchResult>, page: Vec<SearchResult>) -> bool {
+    let before = stories.len();
+    for story in page {
+        if !stories.iter().any(|known| known.id == story.id) {
+            stories.push(story);
+        }
+    }
+    stories.len() > before
+}
+
+/// The page listing the fictions of the author whose profile is `input`:
+/// its URL, with or without `https://`, or just its number.
+fn author_fictions_url(input: &str) -> Result<Url> {
+    let input = input.trim();
+    let url = if !input.is_empty() && input.chars().all(|c| c.is_ascii_digit()) {
+        format!("https://www.royalroad.com/profile/{}", input)
+    } else if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("https://{}", input)
+    };
+    let not_profile = || {
+        eyre!(
+            "{:?} is not a Royal Road author's profile, e.g. https://www.royalroad.com/profile/12345",
+            input
+        )
+    };
+    let url = Url::parse(&url).map_err(|_| not_profile())?;
+    if !on_domain(&url, "royalroad.com") {
+        return Err(not_profile());
+    }
+    let mut segments = url.path_segments().ok_or_else(not_profile)?;
+    let id: u64 = match (segments.next(), segments.next()) {
+        (Some("profile"), Some(id)) => id.parse().map_err(|_| not_profile())?,
+        _ => return Err(not_profile()),
+    };
+    Ok(Url::parse(&format!(
+        "https://www.royalroad.com/profile/{}/fictions",
+        id
+    ))?)
+}
+
+/// A fiction's number in its link.
+fn fiction_id(link: &str) -> Option<u64> {
+    let mut segments = link.split('/').skip_while(|segment| *segment != "fiction");
+    segments.nth(1)?.parse().ok()
+}
+
+/// The number in a statistic such as `1,234 Chapters`, if it counts
+/// `what` (singular).
+fn stat(text: &str, what: &str) -> Option<usize> {
+    let (number, name) = text.trim().split_once(' ')?;
+    let name = name.trim();
+    if !name
+        .strip_suffix(['s', 'S'])
+        .unwrap_or(name)
+        .eq_ignore_ascii_case(what)
+    {
+        return None;
+    }
+    number.replace(',', "").parse().ok()
+}
+
+/// Royal Road counts a story's length in pages of this many words.
+const WORDS_PER_PAGE: u64 = 275;
+
+/// The story's length in words, from the page count in its statistics.
+fn words(doc: &Html) -> Option<u64> {
+    let stats: Vec<String> = doc
+        .select(&selector("div.stats-content li").ok()?)
+        .map(|li| li.text().collect::<String>().trim().to_string())
+        .collect();
+    let pages = stats
+        .iter()
+        .position(|stat| {
+            stat.trim_end_matches([' ', ':'])
+                .eq_ignore_ascii_case("pages")
+        })
+        .and_then(|i| stats.get(i + 1))?;
+    let pages: u64 = pages.replace(',', "").parse().ok()?;
+    Some(pages * WORDS_PER_PAGE)
+}
+
+/// The score in a star's `data-content` or `aria-label`, such as
+/// `4.63 / 5` or `4.5 stars`.
+fn score(text: &str) -> Option<f32> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| Regex::new(r"(\d+(?:\.\d+)?)\s*(?:/\s*5|stars?)").unwrap());
+    re.captures(text)?[1].parse().ok()
+}
+
+/// The first score given by `element` or an element inside it.
+fn element_score(element: scraper::ElementRef) -> Option<f32> {
+    std::iter::once(element)
+        .chain(element.descendants().filter_map(scraper::ElementRef::wrap))
+        .flat_map(|el| {
+            [
+                el.value().attr("data-content"),
+                el.value().attr("aria-label"),
+            ]
+        })
+        .flatten()
+        .find_map(score)
+}
+
+/// The scores and followers in the story's statistics, which list each
+/// name followed by its value.
+fn statistics(doc: &Html) -> (Vec<(String, f32)>, Option<usize>) {
+    let Ok(li) = selector("div.stats-content li") else {
+        return (Vec::new(), None);
+    };
+    let items: Vec<_> = doc.select(&li).collect();
+    let mut scores = Vec::new();
+    let mut followers = None;
+    for pair in items.windows(2) {
+        let name = pair[0].text().collect::<String>();
+        let name = name.trim().trim_end_matches([' ', ':']);
+        if let Some(kind) = name
+            .strip_suffix(" Score")
+            .or_else(|| name.strip_suffix(" score"))
+        {
+            if let Some(score) = element_score(pair[1]) {
+                scores.push((kind.trim().to_string(), score));
+            }
+        } else if name.eq_ignore_ascii_case("followers") {
+            let value = pair[1].text().collect::<String>();
+            followers = value.trim().replace(',', "").parse().ok();
+        }
+    }
+    (scores, followers)
+}
+
+/// The first `max` reviews on the fiction page.
+fn reviews(doc: &Html, max: usize) -> Result<Vec<Review>> {
+    let title = selector(".review-title h4, .review-header h4")?;
+    let author = selector(r#".review-meta a[href*="/profile/"]"#)?;
+    let content = selector(".review-content")?;
+    let paragraph = selector("p")?;
+
+    let mut reviews = Vec::new();
+    for review in doc.select(&selector("div.review")?).take(max) {
+        let text = |sel| {
+            review
+                .select(sel)
+                .next()
+                .map(|el| el.text().collect::<String>().trim().to_string())
+                .unwrap_or_default()
+        };
+        let Some(content) = review.select(&content).next() else {
+            continue;
+        };
+        let mut paragraphs: Vec<String> = content
+            .select(&paragraph)
+            .map(|p| p.text().collect::<String>())
+            .collect();
+        if paragraphs.is_empty() {
+            paragraphs = content.text().map(str::to_string).collect();
+        }
+        let paragraphs = paragraphs
+            .iter()
+            .map(|p| p.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|p| !p.is_empty())
+            .collect();
+        reviews.push(Review {
+            title: text(&title),
+            author: text(&author),
+            score: element_score(review),
+            paragraphs,
+        });
+    }
+    Ok(reviews)
+}
+
+/// The story's status among the labels under its title, which also say
+/// e.g. whether it is original or fan fiction.
+fn status(doc: &Html) -> Option<StoryStatus> {
+    doc.select(&selector("div.fiction-info span.label").ok()?)
+        .find_map(|label| StoryStatus::parse(&label.text().collect::<String>()))
+}
+
+/// The fiction's pages that aren't chapters, such as a glossary or a map,
+/// linked from the fiction page as `/fiction/<id>/<title>/page/<id>/<name>`.
+fn pages(doc: &Html) -> Vec<Chapter> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r"^(https://www\.royalroad\.com)?/fiction/\d+/[^/]*/page/\d+").unwrap()
+    });
+    let Ok(links) = selector("a[href]") else {
+        return Vec::new();
+    };
+    let mut pages: Vec<Chapter> = Vec::new();
+    for link in doc.select(&links) {
+        let href = link.value().attr("href").unwrap_or_default();
+        let name = link.text().collect::<String>().trim().to_string();
+        if !re.is_match(href) || name.is_empty() || pages.iter().any(|page| page.link == href) {
+            continue;
+        }
+        pages.push(Chapter {
+            name,
+            link: href.to_string(),
+            volume: None,
+            published: None,
+            words: None,
+            order: None,
+        });
+    }
+    pages
+}
+
+impl RoyalRoad {
+    /// Logs `client` in, so that advance chapters and stories only open to
+    /// the account can be downloaded with it.
+    pub async fn login(&self, client: &Client, email: &str, password: &str) -> Result<()> {
+        let url = Url::parse("https://www.royalroad.com/account/login")?;
+        let page = client.text(client.get(url.clone()).await?).await?;
+        let token = Html::parse_document(&page)
+            .select(&selector(r#"input[name="__RequestVerificationToken"]"#)?)
+            .next()
+            .and_then(|input| input.value().attr("value"))
+            .map(str::to_string)
+            .ok_or_else(|| eyre!("could not find the Royal Road login form"))?;
+
+        let resp = client
+            .post_form(
+                url,
+                &[
+                    ("__RequestVerificationToken", &token),
+                    ("Email", email),
+                    ("Password", password),
+                ],
+            )
+            .await?;
+
+        // a failed login shows the form again, a successful one redirects
+        let landed = resp.url().clone();
+        let page = client.text(resp).await?;
+        if landed.path().starts_with("/account/login") || page.contains("validation-summary-errors")
+        {
+            return Err(eyre!(
+                "could not log in to Royal Road as {}, check the email and password",
+                email
+            ));
+        }
+
+        log::info!("logged in to Royal Road as {}", email);
+        Ok(())
+    }
+
+    /// Every story on the account's `list`, in the list's order. `client`
+    /// has to be logged in, with [`RoyalRoad::login`] or the cookies of a
+    /// browser session.
+    pub async fn reading_list(
+        &self,
+        client: &Client,
+        list: ReadingList,
+    ) -> Result<Vec<SearchResult>> {
+        let mut stories: Vec<SearchResult> = Vec::new();
+        for page in 1..=MAX_LIST_PAGES {
+            let mut url = Url::parse("https://www.royalroad.com/")?.join(list.path())?;
+            url.query_pairs_mut().append_pair("page", &page.to_string());
+            let resp = client.get(url).await?;
+            // without a session the site sends the login form instead
+            if resp.url().path().starts_with("/account/login") {
+                return Err(eyre!(
+                    "the {} is only shown to its account; log in with --username, --cookies-file or --browser-cookies",
+                    list.name()
+                ));
+            }
+            let page = client.text(resp).await?;
+            if !add_page(&mut stories, self.parse_search(&page)?) {
+                break;
+            }
+        }
+        Ok(stories)
+    }
+
+    /// Every story of the author whose profile is `profile`, a URL like
+    /// `https://www.royalroad.com/profile/12345` or just its number, in the
+    /// order the profile lists them.
+    pub async fn author_fictions(
+        &self,
+        client: &Client,
+        profile: &str,
+    ) -> Result<Vec<SearchResult>> {
+        let fictions = author_fictions_url(profile)?;
+        let mut stories: Vec<SearchResult> = Vec::new();
+        for page in 1..=MAX_LIST_PAGES {
+            let mut url = fictions.clone();
+            url.query_pairs_mut().append_pair("page", &page.to_string());
+            let page = client.text(client.get(url).await?).await?;
+            if !add_page(&mut stories, self.parse_search(&page)?) {
+                break;
+            }
+        }
+        Ok(stories)
+    }
+
+    /// The stories whose title matches `query`, ordered by `sort`, from
+    /// page `page` (from 1) of the results.
+    pub async fn search(
+        &self,
+        client: &Client,
+        query: &str,
+        sort: SearchSort,
+        page: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let mut url = Url::parse("https://www.royalroad.com/fictions/search")?;
+        url.query_pairs_mut()
+            .append_pair("title", query)
+            .append_pair("orderBy", sort.order_by())
+            .append_pair("page", &page.to_string());
+        let page = client.text(client.get(url).await?).await?;
+        self.parse_search(&page)
+    }
+
+    /// Reads the stories on a page of search results.
+    pub fn parse_search(&self, html: &str) -> Result<Vec<SearchResult>> {
+        let doc = Html::parse_document(html);
+        let title = selector("h2.fiction-title a")?;
+        let star = selector("div.stats span.star[title]")?;
+        let stats = selector("div.stats span")?;
+        let base = Url::parse("https://www.royalroad.com/")?;
+
+        let mut results = Vec::new();
+        for item in doc.select(&selector("div.fiction-list-item")?) {
+            let Some(link) = item.select(&title).next() else {
+                continue;
+            };
+            let Some(href) = link.value().attr("href") else {
+                continue;
+            };
+            let Some(id) = fiction_id(href) else {
+                continue;
+            };
+            let stats: Vec<String> = item
+                .select(&stats)
+                .map(|span| span.text().collect())
+                .collect();
+            let find = |what| stats.iter().find_map(|text| stat(text, what));
+
+            results.push(SearchResult {
+                id,
+                title: link.text().collect::<String>().trim().to_string(),
+                url: base.join(href)?.to_string(),
+                rating: item
+                    .select(&star)
+                    .next()
+                    .and_then(|star| star.value().attr("title")?.parse().ok()),
+                chapters: find("chapter"),
+                followers: find("follower"),
+            });
+        }
+        Ok(results)
+    }
+}
+
+impl SiteScraper for RoyalRoad {
+    fn name(&self) -> &'static str {
+        "Royal Road"
+    }
+
+    fn domains(&self) -> &'static [&'static str] {
+        &["royalroad.com"]
+    }
+
+    fn story_url(&self, url: &Url) -> Result<Url> {
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+
+        // `/fiction/123/title`, and below it the chapters; the title may be
+        // left out, and the site redirects to the page with it
+        match segments.as_slice() {
+            ["fiction" | "fictions", id, rest @ ..] if id.chars().all(|c| c.is_ascii_digit()) => {
+                let slug = match rest {
+                    [slug, ..] if *slug != "chapter" => format!("/{}", slug),
+                    _ => String::new(),
+                };
+                Ok(Url::parse(&format!(
+                    "https://www.royalroad.com/fiction/{}{}",
+                    id, slug
+                ))?)
+            }
+            _ => Err(ScrapeError::NotAStory {
+                url: url.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    fn feed_url(&self, story: &Url) -> Option<Url> {
+        let mut segments = story.path_segments()?;
+        segments.find(|segment| *segment == "fiction")?;
+        let id = segments.next()?;
+        Url::parse(&format!(
+            "https://www.royalroad.com/fiction/syndication/{}",
+            id
+        ))
+        .ok()
+    }
+
+    fn parse_story(&self, html: &str) -> Result<Story> {
+        let doc = Html::parse_document(html);
+
+        let root = doc.root_element();
+
+        // stories without a cover of their own have none or a placeholder
+        let cover = selectors::value(root, "royalroad.cover").unwrap_or_default();
+        let author = selectors::required(root, "royalroad.author", "author")?;
+        let title = selectors::required(root, "royalroad.title", "title")?;
+        let description = selectors::required(root, "royalroad.description", "description")?;
+        let url = selectors::value(root, "royalroad.url").unwrap_or_default();
+        let tags = selectors::values(root, "royalroad.tags");
+
+        let volumes = volumes(html);
+        let volume = |id: u64| {
+            volumes
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| format!("Volume {}", id))
+        };
+        let script = script_chapters(html);
+        let table = selectors::select_first(root, "royalroad.chapters");
+        if table.is_none() && script.is_none() {
+            return Err(
+                ScrapeError::named_selector_changed("chapter list", "royalroad.chapters").into(),
+            );
+        }
+
+        let link = selector("td:first-child > a")?;
+        let time = selector("time[unixtime]")?;
+        let mut chapters = Vec::new();
+
+        let rows = match table {
+            Some(table) => selectors::select_all(table, "royalroad.chapter-rows"),
+            None => Vec::new(),
+        };
+        for row in rows {
+            let Some(chap) = row.select(&link).next() else {
+                continue;
+            };
+            let Some(link) = chap.attr("href") else {
+                continue;
+            };
+            let name = chap.text().collect::<String>();
+            let name = name.trim();
+            let volume = row
+                .value()
+                .attr("data-volume-id")
+                .and_then(|id| id.parse::<u64>().ok())
+                .map(volume);
+
+            let published = row
+                .select(&time)
+                .next()
+                .and_then(|time| time.value().attr("unixtime"))
+                .and_then(|secs| secs.parse().ok());
+
+            chapters.push(Chapter {
+                name: name.to_string(),
+                link: link.to_string(),
+                volume,
+                published,
+                words: None,
+                order: row
+                    .value()
+                    .attr("data-order")
+                    .and_then(|order| order.trim().parse().ok()),
+            });
+        }
+
+        // the table may leave chapters out, which the script's list has
+        if let Some(script) = script.filter(|script| script.len() > chapters.len()) {
+            log::info!(
+                "the chapter table lists {} of {} chapters, reading them from the page's script",
+                chapters.len(),
+                script.len()
+            );
+            chapters = script
+                .into_iter()
+                .map(|chapter| Chapter {
+                    name: chapter.title.trim().to_string(),
+                    link: chapter.url,
+                    volume: chapter.volume_id.map(volume),
+                    published: chapter
+                        .date
+                        .and_then(|date| chrono::DateTime::parse_from_rfc3339(&date).ok())
+                        .and_then(|date| u64::try_from(date.timestamp()).ok()),
+                    words: None,
+                    order: chapter.order,
+                })
+                .collect();
+        }
+
+        Ok(Story {
+            url,
+            title,
+            author,
+            description,
+            cover,
+            tags,
+            chapters,
+            words: words(&doc),
+            status: status(&doc),
+            pages: pages(&doc),
+            language: page_language(&doc),
+            warnings: selectors::values(root, "royalroad.warnings"),
+        })
+    }
+
+    fn parse_reception(&self, html: &str, max: usize) -> Option<Reception> {
+        let doc = Html::parse_document(html);
+        let (scores, followers) = statistics(&doc);
+        let reviews = match reviews(&doc, max) {
+            Ok(reviews) => reviews,
+            Err(err) => {
+                log::warn!("could not read the reviews: {:#}", err);
+                Vec::new()
+            }
+        };
+        Some(Reception {
+            scores,
+            followers,
+            reviews,
+            date: String::new(),
+        })
+    }
+
+    // chapters, and the fiction's other pages
+    fn content_selector(&self) -> String {
+        selectors::joined("royalroad.content")
+    }
+
+    fn unavailable(&self, text: &str) -> Option<Unavailable> {
+        const LOCKED: [&str; 4] = [
+            "this chapter is locked",
+            "must be logged in",
+            "need to be logged in",
+            "only available to",
+        ];
+        const DELETED: [&str; 4] = [
+            "has been deleted",
+            "has been removed",
+            "has been unpublished",
+            "no longer available",
+        ];
+        if LOCKED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Locked)
+        } else if DELETED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Deleted)
+        } else {
+            None
+        }
+    }
+
+    fn author_note_selector(&self) -> String {
+        selectors::joined("royalroad.author-notes")
+    }
+}
@@ -0,0 +1,183 @@
+use eyre::Result;
+use reqwest::Url;
+use scraper::Html;
+
+use super::{page_language, unix_time, SiteScraper, Unavailable};
+use crate::{selector, selectors, Chapter, ScrapeError, Story};
+
+pub struct ScribbleHub;
+
+/// Reads a publish date as the table of contents shows it, e.g.
+/// `Aug 5, 2020 12:34 AM`, into a unix timestamp.
+fn published(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let mut parts = date.split([' ', ',']).filter(|part| !part.is_empty());
+    let month = parts.next()?.to_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? as i64 + 1;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let (hour, minute) = match parts.next().and_then(|time| time.split_once(':')) {
+        Some((hour, minute)) => (hour.parse::<i64>().ok()? % 12, minute.parse::<i64>().ok()?),
+        None => (0, 0),
+    };
+    let hour = match parts.next() {
+        Some(pm) if pm.eq_ignore_ascii_case("pm") => hour + 12,
+        _ => hour,
+    };
+
+    unix_time(year, month, day, hour, minute)
+}
+
+/// Reads the chapters listed on one page of a series' table of contents.
+fn chapters(doc: &Html) -> Result<Vec<Chapter>> {
+    let link = selector("a.toc_a")?;
+    let date = selector("span.fic_date_pub")?;
+    Ok(
+        selectors::select_all(doc.root_element(), "scribblehub.chapters")
+            .into_iter()
+            .filter_map(|li| {
+                let a = li.select(&link).next()?;
+                Some(Chapter {
+                    name: a.text().collect::<String>().trim().to_string(),
+                    link: a.value().attr("href")?.to_string(),
+                    volume: None,
+                    published: li
+                        .select(&date)
+                        .next()
+                        .and_then(|span| span.value().attr("title"))
+                        .and_then(published),
+                    words: None,
+                    order: li
+                        .value()
+                        .attr("order")
+                        .or_else(|| li.value().attr("data-order"))
+                        .and_then(|order| order.trim().parse().ok()),
+                })
+            })
+            .collect(),
+    )
+}
+
+impl SiteScraper for ScribbleHub {
+    fn name(&self) -> &'static str {
+        "Scribble Hub"
+    }
+
+    fn domains(&self) -> &'static [&'static str] {
+        &["scribblehub.com"]
+    }
+
+    /// Chapter pages (`/read/<id>-<slug>/chapter/<nr>/`) link back to the
+    /// series (`/series/<id>/<slug>/`).
+    fn story_url(&self, url: &Url) -> Result<Url> {
+        let segments: Vec<_> = url
+            .path_segments()
+            .map(Iterator::collect)
+            .unwrap_or_default();
+
+        match segments.as_slice() {
+            ["series", id, slug, ..] => Ok(url.join(&format!("/series/{}/{}/", id, slug))?),
+            ["read", id_slug, ..] => {
+                let (id, slug) = id_slug
+                    .split_once('-')
+                    .ok_or_else(|| ScrapeError::NotAStory {
+                        url: url.to_string(),
+                    })?;
+                Ok(url.join(&format!("/series/{}/{}/", id, slug))?)
+            }
+            _ => Err(ScrapeError::NotAStory {
+                url: url.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    fn parse_story(&self, html: &str) -> Result<Story> {
+        let doc = Html::parse_document(html);
+
+        let root = doc.root_element();
+        let cover = selectors::value(root, "scribblehub.cover").unwrap_or_default();
+        let description = selectors::values(root, "scribblehub.description").join("\n");
+        let url = selectors::value(root, "scribblehub.url").unwrap_or_default();
+        let tags = selectors::values(root, "scribblehub.tags");
+
+        Ok(Story {
+            url,
+            title: selectors::required(root, "scribblehub.title", "title")?,
+            author: selectors::required(root, "scribblehub.author", "author")?,
+            description,
+            cover,
+            tags,
+            chapters: chapters(&doc)?,
+            words: None,
+            status: None,
+            pages: Vec::new(),
+            language: page_language(&doc),
+            warnings: Vec::new(),
+        })
+    }
+
+    /// The table of contents is split into pages of `?toc=<n>`; the story
+    /// page is the first.
+    fn toc_pages(&self, html: &str, url: &Url) -> Vec<Url> {
+        let doc = Html::parse_document(html);
+        let last = doc
+            .select(&selector("ul.simple-pagination a").unwrap())
+            .filter_map(|a| a.value().attr("href"))
+            .filter_map(|href| url.join(href).ok())
+            .filter_map(|page| {
+                page.query_pairs()
+                    .find(|(key, _)| key == "toc")
+                    .and_then(|(_, n)| n.parse::<u32>().ok())
+            })
+            .max()
+            .unwrap_or(1);
+
+        (2..=last)
+            .map(|n| {
+                let mut page = url.clone();
+                page.set_query(Some(&format!("toc={}", n)));
+                page
+            })
+            .collect()
+    }
+
+    fn parse_toc_page(&self, html: &str) -> Result<Vec<Chapter>> {
+        chapters(&Html::parse_document(html))
+    }
+
+    /// The table of contents lists the newest chapter first.
+    fn order_chapters(&self, chapters: &mut Vec<Chapter>) {
+        chapters.reverse();
+    }
+
+    fn lists_newest_first(&self) -> bool {
+        true
+    }
+
+    fn content_selector(&self) -> String {
+        selectors::joined("scribblehub.content")
+    }
+
+    fn unavailable(&self, text: &str) -> Option<Unavailable> {
+        const LOCKED: [&str; 3] = [
+            "this chapter is locked",
+            "must be logged in",
+            "login to view",
+        ];
+        const DELETED: [&str; 3] = ["has been deleted", "does not exist", "no longer available"];
+        if LOCKED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Locked)
+        } else if DELETED.iter().any(|phrase| text.contains(phrase)) {
+            Some(Unavailable::Deleted)
+        } else {
+            None
+        }
+    }
+
+    fn author_note_selector(&self) -> String {
+        selectors::joined("scribblehub.author-notes")
+    }
+}
@@ -0,0 +1,112 @@
+//! Spoiler blocks, which the sites collapse behind a button that needs
+//! scripts. Copied as they are, e-readers show either nothing or the button,
+//! since the text itself is hidden with an inline `display: none`.
+
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::clean::{find, new_element};
+
+/// How spoilers are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Spoilers {
+    /// A block set apart and labeled "Spoiler:", with its text always
+    /// shown. EPUB 2 has nothing better.
+    #[default]
+    Inline,
+    /// `<details>` with the caption as `<summary>`, which readers of HTML5
+    /// content let the reader open.
+    Details,
+}
+
+/// Royal Road's and Scribble Hub's spoiler blocks.
+const SPOILERS: &str = ".spoiler, .spoiler-new, .sp-wrap";
+
+/// The header of a spoiler block, with its caption and button.
+const HEADERS: &str = ".spoiler-header, .spoiler-title, .smalltext, .sp-head";
+
+/// The hidden text of a spoiler block.
+const BODIES: &str = ".spoiler-inner, .spoiler-content, .spoiler-body, .sp-body";
+
+/// The caption of the spoiler block `el`, "Spoiler" unless the author gave
+/// it one.
+fn caption(el: ElementRef, headers: &Selector) -> String {
+    let caption = match el.value().attr("data-caption") {
+        Some(caption) => caption.to_string(),
+        None => el
+            .select(headers)
+            .next()
+            .map(|header| header.text().collect())
+            .unwrap_or_default(),
+    };
+    // e.g. "Spoiler: [Show]", with the button's text
+    let caption = caption.trim().trim_end_matches(':').trim();
+    if caption.is_empty() {
+        "Spoiler".to_string()
+    } else {
+        caption.to_string()
+    }
+}
+
+/// Rewrites the spoiler blocks below `content` as `style`. This must run
+/// before hidden elements are removed, or the spoilers' text goes with them.
+pub(crate) fn convert(doc: &mut Html, content: NodeId, style: Spoilers) {
+    let spoilers = Selector::parse(SPOILERS).unwrap();
+    let headers = Selector::parse(HEADERS).unwrap();
+    let bodies = Selector::parse(BODIES).unwrap();
+
+    // a spoiler inside another is converted along with the outer one, as
+    // part of its text
+    let blocks = find(doc, content, |el| {
+        spoilers.matches(&el)
+            && !el
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(|el| spoilers.matches(&el))
+    });
+    if !blocks.is_empty() {
+        log::debug!("converting {} spoiler(s)", blocks.len());
+    }
+
+    for id in blocks {
+        let el = ElementRef::wrap(doc.tree.get(id).unwrap()).unwrap();
+        let caption = caption(el, &headers);
+        // the text is in the body if there is one, otherwise it is all but
+        // the header
+        let text: Vec<NodeId> = match el.select(&bodies).next() {
+            Some(body) => body.children().map(|c| c.id()).collect(),
+            None => el
+                .children()
+                .filter(|child| {
+                    ElementRef::wrap(*child).is_none_or(|child| {
+                        !headers.matches(&child) && child.value().name() != "input"
+                    })
+                })
+                .map(|c| c.id())
+                .collect(),
+        };
+
+        let (block, label, label_text) = match style {
+            Spoilers::Inline => (
+                new_element("div", &[("class", "spoiler")]),
+                new_element("p", &[("class", "spoiler-label")]),
+                format!("{}:", caption),
+            ),
+            Spoilers::Details => (
+                new_element("details", &[("class", "spoiler")]),
+                new_element("summary", &[]),
+                caption,
+            ),
+        };
+
+        let mut node = doc.tree.get_mut(id).unwrap();
+        let mut block = node.insert_before(block);
+        block.append(label).append(Node::Text(scraper::node::Text {
+            text: label_text.into(),
+        }));
+        for child in text {
+            block.append_id(child);
+        }
+        node.detach();
+    }
+}
@@ -0,0 +1,115 @@
+//! Where the bulk of an EPUB waits to be written. epub-builder keeps its
+//! whole archive in memory, so chapters and images go to a zip of their own
+//! instead, compressed as they are added; once that outgrows
+//! [`SPOOL_LIMIT`] it moves to a temporary file. epub-builder is only given
+//! empty stand-ins, which are swapped for the real entries when the book is
+//! written, so even a story of thousands of chapters needs no more than a
+//! few megabytes of memory.
+
+use eyre::{Result, WrapErr};
+use std::collections::HashSet;
+use std::io::{Seek, Write};
+use tempfile::SpooledTempFile;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// How much of a book is kept in memory before it is spooled to disk.
+pub const SPOOL_LIMIT: usize = 16 << 20;
+
+/// A buffer that spills to a temporary file past [`SPOOL_LIMIT`].
+pub(crate) fn spool() -> SpooledTempFile {
+    SpooledTempFile::new(SPOOL_LIMIT)
+}
+
+/// Copies all of `spool` to `out`.
+pub(crate) fn copy_spool(spool: &mut SpooledTempFile, out: &mut dyn Write) -> Result<()> {
+    spool.rewind()?;
+    std::io::copy(spool, out).wrap_err("could not write the book")?;
+    Ok(())
+}
+
+/// How the entries of an EPUB are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    /// The deflate level, from 0, which stores every entry as it is, to 9.
+    /// Default: 6
+    pub level: u32,
+
+    /// Store images as they are, since JPEG, PNG, GIF and WebP images are
+    /// compressed already and deflating them only takes time. Default:
+    /// false
+    pub store_images: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            level: 6,
+            store_images: false,
+        }
+    }
+}
+
+impl Compression {
+    /// How the entry `path` is compressed.
+    pub(crate) fn options(&self, path: &str) -> FileOptions {
+        let image = [".jpg", ".jpeg", ".png", ".gif", ".webp"]
+            .iter()
+            .any(|ext| path.to_ascii_lowercase().ends_with(ext));
+        if self.level == 0 || (self.store_images && image) {
+            return FileOptions::default().compression_method(CompressionMethod::Stored);
+        }
+        FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .compression_level(Some(self.level.min(9) as i32))
+    }
+}
+
+/// The spooled entries of a book, by their path in the EPUB.
+pub(crate) struct ContentSpool {
+    writer: ZipWriter<SpooledTempFile>,
+    files: HashSet<String>,
+    compression: Compression,
+}
+
+impl ContentSpool {
+    pub(crate) fn new() -> ContentSpool {
+        ContentSpool {
+            writer: ZipWriter::new(spool()),
+            files: HashSet::new(),
+            compression: Compression::default(),
+        }
+    }
+
+    /// Compresses the entries added from now on, and those of the book
+    /// it is written into, as `compression` says.
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub(crate) fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Compresses `data` into the spool as the entry `path`, e.g.
+    /// `OEBPS/chapter_3.xhtml`.
+    pub(crate) fn add(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.writer
+            .start_file(path, self.compression.options(path))
+            .wrap_err_with(|| format!("could not spool {}", path))?;
+        self.writer
+            .write_all(data)
+            .wrap_err_with(|| format!("could not spool {}", path))?;
+        self.files.insert(path.to_string());
+        Ok(())
+    }
+
+    pub(crate) fn contains(&self, path: &str) -> bool {
+        self.files.contains(path)
+    }
+
+    /// Ends the spool, to read the entries back.
+    pub(crate) fn finish(&mut self) -> Result<ZipArchive<SpooledTempFile>> {
+        Ok(ZipArchive::new(self.writer.finish()?)?)
+    }
+}
@@ -0,0 +1,126 @@
+//! The stylesheet books are written with, and the fonts embedded in them.
+
+use eyre::{eyre, Result, WrapErr};
+use std::path::{Path, PathBuf};
+
+use crate::STYLESHEET;
+
+/// A font file bundled with the book.
+#[derive(Debug, Clone)]
+pub struct Font {
+    /// Path of the font inside the book, e.g. `fonts/Literata-Bold.ttf`.
+    pub file: String,
+    /// The family the font is declared as, e.g. `Literata`.
+    pub family: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub mime: &'static str,
+    pub data: Vec<u8>,
+}
+
+fn font_mime(ext: &str) -> Option<&'static str> {
+    match ext.to_lowercase().as_str() {
+        "ttf" => Some("application/x-font-ttf"),
+        "otf" => Some("application/vnd.ms-opentype"),
+        "woff" => Some("font/woff"),
+        "woff2" => Some("font/woff2"),
+        _ => None,
+    }
+}
+
+impl Font {
+    /// Reads the font at `path`. The family, weight and style are taken
+    /// from the file name, so `Literata-BoldItalic.ttf` is the bold italic
+    /// face of `Literata`.
+    pub fn load(path: &Path) -> Result<Font> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| eyre!("{} is not a font file", path.display()))?;
+        let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+        let mime = font_mime(ext).ok_or_else(|| {
+            eyre!(
+                "{} is not a font, use a .ttf, .otf, .woff or .woff2 file",
+                path.display()
+            )
+        })?;
+        let data =
+            std::fs::read(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+
+        let (family, face) = match stem.rsplit_once('-') {
+            Some((family, face)) if !family.is_empty() => (family, face.to_lowercase()),
+            _ => (stem, String::new()),
+        };
+        Ok(Font {
+            file: format!("fonts/{}", name),
+            family: family.to_string(),
+            bold: face.contains("bold"),
+            italic: face.contains("italic") || face.contains("oblique"),
+            mime,
+            data,
+        })
+    }
+
+    fn font_face(&self, url: &str) -> String {
+        format!(
+            "@font-face {{\n    font-family: \"{}\";\n    font-weight: {};\n    font-style: {};\n    src: url(\"{}\");\n}}\n",
+            self.family.replace('"', "\\\""),
+            if self.bold { "bold" } else { "normal" },
+            if self.italic { "italic" } else { "normal" },
+            url
+        )
+    }
+}
+
+/// The stylesheet of a book and the fonts it uses.
+#[derive(Debug, Clone)]
+pub struct Style {
+    pub css: String,
+    pub fonts: Vec<Font>,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            css: STYLESHEET.to_string(),
+            fonts: Vec::new(),
+        }
+    }
+}
+
+impl Style {
+    /// The stylesheet at `css`, or the default one, with the fonts at
+    /// `fonts`. The default stylesheet sets the text in the first font's
+    /// family; a stylesheet of your own picks the families itself.
+    pub fn load(css: Option<&Path>, fonts: &[PathBuf]) -> Result<Style> {
+        let fonts = fonts
+            .iter()
+            .map(|path| Font::load(path))
+            .collect::<Result<Vec<_>>>()?;
+
+        let css = match css {
+            Some(path) => std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("could not read {}", path.display()))?,
+            None => match fonts.first() {
+                Some(font) => format!(
+                    "{}\n    body {{\n        font-family: \"{}\", serif;\n    }}\n",
+                    STYLESHEET,
+                    font.family.replace('"', "\\\"")
+                ),
+                None => STYLESHEET.to_string(),
+            },
+        };
+        Ok(Style { css, fonts })
+    }
+
+    /// The stylesheet, followed by a `@font-face` rule for every font. `url`
+    /// gives the address of a font as seen from the stylesheet.
+    pub fn stylesheet(&self, url: impl Fn(&Font) -> String) -> String {
+        let mut css = self.css.clone();
+        for font in &self.fonts {
+            css.push('\n');
+            css.push_str(&font.font_face(&url(font)));
+        }
+        css
+    }
+}
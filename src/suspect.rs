@@ -0,0 +1,44 @@
+//! Telling a chapter page that is only a wall from the chapter: the content
+//! selector still matches, but what it holds asks the reader to enable
+//! JavaScript or log in, or is nothing at all.
+
+use crate::text::html_to_text;
+
+/// What a page says in place of the chapter.
+const WALL_PHRASES: &[&str] = &[
+    "enable javascript",
+    "javascript is disabled",
+    "javascript is required",
+    "log in to read",
+    "login to read",
+    "sign in to read",
+    "log in to continue",
+    "sign in to continue",
+    "you must be logged in",
+    "you need to be logged in",
+    "checking your browser",
+    "access denied",
+];
+
+/// Chapters longer than this are taken for the story, whatever they say. A
+/// wall is a sentence or two, while a short chapter can well have a system
+/// message say "access denied".
+const MAX_WALL_WORDS: usize = 40;
+
+/// Why the cleaned chapter `content` looks like something other than the
+/// chapter, if it does.
+pub(crate) fn suspect(content: &str) -> Option<String> {
+    let text = html_to_text(content).to_lowercase();
+    let words = text.split_whitespace().count();
+    if words == 0 && !content.contains("<img") {
+        return Some("it has no text".to_string());
+    }
+    if words > MAX_WALL_WORDS {
+        return None;
+    }
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    WALL_PHRASES
+        .iter()
+        .find(|phrase| text.contains(*phrase))
+        .map(|phrase| format!("it says {:?}", phrase))
+}
@@ -0,0 +1,131 @@
+//! Tables, mostly the stat sheets of LitRPG stories, which overflow the
+//! narrow screen of an e-reader.
+
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Node};
+
+use crate::clean::{find, new_element};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TableMode {
+    /// Keep the tables, fitted to the page by the stylesheet
+    #[default]
+    Fit,
+    /// Write tables too wide for the page as preformatted text, with the
+    /// columns lined up
+    Text,
+}
+
+/// Tables whose rows are wider than this many characters are too wide for
+/// the page.
+const WIDE_TABLE: usize = 50;
+
+const SEPARATOR: &str = " | ";
+
+/// The cells of every row of `table`, as text, leaving out nested tables,
+/// and whether the row is a header.
+fn rows(table: ElementRef) -> Vec<(Vec<String>, bool)> {
+    table
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter(|el| el.value().name() == "tr")
+        .filter(|tr| {
+            // rows of this table, not of one inside it
+            tr.ancestors()
+                .filter_map(ElementRef::wrap)
+                .find(|el| el.value().name() == "table")
+                .is_some_and(|el| el.id() == table.id())
+        })
+        .map(|tr| {
+            let cells: Vec<ElementRef> = tr
+                .children()
+                .filter_map(ElementRef::wrap)
+                .filter(|el| matches!(el.value().name(), "td" | "th"))
+                .collect();
+            let header = !cells.is_empty() && cells.iter().all(|el| el.value().name() == "th");
+            let cells = cells
+                .iter()
+                .map(|el| {
+                    el.text()
+                        .flat_map(str::split_whitespace)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            (cells, header)
+        })
+        .collect()
+}
+
+/// The width of every column of `rows`.
+fn widths(rows: &[(Vec<String>, bool)]) -> Vec<usize> {
+    let mut widths = Vec::new();
+    for (cells, _) in rows {
+        for (i, cell) in cells.iter().enumerate() {
+            let width = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(w) if *w < width => *w = width,
+                Some(_) => {}
+                None => widths.push(width),
+            }
+        }
+    }
+    widths
+}
+
+/// The rows as lines of text, a line of dashes below the header rows.
+fn table_text(rows: &[(Vec<String>, bool)], widths: &[usize]) -> String {
+    let mut text = String::new();
+    for (n, (cells, header)) in rows.iter().enumerate() {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        text.push_str(line.join(SEPARATOR).trim_end());
+        text.push('\n');
+
+        let next_is_header = rows.get(n + 1).is_some_and(|(_, header)| *header);
+        if *header && !next_is_header {
+            let rule: Vec<String> = widths[..cells.len()]
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect();
+            text.push_str(&rule.join("-+-"));
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// Rewrites the tables below `content` as `mode`.
+pub(crate) fn convert(doc: &mut Html, content: NodeId, mode: TableMode) {
+    if mode == TableMode::Fit {
+        return;
+    }
+
+    // a table inside another goes along with it
+    let tables = find(doc, content, |el| {
+        el.value().name() == "table"
+            && !el
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(|el| el.value().name() == "table")
+    });
+
+    for id in tables {
+        let table = ElementRef::wrap(doc.tree.get(id).unwrap()).unwrap();
+        let rows = rows(table);
+        let widths = widths(&rows);
+        let width = widths.iter().sum::<usize>() + SEPARATOR.len() * widths.len().saturating_sub(1);
+        if width <= WIDE_TABLE {
+            continue;
+        }
+
+        let text = table_text(&rows, &widths);
+        let mut node = doc.tree.get_mut(id).unwrap();
+        node.insert_before(new_element("pre", &[("class", "table")]))
+            .append(Node::Text(scraper::node::Text { text: text.into() }));
+        node.detach();
+    }
+}
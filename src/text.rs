@@ -0,0 +1,103 @@
+//! Conversion of chapter content to plain text, for text-to-speech and
+//! other tools that only want the words.
+
+use ego_tree::iter::Edge;
+use scraper::{Html, Node};
+
+/// The elements that are paragraphs of their own.
+const BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "li",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "tr",
+    "table",
+    "ul",
+    "ol",
+    "section",
+    "aside",
+    "details",
+    "summary",
+    "figure",
+    "figcaption",
+];
+
+/// What a scene break is written as.
+const SCENE_BREAK: &str = "* * *";
+
+/// `html` as plain text: a paragraph for every block, separated by blank
+/// lines, lines broken where the html breaks them, and table cells
+/// separated by tabs. Images, scripts and styles are left out.
+pub(crate) fn html_to_text(html: &str) -> String {
+    let doc = Html::parse_fragment(html);
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut line = String::new();
+
+    for edge in doc.root_element().traverse() {
+        match edge {
+            Edge::Open(node) => match node.value() {
+                Node::Text(text) => {
+                    let hidden = node
+                        .ancestors()
+                        .filter_map(|node| node.value().as_element())
+                        .any(|el| matches!(el.name(), "script" | "style" | "noscript"));
+                    // the html's own line breaks are only whitespace
+                    if !hidden {
+                        line.push_str(&text.replace(['\n', '\r', '\t'], " "));
+                    }
+                }
+                Node::Element(el) => match el.name() {
+                    "br" => line.push('\n'),
+                    "hr" => {
+                        flush(&mut line, &mut paragraphs);
+                        paragraphs.push(SCENE_BREAK.to_string());
+                    }
+                    "td" | "th" if node.prev_siblings().any(|node| node.value().is_element()) => {
+                        line.push('\t')
+                    }
+                    name if BLOCKS.contains(&name) => flush(&mut line, &mut paragraphs),
+                    _ => (),
+                },
+                _ => (),
+            },
+            Edge::Close(node) => {
+                let closes_block = node
+                    .value()
+                    .as_element()
+                    .is_some_and(|el| BLOCKS.contains(&el.name()));
+                if closes_block {
+                    flush(&mut line, &mut paragraphs);
+                }
+            }
+        }
+    }
+    flush(&mut line, &mut paragraphs);
+    paragraphs.join("\n\n")
+}
+
+/// Ends the paragraph in `line`, adding it to `paragraphs` unless it is
+/// blank.
+fn flush(line: &mut String, paragraphs: &mut Vec<String>) {
+    let text = line
+        .lines()
+        .map(|line| {
+            line.split('\t')
+                .map(|cell| cell.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>()
+                .join("\t")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = text.trim_matches('\n');
+    if !text.trim().is_empty() {
+        paragraphs.push(text.to_string());
+    }
+    line.clear();
+}
@@ -0,0 +1,136 @@
+use crate::Chapter;
+use eyre::{eyre, Result, WrapErr};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Replacement chapter titles loaded from a `--title-map` file.
+///
+/// The file is a JSON object whose keys are either a 1-based chapter index
+/// or an original chapter title, and whose values are the new titles:
+///
+/// ```json
+/// { "3": "Chapter 3 - The Road", "Interlude": "Arc 1 Interlude" }
+/// ```
+///
+/// Index keys take precedence over title keys.
+#[derive(Debug, Default)]
+pub struct TitleMap {
+    by_index: HashMap<usize, String>,
+    by_title: HashMap<String, String>,
+}
+
+impl TitleMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<TitleMap> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("could not read title map {}", path.display()))?;
+
+        TitleMap::parse(&data).wrap_err_with(|| format!("invalid title map {}", path.display()))
+    }
+
+    pub fn parse(data: &str) -> Result<TitleMap> {
+        let entries: HashMap<String, String> = serde_json::from_str(data)?;
+
+        let mut map = TitleMap::default();
+        for (key, title) in entries {
+            if title.trim().is_empty() {
+                return Err(eyre!("replacement title for {:?} is empty", key));
+            }
+
+            match key.trim().parse::<usize>() {
+                Ok(0) => return Err(eyre!("chapter indices start at 1")),
+                Ok(index) => {
+                    map.by_index.insert(index, title);
+                }
+                Err(_) => {
+                    map.by_title.insert(key.trim().to_string(), title);
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Renames the matching chapters in place, warning about entries that
+    /// did not match any chapter.
+    pub fn apply(&self, chapters: &mut [Chapter]) {
+        let mut used_titles = HashSet::new();
+
+        for (i, chapter) in chapters.iter_mut().enumerate() {
+            if let Some(title) = self.by_index.get(&(i + 1)) {
+                chapter.name = title.clone();
+            } else if let Some(title) = self.by_title.get(&chapter.name) {
+                used_titles.insert(chapter.name.clone());
+                chapter.name = title.clone();
+            }
+        }
+
+        for index in self.by_index.keys().filter(|i| **i > chapters.len()) {
+            log::warn!("title map entry {} matches no chapter", index);
+        }
+        for title in self.by_title.keys().filter(|t| !used_titles.contains(*t)) {
+            log::warn!("title map entry {:?} matches no chapter", title);
+        }
+    }
+}
+
+/// Chapter titles made from a `--chapter-title-template` such as
+/// `{index}. {title}`, where `{index}` is the chapter's number in the book,
+/// from 1, and `{title}` its title.
+#[derive(Debug, Clone)]
+pub struct TitleTemplate {
+    template: String,
+}
+
+impl TitleTemplate {
+    pub fn parse(template: &str) -> Result<TitleTemplate> {
+        let template = TitleTemplate {
+            template: template.to_string(),
+        };
+        if template.template.trim().is_empty() {
+            return Err(eyre!("the chapter title template is empty"));
+        }
+        template.try_render(1, "")?;
+        Ok(template)
+    }
+
+    /// The title of chapter `index` (from 1), titled `title` on the site.
+    pub fn render(&self, index: usize, title: &str) -> String {
+        self.try_render(index, title)
+            .expect("the template was checked when it was parsed")
+    }
+
+    fn try_render(&self, index: usize, title: &str) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            let end = rest[start..].find('}').ok_or_else(|| {
+                eyre!(
+                    "unclosed {{ in the chapter title template {:?}",
+                    self.template
+                )
+            })?;
+            match &rest[start + 1..start + end] {
+                "index" => out.push_str(&index.to_string()),
+                "title" => out.push_str(title),
+                name => {
+                    return Err(eyre!(
+                        "unknown placeholder {{{}}} in the chapter title template, use {{index}} or {{title}}",
+                        name
+                    ))
+                }
+            }
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Renames the chapters in place.
+    pub fn apply(&self, chapters: &mut [Chapter]) {
+        for (i, chapter) in chapters.iter_mut().enumerate() {
+            chapter.name = self.render(i + 1, &chapter.name);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! A terminal interface for picking the chapters of a story to download,
+//! and the book's options, instead of writing out a range of chapters.
+
+use clap::ValueEnum;
+use eyre::{Result, WrapErr};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::{iso_date, thousands, ChapterSelection, EpubVersion, Format, Story, Typography};
+
+/// The book's options that can be changed before downloading.
+#[derive(Debug, Clone, Copy)]
+pub struct TuiOptions {
+    pub format: Format,
+    pub epub_version: EpubVersion,
+    pub typography: Typography,
+    pub authors_notes: bool,
+}
+
+/// How far Page Up and Page Down move.
+const PAGE: usize = 20;
+
+const HELP: &str = "↑↓ move  space select  v range  s volume  a all  n none  i invert  \
+                    f format  e epub version  t typography  o author's notes  \
+                    enter download  q quit";
+
+struct App<'a> {
+    story: &'a Story,
+    selected: Vec<bool>,
+    list: ListState,
+    /// Where the range being selected with `v` starts.
+    mark: Option<usize>,
+    options: TuiOptions,
+}
+
+/// The value after `value` of a command line option, wrapping around.
+fn next<T: ValueEnum + PartialEq>(value: T) -> T {
+    let values = T::value_variants();
+    let i = values.iter().position(|v| *v == value).unwrap_or(0);
+    values[(i + 1) % values.len()].clone()
+}
+
+fn name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+impl App<'_> {
+    fn cursor(&self) -> usize {
+        self.list.selected().unwrap_or(0)
+    }
+
+    fn move_to(&mut self, nr: usize) {
+        let last = self.story.chapters.len().saturating_sub(1);
+        self.list.select(Some(nr.min(last)));
+    }
+
+    /// Selects all of the chapters from `start` to `end`, or unselects them
+    /// if they already are.
+    fn toggle_range(&mut self, start: usize, end: usize) {
+        let (start, end) = (start.min(end), start.max(end));
+        let range = &mut self.selected[start..=end];
+        let select = !range.iter().all(|selected| *selected);
+        range.fill(select);
+    }
+
+    /// The chapters of the volume the chapter `nr` is in.
+    fn volume(&self, nr: usize) -> (usize, usize) {
+        let chapters = &self.story.chapters;
+        let volume = &chapters[nr].volume;
+        let start = chapters[..nr]
+            .iter()
+            .rposition(|chapter| chapter.volume != *volume)
+            .map_or(0, |i| i + 1);
+        let end = chapters[nr..]
+            .iter()
+            .position(|chapter| chapter.volume != *volume)
+            .map_or(chapters.len(), |i| nr + i);
+        (start, end - 1)
+    }
+
+    fn selection(&self) -> Option<ChapterSelection> {
+        ChapterSelection::of(
+            self.selected
+                .iter()
+                .enumerate()
+                .filter(|(_, selected)| **selected)
+                .map(|(i, _)| i + 1),
+        )
+    }
+
+    /// Handles the key `key`. Returns `Some` when the reader is done: with
+    /// the selection to download, or `None` if they quit.
+    fn key(&mut self, key: KeyCode) -> Option<Option<ChapterSelection>> {
+        let cursor = self.cursor();
+        match key {
+            KeyCode::Char('q') => return Some(None),
+            KeyCode::Esc if self.mark.is_some() => self.mark = None,
+            KeyCode::Esc => return Some(None),
+            KeyCode::Enter => {
+                if let Some(selection) = self.selection() {
+                    return Some(Some(selection));
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.move_to(cursor.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => self.move_to(cursor + 1),
+            KeyCode::PageUp => self.move_to(cursor.saturating_sub(PAGE)),
+            KeyCode::PageDown => self.move_to(cursor + PAGE),
+            KeyCode::Home | KeyCode::Char('g') => self.move_to(0),
+            KeyCode::End | KeyCode::Char('G') => self.move_to(usize::MAX),
+            KeyCode::Char(' ') => self.selected[cursor] = !self.selected[cursor],
+            KeyCode::Char('v') => match self.mark.take() {
+                Some(mark) => self.toggle_range(mark, cursor),
+                None => self.mark = Some(cursor),
+            },
+            KeyCode::Char('s') => {
+                let (start, end) = self.volume(cursor);
+                self.toggle_range(start, end);
+            }
+            KeyCode::Char('a') => self.selected.fill(true),
+            KeyCode::Char('n') => self.selected.fill(false),
+            KeyCode::Char('i') => self.selected.iter_mut().for_each(|s| *s = !*s),
+            KeyCode::Char('f') => self.options.format = next(self.options.format),
+            KeyCode::Char('e') => self.options.epub_version = next(self.options.epub_version),
+            KeyCode::Char('t') => self.options.typography = next(self.options.typography),
+            KeyCode::Char('o') => self.options.authors_notes = !self.options.authors_notes,
+            _ => {}
+        }
+        None
+    }
+
+    fn header(&self) -> Paragraph<'_> {
+        let story = self.story;
+        let length = match story.words {
+            Some(words) => format!(
+                "{} chapters, about {} words",
+                story.chapters.len(),
+                thousands(words as usize)
+            ),
+            None => format!("{} chapters", story.chapters.len()),
+        };
+        let length = match story.status {
+            Some(status) => format!("{} · {}", status.name(), length),
+            None => length,
+        };
+        let mut lines = vec![
+            Line::from(vec![
+                Span::from(story.title.as_str()).bold(),
+                Span::from(format!(" by {}", story.author)),
+            ]),
+            Line::from(length),
+        ];
+        if !story.tags.is_empty() {
+            lines.push(Line::from(story.tags.join(", ")).italic());
+        }
+        lines.push(Line::default());
+        lines.extend(story.description.lines().map(Line::from));
+        Paragraph::new(lines)
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::BOTTOM))
+    }
+
+    fn chapters(&self) -> List<'static> {
+        let cursor = self.cursor();
+        let marked = self.mark.map(|mark| (mark.min(cursor), mark.max(cursor)));
+        let mut volume = None;
+        let items = self.story.chapters.iter().enumerate().map(|(i, chapter)| {
+            let mut lines = Vec::new();
+            // each volume starts with its name
+            if chapter.volume.is_some() && chapter.volume != volume {
+                volume = chapter.volume.clone();
+                lines.push(Line::from(chapter.volume.clone().unwrap_or_default()).bold());
+            }
+            let check = if self.selected[i] { "[x]" } else { "[ ]" };
+            let date = chapter.published.map(iso_date).unwrap_or_default();
+            let mut line = Line::from(format!(
+                "{} {:>5}. {:<10}  {}",
+                check,
+                i + 1,
+                date,
+                chapter.name
+            ));
+            if marked.is_some_and(|(start, end)| (start..=end).contains(&i)) {
+                line = line.underlined();
+            }
+            lines.push(line);
+            ListItem::new(lines)
+        });
+        List::new(items).highlight_style(Modifier::REVERSED)
+    }
+
+    fn status(&self) -> Paragraph<'_> {
+        let selected = self.selected.iter().filter(|s| **s).count();
+        let mut status = format!(
+            "{} of {} selected · {}",
+            selected,
+            self.selected.len(),
+            self.options.format.name()
+        );
+        if matches!(self.options.format, Format::Epub | Format::Kepub) {
+            status.push_str(&format!(" · EPUB {}", name(&self.options.epub_version)));
+        }
+        status.push_str(&format!(
+            " · typography {} · author's notes {}",
+            name(&self.options.typography),
+            if self.options.authors_notes {
+                "kept"
+            } else {
+                "dropped"
+            }
+        ));
+        if self.mark.is_some() {
+            status.push_str(" · selecting a range, v to finish, esc to cancel");
+        }
+        Paragraph::new(vec![Line::from(status).bold(), Line::from(HELP)])
+            .wrap(Wrap { trim: true })
+            .block(Block::default().borders(Borders::TOP))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, chapters, status] = Layout::vertical([
+            Constraint::Max(10),
+            Constraint::Min(5),
+            Constraint::Length(4),
+        ])
+        .areas(frame.area());
+        frame.render_widget(self.header(), header);
+        let list = self.chapters();
+        frame.render_stateful_widget(list, chapters, &mut self.list);
+        frame.render_widget(self.status(), status);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<Option<ChapterSelection>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if let Some(done) = self.key(key.code) {
+                    return Ok(done);
+                }
+            }
+        }
+    }
+}
+
+/// Shows `story` and lets the reader pick the chapters to download, at
+/// first those in `selection` or else all of them, and change `options`.
+/// Returns the chosen chapters, or `None` if the reader quit instead.
+pub fn choose_chapters(
+    story: &Story,
+    selection: Option<&ChapterSelection>,
+    options: &mut TuiOptions,
+) -> Result<Option<ChapterSelection>> {
+    if story.chapters.is_empty() {
+        return Ok(None);
+    }
+    let mut app = App {
+        story,
+        selected: (1..=story.chapters.len())
+            .map(|nr| selection.is_none_or(|selection| selection.contains(nr)))
+            .collect(),
+        list: ListState::default().with_selected(Some(0)),
+        mark: None,
+        options: *options,
+    };
+
+    let mut terminal = ratatui::try_init().wrap_err("could not start the terminal interface")?;
+    let result = app.run(&mut terminal);
+    ratatui::try_restore()?;
+
+    *options = app.options;
+    result
+}
@@ -0,0 +1,152 @@
+//! Quote, dash and ellipsis normalization for chapter text.
+
+use ego_tree::NodeId;
+use html5ever::tendril::StrTendril;
+use scraper::{Html, Node};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Typography {
+    /// Curly quotes, proper em/en dashes and ellipses
+    Smart,
+    /// Straight quotes, plain hyphens and three dots
+    Straight,
+    /// Leave the text as written
+    #[default]
+    Off,
+}
+
+/// Elements whose text is never touched.
+pub(crate) fn is_verbatim(name: &str) -> bool {
+    matches!(name, "pre" | "code" | "kbd" | "samp" | "script" | "style")
+}
+
+pub(crate) fn is_block(name: &str) -> bool {
+    matches!(
+        name,
+        "p" | "div" | "blockquote" | "li" | "td" | "th" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+    )
+}
+
+/// Rewrites the quotes and dashes of every text node below `content`.
+pub(crate) fn apply(doc: &mut Html, content: NodeId, mode: Typography) {
+    if mode == Typography::Off {
+        return;
+    }
+
+    // (text node, whether it starts a new block)
+    let mut nodes = Vec::new();
+    let mut last_block = None;
+    for node in doc.tree.get(content).unwrap().descendants() {
+        if !node.value().is_text() {
+            continue;
+        }
+
+        let mut verbatim = false;
+        let mut block = None;
+        for ancestor in node.ancestors() {
+            let Some(el) = ancestor.value().as_element() else {
+                continue;
+            };
+            verbatim |= is_verbatim(el.name());
+            if block.is_none() && is_block(el.name()) {
+                block = Some(ancestor.id());
+            }
+        }
+        if verbatim {
+            continue;
+        }
+
+        nodes.push((node.id(), block != last_block));
+        last_block = block;
+    }
+
+    let mut prev = None;
+    for (id, new_block) in nodes {
+        if new_block {
+            prev = None;
+        }
+
+        let mut node = doc.tree.get_mut(id).unwrap();
+        let Node::Text(text) = node.value() else {
+            continue;
+        };
+
+        let converted = match mode {
+            Typography::Smart => smarten(&text.text, &mut prev),
+            Typography::Straight => straighten(&text.text),
+            Typography::Off => unreachable!(),
+        };
+        if !converted.is_empty() {
+            prev = converted.chars().last();
+        }
+        text.text = StrTendril::from(converted);
+    }
+}
+
+fn opens(prev: Option<char>) -> bool {
+    match prev {
+        None => true,
+        Some(c) => c.is_whitespace() || matches!(c, '(' | '[' | '{' | '—' | '–' | '“' | '‘'),
+    }
+}
+
+/// Words that start with an apostrophe for letters left out, which looks
+/// like an opening quote.
+const ELISIONS: &[&str] = &[
+    "tis", "twas", "twere", "twill", "twould", "em", "cause", "cos", "cuz", "til", "bout", "nuff",
+    "n",
+];
+
+/// Whether a `'` where a quote would open, followed by `rest`, is an
+/// apostrophe instead, as in `'90s` or `'tis`. Other words after it are
+/// taken for the start of a quote.
+fn elides(rest: &[char]) -> bool {
+    let word: String = rest
+        .iter()
+        .take_while(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect();
+    word.starts_with(|c: char| c.is_ascii_digit()) || ELISIONS.contains(&word.as_str())
+}
+
+/// Converts straight quotes to curly ones, hyphen runs to dashes and three
+/// dots to an ellipsis. `prev` is the character preceding `text`, carried
+/// across text nodes.
+pub fn smarten(text: &str, prev: &mut Option<char>) -> String {
+    let text = text
+        .replace("...", "…")
+        .replace("---", "—")
+        .replace("--", "—")
+        .replace(" - ", " – ");
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let c = match c {
+            '"' if opens(*prev) => '“',
+            '"' => '”',
+            '\'' if opens(*prev) && !elides(&chars[i + 1..]) => '‘',
+            '\'' => '’',
+            c => c,
+        };
+        out.push(c);
+        *prev = Some(c);
+    }
+    out
+}
+
+/// Converts curly quotes to straight ones, dashes to hyphens and ellipses to
+/// three dots. Primes, as in `6′2″`, are measurements rather than quotes and
+/// stay.
+pub fn straighten(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '“' | '”' | '„' => "\"".to_string(),
+            '‘' | '’' | '‚' => "'".to_string(),
+            '—' => "--".to_string(),
+            '–' => "-".to_string(),
+            '…' => "...".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,382 @@
+//! A lightweight self-check of a generated EPUB.
+//!
+//! This is not a replacement for epubcheck: it only reopens the archive and
+//! makes sure the pieces we wrote are actually there. [`validate_epub`] goes
+//! further, into the chapters themselves, and hands the book to epubcheck
+//! as well where it is installed.
+
+use eyre::{eyre, Result, WrapErr};
+use scraper::{Html, Selector};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use zip::ZipArchive;
+
+use crate::calibre::on_path;
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Descriptions of the checks that were run.
+    pub checks: Vec<String>,
+    /// Problems found. The book is fine if this is empty.
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+pub(crate) fn parse_xml(text: &str) -> Html {
+    use html5ever::tendril::TendrilSink;
+    xml5ever::driver::parse_document(Html::new_document(), Default::default()).one(text)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>> {
+    let mut file = archive
+        .by_name(name)
+        .map_err(|_| eyre!("missing {}", name))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reopens the EPUB at `path` and checks its container, package document
+/// and that every declared file exists and is non-empty.
+pub fn verify_epub(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    let path = path.as_ref();
+    let file = File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?;
+    let mut archive = ZipArchive::new(file).wrap_err("output is not a valid zip archive")?;
+    let mut report = VerifyReport::default();
+
+    report.checks.push("mimetype is the first entry".into());
+    match archive.by_index(0) {
+        Ok(mut first) if first.name() == "mimetype" => {
+            let mut mime = String::new();
+            first.read_to_string(&mut mime)?;
+            if mime != "application/epub+zip" {
+                report
+                    .problems
+                    .push(format!("unexpected mimetype {:?}", mime));
+            }
+        }
+        _ => report
+            .problems
+            .push("mimetype is not the first entry".into()),
+    }
+
+    report
+        .checks
+        .push("META-INF/container.xml names a package".into());
+    let container = String::from_utf8(read_entry(&mut archive, "META-INF/container.xml")?)?;
+    let container = parse_xml(&container);
+    let rootfile = Selector::parse("rootfile").unwrap();
+    let Some(opf_path) = container
+        .select(&rootfile)
+        .next()
+        .and_then(|el| el.value().attr("full-path"))
+    else {
+        report.problems.push("container.xml has no rootfile".into());
+        return Ok(report);
+    };
+    let opf_path = opf_path.to_string();
+
+    report.checks.push(format!("{} parses", opf_path));
+    let opf = match read_entry(&mut archive, &opf_path) {
+        Ok(opf) => parse_xml(&String::from_utf8(opf)?),
+        Err(err) => {
+            report.problems.push(err.to_string());
+            return Ok(report);
+        }
+    };
+    let base = match opf_path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/", dir),
+        None => String::new(),
+    };
+
+    let item = Selector::parse("manifest > item").unwrap();
+    let mut ids = HashSet::new();
+    let mut declared = 0;
+    for item in opf.select(&item) {
+        let (Some(id), Some(href)) = (item.value().attr("id"), item.value().attr("href")) else {
+            report
+                .problems
+                .push("manifest item without id or href".into());
+            continue;
+        };
+        ids.insert(id.to_string());
+        declared += 1;
+
+        let name = format!("{}{}", base, href);
+        match archive.by_name(&name) {
+            Ok(file) if file.size() == 0 => report.problems.push(format!("{} is empty", name)),
+            Ok(_) => (),
+            Err(_) => report
+                .problems
+                .push(format!("{} is declared but missing", name)),
+        }
+    }
+    report.checks.push(format!(
+        "{} manifest files exist and are non-empty",
+        declared
+    ));
+
+    let itemref = Selector::parse("spine > itemref").unwrap();
+    let mut spine = 0;
+    for itemref in opf.select(&itemref) {
+        spine += 1;
+        match itemref.value().attr("idref") {
+            Some(idref) if ids.contains(idref) => (),
+            Some(idref) => report
+                .problems
+                .push(format!("spine references unknown item {:?}", idref)),
+            None => report.problems.push("spine itemref without idref".into()),
+        }
+    }
+    report
+        .checks
+        .push(format!("{} spine entries reference manifest items", spine));
+    if spine == 0 {
+        report.problems.push("spine is empty".into());
+    }
+
+    Ok(report)
+}
+
+/// [`verify_epub`]'s checks, and that every XHTML file is well-formed and
+/// every image it shows is in the archive. If epubcheck is on the `PATH`,
+/// the book is checked with it too, and the errors it reports count as
+/// problems.
+pub fn validate_epub(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    let path = path.as_ref();
+    let mut report = verify_epub(path)?;
+    let file = File::open(path).wrap_err_with(|| format!("could not open {}", path.display()))?;
+    let mut archive = ZipArchive::new(file).wrap_err("output is not a valid zip archive")?;
+    let names: HashSet<String> = archive.file_names().map(str::to_string).collect();
+    let mut pages: Vec<&String> = names
+        .iter()
+        .filter(|name| name.ends_with(".xhtml") || name.ends_with(".html"))
+        .collect();
+    pages.sort();
+
+    let img = Selector::parse("img[src]").unwrap();
+    let svg_image = Selector::parse("image").unwrap();
+    let mut images = 0;
+    for name in &pages {
+        let text = match String::from_utf8(read_entry(&mut archive, name)?) {
+            Ok(text) => text,
+            Err(_) => {
+                report.problems.push(format!("{} is not UTF-8", name));
+                continue;
+            }
+        };
+        let doc = parse_xml(&text);
+        for error in &doc.errors {
+            report
+                .problems
+                .push(format!("{}: XML error: {}", name, error));
+        }
+        // the parser closes whatever is still open at the end without a word
+        if !text.trim_end().ends_with("</html>") {
+            report
+                .problems
+                .push(format!("{} doesn't end with </html>", name));
+        }
+
+        let dir = match name.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/", dir),
+            None => String::new(),
+        };
+        let srcs = doc
+            .select(&img)
+            .filter_map(|el| el.value().attr("src"))
+            .chain(doc.select(&svg_image).filter_map(|el| {
+                // `xlink:href` is namespaced, which `attr` doesn't look up
+                el.value()
+                    .attrs()
+                    .find(|(attr, _)| *attr == "href")
+                    .map(|(_, value)| value)
+            }));
+        for src in srcs {
+            if src.contains("://") || src.starts_with("data:") {
+                continue;
+            }
+            images += 1;
+            let target = resolve(&dir, src);
+            if !names.contains(&target) {
+                report
+                    .problems
+                    .push(format!("{} shows {}, which isn't in the book", name, src));
+            }
+        }
+    }
+    report
+        .checks
+        .push(format!("{} XHTML files are well-formed", pages.len()));
+    report
+        .checks
+        .push(format!("{} images in them are in the book", images));
+
+    match on_path("epubcheck") {
+        Some(epubcheck) => epubcheck_report(&epubcheck, path, &mut report)?,
+        None => log::info!("epubcheck isn't on the PATH, so the book isn't checked with it"),
+    }
+    Ok(report)
+}
+
+/// `href` as the name of an archive entry, relative to the directory `dir`.
+fn resolve(dir: &str, href: &str) -> String {
+    let href = href.split(['#', '?']).next().unwrap_or_default();
+    let mut parts: Vec<&str> = dir.split('/').filter(|part| !part.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Runs epubcheck on the book at `path` and adds the errors it reports to
+/// `report`.
+fn epubcheck_report(epubcheck: &Path, path: &Path, report: &mut VerifyReport) -> Result<()> {
+    report.checks.push("epubcheck finds no errors".into());
+    let output = Command::new(epubcheck)
+        .arg(path)
+        .output()
+        .wrap_err("could not run epubcheck")?;
+    let log = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &*String::from_utf8_lossy(&output.stderr);
+    // e.g. "ERROR(RSC-005): book.epub/OEBPS/chapter_1.xhtml(3,4): ..."
+    let errors: Vec<&str> = log
+        .lines()
+        .filter(|line| line.starts_with("ERROR") || line.starts_with("FATAL"))
+        .collect();
+    for error in &errors {
+        report.problems.push(format!("epubcheck: {}", error));
+    }
+    if errors.is_empty() && !output.status.success() {
+        report.problems.push(format!(
+            "epubcheck failed ({}): {}",
+            output.status,
+            log.trim()
+        ));
+    }
+    Ok(())
+}
+
+const FB2_NAMESPACE: &str = "http://www.gribuser.ru/xml/fictionbook/2.0";
+
+/// Reopens the FB2 file at `path` and checks that it is well-formed, has
+/// the metadata FB2 requires, at least one section, and that every image
+/// refers to an embedded binary that decodes.
+pub fn verify_fb2(path: impl AsRef<Path>) -> Result<VerifyReport> {
+    use base64::Engine;
+
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("could not open {}", path.display()))?;
+    let doc = parse_xml(&text);
+    let mut report = VerifyReport::default();
+
+    report.checks.push("the document is well-formed XML".into());
+    for error in &doc.errors {
+        report.problems.push(format!("XML error: {}", error));
+    }
+
+    report
+        .checks
+        .push("the root element is an FB2 <FictionBook>".into());
+    let root = doc.root_element();
+    if root.value().name() != "FictionBook" || &*root.value().name.ns != FB2_NAMESPACE {
+        report.problems.push(format!(
+            "unexpected root element <{}> in namespace {:?}",
+            root.value().name(),
+            &*root.value().name.ns
+        ));
+        return Ok(report);
+    }
+
+    let elements = |name: &'static str| {
+        root.descendants()
+            .filter_map(scraper::ElementRef::wrap)
+            .filter(move |el| el.value().name() == name)
+    };
+
+    report
+        .checks
+        .push("title-info has a genre, author, book-title and lang".into());
+    match elements("title-info").next() {
+        Some(info) => {
+            for required in ["genre", "author", "book-title", "lang"] {
+                let found = info
+                    .children()
+                    .filter_map(scraper::ElementRef::wrap)
+                    .any(|el| el.value().name() == required);
+                if !found {
+                    report
+                        .problems
+                        .push(format!("title-info has no <{}>", required));
+                }
+            }
+        }
+        None => report.problems.push("missing <title-info>".into()),
+    }
+
+    let sections = elements("section").count();
+    report
+        .checks
+        .push(format!("the body has sections ({})", sections));
+    if elements("body").next().is_none() || sections == 0 {
+        report.problems.push("the body has no sections".into());
+    }
+
+    let mut binaries = HashSet::new();
+    for binary in elements("binary") {
+        let Some(id) = binary.value().attr("id") else {
+            report.problems.push("binary without an id".into());
+            continue;
+        };
+        let data: String = binary.text().flat_map(|t| t.split_whitespace()).collect();
+        match base64::engine::general_purpose::STANDARD.decode(data) {
+            Ok(data) if data.is_empty() => report.problems.push(format!("binary {} is empty", id)),
+            Ok(_) => (),
+            Err(err) => report
+                .problems
+                .push(format!("binary {} is not valid base64: {}", id, err)),
+        }
+        binaries.insert(id.to_string());
+    }
+    report
+        .checks
+        .push(format!("{} binaries decode as base64", binaries.len()));
+
+    let mut images = 0;
+    for image in elements("image") {
+        images += 1;
+        // `l:href` is namespaced, which `attr` doesn't look up
+        let href = image
+            .value()
+            .attrs()
+            .find(|(name, _)| *name == "href")
+            .map(|(_, value)| value);
+        match href {
+            Some(href) if binaries.contains(href.trim_start_matches('#')) => (),
+            Some(href) => report
+                .problems
+                .push(format!("image refers to unknown binary {:?}", href)),
+            None => report.problems.push("image without an href".into()),
+        }
+    }
+    report
+        .checks
+        .push(format!("{} images refer to embedded binaries", images));
+
+    Ok(report)
+}
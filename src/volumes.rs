@@ -0,0 +1,417 @@
+//! Splitting a long story into several books, for readers that struggle
+//! with thousands of chapters in one file.
+
+use eyre::{eyre, Result, WrapErr};
+use regex::Regex;
+use scraper::Html;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::{
+    chapter_file, BookWriter, Chapter, Cover, Image, Manifest, Reception, Series, Story, Style,
+};
+
+/// Where a story is split into volumes.
+#[derive(Debug, Clone)]
+pub enum Split {
+    /// A new volume every this many chapters.
+    Chapters(usize),
+    /// A new volume once a volume has at least this many words.
+    Words(usize),
+    /// A volume for every volume the author filed chapters under.
+    Volume,
+    /// A volume for every part of the story a regex names in the chapter
+    /// titles, such as the arcs of `Arc (\d+)`, by its group named `volume`,
+    /// else its first group, else all it matches. A chapter it doesn't match
+    /// is in the part before it, and those before the first part in the
+    /// first part.
+    Pattern(Regex),
+}
+
+impl Split {
+    /// Parses `--split-every`: a number of chapters such as `500`, or of
+    /// words such as `250000w`.
+    pub fn every(spec: &str) -> Result<Split> {
+        let spec = spec.trim();
+        let (number, words) = match spec.strip_suffix(['w', 'W']) {
+            Some(number) => (number, true),
+            None => (spec, false),
+        };
+        let number: usize = number.trim().parse().wrap_err_with(|| {
+            format!("invalid --split-every {:?}, use e.g. 500 or 250000w", spec)
+        })?;
+        if number == 0 {
+            return Err(eyre!("--split-every can't be zero"));
+        }
+        Ok(if words {
+            Split::Words(number)
+        } else {
+            Split::Chapters(number)
+        })
+    }
+}
+
+/// The number of words in serialized chapter content.
+pub fn word_count(content: &str) -> usize {
+    Html::parse_fragment(content)
+        .root_element()
+        .text()
+        .map(|text| text.split_whitespace().count())
+        .sum()
+}
+
+/// The volume a title such as `Volume 2, Chapter 13`, `Vol. 2 Ch. 13`,
+/// `Book III - The Return` or `Chapter 5 [Arc 3]` names, as e.g. `Volume 2`.
+fn title_volume(name: &str) -> Option<String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        let volume = r"((?i:vol(?:ume)?\.?|book|arc|season))\s*(\d+|[IVXLC]+)";
+        Regex::new(&format!(
+            r"^\s*[\[(]?\s*{volume}\b|[\[(]\s*{volume}\s*[\])]"
+        ))
+        .unwrap()
+    });
+    let caps = re.captures(name)?;
+    let kind = caps.get(1).or(caps.get(3))?.as_str().to_lowercase();
+    let number = caps.get(2).or(caps.get(4))?.as_str();
+    let kind = match kind.trim_end_matches('.') {
+        "vol" | "volume" => "Volume",
+        "book" => "Book",
+        "arc" => "Arc",
+        _ => "Season",
+    };
+    Some(format!("{} {}", kind, number))
+}
+
+/// The volume `pattern` finds in a title: its group named `volume`, else its
+/// first group, else all it matches.
+fn pattern_volume(pattern: &Regex, name: &str) -> Option<String> {
+    let caps = pattern.captures(name)?;
+    let volume = caps
+        .name("volume")
+        .or_else(|| caps.get(1))
+        .or_else(|| caps.get(0))?;
+    Some(volume.as_str().trim().to_string()).filter(|volume| !volume.is_empty())
+}
+
+/// Files the chapters under volumes told from their titles, for stories
+/// whose site has no volumes but whose titles name them. With `pattern` its
+/// matches name the volumes, in place of any the site has; without, titles
+/// such as `Volume 2, Chapter 13` or `Chapter 5 [Arc 3]` do, unless the site
+/// has volumes. A chapter whose title names none, such as an interlude, is in
+/// the volume of the chapter before it. Returns the number of volumes.
+pub fn detect_volumes(chapters: &mut [Chapter], pattern: Option<&Regex>) -> usize {
+    if pattern.is_some() || chapters.iter().all(|chapter| chapter.volume.is_none()) {
+        let mut volume = None;
+        for chapter in chapters.iter_mut() {
+            let named = match pattern {
+                Some(pattern) => pattern_volume(pattern, &chapter.name),
+                None => title_volume(&chapter.name),
+            };
+            if named.is_some() {
+                volume = named;
+            }
+            chapter.volume = volume.clone();
+        }
+    }
+
+    let mut volumes = 0;
+    let mut last = None;
+    for chapter in chapters.iter() {
+        if chapter.volume.is_some() && chapter.volume.as_ref() != last {
+            volumes += 1;
+        }
+        last = chapter.volume.as_ref();
+    }
+    volumes
+}
+
+/// The file volume `nr` (from 1) of the book at `path` is written to, e.g.
+/// `Story Vol. 2.epub` for `Story.epub`.
+pub fn volume_path(path: &Path, nr: usize) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (stem, ext) = match name.strip_suffix(".kepub.epub") {
+        Some(stem) => (stem.to_string(), ".kepub.epub".to_string()),
+        None => match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+            _ => (name.clone(), String::new()),
+        },
+    };
+    path.with_file_name(format!("{} Vol. {}{}", stem, nr, ext))
+}
+
+struct Volume {
+    writer: Box<dyn BookWriter>,
+    story: Story,
+    /// The files of the chapters in this volume.
+    files: HashSet<String>,
+    words: usize,
+    label: Option<String>,
+}
+
+type NewWriter = Box<dyn Fn(&Story) -> Result<Box<dyn BookWriter>>>;
+
+/// Writes a story as several books, one per volume, each made by the
+/// writer `new_writer` returns. Chapters must be added in order.
+pub struct SplitWriter {
+    new_writer: NewWriter,
+    story: Story,
+    split: Split,
+    cover: Option<(Cover, bool)>,
+    /// Whether the volumes get a title page, and if it is in the table of
+    /// contents.
+    title_page: Option<bool>,
+    /// The same for the front matter.
+    front_matter: Option<bool>,
+    toc: bool,
+    /// Images added since the last chapter, which go with the next one.
+    images: Vec<Image>,
+    /// The series of the first volume; the others follow it.
+    series: Series,
+    style: Option<Style>,
+    volumes: Vec<Volume>,
+}
+
+impl SplitWriter {
+    pub fn new(
+        story: &Story,
+        split: Split,
+        new_writer: impl Fn(&Story) -> Result<Box<dyn BookWriter>> + 'static,
+    ) -> SplitWriter {
+        SplitWriter {
+            new_writer: Box::new(new_writer),
+            story: story.clone(),
+            split,
+            cover: None,
+            title_page: None,
+            front_matter: None,
+            toc: false,
+            images: Vec::new(),
+            series: Series {
+                name: story.title.clone(),
+                index: 1.0,
+            },
+            style: None,
+            volumes: Vec::new(),
+        }
+    }
+
+    /// The series of volume `nr` (from 1), the part `label` of the story.
+    /// Parts numbered in the titles keep their number, so the volumes of a
+    /// story downloaded from its third arc start at 3.
+    fn volume_series(&self, nr: usize, label: Option<&str>) -> Series {
+        let part = label
+            .filter(|_| matches!(self.split, Split::Pattern(_)))
+            .and_then(|label| label.split_whitespace().last())
+            .and_then(|number| number.parse::<f64>().ok());
+        Series {
+            name: self.series.name.clone(),
+            index: match part {
+                Some(part) => self.series.index + part - 1.0,
+                None => self.series.index + (nr - 1) as f64,
+            },
+        }
+    }
+
+    fn start_volume(&mut self, label: Option<String>) -> Result<()> {
+        let nr = self.volumes.len() + 1;
+        let mut story = self.story.clone();
+        story.title = format!("{} Vol. {}", self.story.title, nr);
+        story.chapters.clear();
+
+        let mut writer = (self.new_writer)(&story)?;
+        writer.set_series(&self.volume_series(nr, label.as_deref()));
+        if let Some(style) = &self.style {
+            writer.set_style(style);
+        }
+        if let Some((cover, in_toc)) = &self.cover {
+            writer.add_cover(cover, *in_toc)?;
+        }
+        if let Some(in_toc) = self.title_page {
+            writer.add_title_page(&story, in_toc)?;
+        }
+        if let Some(in_toc) = self.front_matter {
+            writer.add_front_matter(&story, in_toc)?;
+        }
+        if self.toc {
+            writer.add_toc();
+        }
+
+        self.volumes.push(Volume {
+            writer,
+            story,
+            files: HashSet::new(),
+            words: 0,
+            label,
+        });
+        Ok(())
+    }
+
+    /// The volume `chapter` says it is in.
+    fn label(&self, chapter: &Chapter) -> Option<String> {
+        match &self.split {
+            Split::Pattern(pattern) => pattern_volume(pattern, &chapter.name),
+            _ => chapter.volume.clone(),
+        }
+    }
+
+    /// Whether a chapter in the volume `label` starts a new volume.
+    fn starts_volume(&self, label: Option<&String>) -> bool {
+        let Some(volume) = self.volumes.last() else {
+            return true;
+        };
+        match self.split {
+            Split::Chapters(n) => volume.files.len() >= n,
+            Split::Words(n) => volume.words >= n,
+            Split::Volume => label != volume.label.as_ref(),
+            Split::Pattern(_) => {
+                label.is_some() && volume.label.is_some() && label != volume.label.as_ref()
+            }
+        }
+    }
+}
+
+impl BookWriter for SplitWriter {
+    fn add_cover(&mut self, cover: &Cover, in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_cover(cover, in_toc)?;
+        }
+        self.cover = Some((cover.clone(), in_toc));
+        Ok(())
+    }
+
+    fn add_title_page(&mut self, _story: &Story, in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_title_page(&volume.story, in_toc)?;
+        }
+        self.title_page = Some(in_toc);
+        Ok(())
+    }
+
+    fn add_front_matter(&mut self, _story: &Story, in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_front_matter(&volume.story, in_toc)?;
+        }
+        self.front_matter = Some(in_toc);
+        Ok(())
+    }
+
+    fn add_toc(&mut self) {
+        for volume in &mut self.volumes {
+            volume.writer.add_toc();
+        }
+        self.toc = true;
+    }
+
+    fn add_chapter(&mut self, nr: usize, chapter: &Chapter, content: &str) -> Result<()> {
+        let label = self.label(chapter);
+        if self.starts_volume(label.as_ref()) {
+            self.start_volume(label)?;
+        } else if matches!(self.split, Split::Pattern(_)) && label.is_some() {
+            // the chapters before the first part join it
+            let nr = self.volumes.len();
+            if self.volumes[nr - 1].label.is_none() {
+                let series = self.volume_series(nr, label.as_deref());
+                let volume = &mut self.volumes[nr - 1];
+                volume.writer.set_series(&series);
+                volume.label = label;
+            }
+        }
+
+        let volume = self.volumes.last_mut().unwrap();
+        for image in std::mem::take(&mut self.images) {
+            volume.writer.add_image(&image)?;
+        }
+        volume.writer.add_chapter(nr, chapter, content)?;
+        volume.files.insert(chapter_file(nr));
+        volume.story.chapters.push(chapter.clone());
+        if matches!(self.split, Split::Words(_)) {
+            volume.words += word_count(content);
+        }
+        Ok(())
+    }
+
+    fn add_image(&mut self, image: &Image) -> Result<()> {
+        self.images.push(image.clone());
+        Ok(())
+    }
+
+    fn add_source_page(&mut self, story: &Story, in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_source_page(story, in_toc)?;
+        }
+        Ok(())
+    }
+
+    fn add_colophon(&mut self, paragraphs: &[String], in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_colophon(paragraphs, in_toc)?;
+        }
+        Ok(())
+    }
+
+    fn add_reviews(&mut self, reception: &Reception, in_toc: bool) -> Result<()> {
+        for volume in &mut self.volumes {
+            volume.writer.add_reviews(reception, in_toc)?;
+        }
+        Ok(())
+    }
+
+    /// Every volume gets a manifest of its own chapters.
+    fn add_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        for volume in &mut self.volumes {
+            let mut manifest = manifest.clone();
+            manifest.title = volume.story.title.clone();
+            manifest
+                .chapters
+                .retain(|chapter| volume.files.contains(&chapter.file));
+            volume.writer.add_manifest(&manifest)?;
+        }
+        Ok(())
+    }
+
+    /// Only the last volume is missing chapters.
+    fn mark_incomplete(&mut self, _story: &Story, added: usize, total: usize) {
+        if let Some(volume) = self.volumes.last_mut() {
+            volume.writer.mark_incomplete(&volume.story, added, total);
+        }
+    }
+
+    fn set_series(&mut self, series: &Series) {
+        self.series = series.clone();
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        for volume in &mut self.volumes {
+            volume.writer.set_style(style);
+        }
+        self.style = Some(style.clone());
+    }
+
+    fn write(&mut self, _out: &mut dyn Write) -> Result<()> {
+        Err(eyre!("a split book is written as several files"))
+    }
+
+    fn write_to(&mut self, path: &Path) -> Result<()> {
+        if self.volumes.is_empty() {
+            self.start_volume(None)?;
+        }
+        for (i, volume) in self.volumes.iter_mut().enumerate() {
+            let path = volume_path(path, i + 1);
+            log::info!("writing {}", path.display());
+            volume.writer.write_to(&path)?;
+        }
+        Ok(())
+    }
+
+    fn files(&self, path: &Path) -> Vec<PathBuf> {
+        (1..=self.volumes.len().max(1))
+            .map(|nr| volume_path(path, nr))
+            .collect()
+    }
+}
@@ -0,0 +1,78 @@
+//! Removal of the hidden anti-piracy paragraphs Royal Road puts in chapters.
+//!
+//! The paragraphs ("this story has been stolen from Royal Road...") get a
+//! randomized class that a `<style>` block on the page hides, so readers on
+//! the site never see them. Copied into a book without that style they show
+//! up in the middle of the text.
+
+use ego_tree::NodeId;
+use regex::Regex;
+use scraper::{Html, Selector};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use crate::clean::find;
+
+fn rule() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"([^{}]+)\{([^{}]*)\}").unwrap())
+}
+
+fn hides(declarations: &str) -> bool {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?i)display\s*:\s*none").unwrap())
+        .is_match(declarations)
+}
+
+fn comment() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?s)/\*.*?\*/").unwrap())
+}
+
+/// The classes that the page's `<style>` blocks hide with plain `.class`
+/// selectors.
+fn hidden_classes(doc: &Html) -> HashSet<String> {
+    let mut classes = HashSet::new();
+
+    for style in doc.select(&Selector::parse("style").unwrap()) {
+        let css = style.text().collect::<String>();
+        let css = comment().replace_all(&css, "");
+        for rule in rule().captures_iter(&css) {
+            if !hides(&rule[2]) {
+                continue;
+            }
+            for selector in rule[1].split(',').map(str::trim) {
+                let Some(class) = selector.strip_prefix('.') else {
+                    continue;
+                };
+                if !class.is_empty()
+                    && class
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    classes.insert(class.to_string());
+                }
+            }
+        }
+    }
+
+    classes
+}
+
+/// Removes the elements below `content` that the page hides, either through
+/// a class from a `<style>` block or an inline `display: none`.
+pub(crate) fn strip_hidden(doc: &mut Html, content: NodeId) {
+    let classes = hidden_classes(doc);
+
+    let hidden = find(doc, content, |el| {
+        el.value().classes().any(|class| classes.contains(class))
+            || el.value().attr("style").is_some_and(hides)
+    });
+    if !hidden.is_empty() {
+        log::debug!("removing {} hidden element(s)", hidden.len());
+    }
+
+    for id in hidden {
+        doc.tree.get_mut(id).unwrap().detach();
+    }
+}
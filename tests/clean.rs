@@ -0,0 +1,184 @@
+//! The cleanup passes run over the recorded chapter pages in
+//! `tests/fixtures`.
+
+mod common;
+
+use common::fixture;
+use rustyroad::{parse_chapter_content, parse_shared_chapters, CleanOpts, NotePolicy, Typography};
+
+#[test]
+fn normalizes_scene_breaks() {
+    let clean = CleanOpts {
+        normalize_breaks: true,
+        ..CleanOpts::default()
+    };
+    let content = parse_chapter_content(&fixture("scene-breaks.html"), &clean).unwrap();
+
+    // runs of markers and `<hr>`s make one break each
+    assert_eq!(content.matches(r#"<hr class="scene-break"/>"#).count(), 6);
+    for marker in ["* * *", "~~~", "◇◇◇", "⁂", "***"] {
+        assert!(!content.contains(marker), "{} is still there", marker);
+    }
+    // not every short line is a break
+    assert!(content.contains("<p>-</p>"));
+    assert!(content.contains("<p>A single dash - like this - is not a break.</p>"));
+    assert!(content.contains("<p>Neither is a lone asterisk.</p>"));
+}
+
+#[test]
+fn leaves_scene_breaks_alone_by_default() {
+    let content =
+        parse_chapter_content(&fixture("scene-breaks.html"), &CleanOpts::default()).unwrap();
+
+    assert!(!content.contains("scene-break"));
+    assert!(content.contains(r#"<p style="text-align: center">* * *</p>"#));
+    assert!(content.contains("<p>***</p>"));
+}
+
+fn translated(policy: NotePolicy) -> String {
+    let clean = CleanOpts {
+        translator_notes: policy,
+        ..CleanOpts::default()
+    };
+    parse_chapter_content(&fixture("translated-chapter.html"), &clean).unwrap()
+}
+
+#[test]
+fn keeps_translator_notes_by_default() {
+    let content = translated(NotePolicy::Keep);
+
+    assert!(content.contains("<p>TL Note: The hour of the snake"));
+    assert!(content.contains(r#"<div class="tl-note">"#));
+    assert!(content.contains("<p>(T/N: qi is the life energy"));
+    assert!(!content.contains("tn-ref"));
+}
+
+#[test]
+fn strips_translator_notes() {
+    let content = translated(NotePolicy::Strip);
+
+    assert!(!content.contains("TL Note"));
+    assert!(!content.contains("T/N"));
+    assert!(!content.contains("tl-note"));
+    // the prose around them stays, including a line that only mentions a note
+    assert!(content.contains("<p>He bowed deeply, cupping his fist in salute.</p>"));
+    assert!(content.contains("the note he carried read only"));
+}
+
+#[test]
+fn moves_translator_notes_to_footnotes() {
+    let content = translated(NotePolicy::Footnote);
+
+    let notes = content.find(r#"<div class="translator-notes">"#).unwrap();
+    let (text, notes) = content.split_at(notes);
+    // each note is linked from the end of the paragraph before it
+    assert_eq!(text.matches(r#"<sup class="tn-ref">"#).count(), 3);
+    assert!(text.contains(r#"salute.<sup class="tn-ref">"#));
+    assert!(text.contains(r##"href="#tn-2""##));
+    assert!(!text.contains("TL Note") && !text.contains("T/N"));
+    assert!(notes.contains("TL Note: The hour of the snake"));
+    assert!(notes.contains("cupping the fist is a traditional martial greeting"));
+    assert!(notes.contains("(T/N: qi is the life energy"));
+    assert!(notes.contains(r##"<a href="#tnref-3">[TN3]</a>"##));
+}
+
+#[test]
+fn splits_a_page_shared_by_several_chapters() {
+    let chapters = parse_shared_chapters(
+        &fixture("anchor-split.html"),
+        &[None, Some("part-2"), Some("part-3")],
+        &CleanOpts::default(),
+    )
+    .unwrap();
+
+    assert_eq!(chapters.len(), 3);
+    assert!(chapters[0].contains("The Innkeeper"));
+    assert!(chapters[0].contains("waited for the night's first guest"));
+    assert!(!chapters[0].contains("The Bard"));
+    // an anchor on a heading starts at the heading
+    assert!(chapters[1].contains(r#"<h3 id="part-2">The Bard</h3>"#));
+    assert!(!chapters[1].contains("The Thief"));
+    // a named `<a>` starts at the paragraph holding it
+    assert!(chapters[2].contains("<strong>The Thief</strong>"));
+    assert!(chapters[2].contains("reached for the strongbox"));
+}
+
+#[test]
+fn missing_anchors_fail_the_split() {
+    let err = parse_shared_chapters(
+        &fixture("anchor-split.html"),
+        &[None, Some("part-9")],
+        &CleanOpts::default(),
+    )
+    .unwrap_err();
+
+    assert!(err.to_string().contains("#part-9"), "{}", err);
+}
+
+/// `text` as a paragraph of a chapter, cleaned with the default options.
+fn normalized(text: &str) -> String {
+    typeset(text, Typography::default())
+}
+
+#[test]
+fn repairs_mojibake() {
+    assert!(normalized("I donâ€™t know.").contains("<p>I don’t know.</p>"));
+    assert!(normalized("A cafÃ© au lait.").contains("<p>A café au lait.</p>"));
+}
+
+#[test]
+fn leaves_accents_before_punctuation_alone() {
+    for text in ["“Un café…”", "„Weiß“", "Déjà’s rosé—and crème”"] {
+        assert!(
+            normalized(text).contains(&format!("<p>{}</p>", text)),
+            "{} was changed",
+            text
+        );
+    }
+}
+
+#[test]
+fn keeps_runs_of_no_break_spaces() {
+    let content = normalized("HP:\u{a0}\u{a0}\u{a0}100   MP:  40");
+
+    // the no-break spaces line up a stat block, only the plain spaces collapse
+    assert!(
+        content.contains("<p>HP:&#160;&#160;&#160;100 MP: 40</p>"),
+        "{}",
+        content
+    );
+}
+
+/// `text` as a paragraph of a chapter, cleaned with `typography`.
+fn typeset(text: &str, typography: Typography) -> String {
+    let page = format!(
+        r#"<div class="chapter-inner chapter-content"><p>{}</p></div>"#,
+        text
+    );
+    let clean = CleanOpts {
+        typography,
+        ..CleanOpts::default()
+    };
+    parse_chapter_content(&page, &clean).unwrap()
+}
+
+#[test]
+fn leading_apostrophes_are_not_opening_quotes() {
+    let content = typeset(
+        "'Tis the '90s, and 'twas 'Hello' she said.",
+        Typography::Smart,
+    );
+
+    assert!(
+        content.contains("’Tis the ’90s, and ’twas ‘Hello’ she said."),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn straightening_keeps_primes() {
+    let content = typeset("“He’s 6′2″ tall.”", Typography::Straight);
+
+    assert!(content.contains(r#""He's 6′2″ tall.""#), "{}", content);
+}
@@ -0,0 +1,72 @@
+//! A [`Transport`] answering from the recorded pages in `tests/fixtures`,
+//! so the fetch pipeline runs end to end without the network.
+
+// each test crate uses only some of it
+#![allow(dead_code)]
+
+use futures::future::BoxFuture;
+use reqwest::{Request, Response};
+use rustyroad::{Client, ClientOpts, Transport};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const STORY_URL: &str = "https://www.royalroad.com/fiction/12345/the-example-story";
+
+/// Reads a fixture by its file name.
+pub fn fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+/// Pages by URL. Any other URL is answered with a 404.
+#[derive(Clone, Default)]
+pub struct Fixtures {
+    pages: HashMap<String, (u16, String)>,
+    /// The URLs asked for, in order.
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl Fixtures {
+    /// Answers `url` with the fixture `name`.
+    pub fn page(self, url: &str, name: &str) -> Fixtures {
+        self.respond(url, 200, fixture(name))
+    }
+
+    pub fn respond(mut self, url: &str, status: u16, body: impl Into<String>) -> Fixtures {
+        self.pages.insert(url.to_string(), (status, body.into()));
+        self
+    }
+
+    pub fn client(&self) -> Client {
+        let opts = ClientOpts {
+            max_retries: 0,
+            ..ClientOpts::default()
+        };
+        Client::with_transport(opts, self.clone()).unwrap()
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Transport for Fixtures {
+    fn execute(&self, request: Request) -> BoxFuture<'static, reqwest::Result<Response>> {
+        let url = request.url().to_string();
+        self.requests.lock().unwrap().push(url.clone());
+        let (status, body) = self
+            .pages
+            .get(&url)
+            .cloned()
+            .unwrap_or_else(|| (404, String::new()));
+        let resp = http::Response::builder()
+            .status(status)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(body)
+            .unwrap();
+        Box::pin(async move { Ok(Response::from(resp)) })
+    }
+}
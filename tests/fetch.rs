@@ -0,0 +1,498 @@
+//! The fetch pipeline run end to end against recorded pages: the story and
+//! chapter fetches, and the cleanup passes the chapters go through.
+//!
+//! When a site changes its markup, save the new page to `tests/fixtures`
+//! and add a test for it here, or to `tests/clean.rs` for a cleanup pass.
+
+mod common;
+
+use common::{fixture, Fixtures, STORY_URL};
+use rustyroad::{
+    chapter_url, fetch_chapter_content, fetch_shared_chapters, fetch_story, fetch_story_archived,
+    fetch_story_early, verify_fb2, BookWriter, Cache, ChapterError, CleanOpts, EpubWriter,
+    Fb2Builder, FetchOpts, HttpError, KepubWriter, ListedChapters, Story, Typography,
+};
+
+const CHAPTER_URL: &str =
+    "https://www.royalroad.com/fiction/12345/the-example-story/chapter/100002/chapter-1-beginnings";
+const SERIES_URL: &str =
+    "https://www.scribblehub.com/series/424242/the-lighthouse-keepers-apprentice/";
+
+async fn chapter(name: &str, clean: CleanOpts) -> String {
+    let fixtures = Fixtures::default().page(CHAPTER_URL, name);
+    fetch_chapter_content(
+        &fixtures.client(),
+        CHAPTER_URL,
+        &clean,
+        &FetchOpts::default(),
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn fetches_a_royal_road_story() {
+    let fixtures = Fixtures::default().page(STORY_URL, "fiction.html");
+    let story = fetch_story(&fixtures.client(), STORY_URL.to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(story.url, STORY_URL);
+    assert_eq!(story.title, "The Example Story");
+    assert_eq!(story.author, "Jane Quill");
+    assert_eq!(story.tags, ["Adventure", "Fantasy", "Progression"]);
+    assert_eq!(story.chapters.len(), 12);
+    assert_eq!(story.chapters[0].name, "Prologue");
+    assert_eq!(story.chapters[1].name, "Chapter 1 - Beginnings");
+    assert_eq!(
+        story.chapters[1].link,
+        "/fiction/12345/the-example-story/chapter/100002/chapter-1-beginnings"
+    );
+    assert_eq!(story.chapters[0].published, Some(1609459200));
+    // the whole chapter list is on the story page
+    assert_eq!(fixtures.requests(), [STORY_URL]);
+}
+
+#[tokio::test]
+async fn keeps_chapters_that_share_a_page_by_anchor() {
+    let page = fixture("fiction.html").replace(
+        "/chapter/100003/chapter-2-the-road",
+        "/chapter/100002/chapter-1-beginnings#part-2",
+    );
+    let fixtures = Fixtures::default().respond(STORY_URL, 200, page);
+    let story = fetch_story(&fixtures.client(), STORY_URL.to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(story.chapters.len(), 12);
+    assert_eq!(
+        story.chapters[2].link,
+        "/fiction/12345/the-example-story/chapter/100002/chapter-1-beginnings#part-2"
+    );
+}
+
+#[tokio::test]
+async fn fetches_every_page_of_a_scribble_hub_chapter_list() {
+    let mut fixtures = Fixtures::default().page(SERIES_URL, "scribblehub-series.html");
+    for page in 2..=4 {
+        fixtures = fixtures.page(
+            &format!("{}?toc={}", SERIES_URL, page),
+            &format!("scribblehub-series-{}.html", page),
+        );
+    }
+    let story = fetch_story(&fixtures.client(), SERIES_URL.to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(story.title, "The Lighthouse Keeper's Apprentice");
+    assert_eq!(story.author, "Moss Harbor");
+    let names: Vec<&str> = story.chapters.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names.len(), 12);
+    assert_eq!(names[0], "Chapter 1 - The Rock");
+    assert_eq!(names[11], "Chapter 12 - Low Tide");
+    assert_eq!(fixtures.requests().len(), 4);
+}
+
+#[tokio::test]
+async fn lists_the_first_page_of_the_chapter_list_early() {
+    let mut fixtures = Fixtures::default().page(SERIES_URL, "scribblehub-series.html");
+    for page in 2..=4 {
+        fixtures = fixtures.page(
+            &format!("{}?toc={}", SERIES_URL, page),
+            &format!("scribblehub-series-{}.html", page),
+        );
+    }
+    let mut early = None;
+    let story = fetch_story_early(
+        &fixtures.client(),
+        SERIES_URL.to_string(),
+        None,
+        |listed, part| {
+            let names: Vec<String> = listed.iter().map(|c| c.name.clone()).collect();
+            early = Some((names, part, fixtures.requests().len()));
+        },
+    )
+    .await
+    .unwrap();
+
+    // the story page holds the newest chapters, and the rest is read after
+    let (names, part, requests) = early.unwrap();
+    assert_eq!(part, ListedChapters::Last);
+    assert_eq!(requests, 1);
+    assert!(!names.is_empty() && names.len() < story.chapters.len());
+    let last: Vec<&str> = story.chapters[story.chapters.len() - names.len()..]
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect();
+    assert_eq!(names, last);
+}
+
+#[tokio::test]
+async fn takes_a_prefetched_chapter_page() {
+    let fixtures = Fixtures::default().page(CHAPTER_URL, "chapter.html");
+    let client = fixtures.client();
+    let fetch = FetchOpts::default();
+    fetch.prefetch.start(&client, CHAPTER_URL, &fetch).unwrap();
+    assert!(fetch.prefetch.contains(CHAPTER_URL));
+
+    let dir = tempfile::tempdir().unwrap();
+    let raw = dir.path().join("chapter.html");
+    let content = fetch_chapter_content(
+        &client,
+        CHAPTER_URL,
+        &CleanOpts::default(),
+        &fetch,
+        Some(&raw),
+    )
+    .await
+    .unwrap();
+    assert!(content.contains("Cartographer the north through hills"));
+    assert_eq!(
+        std::fs::read_to_string(&raw).unwrap(),
+        fixture("chapter.html")
+    );
+    assert_eq!(fixtures.requests(), [CHAPTER_URL]);
+
+    // it is taken once, and a page no chapter is on any more is dropped
+    assert!(!fetch.prefetch.contains(CHAPTER_URL));
+    fetch.prefetch.start(&client, CHAPTER_URL, &fetch).unwrap();
+    fetch.prefetch.keep([STORY_URL]);
+    assert!(!fetch.prefetch.contains(CHAPTER_URL));
+}
+
+#[tokio::test]
+async fn archives_the_story_page() {
+    let dir = tempfile::tempdir().unwrap();
+    let fixtures = Fixtures::default().page(STORY_URL, "fiction.html");
+    fetch_story_archived(&fixtures.client(), STORY_URL.to_string(), Some(dir.path()))
+        .await
+        .unwrap();
+
+    let saved = std::fs::read_to_string(dir.path().join("story.html")).unwrap();
+    assert_eq!(saved, fixture("fiction.html"));
+}
+
+#[tokio::test]
+async fn cloudflare_challenges_fail_the_fetch() {
+    let fixtures = Fixtures::default().page(STORY_URL, "challenge.html");
+    let err = fetch_story(&fixtures.client(), STORY_URL.to_string())
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref::<HttpError>(),
+        Some(HttpError::Challenge { .. })
+    ));
+}
+
+#[tokio::test]
+async fn missing_chapters_are_not_found() {
+    let fixtures = Fixtures::default();
+    let err = fetch_chapter_content(
+        &fixtures.client(),
+        CHAPTER_URL,
+        &CleanOpts::default(),
+        &FetchOpts::default(),
+        None,
+    )
+    .await
+    .unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref::<ChapterError>(),
+        Some(ChapterError::NotFound { url }) if url == CHAPTER_URL
+    ));
+}
+
+/// Fetches a chapter page holding `text`, returning its content, how many
+/// chapters were suspect and the pages requested.
+async fn walled(text: &str) -> (String, usize, Vec<String>) {
+    let page = format!(
+        r#"<div class="chapter-inner chapter-content"><p>{}</p></div>"#,
+        text
+    );
+    let fixtures = Fixtures::default().respond(CHAPTER_URL, 200, page);
+    let client = fixtures.client();
+    let content = fetch_chapter_content(
+        &client,
+        CHAPTER_URL,
+        &CleanOpts::default(),
+        &FetchOpts::default(),
+        None,
+    )
+    .await
+    .unwrap();
+    (content, client.suspects(), fixtures.requests())
+}
+
+#[tokio::test]
+async fn keeps_walls_as_suspect_chapters() {
+    let (content, suspects, requests) =
+        walled("Please enable JavaScript to read this chapter.").await;
+
+    assert!(content.contains("enable JavaScript"));
+    assert_eq!(suspects, 1);
+    // fetched once more before giving up on it
+    assert_eq!(requests, [CHAPTER_URL, CHAPTER_URL]);
+}
+
+#[tokio::test]
+async fn short_chapters_mentioning_a_wall_are_not_suspect() {
+    let text = "The terminal flickered. [Access denied.] Zed swore and kicked the \
+        console, then tried the override code the old engineer had given him. \
+        The screen went dark for a long moment before the lock finally clicked \
+        open and the door to the reactor hall slid aside.";
+    let (_, suspects, requests) = walled(text).await;
+
+    assert_eq!(suspects, 0);
+    assert_eq!(requests, [CHAPTER_URL]);
+}
+
+#[tokio::test]
+async fn saves_the_raw_chapter_page() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = dir.path().join("chapter.html");
+    let fixtures = Fixtures::default().page(CHAPTER_URL, "chapter.html");
+    fetch_chapter_content(
+        &fixtures.client(),
+        CHAPTER_URL,
+        &CleanOpts::default(),
+        &FetchOpts::default(),
+        Some(&raw),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(raw).unwrap(),
+        fixture("chapter.html")
+    );
+}
+
+#[tokio::test]
+async fn refreshing_sees_pages_cached_without_validators_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let fetch = |fixtures: Fixtures, refresh: bool| {
+        let cache = Cache::new(dir.path()).unwrap();
+        async move {
+            let fetch = FetchOpts {
+                cache: Some(cache),
+                refresh,
+                ..FetchOpts::default()
+            };
+            let client = fixtures.client();
+            fetch_chapter_content(&client, CHAPTER_URL, &CleanOpts::default(), &fetch, None)
+                .await
+                .unwrap()
+        }
+    };
+    let revised = || Fixtures::default().page(CHAPTER_URL, "watermarked-chapter.html");
+
+    let first = fetch(Fixtures::default().page(CHAPTER_URL, "chapter.html"), false).await;
+    assert!(first.contains("Cartographer the north"));
+    // the page has no ETag or Last-Modified, so the cached copy is used as is
+    let cached = revised();
+    assert_eq!(fetch(cached.clone(), false).await, first);
+    assert!(cached.requests().is_empty());
+
+    let refreshed = revised();
+    let content = fetch(refreshed.clone(), true).await;
+    assert!(content.contains("The pass was narrow"));
+    assert_eq!(refreshed.requests(), [CHAPTER_URL]);
+    // and the cache holds the revision from then on
+    assert_eq!(fetch(revised(), false).await, content);
+}
+
+#[tokio::test]
+async fn extracts_the_chapter_content() {
+    let content = chapter("chapter.html", CleanOpts::default()).await;
+
+    assert!(content.starts_with(r#"<div class="chapter-inner chapter-content">"#));
+    assert!(content.contains("<p>Cartographer the north through hills"));
+    assert!(!content.contains("fic-header"));
+    // authors' notes are left out unless asked for
+    assert!(!content.contains("Welcome to the first chapter!"));
+    assert!(!content.contains("Next chapter goes up on Thursday."));
+}
+
+#[tokio::test]
+async fn extracts_a_scribble_hub_chapter() {
+    let url =
+        "https://www.scribblehub.com/read/424242-the-lighthouse-keepers-apprentice/chapter/900010/";
+    let fixtures = Fixtures::default().page(url, "scribblehub-chapter.html");
+    let content = fetch_chapter_content(
+        &fixtures.client(),
+        url,
+        &CleanOpts::default(),
+        &FetchOpts::default(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert!(content.contains("The fog came in before supper"));
+    assert!(content.contains("Three times, patiently."));
+    assert!(!content.contains("Chapter 10 - Fog"));
+    assert!(!content.contains("Thanks for reading!"));
+}
+
+#[tokio::test]
+async fn keeps_authors_notes_when_asked() {
+    let clean = CleanOpts {
+        authors_notes: true,
+        ..CleanOpts::default()
+    };
+    let content = chapter("chapter.html", clean).await;
+
+    let before = content.find("Welcome to the first chapter!").unwrap();
+    let text = content.find("Cartographer the north").unwrap();
+    let after = content.find("Next chapter goes up on Thursday.").unwrap();
+    assert!(before < text && text < after);
+    assert_eq!(content.matches(r#"<div class="authors-note">"#).count(), 2);
+}
+
+#[tokio::test]
+async fn strips_hidden_watermarks() {
+    let content = chapter("watermarked-chapter.html", CleanOpts::default()).await;
+
+    assert!(content.contains("The pass was narrow"));
+    assert!(content.contains("She pulled her coat tighter"));
+    assert!(content.contains(r#"<p class="visible-note">Far below"#));
+    assert!(!content.contains("Royal Road"));
+    assert!(!content.contains("Amazon"));
+}
+
+#[tokio::test]
+async fn applies_smart_typography() {
+    let clean = CleanOpts {
+        typography: Typography::Smart,
+        ..CleanOpts::default()
+    };
+    let content = chapter("translated-chapter.html", clean).await;
+
+    assert!(content.contains("“Junior brother, you are late,” the elder said."));
+    assert!(content.contains("“Meet me at the pavilion.”"));
+}
+
+#[tokio::test]
+async fn fetches_a_page_shared_by_several_chapters_once() {
+    let fixtures = Fixtures::default().page(CHAPTER_URL, "anchor-split.html");
+    let chapters = fetch_shared_chapters(
+        &fixtures.client(),
+        CHAPTER_URL,
+        &[None, Some("part-2"), Some("part-3")],
+        &CleanOpts::default(),
+        &FetchOpts::default(),
+        None,
+    )
+    .await
+    .unwrap();
+
+    // how the page is split is up to tests/clean.rs
+    assert_eq!(chapters.len(), 3);
+    assert_eq!(fixtures.requests(), [CHAPTER_URL]);
+}
+
+/// The example story with its first chapters fetched from fixtures, and
+/// their content.
+async fn example_story() -> (Story, Vec<String>) {
+    let pages = [
+        "chapter.html",
+        "styled-chapter.html",
+        "watermarked-chapter.html",
+    ];
+    let mut fixtures = Fixtures::default().page(STORY_URL, "fiction.html");
+    let client = fixtures.client();
+    let mut story = fetch_story(&client, STORY_URL.to_string()).await.unwrap();
+    story.chapters.truncate(pages.len());
+    for (chapter, page) in story.chapters.iter().zip(pages) {
+        fixtures = fixtures.page(chapter_url(&chapter.link).unwrap().as_str(), page);
+    }
+
+    let client = fixtures.client();
+    let mut contents = Vec::new();
+    for chapter in &story.chapters {
+        let url = chapter_url(&chapter.link).unwrap();
+        let content = fetch_chapter_content(
+            &client,
+            url.as_str(),
+            &CleanOpts::default(),
+            &FetchOpts::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        contents.push(content);
+    }
+    (story, contents)
+}
+
+fn write_book(mut writer: impl BookWriter, story: &Story, contents: &[String]) -> Vec<u8> {
+    writer.add_toc();
+    for (nr, (chapter, content)) in story.chapters.iter().zip(contents).enumerate() {
+        writer.add_chapter(nr, chapter, content).unwrap();
+    }
+    let mut out = Vec::new();
+    writer.write(&mut out).unwrap();
+    out
+}
+
+#[tokio::test]
+async fn reproducible_books_are_byte_identical() {
+    let mut books = Vec::new();
+    // each run parses the pages afresh, as separate runs of the command do
+    for _ in 0..2 {
+        let (story, contents) = example_story().await;
+        let mut epub = EpubWriter::new(&story).unwrap();
+        epub.set_reproducible(&story);
+        let mut kepub = KepubWriter::new(&story).unwrap();
+        kepub.set_reproducible(&story);
+        let epub = write_book(epub, &story, &contents);
+        let kepub = write_book(kepub, &story, &contents);
+        books.push((epub, kepub));
+    }
+
+    assert!(books[0].0 == books[1].0, "the EPUBs differ");
+    assert!(books[0].1 == books[1].1, "the kepubs differ");
+}
+
+#[tokio::test]
+async fn writes_an_fb2_that_opens_again() {
+    let (story, contents) = example_story().await;
+    let mut writer = Fb2Builder::new(&story);
+    writer.add_source_page(&story, true).unwrap();
+    let book = write_book(writer, &story, &contents);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("book.fb2");
+    std::fs::write(&path, &book).unwrap();
+
+    let report = verify_fb2(&path).unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    let text = String::from_utf8(book).unwrap();
+    assert!(text.contains("<book-title>The Example Story</book-title>"));
+    for (nr, chapter) in story.chapters.iter().enumerate() {
+        let section = format!(
+            "<section id=\"chapter-{}\"><title><p>{}</p></title>",
+            nr, chapter.name
+        );
+        assert!(text.contains(&section), "no section for {}", chapter.name);
+    }
+    assert!(text.contains("The ford was deeper than the map had promised."));
+    assert!(text.contains(r#"<section id="source">"#));
+}
+
+#[tokio::test]
+async fn an_incomplete_epub_ends_with_a_notice() {
+    let (story, contents) = example_story().await;
+    let mut writer = EpubWriter::new(&story).unwrap();
+    writer.mark_incomplete(&story, 2, story.chapters.len());
+    let book = write_book(writer, &story, &contents[..2]);
+
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(book)).unwrap();
+    let mut notice = String::new();
+    let mut page = zip.by_name("OEBPS/incomplete.xhtml").unwrap();
+    std::io::Read::read_to_string(&mut page, &mut notice).unwrap();
+    assert!(notice.contains("This book only contains 2 of 3 chapters"));
+}